@@ -0,0 +1,32 @@
+use std::rc::Rc;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use ceya::{environment::EnvironmentArena, parser, scanner, vm};
+
+/// A loop calling a recursive function, walked by the interpreter and run as bytecode by `sim --vm`.
+fn loops(c: &mut Criterion) {
+    let source = "fn fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        let total = 0; for (let i = 0; i < 100; i = i + 1) { total = total + fib(10); }";
+    let stmts = parser::parse(scanner::scan(source)).unwrap();
+
+    c.bench_function("loops_interpreter", |b| b.iter(|| {
+        let mut env_arena = EnvironmentArena::new();
+        let env = env_arena.add(None);
+        for stmt in &stmts {
+            stmt.execute(&mut env_arena, env);
+        }
+        black_box(env_arena)
+    }));
+
+    let script = vm::compile(&stmts);
+    c.bench_function("loops_vm", |b| b.iter(|| {
+        let mut env_arena = EnvironmentArena::new();
+        let env = env_arena.add(None);
+        vm::run(Rc::clone(&script), &mut env_arena, env);
+        black_box(env_arena)
+    }));
+}
+
+criterion_group!(benches, loops);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use ceya::{ast::Value, environment::EnvironmentArena, parser, scanner};
+
+/// Reading a string, from a literal or a variable, shares it instead of copying it.
+fn strings(c: &mut Criterion) {
+    let text = "ceya ".repeat(200);
+    let mut env_arena = EnvironmentArena::new();
+    let env = env_arena.add(None);
+    env_arena.define(env, "text", Value::String(text.as_str().into()));
+
+    let literal = parser::parse_expression(scanner::scan(&format!("\"{0}\" == \"{0}\"", text))).unwrap();
+    c.bench_function("string_literal", |b| b.iter(|| black_box(literal.evaluate(&mut env_arena, env))));
+
+    let variable = parser::parse_expression(scanner::scan("text == text")).unwrap();
+    c.bench_function("string_variable", |b| b.iter(|| black_box(variable.evaluate(&mut env_arena, env))));
+}
+
+criterion_group!(benches, strings);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+#ifndef CEYA_H
+#define CEYA_H
+
+/* Link against the ceya cdylib (ceya.dll / libceya.so). Returned strings must be released with ceya_free. */
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Runs a program and returns what it printed, NULL if source is not valid UTF-8. */
+char *ceya_run(const char *source);
+
+/* Evaluates an expression and returns its value as text, NULL if it doesn't parse. */
+char *ceya_eval(const char *expr);
+
+void ceya_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -1,67 +1,703 @@
-use crate::{scanner::{Token, TokenType}, environment::EnvironmentArena};
-use std::{fmt::{Debug, Formatter, Error, Display, Write}, rc::Rc, str::FromStr};
-use rand::Rng;
+use crate::{scanner::{self, Span, Token, TokenType}, environment::EnvironmentArena, ir::Block, vm, backend::{emit_call_function, emit_depth, emit_procedure, peephole, emit_select, emit_xor, DataSection, Stream, Target}, runtime::{self, emit_print, emit_write}, stdlib, typecheck::Type, error::{self, CeyaError}};
+use std::{cell::{Cell, RefCell, RefMut}, collections::{hash_map::Entry, HashMap}, fmt::{Debug, Formatter, Error, Display, Write}, rc::Rc, str::FromStr};
+use clap::ValueEnum;
 
 #[derive(Clone)]
 pub enum Fun { // TODO: make this an enum with 1 variant with a callee, so we can execute native functions
     Code    { name: String, params: Vec<Rc<Token>>, body: Rc<Stmt>, closure: usize },
-    Native  { name: String, params: Vec<Rc<Token>>, callee: Rc<dyn Fn(Vec<Value>) -> Value> }
+    /// A Rust function, failing with the message of a runtime error.
+    Native  { name: String, arity: Option<usize>, callee: Rc<dyn Fn(Vec<Value>) -> Result<Value, String>> },
+    /// Function compiled by `sim --vm`, with the variables it captured from the enclosing functions.
+    Bytecode { function: Rc<vm::Function>, captures: Rc<[Rc<RefCell<Value>>]> }
+}
+
+/// Deepest nesting of calls to ceya functions in `sim`, each of them recursing through the interpreter.
+pub const MAX_CALL_DEPTH: usize = 200;
+
+impl PartialEq for Fun {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Code { body: a, closure: ca, .. }, Self::Code { body: b, closure: cb, .. }) => Rc::ptr_eq(a, b) && ca == cb,
+            (Self::Native { callee: a, .. }, Self::Native { callee: b, .. }) => Rc::ptr_eq(a, b),
+            (Self::Bytecode { function: a, captures: ca }, Self::Bytecode { function: b, captures: cb }) => Rc::ptr_eq(a, b) && Rc::ptr_eq(ca, cb),
+            _ => false
+        }
+    }
 }
 
 impl Fun {
-    fn call(&self, arguments: Vec<Value>, env_arena: &mut EnvironmentArena) -> Value {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Code { name, .. } => name,
+            Self::Native { name, .. } => name,
+            Self::Bytecode { function, .. } => &function.name
+        }
+    }
+
+    /// Number of arguments the function expects, `None` for variadic natives.
+    pub fn arity(&self) -> Option<usize> {
+        match self {
+            Self::Code { params, .. } => Some(params.len()),
+            Self::Native { arity, .. } => *arity,
+            Self::Bytecode { function, .. } => Some(function.arity)
+        }
+    }
+
+    /// Calls the function, its errors reported at `span` and giving `fu`.
+    pub(crate) fn call(&self, arguments: Vec<Value>, span: Span, env_arena: &mut EnvironmentArena) -> Value {
+        // the rest of the expression that failed doesn't run
+        if env_arena.failed() {
+            return Value::Null;
+        }
+
         match self {
-            Self::Code { name: _, params, body, closure } => {
+            Self::Code { name, params, body, closure } => {
+                if env_arena.calls >= MAX_CALL_DEPTH {
+                    env_arena.report(stack_overflow(name, span));
+                    return Value::Null;
+                }
+
+                // parameters live next to the captured environment, so the body sees both
                 let env = env_arena.add(Some(*closure));
-                for (i, el) in params.iter().enumerate() {
-                    env_arena.define(env, &el.lexeme, arguments.get(i).unwrap().clone());
+                for (param, argument) in params.iter().zip(arguments) {
+                    env_arena.define(env, &param.lexeme, argument);
                 }
-                if let Some(v) = body.execute(env_arena, env) {
-                    return v;
+                env_arena.calls += 1;
+                let depth = env_arena.stack.len();
+                let res = body.execute(env_arena, env);
+                // like the compiled epilogue, returning drops what the body left on the stack
+                env_arena.stack.truncate(depth);
+                env_arena.calls -= 1;
+                match res {
+                    Flow::Return(value) => value,
+                    _ => Value::Null
                 }
-                Value::Null
             },
-            Self::Native { name: _, params: _, callee } => {
-                (callee)(arguments)
-            }
+            Self::Native { name: _, arity: _, callee } => {
+                (callee)(arguments).unwrap_or_else(|message| {
+                    env_arena.report(CeyaError::Runtime { span, message });
+                    Value::Null
+                })
+            },
+            Self::Bytecode { .. } => unreachable!("bytecode functions only exist while the VM runs")
         }
     }
 }
 
+pub(crate) fn stack_overflow(name: &str, span: Span) -> CeyaError {
+    CeyaError::Runtime { span, message: format!("Stack overflow calling '{}', more than {} nested calls.", name, MAX_CALL_DEPTH) }
+}
+
 #[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Value {
-    String(String),
+    String(Rc<str>),
     Number(f64),
     Boolean(bool),
     Null,
-    // Fun(Fun)
+    /// Shared by every variable holding it: changing an element through one changes it for all.
+    List(Rc<RefCell<Vec<Value>>>),
+    /// Shared like lists.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Map(Rc<RefCell<Map>>),
+    /// A `struct` declaration, which `Name { field: value }` makes instances of.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Struct(Rc<Struct>),
+    /// Shared like lists.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Instance(Rc<RefCell<Instance>>),
+    /// The values of `return a, b;`, which `let (a, b) = f();` unpacks.
+    Tuple(Rc<[Value]>),
+    /// Functions hold closures and environments, they can't be persisted.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Fun(Fun)
+}
+
+impl Value {
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Boolean(b) => *b,
+            Value::Null => false,
+            Value::String(s) => !s.is_empty(),
+            Value::List(list) => !list.borrow().is_empty(),
+            Value::Map(map) => !map.borrow().is_empty(),
+            Value::Struct(_) | Value::Instance(_) | Value::Tuple(_) => true,
+            Value::Number(n) => *n != 0.,
+            Value::Fun(_fun) => true
+        }
+    }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         match *self {
             Value::String(ref s) => write!(f, "{}", s),
-            Value::Number(n) => write!(f, "{}", n),
+            Value::Number(n) => write!(f, "{}", format_number(n)),
             Value::Boolean(b) => write!(f, "{}", b),
             Value::Null => write!(f, "null"),
-            // Value::Fun(ref fun) => write!(f, "fun {}", match fun { 
-            //     Fun::Code { ref name, params: _, body: _, closure: _ } => name,
-            //     Fun::Native { ref name, params: _, callee: _ } => name
-            //  })
+            Value::List(ref list) => {
+                write!(f, "[")?;
+                for (i, element) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    fmt_element(element, f)?;
+                }
+                write!(f, "]")
+            },
+            Value::Map(ref map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().entries().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    fmt_element(key, f)?;
+                    write!(f, ": ")?;
+                    fmt_element(value, f)?;
+                }
+                write!(f, "}}")
+            },
+            Value::Struct(ref class) => write!(f, "struct {}", class.name),
+            Value::Instance(ref instance) => {
+                let instance = instance.borrow();
+                write!(f, "{} {{", instance.class.name)?;
+                for (i, (field, value)) in instance.class.fields.iter().zip(&instance.values).enumerate() {
+                    write!(f, "{} {}: ", if i > 0 { "," } else { "" }, field)?;
+                    fmt_element(value, f)?;
+                }
+                write!(f, "{}}}", if instance.values.is_empty() { "" } else { " " })
+            },
+            Value::Tuple(ref values) => {
+                write!(f, "(")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    fmt_element(value, f)?;
+                }
+                write!(f, ")")
+            },
+            Value::Fun(ref fun) => write!(f, "fun {}", fun.name())
+        }
+    }
+}
+
+/// Like inside a list, for the messages of failing tests.
+impl Debug for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        fmt_element(self, f)
+    }
+}
+
+/// A value inside a list or a map, strings quoted so that `["1"]` and `[1]` print differently.
+fn fmt_element(value: &Value, f: &mut Formatter<'_>) -> Result<(), Error> {
+    match value {
+        Value::String(s) => write!(f, "\"{}\"", s),
+        value => write!(f, "{}", value)
+    }
+}
+
+/// Entries of a map, kept in insertion order, which `keys` and `values` list them in.
+#[derive(Clone, Default, PartialEq)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,
+    positions: HashMap<Key, usize>
+}
+
+/// A map key, compared by value: numbers by their bits, `-0` being `0`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    String(Rc<str>),
+    Number(u64),
+    Boolean(bool)
+}
+
+impl Key {
+    fn of(value: &Value, span: Span) -> Result<Key, CeyaError> {
+        match value {
+            Value::String(s) => Ok(Key::String(s.clone())),
+            Value::Number(n) => Ok(Key::Number((n + 0.).to_bits())),
+            Value::Boolean(b) => Ok(Key::Boolean(*b)),
+            _ => Err(CeyaError::Runtime { span, message: "Map keys must be strings, numbers or booleans.".into() })
+        }
+    }
+}
+
+impl Map {
+    /// A map of the keys and values alternating in `values`, later keys replacing earlier ones.
+    pub(crate) fn from_pairs(values: Vec<Value>, span: Span) -> Result<Map, CeyaError> {
+        let mut map = Map::default();
+        let mut values = values.into_iter();
+        while let (Some(key), Some(value)) = (values.next(), values.next()) {
+            map.insert(key, value, span)?;
+        }
+        Ok(map)
+    }
+
+    fn get(&self, key: &Value, span: Span) -> Result<Option<&Value>, CeyaError> {
+        Ok(self.positions.get(&Key::of(key, span)?).map(|&i| &self.entries[i].1))
+    }
+
+    fn insert(&mut self, key: Value, value: Value, span: Span) -> Result<(), CeyaError> {
+        match self.positions.entry(Key::of(&key, span)?) {
+            Entry::Occupied(entry) => self.entries[*entry.get()].1 = value,
+            Entry::Vacant(entry) => {
+                entry.insert(self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Name and fields of a `struct` declaration, with its operator methods.
+#[derive(PartialEq)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<String>,
+    pub methods: Vec<Fun>
+}
+
+/// Methods a struct can declare for binary operators, called with both operands when the left one
+/// is an instance of it.
+pub const OPERATOR_METHODS: [(TokenType, &str); 10] = [
+    (TokenType::Plus, "__add__"),
+    (TokenType::Minus, "__sub__"),
+    (TokenType::Star, "__mul__"),
+    (TokenType::Slash, "__div__"),
+    (TokenType::EqualEqual, "__eq__"),
+    (TokenType::BangEqual, "__ne__"),
+    (TokenType::Less, "__lt__"),
+    (TokenType::LessEqual, "__le__"),
+    (TokenType::Greater, "__gt__"),
+    (TokenType::GreaterEqual, "__ge__")
+];
+
+/// The method `l operator r` calls in `sim` when `l` is an instance of a struct declaring it, and
+/// whether its result is negated: `!=` falls back to `__eq__` when there is no `__ne__`.
+pub(crate) fn overload(operator: &TokenType, l: &Value) -> Option<(Fun, bool)> {
+    let Value::Instance(instance) = l else { return None; };
+    let class = Rc::clone(&instance.borrow().class);
+    let method = |name: &str| class.methods.iter().find(|method| method.name() == name).cloned();
+    let (_, name) = OPERATOR_METHODS.iter().find(|(other, _)| other == operator)?;
+    match method(name) {
+        Some(method) => Some((method, false)),
+        None if *operator == TokenType::BangEqual => method("__eq__").map(|method| (method, true)),
+        None => None
+    }
+}
+
+/// Values of the fields of an instance, in the order of its struct's declaration.
+#[derive(PartialEq)]
+pub struct Instance {
+    pub class: Rc<Struct>,
+    pub values: Vec<Value>
+}
+
+/// `Name { field: value }` in `sim`, with the fields left out set to `fu`.
+pub(crate) fn instantiate(class: Value, fields: Vec<(&str, Value)>, span: Span) -> Result<Value, CeyaError> {
+    let Value::Struct(class) = class else {
+        return Err(CeyaError::Runtime { span, message: format!("Can only instantiate structs, not '{}'.", class) });
+    };
+    let mut values = vec![Value::Null; class.fields.len()];
+    for (name, value) in fields {
+        values[field_index(&class, name, span)?] = value;
+    }
+    Ok(Value::Instance(Rc::new(RefCell::new(Instance { class, values }))))
+}
+
+fn field_index(class: &Struct, name: &str, span: Span) -> Result<usize, CeyaError> {
+    class.fields.iter().position(|field| field == name)
+        .ok_or_else(|| CeyaError::Runtime { span, message: format!("Struct {} has no field '{}'.", class.name, name) })
+}
+
+/// `object.name` in `sim`, shared by the interpreter and the VM.
+pub(crate) fn get_field(object: Value, name: &str, span: Span) -> Result<Value, CeyaError> {
+    match object {
+        Value::Instance(instance) => {
+            let instance = instance.borrow();
+            Ok(instance.values[field_index(&instance.class, name, span)?].clone())
+        },
+        _ => Err(CeyaError::Runtime { span, message: "Only struct instances have fields.".into() })
+    }
+}
+
+/// `object.name = value` in `sim`, giving `value` like other assignments.
+pub(crate) fn set_field(object: Value, name: &str, value: Value, span: Span) -> Result<Value, CeyaError> {
+    match object {
+        Value::Instance(instance) => {
+            let mut instance = instance.borrow_mut();
+            let index = field_index(&instance.class, name, span)?;
+            instance.values[index] = value.clone();
+            Ok(value)
+        },
+        _ => Err(CeyaError::Runtime { span, message: "Only struct instances have fields.".into() })
+    }
+}
+
+/// The values of `let (a, b) = value;` in `sim`, shared by the interpreter and the VM.
+pub(crate) fn unpack(value: Value, count: usize, span: Span) -> Result<Vec<Value>, CeyaError> {
+    match value {
+        Value::Tuple(values) if values.len() == count => Ok(values.to_vec()),
+        Value::Tuple(values) => Err(CeyaError::Runtime { span, message: format!("Expected {} values, but found {}.", count, values.len()) }),
+        _ => Err(CeyaError::Runtime { span, message: "Can only unpack the values a function returns together.".into() })
+    }
+}
+
+/// The text of a number, both in `sim` and in compiled programs: the shortest digits reading back
+/// as the same number, in scientific notation from 1e21 and below 1e-6 like JavaScript.
+pub fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        "nan".into()
+    } else if n == 0. {
+        "0".into() // compiled integers have no negative zero
+    } else if n.is_infinite() || (1e-6..1e21).contains(&n.abs()) {
+        n.to_string() // infinities display as `inf`/`-inf`
+    } else {
+        format!("{:e}", n)
+    }
+}
+
+/// Result of a binary operator in `sim`, shared by the interpreter and the VM.
+pub fn apply_binary(operator: &TokenType, l: Value, r: Value) -> Value {
+    match operator {
+        TokenType::Minus => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
+             _ => Value::Null
+        },
+        TokenType::Slash => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a / b),
+            _ => Value::Null
+        },
+        TokenType::Star => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
+            _ => Value::Null
+        },
+        TokenType::Plus => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+            (Value::String(a), Value::String(b)) => Value::String(format!("{}{}", &a, &b).into()),
+            (Value::String(a), Value::Number(b)) => Value::String(format!("{}{}", &a, b).into()),
+            (Value::Number(a), Value::String(b)) => Value::String(format!("{}{}", a, &b).into()),
+            _ => Value::Null
+        },
+        TokenType::Greater => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Boolean(a > b),
+            _ => Value::Boolean(false)
+        },
+        TokenType::GreaterEqual => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Boolean(a >= b),
+            _ => Value::Boolean(false)
+        },
+        TokenType::Less => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Boolean(a < b),
+            _ => Value::Boolean(false)
+        },
+        TokenType::LessEqual => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Boolean(a <= b),
+            _ => Value::Boolean(false)
+        },
+        // Numbers follow IEEE 754: `nan` is unequal to everything, itself included.
+        TokenType::BangEqual => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Boolean(a != b),
+            (Value::String(a), Value::String(b)) => Value::Boolean(a != b),
+            (Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(a != b),
+            (Value::List(a), Value::List(b)) => Value::Boolean(!Rc::ptr_eq(&a, &b)),
+            (Value::Map(a), Value::Map(b)) => Value::Boolean(!Rc::ptr_eq(&a, &b)),
+            (Value::Struct(a), Value::Struct(b)) => Value::Boolean(!Rc::ptr_eq(&a, &b)),
+            (Value::Instance(a), Value::Instance(b)) => Value::Boolean(!Rc::ptr_eq(&a, &b)),
+            (a @ Value::Tuple(_), b @ Value::Tuple(_)) => Value::Boolean(!apply_binary(&TokenType::EqualEqual, a, b).is_truthy()),
+            (Value::Null, Value::Null) => Value::Boolean(false),
+            _ => Value::Boolean(true)
+        },
+        TokenType::Xor => Value::Boolean(l.is_truthy() != r.is_truthy()),
+        TokenType::EqualEqual => match (l, r) {
+            (Value::Number(a), Value::Number(b)) => Value::Boolean(a == b),
+            (Value::String(a), Value::String(b)) => Value::Boolean(a == b),
+            (Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(a == b),
+            // the same list, like compiled code comparing addresses
+            (Value::List(a), Value::List(b)) => Value::Boolean(Rc::ptr_eq(&a, &b)),
+            (Value::Map(a), Value::Map(b)) => Value::Boolean(Rc::ptr_eq(&a, &b)),
+            (Value::Struct(a), Value::Struct(b)) => Value::Boolean(Rc::ptr_eq(&a, &b)),
+            (Value::Instance(a), Value::Instance(b)) => Value::Boolean(Rc::ptr_eq(&a, &b)),
+            // tuples are made anew by each return, they compare by their values
+            (Value::Tuple(a), Value::Tuple(b)) => Value::Boolean(a.len() == b.len()
+                && a.iter().zip(b.iter()).all(|(a, b)| apply_binary(&TokenType::EqualEqual, a.clone(), b.clone()).is_truthy())),
+            (Value::Null, Value::Null) => Value::Boolean(true),
+            _ => Value::Boolean(false)
+        },
+        _ => Value::Null
+    }
+}
+
+/// Result of a unary operator in `sim`, shared by the interpreter and the VM.
+pub fn apply_unary(operator: &TokenType, r: Value) -> Value {
+    match operator {
+        TokenType::Minus => match r {
+            Value::Number(n) => Value::Number(-n),
+            _ => Value::Null
+        },
+        // the same truthiness as conditions, which compiled code can test on its 0
+        TokenType::Bang => Value::Boolean(!r.is_truthy()),
+        _ => Value::Null
+    }
+}
+
+/// How much of the source is echoed as comments in the generated assembly.
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum AsmComments {
+    Off,
+    /// One comment per statement.
+    Minimal,
+    /// One comment per statement and expression node.
+    #[default]
+    Full
+}
+
+/// Optimization passes to run, selected with `-O`.
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum OptLevel {
+    /// Naive stack-machine code, one block per source construct.
+    #[value(name = "0")]
+    O0,
+    /// Fold constant `if`/`while` conditions and drop the dead branches, multiply and divide by
+    /// constant powers of two with shifts, branch directly on comparisons.
+    #[default]
+    #[value(name = "1")]
+    O1,
+    /// Also compute arithmetic through the SSA middle-end (constant and copy propagation, CSE)
+    /// and remove redundant push/pop pairs.
+    #[value(name = "2")]
+    O2,
+    /// Same passes as -O2, which already only shrink the code.
+    #[value(name = "s")]
+    Os
+}
+
+impl OptLevel {
+    pub fn folds(&self) -> bool {
+        *self != Self::O0
+    }
+
+    pub fn reduces_strength(&self) -> bool {
+        *self != Self::O0
+    }
+
+    pub fn fuses_branches(&self) -> bool {
+        *self != Self::O0
+    }
+
+    pub fn uses_ssa(&self) -> bool {
+        matches!(self, Self::O2 | Self::Os)
+    }
+
+    pub fn peephole(&self) -> bool {
+        matches!(self, Self::O2 | Self::Os)
+    }
+}
+
+/// Code generation settings selected on the `com` command line.
+#[derive(Default)]
+pub struct CompileOptions {
+    /// Abort on signed overflow in add/sub/imul instead of silently wrapping.
+    pub checked_arith: bool,
+    pub comments: AsmComments,
+    pub opt_level: OptLevel,
+    pub target: Target,
+    /// The runtime prints through emitted routines instead of printf, constants are written directly.
+    pub builtin_print: bool,
+    /// Report warnings as errors and fail the build (`--Werror`).
+    pub werror: bool
+}
+
+/// Code generation state shared by the whole program: statements append their asm to its buffer
+/// and register what they need (constants, labels, variables) as they are compiled.
+#[derive(Default)]
+pub struct Compiler {
+    pub options: CompileOptions,
+    /// Asm of the code being compiled, `main` or the body of a function.
+    code: RefCell<String>,
+    /// Last label number handed out, sequential so the same program always compiles to the same asm.
+    pub labels: Cell<usize>,
+    pub warnings: Cell<usize>,
+    /// Constructs the native backend can't compile, which fail the build.
+    pub errors: Cell<usize>,
+    /// Every label defined so far with the source line that produced it, for `--emit-map`.
+    pub symbols: RefCell<Vec<(String, u32)>>,
+    /// Constants referenced by the generated code, emitted after it.
+    pub data: RefCell<DataSection>,
+    /// Set when `eprint` is compiled, which writes through the emitted output routines even with printf.
+    pub stderr: Cell<bool>,
+    /// Set when `input()` is compiled, which needs the runtime's `ceya_read_line`.
+    pub input: Cell<bool>,
+    /// Set when a list literal is compiled, which allocates through the runtime's `ceya_alloc`.
+    pub alloc: Cell<bool>,
+    /// Variables in scope, innermost block last, with where each is stored and its type: a `.bss`
+    /// label, or a slot of the frame (`rbp - 8`) inside functions.
+    pub scopes: RefCell<Vec<HashMap<String, (String, StaticType)>>>,
+    /// Variables declared so far, numbering their labels.
+    pub variables: Cell<usize>,
+    /// Functions declared so far, with their label, number of parameters and number of values returned.
+    pub signatures: RefCell<HashMap<String, (String, usize, usize)>>,
+    pub frame: RefCell<Frame>,
+    /// Code of the compiled functions, emitted after `main`.
+    pub functions: RefCell<String>
+}
+
+/// State of the function being compiled, swapped out while a nested one is.
+#[derive(Default)]
+pub struct Frame {
+    /// Label of the function, `None` in `main`.
+    pub label: Option<String>,
+    /// Frame slots taken by its `let`s so far.
+    pub locals: usize,
+    /// Labels `break` and `continue` jump to in the enclosing loops, innermost last.
+    pub loops: Vec<(String, String)>
+}
+
+impl Compiler {
+    pub fn new(options: CompileOptions) -> Self {
+        Self { options, ..Default::default() }
+    }
+
+    /// Compiles a top-level statement and returns its asm.
+    pub fn compile(&self, stmt: &Stmt) -> String {
+        stmt.compile(self);
+        self.code.take()
+    }
+
+    fn emit(&self, asm: &str) {
+        self.code.borrow_mut().push_str(asm);
+    }
+
+    fn out(&self) -> RefMut<'_, String> {
+        self.code.borrow_mut()
+    }
+
+    /// Address and type of the variable `name` refers to, looking from the innermost scope out.
+    pub fn variable(&self, name: &str) -> Option<(String, StaticType)> {
+        self.scopes.borrow().iter().rev().find_map(|scope| scope.get(name).cloned())
+    }
+
+    /// Allocates a new variable in the innermost scope and returns its address. Each declaration gets
+    /// its own, so shadowing `let`s in nested blocks don't overwrite the outer variable.
+    fn declare(&self, name: &str, typ: StaticType) -> String {
+        let mut frame = self.frame.borrow_mut();
+        let label = if frame.label.is_some() {
+            // a slot per call, so recursive calls don't share their locals
+            frame.locals += 1;
+            format!("rbp - {}", 8 * frame.locals)
+        } else {
+            let label = format!("var_{}", self.variables.get());
+            self.variables.set(self.variables.get() + 1);
+            self.data.borrow_mut().global(&label, 0);
+            label
+        };
+
+        let mut scopes = self.scopes.borrow_mut();
+        if scopes.is_empty() {
+            scopes.push(HashMap::new());
+        }
+        scopes.last_mut().unwrap().insert(name.into(), (label.clone(), typ));
+        label
+    }
+
+    pub fn warn(&self, span: Span, message: &str) {
+        self.warnings.set(self.warnings.get() + 1);
+        if self.options.werror {
+            eprintln!("{}", error::render(&CeyaError::Codegen { span, message: message.into() }));
+        } else {
+            eprintln!("Warning: [line {}:{}] {}", span.start, span.column, message);
+        }
+    }
+
+    /// Reports code that can't be compiled. The rest still is, to report every error at once.
+    pub fn error(&self, span: Span, message: &str) {
+        self.errors.set(self.errors.get() + 1);
+        eprintln!("{}", error::render(&CeyaError::Codegen { span, message: message.into() }));
+    }
+
+    /// Emits a label and records it in the symbol map.
+    pub fn define_label(&self, name: &str, line: u32) {
+        self.symbols.borrow_mut().push((name.into(), line));
+        writeln!(self.out(), "{}:", name).unwrap();
+    }
+
+    pub fn next_label(&self) -> usize {
+        self.labels.set(self.labels.get() + 1);
+        self.labels.get()
+    }
+}
+
+/// Type of a compiled expression, known from its shape since compiled values carry no tag.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StaticType {
+    Number,
+    Boolean,
+    /// Address of a zero-terminated string in the data segment.
+    String,
+    /// Address of a list: its length, then its elements.
+    List
+}
+
+impl StaticType {
+    /// The representation of values of an annotated type, numbers when nothing tells.
+    pub fn of(annotation: Option<Type>) -> StaticType {
+        match annotation {
+            Some(Type::Str) => StaticType::String,
+            Some(Type::Bool) => StaticType::Boolean,
+            Some(Type::List) => StaticType::List,
+            _ => StaticType::Number
         }
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expr {
-   //Assign   { name: Rc<Token>, value: Box<Expr> },
-   Binary   { left: Box<Expr>, operator: Rc<Token>, right: Box<Expr> },
-   //Logical  { left: Box<Expr>, operator: Rc<Token>, right: Box<Expr> },
-   Grouping { expression: Box<Expr> },
-   Literal  { value: Value },
-   Unary    { operator: Rc<Token>, right: Box<Expr> },
-   Soro,
-   //Variable { name: Rc<Token> },
-   //Call     { callee: Box<Expr>, paren: Rc<Token>, arguments: Vec<Box<Expr>> }
+   Assign   { name: Rc<Token>, value: Box<Expr>, span: Span },
+   Binary   { left: Box<Expr>, operator: Rc<Token>, right: Box<Expr>, span: Span },
+   /// `and`/`or`, which only evaluate `right` when `left` doesn't decide the result.
+   Logical  { left: Box<Expr>, operator: Rc<Token>, right: Box<Expr>, span: Span },
+   Grouping { expression: Box<Expr>, span: Span },
+   Literal  { value: Value, span: Span },
+   Unary    { operator: Rc<Token>, right: Box<Expr>, span: Span },
+   /// `condition ? then : els`, which only evaluates the branch taken.
+   Ternary  { condition: Box<Expr>, then: Box<Expr>, els: Box<Expr>, span: Span },
+   Soro     { span: Span },
+   /// Number of values on the operand stack.
+   Depth    { span: Span },
+   Variable { name: Rc<Token>, span: Span },
+   Call     { callee: Box<Expr>, paren: Rc<Token>, arguments: Vec<Expr>, span: Span },
+   /// `[a, b, c]`, a new list each time it is evaluated.
+   List     { elements: Vec<Expr>, span: Span },
+   /// `{key: value}`, a new map each time it is evaluated.
+   Map      { entries: Vec<(Expr, Expr)>, span: Span },
+   /// `object[index]`
+   Index    { object: Box<Expr>, index: Box<Expr>, span: Span },
+   /// `object[start..end]`, either bound left out for the start or the end of the string or list.
+   Slice    { object: Box<Expr>, start: Option<Box<Expr>>, end: Option<Box<Expr>>, span: Span },
+   /// `object[index] = value`, which gives `value`.
+   SetIndex { object: Box<Expr>, index: Box<Expr>, value: Box<Expr>, span: Span },
+   /// `Name { field: value }`, `class` being the variable holding the struct.
+   Instance { class: Box<Expr>, fields: Vec<(Rc<Token>, Expr)>, span: Span },
+   /// `object.name`
+   Get      { object: Box<Expr>, name: Rc<Token>, span: Span },
+   /// `object.name = value`, which gives `value`.
+   Set      { object: Box<Expr>, name: Rc<Token>, value: Box<Expr>, span: Span },
+   /// `a, b` after `return`, the values of a function returned together.
+   Tuple    { elements: Vec<Expr>, span: Span }
 }
 
 impl Debug for Expr {
@@ -74,337 +710,728 @@ impl Expr {
     //TODO: compiling errors instead of just returning null
     pub fn evaluate(&self, env_arena: &mut EnvironmentArena, environment: usize) -> Value {
         match self {
-            // Self::Assign { name, value } => {
-            //     let v = value.evaluate(env_arena, environment);
-            //     if let Err(e) = env_arena.assign(environment, name, v.clone()) {
-            //         eprintln!("{}", e);
-            //     }
-            //     v
-            // }
-            Self::Binary { left, operator, right } => {
+            Self::Assign { name, value, .. } => {
+                let v = value.evaluate(env_arena, environment);
+                if let Err(e) = env_arena.assign(environment, name, v.clone()) {
+                    env_arena.report(e);
+                }
+                v
+            },
+            Self::Binary { left, operator, right, span } => {
                 let l = left.evaluate(env_arena, environment);
+                if !right.uses_soro() {
+                    let r = right.evaluate(env_arena, environment);
+                    return binary(&operator.typ, l, r, *span, env_arena);
+                }
+
+                // compiled code keeps the left operand on the stack while computing the right one
+                env_arena.stack.push(l);
                 let r = right.evaluate(env_arena, environment);
+                env_arena.stack.push(r);
+                let mut operands = take_operands(env_arena, 2, *span);
+                let r = operands.pop().expect("right operand");
+                let l = operands.pop().expect("left operand");
+                binary(&operator.typ, l, r, *span, env_arena)
+            },
+            Self::Logical { left, operator, right, .. } => {
+                let value = left.evaluate(env_arena, environment);
 
+                // the operand that decided the result is the value, like in Lox
                 match operator.typ {
-                    TokenType::Minus => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
-                         _ => Value::Null
-                    },
-                    TokenType::Slash => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Number(a / b),
-                        _ => Value::Null
-                    },
-                    TokenType::Star => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
-                        _ => Value::Null
-                    },
-                    TokenType::Plus => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
-                        (Value::String(a), Value::String(b)) => Value::String(format!("{}{}", &a, &b)),
-                        (Value::String(a), Value::Number(b)) => Value::String(format!("{}{}", &a, b)),
-                        (Value::Number(a), Value::String(b)) => Value::String(format!("{}{}", a, &b)),
-                        _ => Value::Null
-                    },
-                    TokenType::Greater => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Boolean(a > b),
-                        _ => Value::Boolean(false)
-                    },
-                    TokenType::GreaterEqual => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Boolean(a >= b),
-                        _ => Value::Boolean(false)
-                    },
-                    TokenType::Less => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Boolean(a < b),
-                        _ => Value::Boolean(false)
-                    },
-                    TokenType::LessEqual => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Boolean(a <= b),
-                        _ => Value::Boolean(false)
-                    },
-                    TokenType::BangEqual => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Boolean(a != b),
-                        (Value::String(a), Value::String(b)) => Value::Boolean(a != b),
-                        (Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(a != b),
-                        (Value::Null, Value::Null) => Value::Boolean(!true),
-                        _ => Value::Boolean(!false)
-                    },
-                    TokenType::EqualEqual => match (l, r) {
-                        (Value::Number(a), Value::Number(b)) => Value::Boolean(a == b),
-                        (Value::String(a), Value::String(b)) => Value::Boolean(a == b),
-                        (Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(a == b),
-                        (Value::Null, Value::Null) => Value::Boolean(true),
-                        _ => Value::Boolean(false)
-                    },
-                    _ => Value::Null
+                    TokenType::Or if value.is_truthy() => value,
+                    TokenType::And if !value.is_truthy() => value,
+                    _ => right.evaluate(env_arena, environment)
                 }
             },
-            // Self::Logical { left, operator, right } => {
-            //     let value = left.evaluate(env_arena, environment);
-
-            //     match operator.typ {
-            //         TokenType::Or => {
-            //             if left.is_true(env_arena, environment) {
-            //                 return value;
-            //             }
-            //         },
-            //         TokenType::And => {
-            //             if !left.is_true(env_arena, environment) {
-            //                 return value;
-            //             }
-            //         },
-            //         _ => ()
-            //     };
-
-            //     right.evaluate(env_arena, environment)
-            // }
-            Self::Grouping { expression } => {
+            Self::Grouping { expression, .. } => {
                 expression.evaluate(env_arena, environment)
             },
-            Self::Literal { value } => {
+            Self::Ternary { condition, then, els, .. } => match condition.is_true(env_arena, environment) {
+                true => then.evaluate(env_arena, environment),
+                false => els.evaluate(env_arena, environment)
+            },
+            Self::Literal { value, .. } => {
                 value.clone()
             },
-            Self::Unary { operator, right } => {
+            Self::Unary { operator, right, .. } => {
                 let r = right.evaluate(env_arena, environment);
+                apply_unary(&operator.typ, r)
+            },
+            Self::Variable { name, .. } => {
+                match env_arena.get(environment, name) {
+                    Ok(res) => res.clone(),
+                    Err(e) => {
+                        env_arena.report(e);
+                        Value::Null
+                    }
+                }
+            },
+            Self::Call { callee, paren, arguments, .. } => {
+                let call = callee.evaluate(env_arena, environment);
 
-                match operator.typ {
-                    TokenType::Minus => match r {
-                        Value::Number(n) => Value::Number(-n),
-                        _ => Value::Null
-                    },
-                    TokenType::Bang => Value::Boolean(!match r {
-                        Value::Null => false,
-                        Value::Boolean(b) => b,
-                        _ => true
-                    }),
-                    _ => Value::Null
+                match call {
+                    Value::Fun(ref fun) => {
+                        if let Some(arity) = fun.arity() {
+                            if arity != arguments.len() {
+                                env_arena.report(CeyaError::Runtime { span: paren.span(), message: format!("Expected {} arguments, but found {}.", arity, arguments.len()) });
+                                return Value::Null;
+                            }
+                        }
+
+                        let args = evaluate_operands(&arguments.iter().collect::<Vec<_>>(), env_arena, environment, paren.span());
+                        fun.call(args, paren.span(), env_arena)
+                    },
+                    _ => {
+                        env_arena.report(CeyaError::Runtime { span: paren.span(), message: "Can only call functions.".into() });
+                        Value::Null
+                    }
                 }
             },
-            // Self::Variable { name } => {
-            //     if let Ok(res) = env_arena.get(environment, name) {
-            //         return res.clone();
-            //     }
-
-            //     Value::Null
-            // },
-            // Self::Call { ref callee, paren: _, ref arguments } => {
-            //     let call = callee.evaluate(env_arena, environment);
-
-            //     match call {
-            //         Value::Fun(ref fun) => {
-
-            //             let mut exe = |fun: &Fun, params: &Vec<Rc<Token>>| -> Value {
-            //                 if params.len() != arguments.len() {
-            //                     eprintln!("Expected {} arguments, but found {}.", params.len(), arguments.len());
-            //                     return Value::Null;
-            //                 }
-    
-            //                 let mut args: Vec<Value> = vec![];
-            //                 for arg in arguments {
-            //                     args.push(arg.evaluate(env_arena, environment));
-            //                 }
-            //                 fun.call(args, env_arena)
-            //             };
-
-            //             match fun {
-            //                 Fun::Code { name: _, params, body: _, closure: _ } => {
-            //                     exe(fun, params)
-            //                 },
-            //                 Fun::Native { name: _, params, callee: _ } => {
-            //                     exe(fun, params)
-            //                 }
-            //             }
-                        
-            //         },
-            //         _ => {
-            //             call
-            //         }
-            //     }
-            // },
-            Self::Soro => Value::Null
-        }
-    }
-
-    pub fn compile(&self) -> String {
-        let mut res = String::new();
+            Self::List { .. } | Self::Map { .. } | Self::Index { .. } | Self::Slice { .. } | Self::SetIndex { .. } | Self::Tuple { .. } => self.evaluate_list(env_arena, environment),
+            Self::Instance { .. } | Self::Get { .. } | Self::Set { .. } => self.evaluate_struct(env_arena, environment),
+            Self::Soro { span } => pop_operand(env_arena, *span),
+            Self::Depth { .. } => Value::Number(env_arena.stack.len() as f64)
+        }
+    }
+
+    /// The list expressions, apart so that they don't grow the frame of every recursive `evaluate`.
+    #[inline(never)]
+    fn evaluate_list(&self, env_arena: &mut EnvironmentArena, environment: usize) -> Value {
+        let res = match self {
+            Self::List { elements, span } => {
+                let elements = evaluate_operands(&elements.iter().collect::<Vec<_>>(), env_arena, environment, *span);
+                return Value::List(Rc::new(RefCell::new(elements)));
+            },
+            Self::Tuple { elements, span } => {
+                return Value::Tuple(evaluate_operands(&elements.iter().collect::<Vec<_>>(), env_arena, environment, *span).into());
+            },
+            Self::Map { entries, span } => {
+                let operands: Vec<&Expr> = entries.iter().flat_map(|(key, value)| [key, value]).collect();
+                Map::from_pairs(evaluate_operands(&operands, env_arena, environment, *span), *span).map(|map| Value::Map(Rc::new(RefCell::new(map))))
+            },
+            Self::Index { object, index, span } => {
+                let Ok([object, index]) = <[Value; 2]>::try_from(evaluate_operands(&[object, index], env_arena, environment, *span)) else { unreachable!("two operands") };
+                get_index(object, index, *span)
+            },
+            Self::Slice { object, start, end, span } => {
+                let operands: Vec<&Expr> = [Some(object), start.as_ref(), end.as_ref()].into_iter().flatten().map(Box::as_ref).collect();
+                let mut values = evaluate_operands(&operands, env_arena, environment, *span).into_iter();
+                let object = values.next().expect("sliced value");
+                let start = start.as_ref().and_then(|_| values.next());
+                slice(object, start, values.next(), *span)
+            },
+            Self::SetIndex { object, index, value, span } => {
+                let Ok([object, index, value]) = <[Value; 3]>::try_from(evaluate_operands(&[object, index, value], env_arena, environment, *span)) else { unreachable!("three operands") };
+                set_index(object, index, value, *span)
+            },
+            _ => unreachable!("not a list expression")
+        };
+
+        res.unwrap_or_else(|e| {
+            env_arena.report(e);
+            Value::Null
+        })
+    }
+
+    /// The struct expressions, apart for the same reason as the list ones.
+    #[inline(never)]
+    fn evaluate_struct(&self, env_arena: &mut EnvironmentArena, environment: usize) -> Value {
+        let res = match self {
+            Self::Instance { class, fields, span } => {
+                let operands: Vec<&Expr> = std::iter::once(class.as_ref()).chain(fields.iter().map(|(_, value)| value)).collect();
+                let mut values = evaluate_operands(&operands, env_arena, environment, *span).into_iter();
+                let class = values.next().expect("struct operand");
+                instantiate(class, fields.iter().map(|(name, _)| name.lexeme.as_str()).zip(values).collect(), *span)
+            },
+            Self::Get { object, name, span } => {
+                let object = object.evaluate(env_arena, environment);
+                get_field(object, &name.lexeme, *span)
+            },
+            Self::Set { object, name, value, span } => {
+                let Ok([object, value]) = <[Value; 2]>::try_from(evaluate_operands(&[object, value], env_arena, environment, *span)) else { unreachable!("two operands") };
+                set_field(object, &name.lexeme, value, *span)
+            },
+            _ => unreachable!("not a struct expression")
+        };
+
+        res.unwrap_or_else(|e| {
+            env_arena.report(e);
+            Value::Null
+        })
+    }
 
+    pub fn compile(&self, compiler: &Compiler) {
         match self {
-            Self::Literal { value } => {
-                writeln!(&mut res, "   ; {}", self.fmt_output()).unwrap();
+            Self::Literal { value, .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
 
                 match value {
                     Value::Null => {
-                        writeln!(&mut res, "   ; not implemented yet!").unwrap();
+                        writeln!(compiler.out(), "   push 0").unwrap();
+                    },
+                    Value::Number(n) if !(i64::MIN as f64..i64::MAX as f64).contains(n) => {
+                        // Compiled numbers are integers, so nan/inf have no encoding and comparisons on them can't follow IEEE rules.
+                        compiler.error(self.span(), &format!("{} cannot be represented in compiled code.", value));
+                        writeln!(compiler.out(), "   push 0").unwrap();
+                    },
+                    Value::Number(n) if n.fract() != 0. => {
+                        compiler.warn(self.span(), &format!("{} has a fractional part, compiled numbers are integers: using {}.", value, n.trunc()));
+                        writeln!(compiler.out(), "   push {}", n.trunc()).unwrap();
+                    },
+                    // push only takes a sign-extended 32-bit immediate
+                    Value::Number(n) if !(i32::MIN as f64..=i32::MAX as f64).contains(n) => {
+                        writeln!(compiler.out(), "   mov rax, {}", n).unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     Value::Number(n) => {
-                        writeln!(&mut res, "   push {}", n).unwrap();
+                        writeln!(compiler.out(), "   push {}", n).unwrap();
                     },
                     Value::Boolean(b) => {
-                        writeln!(&mut res, "   push {}", if *b { "1" } else { "0" }).unwrap();
+                        writeln!(compiler.out(), "   push {}", if *b { "1" } else { "0" }).unwrap();
                     },
-                    Value::String(_) => {
-                        writeln!(&mut res, "   ; not implemented yet!\n").unwrap(); // TODO
+                    Value::String(s) => {
+                        let label = compiler.data.borrow_mut().string(s);
+                        writeln!(compiler.out(), "   lea rax, [{}]", label).unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
+                    },
+                    Value::List(_) | Value::Map(_) | Value::Struct(_) | Value::Instance(_) | Value::Tuple(_) | Value::Fun(_) => {
+                        writeln!(compiler.out(), "   ; not implemented yet!").unwrap();
                     }
                 }
             },
-            Self::Unary { operator, right } => {
-                writeln!(&mut res, "   ; {}", self.fmt_output()).unwrap();
+            Self::List { elements, .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+
+                // a new array each time the literal is evaluated, its length first
+                for element in elements {
+                    element.compile(compiler);
+                }
+                compiler.alloc.set(true);
+                writeln!(compiler.out(), "   mov rcx, {}", elements.len() + 1).unwrap();
+                writeln!(compiler.out(), "   call {}", runtime::ALLOC).unwrap();
+                writeln!(compiler.out(), "   mov rcx, rax").unwrap();
+                for i in (1..=elements.len()).rev() {
+                    writeln!(compiler.out(), "   pop rax").unwrap();
+                    writeln!(compiler.out(), "   mov [rcx + {}], rax", 8 * i).unwrap();
+                }
+                writeln!(compiler.out(), "   mov rax, {}", elements.len()).unwrap();
+                writeln!(compiler.out(), "   mov [rcx], rax").unwrap();
+                writeln!(compiler.out(), "   push rcx").unwrap();
+            },
+            Self::Map { .. } => {
+                compiler.error(self.span(), "Maps are not supported by the native backend yet.");
+                writeln!(compiler.out(), "   push 0").unwrap();
+            },
+            Self::Index { object, .. } if object.static_type(compiler) == StaticType::String => {
+                compiler.error(self.span(), "Indexing strings is not supported by the native backend yet.");
+                writeln!(compiler.out(), "   push 0").unwrap();
+            },
+            Self::Slice { .. } => {
+                compiler.error(self.span(), "Slices are not supported by the native backend yet.");
+                writeln!(compiler.out(), "   push 0").unwrap();
+            },
+            Self::Instance { .. } | Self::Get { .. } | Self::Set { .. } => {
+                compiler.error(self.span(), "Structs are not supported by the native backend yet.");
+                writeln!(compiler.out(), "   push 0").unwrap();
+            },
+            Self::Tuple { .. } => unreachable!("only returned, which compiles the elements"),
+            Self::Index { object, index, .. } => {
+                object.compile(compiler);
+                index.compile(compiler);
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+
+                writeln!(compiler.out(), "   pop rax").unwrap();
+                writeln!(compiler.out(), "   pop rcx").unwrap();
+                writeln!(compiler.out(), "   mov rbx, [rcx]").unwrap();
+                Expr::compile_bounds_check(compiler);
+                writeln!(compiler.out(), "   shl rax, 3").unwrap();
+                writeln!(compiler.out(), "   add rcx, rax").unwrap();
+                writeln!(compiler.out(), "   push qword [rcx + 8]").unwrap();
+            },
+            Self::SetIndex { object, index, value, .. } => {
+                object.compile(compiler);
+                index.compile(compiler);
+                value.compile(compiler);
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+
+                writeln!(compiler.out(), "   pop rdx").unwrap();
+                writeln!(compiler.out(), "   pop rax").unwrap();
+                writeln!(compiler.out(), "   pop rcx").unwrap();
+                writeln!(compiler.out(), "   mov rbx, [rcx]").unwrap();
+                Expr::compile_bounds_check(compiler);
+                writeln!(compiler.out(), "   shl rax, 3").unwrap();
+                writeln!(compiler.out(), "   add rcx, rax").unwrap();
+                writeln!(compiler.out(), "   mov [rcx + 8], rdx").unwrap();
+                writeln!(compiler.out(), "   push rdx").unwrap();
+            },
+            Self::Unary { operator, right, .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
 
                 match operator.typ {
                     TokenType::Minus => {
-                        write!(&mut res, "{}", right.compile()).unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   neg rax").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        right.compile(compiler);
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   neg rax").unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::Bang => {
-                        writeln!(&mut res, "not implemented yet!").unwrap();
+                        // falsy values are 0 once compiled: false, fu and the number 0
+                        right.compile(compiler);
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   cmp rax, 0").unwrap();
+                        writeln!(compiler.out(), "   sete al").unwrap();
+                        writeln!(compiler.out(), "   movzx rax, al").unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     _ => {
-                        writeln!(&mut res, "error").unwrap();
+                        compiler.error(self.span(), &format!("'{}' is not supported by the native backend yet.", operator.lexeme));
+                        writeln!(compiler.out(), "   push 0").unwrap();
                     }
                 }
             },
-            Self::Binary { left, operator, right } => {
-                write!(&mut res, "{}", left.compile()).unwrap();
-                write!(&mut res, "{}", right.compile()).unwrap();
+            Self::Binary { left, operator, right, .. } => {
+                if self.compile_shift(compiler) {
+                    return;
+                }
+                // compiled strings are addresses, adding or comparing them would be meaningless
+                if left.static_type(compiler) == StaticType::String || right.static_type(compiler) == StaticType::String {
+                    compiler.warn(self.span(), &format!("'{}' operates on strings, which compiled code can only store and print yet, using 0.", self.fmt_source()));
+                    writeln!(compiler.out(), "   push 0").unwrap();
+                    return;
+                }
+
+                left.compile(compiler);
+                right.compile(compiler);
 
-                writeln!(&mut res, "   ; {}", self.fmt_output()).unwrap();
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
 
                 match operator.typ {
                     TokenType::Plus => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   add eax, ebx").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   add rax, rbx").unwrap();
+                        if compiler.options.checked_arith {
+                            writeln!(compiler.out(), "   jo {}", runtime::OVERFLOW_TRAP).unwrap();
+                        }
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::Star => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   imul ebx").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   imul rax, rbx").unwrap();
+                        if compiler.options.checked_arith {
+                            writeln!(compiler.out(), "   jo {}", runtime::OVERFLOW_TRAP).unwrap();
+                        }
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::Minus => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   sub eax, ebx").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   sub rax, rbx").unwrap();
+                        if compiler.options.checked_arith {
+                            writeln!(compiler.out(), "   jo {}", runtime::OVERFLOW_TRAP).unwrap();
+                        }
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::Slash => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   cqo").unwrap();
-                        writeln!(&mut res, "   idiv rbx").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   test rbx, rbx").unwrap();
+                        writeln!(compiler.out(), "   jz {}", runtime::DIV_ZERO_TRAP).unwrap();
+                        writeln!(compiler.out(), "   cqo").unwrap();
+                        writeln!(compiler.out(), "   idiv rbx").unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::Less => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   cmp rax, rbx").unwrap();
-                        writeln!(&mut res, "   setl al").unwrap();
-                        writeln!(&mut res, "   movzx rax, al").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   cmp rax, rbx").unwrap();
+                        writeln!(compiler.out(), "   setl al").unwrap();
+                        writeln!(compiler.out(), "   movzx rax, al").unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::LessEqual => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   cmp rax, rbx").unwrap();
-                        writeln!(&mut res, "   setle al").unwrap();
-                        writeln!(&mut res, "   movzx rax, al").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   cmp rax, rbx").unwrap();
+                        writeln!(compiler.out(), "   setle al").unwrap();
+                        writeln!(compiler.out(), "   movzx rax, al").unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::Greater => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   cmp rax, rbx").unwrap();
-                        writeln!(&mut res, "   setg al").unwrap();
-                        writeln!(&mut res, "   movzx rax, al").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   cmp rax, rbx").unwrap();
+                        writeln!(compiler.out(), "   setg al").unwrap();
+                        writeln!(compiler.out(), "   movzx rax, al").unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::GreaterEqual => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   cmp rax, rbx").unwrap();
-                        writeln!(&mut res, "   setge al").unwrap();
-                        writeln!(&mut res, "   movzx rax, al").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   cmp rax, rbx").unwrap();
+                        writeln!(compiler.out(), "   setge al").unwrap();
+                        writeln!(compiler.out(), "   movzx rax, al").unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::EqualEqual => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   cmp rax, rbx").unwrap();
-                        writeln!(&mut res, "   sete al").unwrap();
-                        writeln!(&mut res, "   movzx rax, al").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   cmp rax, rbx").unwrap();
+                        writeln!(compiler.out(), "   sete al").unwrap();
+                        writeln!(compiler.out(), "   movzx rax, al").unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     TokenType::BangEqual => {
-                        writeln!(&mut res, "   pop rbx").unwrap();
-                        writeln!(&mut res, "   pop rax").unwrap();
-                        writeln!(&mut res, "   cmp rax, rbx").unwrap();
-                        writeln!(&mut res, "   setne al").unwrap();
-                        writeln!(&mut res, "   movzx rax, al").unwrap();
-                        writeln!(&mut res, "   push rax").unwrap();
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   cmp rax, rbx").unwrap();
+                        writeln!(compiler.out(), "   setne al").unwrap();
+                        writeln!(compiler.out(), "   movzx rax, al").unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
+                    },
+                    TokenType::Xor => {
+                        writeln!(compiler.out(), "   pop rbx").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        compiler.emit(&emit_xor());
+                        writeln!(compiler.out(), "   push rax").unwrap();
                     },
                     _ => {
-                        writeln!(&mut res, "    ; not implemented yet!").unwrap();
+                        writeln!(compiler.out(), "    ; not implemented yet!").unwrap();
                     }
                 }
             },
-            Expr::Grouping { expression } => {
-                writeln!(&mut res, "   ; {}", self.fmt_output()).unwrap();
+            Expr::Logical { left, operator, right, .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+
+                // the left operand stays as the result when it decides it, like in `sim`
+                let label = compiler.next_label();
+                left.compile(compiler);
+                writeln!(compiler.out(), "   mov rax, [rsp]").unwrap();
+                writeln!(compiler.out(), "   test rax, rax").unwrap();
+                writeln!(compiler.out(), "   {} .end_{}", if operator.typ == TokenType::Or { "jnz" } else { "jz" }, label).unwrap();
+                writeln!(compiler.out(), "   pop rax").unwrap();
+                right.compile(compiler);
+                compiler.define_label(&format!(".end_{}", label), self.span().start);
+            },
+            Expr::Grouping { expression, .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+
+                expression.compile(compiler);
+            },
+            Expr::Ternary { condition, then, els, .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+
+                if let Some(value) = condition.constant().filter(|_| compiler.options.opt_level.folds()) {
+                    return if value.is_truthy() { then.compile(compiler) } else { els.compile(compiler) };
+                }
 
-                write!(&mut res, "{}", expression.compile()).unwrap();
+                let label = compiler.next_label();
+                condition.compile_branch(compiler, &format!(".else_{}", label), false);
+                then.compile(compiler);
+                writeln!(compiler.out(), "   jmp .end_{}", label).unwrap();
+                compiler.define_label(&format!(".else_{}", label), els.span().start);
+                els.compile(compiler);
+                compiler.define_label(&format!(".end_{}", label), self.span().start);
             },
-            Expr::Soro => {
-                writeln!(&mut res, "   ; {}", self.fmt_output()).unwrap();
+            Expr::Soro { .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
                 // Do nothing
+            },
+            Expr::Depth { .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+                compiler.emit(&emit_depth(&mut compiler.data.borrow_mut()));
+            },
+            Expr::Assign { name, value, .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+
+                value.compile_scalar(compiler, "stored in a compiled variable");
+                match compiler.variable(&name.lexeme) {
+                    Some((label, typ)) => {
+                        // reads are compiled for the type of the declaration
+                        if typ != value.static_type(compiler) {
+                            compiler.warn(self.span(), &format!("'{}' changes the type of '{}', compiled variables keep the type of their declaration.", self.fmt_source(), name.lexeme));
+                        }
+                        writeln!(compiler.out(), "   mov [{}], rax", label).unwrap();
+                    },
+                    None => compiler.warn(self.span(), &format!("Undefined variable '{}', the value is not stored.", name.lexeme))
+                }
+                writeln!(compiler.out(), "   push rax").unwrap();
+            },
+            Expr::Variable { name, .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+                match compiler.variable(&name.lexeme) {
+                    Some((label, _)) => writeln!(compiler.out(), "   push qword [{}]", label).unwrap(),
+                    None => {
+                        compiler.warn(self.span(), &format!("Undefined variable '{}', using 0.", name.lexeme));
+                        writeln!(compiler.out(), "   push 0").unwrap();
+                    }
+                }
+            },
+            Expr::Call { callee, paren: _, arguments, .. } => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+                let function = match callee.as_ref() {
+                    Expr::Variable { name, .. } => compiler.signatures.borrow().get(&name.lexeme).cloned(),
+                    _ => None
+                };
+                match function {
+                    Some((label, arity, _)) if arity == arguments.len() => {
+                        for argument in arguments {
+                            argument.compile_value(compiler);
+                        }
+                        compiler.emit(&emit_call_function(compiler.options.target, &label, arity));
+                    },
+                    Some((_, arity, _)) => {
+                        compiler.warn(self.span(), &format!("Expected {} arguments, but found {}, using 0.", arity, arguments.len()));
+                        writeln!(compiler.out(), "   push 0").unwrap();
+                    },
+                    None if self.native_arguments(compiler, "input").is_some_and(<[Expr]>::is_empty) => {
+                        compiler.input.set(true);
+                        writeln!(compiler.out(), "   call {}", runtime::READ_LINE).unwrap();
+                        writeln!(compiler.out(), "   push rax").unwrap();
+                    },
+                    None if matches!(self.native_arguments(compiler, "len"), Some([list]) if list.static_type(compiler) == StaticType::List) => {
+                        arguments[0].compile(compiler);
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                        writeln!(compiler.out(), "   push qword [rax]").unwrap();
+                    },
+                    None => {
+                        compiler.warn(self.span(), &format!("'{}' is not a function declared with `fn` before, using 0.", callee.fmt_source()));
+                        writeln!(compiler.out(), "   push 0").unwrap();
+                    }
+                }
             }
         }
-
-        res
     }
 
     fn fmt_output(&self) -> String {
         match self {
-            Self::Binary { left, operator, right } => {
+            Self::Binary { left, operator, right, .. } => {
                 Expr::parenthesize(&operator.lexeme, vec![left, right])
             },
-            Self::Grouping { expression } => {
+            Self::Grouping { expression, .. } => {
                 Expr::parenthesize("group", vec![expression])
             },
-            Self::Literal { value } => {
-                format!("{}", value)
+            Self::Literal { value, .. } => match value {
+                // escaped, as the output ends up in asm comments where a newline would start live code
+                Value::String(s) => scanner::escape(s),
+                value => format!("{}", value)
             },
-            // Self::Logical { left, operator, right } => {
-            //     Expr::parenthesize(&operator.lexeme, vec![left, right])
-            // }
-            Self::Unary { operator, right } => {
+            Self::Logical { left, operator, right, .. } => {
+                Expr::parenthesize(&operator.lexeme, vec![left, right])
+            },
+            Self::Unary { operator, right, .. } => {
                 Expr::parenthesize(&operator.lexeme, vec![right])
             },
-            // Self::Variable { name } => {
-            //     format!("{}", &name.lexeme)
-            // },
-            // Self::Assign { name, value } => {
-            //     Expr::parenthesize(&format!("{}=", name.lexeme), vec![value])
-            // },
-            // Self::Call { callee, paren: _, arguments } => {
-            //     let mut args = vec![];
-            //     for expr in arguments {
-            //         args.push(expr);
-            //     }
-            //     Expr::parenthesize(&format!("{}()", callee.fmt_output()), args)
-            // },
-            Self::Soro => {
-                String::from_str("soro").unwrap()
-            }
-        }
-    }
-
-    fn parenthesize(name: &str, exprs: Vec<&Box<Expr>>) -> String {
-        let mut builder = String::new();
-
-        builder.push('(');
-        builder.push_str(name);
-        for expr in exprs {
+            Self::Ternary { condition, then, els, .. } => {
+                Expr::parenthesize("?:", vec![condition, then, els])
+            },
+            Self::Variable { name, .. } => {
+                name.lexeme.to_string()
+            },
+            Self::Assign { name, value, .. } => {
+                Expr::parenthesize(&format!("{}=", name.lexeme), vec![value])
+            },
+            Self::Call { callee, paren: _, arguments, .. } => {
+                let mut args = vec![];
+                for expr in arguments {
+                    args.push(expr);
+                }
+                Expr::parenthesize(&format!("{}()", callee.fmt_output()), args)
+            },
+            Self::List { elements, .. } => {
+                Expr::parenthesize("list", elements.iter().collect())
+            },
+            Self::Map { entries, .. } => {
+                Expr::parenthesize("map", entries.iter().flat_map(|(key, value)| [key, value]).collect())
+            },
+            Self::Index { object, index, .. } => {
+                Expr::parenthesize("[]", vec![object, index])
+            },
+            Self::Instance { class, fields, .. } => {
+                let fields: Vec<String> = fields.iter().map(|(name, value)| format!(" {} {}", name.lexeme, value.fmt_output())).collect();
+                format!("(new {}{})", class.fmt_output(), fields.concat())
+            },
+            Self::Get { object, name, .. } => {
+                format!("(. {} {})", object.fmt_output(), name.lexeme)
+            },
+            Self::Set { object, name, value, .. } => {
+                format!("(.= {} {} {})", object.fmt_output(), name.lexeme, value.fmt_output())
+            },
+            Self::Slice { object, start, end, .. } => {
+                let bound = |bound: &Option<Box<Expr>>| bound.as_ref().map_or("_".into(), |bound| bound.fmt_output());
+                format!("([..] {} {} {})", object.fmt_output(), bound(start), bound(end))
+            },
+            Self::SetIndex { object, index, value, .. } => {
+                Expr::parenthesize("[]=", vec![object, index, value])
+            },
+            Self::Tuple { elements, .. } => {
+                Expr::parenthesize("tuple", elements.iter().collect())
+            },
+            Self::Soro { .. } => {
+                String::from_str("soro").unwrap()
+            },
+            Self::Depth { .. } => {
+                String::from_str("depth").unwrap()
+            }
+        }
+    }
+
+    /// Prints the expression back as ceya source that parses to the same tree.
+    pub fn fmt_source(&self) -> String {
+        match self {
+            Self::Binary { left, operator, right, .. } | Self::Logical { left, operator, right, .. } => {
+                let precedence = Expr::precedence(&operator.typ).unwrap_or_default();
+                // operators are left-associative, so a right operand of equal precedence needs parentheses too
+                let left = match left.binary_precedence() {
+                    Some(p) if p < precedence => format!("({})", left.fmt_source()),
+                    _ => left.fmt_source()
+                };
+                let right = match right.binary_precedence() {
+                    Some(p) if p <= precedence => format!("({})", right.fmt_source()),
+                    _ => right.fmt_source()
+                };
+                format!("{} {} {}", left, operator.lexeme, right)
+            },
+            Self::Grouping { expression, .. } => {
+                format!("({})", expression.fmt_source())
+            },
+            Self::Literal { value, .. } => match value {
+                Value::String(s) => format!("\"{}\"", scanner::escape(s)),
+                Value::Null => "fu".into(),
+                // spelled like the scanner's keywords, not NaN
+                Value::Number(n) if n.is_nan() => "nan".into(),
+                Value::Number(n) if n.is_infinite() => if *n > 0. { "inf" } else { "-inf" }.into(),
+                Value::Number(n) => n.to_string(), // the scanner doesn't read exponents
+                _ => format!("{}", value)
+            },
+            Self::Unary { operator, right, .. } => match right.binary_precedence() {
+                Some(_) => format!("{}({})", operator.lexeme, right.fmt_source()),
+                None => format!("{}{}", operator.lexeme, right.fmt_source())
+            },
+            Self::Ternary { condition, then, els, .. } => {
+                // `?:` nests to the right, and only takes an assignment in the middle
+                let condition = match condition.binary_precedence() {
+                    Some(0) => format!("({})", condition.fmt_source()),
+                    _ => condition.fmt_source()
+                };
+                let els = match els.as_ref() {
+                    Self::Assign { .. } => format!("({})", els.fmt_source()),
+                    _ => els.fmt_source()
+                };
+                format!("{} ? {} : {}", condition, then.fmt_source(), els)
+            },
+            Self::Variable { name, .. } => {
+                name.lexeme.to_string()
+            },
+            Self::Assign { name, value, .. } => {
+                format!("{} = {}", name.lexeme, value.fmt_source())
+            },
+            Self::Call { callee, paren: _, arguments, .. } => {
+                let args: Vec<String> = arguments.iter().map(|arg| arg.fmt_source()).collect();
+                format!("{}({})", callee.fmt_source(), args.join(", "))
+            },
+            Self::List { elements, .. } => {
+                let elements: Vec<String> = elements.iter().map(|element| element.fmt_source()).collect();
+                format!("[{}]", elements.join(", "))
+            },
+            Self::Map { entries, .. } => {
+                let entries: Vec<String> = entries.iter().map(|(key, value)| format!("{}: {}", key.fmt_source(), value.fmt_source())).collect();
+                format!("{{{}}}", entries.join(", "))
+            },
+            Self::Index { object, index, .. } => {
+                format!("{}[{}]", object.fmt_source(), index.fmt_source())
+            },
+            Self::Instance { class, fields, .. } => {
+                let fields: Vec<String> = fields.iter().map(|(name, value)| format!("{}: {}", name.lexeme, value.fmt_source())).collect();
+                match fields.is_empty() {
+                    true => format!("{} {{}}", class.fmt_source()),
+                    false => format!("{} {{ {} }}", class.fmt_source(), fields.join(", "))
+                }
+            },
+            Self::Get { object, name, .. } => {
+                format!("{}.{}", object.fmt_source(), name.lexeme)
+            },
+            Self::Set { object, name, value, .. } => {
+                format!("{}.{} = {}", object.fmt_source(), name.lexeme, value.fmt_source())
+            },
+            Self::Slice { object, start, end, .. } => {
+                let bound = |bound: &Option<Box<Expr>>| bound.as_ref().map_or(String::new(), |bound| bound.fmt_source());
+                format!("{}[{}..{}]", object.fmt_source(), bound(start), bound(end))
+            },
+            Self::SetIndex { object, index, value, .. } => {
+                format!("{}[{}] = {}", object.fmt_source(), index.fmt_source(), value.fmt_source())
+            },
+            Self::Tuple { elements, .. } => {
+                let elements: Vec<String> = elements.iter().map(|element| element.fmt_source()).collect();
+                elements.join(", ")
+            },
+            Self::Soro { .. } => {
+                String::from_str("soro").unwrap()
+            },
+            Self::Depth { .. } => {
+                String::from_str("depth").unwrap()
+            }
+        }
+    }
+
+    /// Binding strength of a binary operator, from `or` (loosest) to `*` and `/`, `None` for other tokens.
+    /// Assignment binds looser still, at 0.
+    pub fn precedence(operator: &TokenType) -> Option<u8> {
+        Some(match operator {
+            TokenType::Or => 1,
+            TokenType::And => 2,
+            TokenType::Xor => 3,
+            TokenType::BangEqual | TokenType::EqualEqual => 4,
+            TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => 5,
+            TokenType::Minus | TokenType::Plus => 6,
+            TokenType::Star | TokenType::Slash => 7,
+            _ => return None
+        })
+    }
+
+    fn binary_precedence(&self) -> Option<u8> {
+        match self {
+            Self::Binary { left: _, operator, right: _, .. } | Self::Logical { left: _, operator, right: _, .. } => Expr::precedence(&operator.typ),
+            Self::Assign { .. } | Self::Ternary { .. } => Some(0),
+            _ => None
+        }
+    }
+
+    fn parenthesize(name: &str, exprs: Vec<&Expr>) -> String {
+        let mut builder = String::new();
+
+        builder.push('(');
+        builder.push_str(name);
+        for expr in exprs {
             builder.push(' ');
             builder.push_str(&expr.fmt_output());
         }
@@ -413,173 +1440,1185 @@ impl Expr {
         builder
     }
 
+    /// Emits a check of the index in `rax` against the length in `rbx`, jumping to the
+    /// `ceya_bounds_fail` routine when out of range (the unsigned compare also catches negatives).
+    pub fn compile_bounds_check(compiler: &Compiler) {
+        writeln!(compiler.out(), "   cmp rax, rbx").unwrap();
+        writeln!(compiler.out(), "   jae {}", runtime::BOUNDS_TRAP).unwrap();
+    }
+
+    /// Comparisons and `!` give booleans, `and`/`or` the type of their operands, everything else compiles to a number.
+    pub fn static_type(&self, compiler: &Compiler) -> StaticType {
+        match self {
+            Self::Literal { value: Value::Boolean(_), .. } => StaticType::Boolean,
+            Self::Literal { value: Value::String(_), .. } => StaticType::String,
+            Self::Grouping { expression, .. } | Self::Assign { name: _, value: expression, .. } => expression.static_type(compiler),
+            Self::Variable { name, .. } => compiler.variable(&name.lexeme).map_or(StaticType::Number, |(_, typ)| typ),
+            Self::Unary { operator, right: _, .. } if operator.typ == TokenType::Bang => StaticType::Boolean,
+            Self::Call { .. } if self.native_arguments(compiler, "input").is_some_and(<[Expr]>::is_empty) => StaticType::String,
+            Self::List { .. } => StaticType::List,
+            Self::Binary { left: _, operator, right: _, .. } => match operator.typ {
+                TokenType::Less | TokenType::LessEqual | TokenType::Greater | TokenType::GreaterEqual
+                    | TokenType::EqualEqual | TokenType::BangEqual | TokenType::Xor => StaticType::Boolean,
+                _ => StaticType::Number
+            },
+            // either operand can be the result, so they need to agree
+            Self::Logical { left: a, operator: _, right: b, .. } | Self::Ternary { condition: _, then: a, els: b, .. } => match (a.static_type(compiler), b.static_type(compiler)) {
+                (l, r) if l == r => l,
+                _ => StaticType::Number
+            },
+            _ => StaticType::Number
+        }
+    }
+
+    /// The arguments of a call to the native `native`, unless a function took its name. `input()`
+    /// compiles to the runtime's `ceya_read_line` and `len` of a list reads its length.
+    fn native_arguments(&self, compiler: &Compiler, native: &str) -> Option<&[Expr]> {
+        match self {
+            Self::Call { callee, arguments, .. } if matches!(callee.as_ref(), Expr::Variable { name, .. } if name.lexeme == native) && !compiler.signatures.borrow().contains_key(native) => Some(arguments),
+            _ => None
+        }
+    }
+
+    /// Folds the expression to a value when it is built from literals only, `None` otherwise.
+    pub fn constant(&self) -> Option<Value> {
+        match self {
+            Self::Literal { value, .. } => Some(value.clone()),
+            Self::Grouping { expression, .. } => expression.constant(),
+            Self::Ternary { condition, then, els, .. } => match condition.constant()?.is_truthy() {
+                true => then.constant(),
+                false => els.constant()
+            },
+            Self::Unary { operator: _, right, .. } => {
+                right.constant()?;
+                Some(self.evaluate(&mut EnvironmentArena::new(), 0))
+            },
+            Self::Binary { left, operator: _, right, .. } | Self::Logical { left, operator: _, right, .. } => {
+                left.constant()?;
+                right.constant()?;
+                Some(self.evaluate(&mut EnvironmentArena::new(), 0))
+            },
+            _ => None
+        }
+    }
+
+    /// Compiles the expression through the SSA middle-end when enabled and possible, through
+    /// `compile` otherwise. Only the root of an expression goes through here.
+    pub fn compile_value(&self, compiler: &Compiler) {
+        match Block::lower(self, compiler.options.checked_arith).filter(|_| compiler.options.opt_level.uses_ssa()) {
+            Some(block) => {
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+                compiler.emit(&block.compile(&compiler.options));
+            },
+            None => self.compile(compiler)
+        }
+    }
+
+    /// Leaves the value in rax. Compiled values are integers and string addresses: fractions and null
+    /// have no representation yet, they are reported and replaced with 0. nan and inf fail the build.
+    fn compile_scalar(&self, compiler: &Compiler, usage: &str) {
+        let representable = match self.constant() {
+            Some(value @ Value::Number(n)) if !n.is_finite() => {
+                compiler.error(self.span(), &format!("{} cannot be represented in compiled code.", value));
+                writeln!(compiler.out(), "   xor eax, eax").unwrap();
+                return;
+            },
+            Some(Value::Number(n)) => n.fract() == 0.,
+            Some(value) => matches!(value, Value::Boolean(_) | Value::String(_)),
+            None => true
+        };
+        if representable {
+            self.compile_value(compiler);
+            writeln!(compiler.out(), "   pop rax").unwrap();
+        } else {
+            compiler.warn(self.span(), &format!("'{}' cannot be {} yet, using 0.", self.fmt_source(), usage));
+            writeln!(compiler.out(), "   xor eax, eax").unwrap();
+        }
+    }
+
+    /// Jumps to `target` when the condition's truthiness equals `when`. Comparisons branch on
+    /// their own `cmp` instead of materializing a 0/1 value and testing it.
+    fn compile_branch(&self, compiler: &Compiler, target: &str, when: bool) {
+        let condition = match self {
+            Self::Binary { left: _, operator, right: _, .. } if compiler.options.opt_level.fuses_branches() => match operator.typ {
+                TokenType::Less => Some(("l", "ge")),
+                TokenType::LessEqual => Some(("le", "g")),
+                TokenType::Greater => Some(("g", "le")),
+                TokenType::GreaterEqual => Some(("ge", "l")),
+                TokenType::EqualEqual => Some(("e", "ne")),
+                TokenType::BangEqual => Some(("ne", "e")),
+                _ => None
+            },
+            _ => None
+        };
+
+        match (self, condition) {
+            (Self::Unary { operator, right, .. }, _) if operator.typ == TokenType::Bang => right.ungrouped().compile_branch(compiler, target, !when),
+            // `or` jumps as soon as one side is truthy and `and` as soon as one is falsy, the other
+            // outcome needs both sides and skips the right one when the left decides
+            (Self::Logical { left, operator, right, .. }, _) if (operator.typ == TokenType::Or) == when => {
+                left.compile_branch(compiler, target, when);
+                right.compile_branch(compiler, target, when);
+            },
+            (Self::Logical { left, operator: _, right, .. }, _) => {
+                let label = compiler.next_label();
+                left.compile_branch(compiler, &format!(".end_{}", label), !when);
+                right.compile_branch(compiler, target, when);
+                compiler.define_label(&format!(".end_{}", label), self.span().start);
+            },
+            (Self::Binary { left, operator: _, right, .. }, Some((taken, not_taken))) => {
+                left.compile(compiler);
+                right.compile(compiler);
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+                writeln!(compiler.out(), "   pop rbx").unwrap();
+                writeln!(compiler.out(), "   pop rax").unwrap();
+                writeln!(compiler.out(), "   cmp rax, rbx").unwrap();
+                writeln!(compiler.out(), "   j{} {}", if when { taken } else { not_taken }, target).unwrap();
+            },
+            _ => {
+                self.compile_value(compiler);
+                writeln!(compiler.out(), "   pop rax").unwrap();
+                writeln!(compiler.out(), "   test rax, rax").unwrap();
+                writeln!(compiler.out(), "   {} {}", if when { "jnz" } else { "jz" }, target).unwrap();
+            }
+        }
+    }
+
+    /// The expression inside any parentheses.
+    fn ungrouped(&self) -> &Expr {
+        match self {
+            Self::Grouping { expression, .. } => expression.ungrouped(),
+            expr => expr
+        }
+    }
+
+    /// Whether evaluating the expression twice gives the same value: it reads neither the stack nor calls anything.
+    fn is_pure(&self) -> bool {
+        match self {
+            // each list, map or instance literal, or slice of a list, is a new one, which `==` tells apart
+            Self::Soro { .. } | Self::Depth { .. } | Self::Call { .. } | Self::Assign { .. } | Self::List { .. } | Self::Map { .. } | Self::Slice { .. }
+                | Self::SetIndex { .. } | Self::Instance { .. } | Self::Set { .. } => false,
+            Self::Get { object, .. } => object.is_pure(),
+            Self::Index { object, index, .. } => object.is_pure() && index.is_pure(),
+            Self::Tuple { elements, .. } => elements.iter().all(Expr::is_pure),
+            Self::Binary { left, operator: _, right, .. } | Self::Logical { left, operator: _, right, .. } => left.is_pure() && right.is_pure(),
+            Self::Grouping { expression, .. } => expression.is_pure(),
+            Self::Unary { operator: _, right, .. } => right.is_pure(),
+            Self::Ternary { condition, then, els, .. } => condition.is_pure() && then.is_pure() && els.is_pure(),
+            Self::Literal { .. } | Self::Variable { .. } => true
+        }
+    }
+
+    /// Warns about a condition that can't change at runtime, which is usually a bug: a constant, or a
+    /// comparison of a value with itself. A literal `while (true)` is taken as intended.
+    fn warn_constant_condition(&self, compiler: &Compiler, keyword: &str) {
+        if keyword == "while" && matches!(self, Self::Literal { value: Value::Boolean(true), .. }) {
+            return;
+        }
+
+        if let Some(value) = self.constant() {
+            compiler.warn(self.span(), &format!("'{}' condition is always {}.", keyword, value.is_truthy()));
+            return;
+        }
+
+        if let Self::Binary { left, operator, right, .. } = self.ungrouped() {
+            let always = match operator.typ {
+                TokenType::EqualEqual | TokenType::LessEqual | TokenType::GreaterEqual => true,
+                TokenType::BangEqual | TokenType::Less | TokenType::Greater => false,
+                _ => return
+            };
+            if left.is_pure() && left.ungrouped().fmt_source() == right.ungrouped().fmt_source() {
+                compiler.warn(self.span(), &format!("'{}' compares a value with itself, the '{}' condition is always {}.", self.fmt_source(), keyword, always));
+            }
+        }
+    }
+
+    /// Whether it reads the operand stack, with `soro` or `depth`.
+    pub(crate) fn uses_soro(&self) -> bool {
+        match self {
+            // the depth changes with everything pushed before it
+            Self::Soro { .. } | Self::Depth { .. } => true,
+            Self::Binary { left, operator: _, right, .. } | Self::Logical { left, operator: _, right, .. } => left.uses_soro() || right.uses_soro(),
+            Self::Grouping { expression, .. } => expression.uses_soro(),
+            Self::Unary { operator: _, right, .. } => right.uses_soro(),
+            Self::Ternary { condition, then, els, .. } => condition.uses_soro() || then.uses_soro() || els.uses_soro(),
+            Self::Assign { name: _, value, .. } => value.uses_soro(),
+            Self::Call { callee, paren: _, arguments, .. } => callee.uses_soro() || arguments.iter().any(Expr::uses_soro),
+            Self::List { elements, .. } | Self::Tuple { elements, .. } => elements.iter().any(Expr::uses_soro),
+            Self::Map { entries, .. } => entries.iter().any(|(key, value)| key.uses_soro() || value.uses_soro()),
+            Self::Index { object, index, .. } => object.uses_soro() || index.uses_soro(),
+            Self::Slice { object, start, end, .. } => object.uses_soro() || start.iter().chain(end).any(|bound| bound.uses_soro()),
+            Self::Instance { class, fields, .. } => class.uses_soro() || fields.iter().any(|(_, value)| value.uses_soro()),
+            Self::Get { object, .. } => object.uses_soro(),
+            Self::Set { object, value, .. } => object.uses_soro() || value.uses_soro(),
+            Self::SetIndex { object, index, value, .. } => object.uses_soro() || index.uses_soro() || value.uses_soro(),
+            Self::Literal { .. } | Self::Variable { .. } => false
+        }
+    }
+
+    /// Exponent of a constant power of two operand (2 to 2^30), which fits a shift count.
+    fn power_of_two(&self) -> Option<u32> {
+        match self.constant()? {
+            Value::Number(n) if n >= 2. && n <= (1u32 << 30) as f64 && n.fract() == 0. && (n as u32).is_power_of_two() => Some((n as u32).trailing_zeros()),
+            _ => None
+        }
+    }
+
+    /// Lowers `x * 2^k` to `shl` and `x / 2^k` to `sar`, `false` when the expression doesn't qualify.
+    /// Shifts don't report overflow, so checked arithmetic keeps `imul`.
+    fn compile_shift(&self, compiler: &Compiler) -> bool {
+        let Self::Binary { left, operator, right, .. } = self else { return false; };
+        if !compiler.options.opt_level.reduces_strength() {
+            return false;
+        }
+
+        match operator.typ {
+            TokenType::Star if !compiler.options.checked_arith => {
+                let (operand, shift) = match (left.power_of_two(), right.power_of_two()) {
+                    (_, Some(shift)) => (left, shift),
+                    // without the constant pushed first, a `soro` inside the right operand would read another value
+                    (Some(shift), None) if matches!(right.as_ref(), Self::Soro { .. }) || !right.uses_soro() => (right, shift),
+                    _ => return false
+                };
+
+                operand.compile(compiler);
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+                writeln!(compiler.out(), "   pop rax").unwrap();
+                writeln!(compiler.out(), "   shl rax, {}", shift).unwrap();
+                writeln!(compiler.out(), "   push rax").unwrap();
+            },
+            TokenType::Slash => {
+                let Some(shift) = right.power_of_two() else { return false; };
+
+                left.compile(compiler);
+                if compiler.options.comments == AsmComments::Full {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_output()).unwrap();
+                }
+                // idiv truncates toward zero but sar rounds down, so negatives get 2^k - 1 added first
+                writeln!(compiler.out(), "   pop rax").unwrap();
+                writeln!(compiler.out(), "   mov rbx, rax").unwrap();
+                writeln!(compiler.out(), "   sar rbx, 63").unwrap();
+                writeln!(compiler.out(), "   shr rbx, {}", 64 - shift).unwrap();
+                writeln!(compiler.out(), "   add rax, rbx").unwrap();
+                writeln!(compiler.out(), "   sar rax, {}", shift).unwrap();
+                writeln!(compiler.out(), "   push rax").unwrap();
+            },
+            _ => return false
+        }
+
+        true
+    }
+
+    /// Line of the first token found in the expression, literals carry none.
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Binary { span, .. } | Self::Logical { span, .. } | Self::Grouping { span, .. } | Self::Literal { span, .. } | Self::Unary { span, .. }
+                | Self::Ternary { span, .. } | Self::Soro { span } | Self::Depth { span } | Self::Variable { span, .. } | Self::Assign { span, .. } | Self::Call { span, .. }
+                | Self::List { span, .. } | Self::Map { span, .. } | Self::Index { span, .. } | Self::Slice { span, .. } | Self::SetIndex { span, .. }
+                | Self::Instance { span, .. } | Self::Get { span, .. } | Self::Set { span, .. } | Self::Tuple { span, .. } => *span
+        }
+    }
+
+    /// Number of expression nodes in the tree, for `sim --stats`.
+    pub fn node_count(&self) -> usize {
+        1 + match self {
+            Self::Binary { left, operator: _, right, .. } | Self::Logical { left, operator: _, right, .. } => left.node_count() + right.node_count(),
+            Self::Grouping { expression, .. } => expression.node_count(),
+            Self::Unary { operator: _, right, .. } => right.node_count(),
+            Self::Ternary { condition, then, els, .. } => condition.node_count() + then.node_count() + els.node_count(),
+            Self::Assign { name: _, value, .. } => value.node_count(),
+            Self::Call { callee, paren: _, arguments, .. } => callee.node_count() + arguments.iter().map(Expr::node_count).sum::<usize>(),
+            Self::List { elements, .. } | Self::Tuple { elements, .. } => elements.iter().map(Expr::node_count).sum(),
+            Self::Map { entries, .. } => entries.iter().map(|(key, value)| key.node_count() + value.node_count()).sum(),
+            Self::Index { object, index, .. } => object.node_count() + index.node_count(),
+            Self::Slice { object, start, end, .. } => object.node_count() + start.iter().chain(end).map(|bound| bound.node_count()).sum::<usize>(),
+            Self::Instance { class, fields, .. } => class.node_count() + fields.iter().map(|(_, value)| value.node_count()).sum::<usize>(),
+            Self::Get { object, .. } => object.node_count(),
+            Self::Set { object, value, .. } => object.node_count() + value.node_count(),
+            Self::SetIndex { object, index, value, .. } => object.node_count() + index.node_count() + value.node_count(),
+            Self::Literal { .. } | Self::Soro { .. } | Self::Depth { .. } | Self::Variable { .. } => 0
+        }
+    }
+
     fn is_true(&self, env_arena: &mut EnvironmentArena, environment: usize) -> bool {
-        match self.evaluate(env_arena, environment) {
-            Value::Boolean(b) => b,
-            Value::Null => false,
-            Value::String(s) => !s.is_empty(),
-            Value::Number(n) => n != 0.,
-            // Value::Fun(_fun) => true
+        self.evaluate(env_arena, environment).is_truthy()
+    }
+}
+
+/// `l operator r` in the interpreter, through the operator method of the struct of `l` when it
+/// declares one. Apart so that the call doesn't grow the frame of every recursive `evaluate`.
+#[inline(never)]
+fn binary(operator: &TokenType, l: Value, r: Value, span: Span, env_arena: &mut EnvironmentArena) -> Value {
+    match overload(operator, &l) {
+        Some((method, false)) => method.call(vec![l, r], span, env_arena),
+        Some((method, true)) => Value::Boolean(!method.call(vec![l, r], span, env_arena).is_truthy()),
+        None => apply_binary(operator, l, r)
+    }
+}
+
+/// Evaluates operands from left to right. When one of them uses `soro`, they go through the operand
+/// stack like in compiled code, which pushes them one after the other where a `soro` can pop them.
+fn evaluate_operands(operands: &[&Expr], env_arena: &mut EnvironmentArena, environment: usize, span: Span) -> Vec<Value> {
+    let parked = operands.iter().any(|operand| operand.uses_soro());
+    let mut values = vec![];
+    for operand in operands {
+        let value = operand.evaluate(env_arena, environment);
+        if parked {
+            env_arena.stack.push(value);
+        } else {
+            values.push(value);
         }
     }
+    if parked {
+        values = take_operands(env_arena, operands.len(), span);
+    }
+    values
+}
+
+/// `object[index]` in `sim`, shared by the interpreter and the VM.
+pub(crate) fn get_index(object: Value, index: Value, span: Span) -> Result<Value, CeyaError> {
+    match (object, index) {
+        (Value::List(list), Value::Number(index)) => {
+            let list = list.borrow();
+            Ok(list[check_index(index, list.len(), span)?].clone())
+        },
+        (Value::List(_), _) => Err(CeyaError::Runtime { span, message: "List indices must be numbers.".into() }),
+        // the character at `index`, counted like `len` does
+        (Value::String(s), Value::Number(index)) => {
+            let index = check_index(index, s.chars().count(), span)?;
+            Ok(Value::String(s.chars().nth(index).expect("index in bounds").to_string().into()))
+        },
+        (Value::String(_), _) => Err(CeyaError::Runtime { span, message: "String indices must be numbers.".into() }),
+        // a missing key gives `fu` for lookups to test, rather than an error
+        (Value::Map(map), key) => Ok(map.borrow().get(&key, span)?.cloned().unwrap_or(Value::Null)),
+        _ => Err(CeyaError::Runtime { span, message: "Can only index strings, lists and maps.".into() })
+    }
+}
+
+/// `object[index] = value` in `sim`, giving `value` like other assignments.
+pub(crate) fn set_index(object: Value, index: Value, value: Value, span: Span) -> Result<Value, CeyaError> {
+    match (object, index) {
+        (Value::List(list), Value::Number(index)) => {
+            let mut list = list.borrow_mut();
+            let index = check_index(index, list.len(), span)?;
+            list[index] = value.clone();
+            Ok(value)
+        },
+        (Value::List(_), _) => Err(CeyaError::Runtime { span, message: "List indices must be numbers.".into() }),
+        (Value::Map(map), key) => {
+            map.borrow_mut().insert(key, value.clone(), span)?;
+            Ok(value)
+        },
+        _ => Err(CeyaError::Runtime { span, message: "Can only assign elements of lists and maps.".into() })
+    }
+}
+
+/// `object[start..end]` in `sim`: a new string or list of the elements from `start` to `end` excluded.
+pub(crate) fn slice(object: Value, start: Option<Value>, end: Option<Value>, span: Span) -> Result<Value, CeyaError> {
+    let len = match &object {
+        Value::String(s) => s.chars().count(),
+        Value::List(list) => list.borrow().len(),
+        _ => return Err(CeyaError::Runtime { span, message: "Can only slice strings and lists.".into() })
+    };
+    let bound = |bound: Option<Value>, default: usize| match bound {
+        None => Ok(default as f64),
+        Some(Value::Number(n)) => Ok(n),
+        Some(_) => Err(CeyaError::Runtime { span, message: "Slice bounds must be numbers.".into() })
+    };
+    let (start, end) = (bound(start, 0)?, bound(end, len)?);
+    if start < 0. || start.fract() != 0. || end.fract() != 0. || start > end || end > len as f64 {
+        return Err(CeyaError::Runtime { span, message: format!("Slice {}..{} out of bounds for length {}.", start, end, len) });
+    }
+
+    let range = start as usize..end as usize;
+    Ok(match object {
+        Value::String(s) => Value::String(s.chars().skip(range.start).take(range.len()).collect::<String>().into()),
+        Value::List(list) => Value::List(Rc::new(RefCell::new(list.borrow()[range].to_vec()))),
+        _ => unreachable!("checked above")
+    })
+}
+
+/// Converts `index` into a position in a sequence of length `len`, failing with the index and length when out of range.
+pub fn check_index(index: f64, len: usize, span: Span) -> Result<usize, CeyaError> {
+    if index < 0. || index.fract() != 0. || index >= len as f64 {
+        return Err(CeyaError::Runtime { span, message: format!("Index {} out of bounds for length {}.", index, len) });
+    }
+
+    Ok(index as usize)
+}
+
+fn underflow(span: Span) -> CeyaError {
+    CeyaError::Runtime { span, message: "Stack underflow.".into() }
+}
+
+/// Pops the operand stack for `soro`, reporting an underflow and giving `fu` when it is empty.
+pub(crate) fn pop_operand(env_arena: &mut EnvironmentArena, span: Span) -> Value {
+    env_arena.stack.pop().unwrap_or_else(|| {
+        env_arena.report(underflow(span));
+        Value::Null
+    })
 }
 
+/// Pops the `count` operands parked on the operand stack, in the order they were pushed. Missing
+/// ones are reported and are `fu`, like compiled code reading past the stack but without the garbage.
+pub(crate) fn take_operands(env_arena: &mut EnvironmentArena, count: usize, span: Span) -> Vec<Value> {
+    let len = env_arena.stack.len();
+    if len >= count {
+        return env_arena.stack.split_off(len - count);
+    }
+
+    env_arena.report(underflow(span));
+    let mut operands = vec![Value::Null; count - len];
+    operands.append(&mut env_arena.stack);
+    operands
+}
+
+/// Runs the stack word `word` (`faran`, `ke`, `swap`, `over`, `rot` or `drop2`) on the operand
+/// stack, or reports an underflow when it doesn't hold the values the word needs.
+pub(crate) fn stack_word(word: &TokenType, env_arena: &mut EnvironmentArena, span: Span) {
+    let needed = match word {
+        TokenType::Faran | TokenType::Ke => 1,
+        TokenType::Rot => 3,
+        _ => 2
+    };
+    if env_arena.stack.len() < needed {
+        env_arena.report(underflow(span));
+        return;
+    }
+
+    let stack = &mut env_arena.stack;
+
+    let len = stack.len();
+    match word {
+        TokenType::Faran => stack.truncate(len - 1),
+        TokenType::Ke => stack.push(stack[len - 1].clone()),
+        TokenType::Swap => stack.swap(len - 1, len - 2),
+        TokenType::Over => stack.push(stack[len - 2].clone()),
+        TokenType::Rot => stack[len - 3..].rotate_left(1),
+        _ => stack.truncate(len - 2)
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Stmt {
-    Block       { statements: Vec<Stmt> },
-    Expression  { expression: Box<Expr> },
-    Print       { expression: Box<Expr> },  
-    Faran,
-    Ke,
-    // Let         { name: Rc<Token>, initializer: Box<Expr> },
-    If          { condition: Box<Expr>, then: Box<Stmt>, els: Option<Box<Stmt>> },
-    While       { condition: Box<Expr>, body: Box<Stmt> },
-    // Fun         { name: Rc<Token>, params: Vec<Rc<Token>>, body: Rc<Stmt> },
-    // Return      { keyword: Rc<Token>, value: Box<Expr> }
+    Block       { statements: Vec<Stmt>, span: Span },
+    Expression  { expression: Box<Expr>, span: Span },
+    /// `print`, or `eprint` writing to stderr.
+    Print       { expression: Box<Expr>, arguments: Vec<Expr>, stderr: bool, span: Span },
+    Faran       { span: Span },
+    Ke          { span: Span },
+    /// `swap`, `over`, `rot` and `drop2`, Forth's `swap`, `over`, `rot` and `2drop`.
+    Swap        { span: Span },
+    Over        { span: Span },
+    Rot         { span: Span },
+    Drop2       { span: Span },
+    /// `let name: annotation = initializer;`, the annotation being optional.
+    Let         { name: Rc<Token>, annotation: Option<Type>, initializer: Box<Expr>, span: Span },
+    /// `let (a, b) = initializer;`, declaring a variable per value of the tuple a function returned.
+    Unpack      { names: Vec<Rc<Token>>, initializer: Box<Expr>, span: Span },
+    If          { condition: Box<Expr>, then: Box<Stmt>, els: Option<Box<Stmt>>, span: Span },
+    /// `for` loops are a `while` with the increment, which runs after the body and on `continue`.
+    While       { condition: Box<Expr>, body: Box<Stmt>, increment: Option<Box<Expr>>, span: Span },
+    /// `fn` declaration, the body is a `Block` shared with the function values made from it. `types`
+    /// holds the annotations of the parameters, `returns` the one after the parameters.
+    Fun         { name: Rc<Token>, params: Vec<Rc<Token>>, types: Vec<Option<Type>>, returns: Option<Type>, body: Rc<Stmt>, span: Span },
+    /// `struct Name { field, other }`, declaring a variable holding the struct. `methods` are the
+    /// `Fun` declarations of its operator methods.
+    Struct      { name: Rc<Token>, fields: Vec<Rc<Token>>, methods: Vec<Stmt>, span: Span },
+    Return      { keyword: Rc<Token>, value: Box<Expr>, span: Span },
+    Break       { span: Span },
+    Continue    { span: Span }
  }
 
+/// `name: type`, or the name alone without annotation.
+fn annotated(name: &Token, annotation: &Option<Type>) -> String {
+    match annotation {
+        Some(typ) => format!("{}: {}", name.lexeme, typ),
+        None => name.lexeme.clone()
+    }
+}
+
+/// `name(a: num, b): str` of a function declaration.
+fn fmt_signature(name: &Token, params: &[Rc<Token>], types: &[Option<Type>], returns: &Option<Type>) -> String {
+    let params: Vec<String> = params.iter().zip(types).map(|(param, typ)| annotated(param, typ)).collect();
+    let returns = returns.map_or(String::new(), |typ| format!(": {}", typ));
+    format!("{}({}){}", name.lexeme, params.join(", "), returns)
+}
+
+/// `a, b` of the names unpacked by `let (a, b)`.
+fn fmt_names(names: &[Rc<Token>]) -> String {
+    names.iter().map(|name| name.lexeme.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+/// How a statement finished, telling the enclosing loops and calls whether to go on.
+pub enum Flow {
+    Normal,
+    Break,
+    Continue,
+    Return(Value),
+    /// A runtime error was reported, nothing runs after it.
+    Abort
+}
+
  impl Stmt {
-    pub fn execute(&self, env_arena: &mut EnvironmentArena, environment: usize) -> Option<Value> {
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Block { span, .. } | Stmt::Expression { span, .. } | Stmt::Print { span, .. } | Stmt::Faran { span }
+                | Stmt::Ke { span } | Stmt::Swap { span } | Stmt::Over { span } | Stmt::Rot { span } | Stmt::Drop2 { span } | Stmt::Let { span, .. } | Stmt::Unpack { span, .. } | Stmt::If { span, .. } | Stmt::While { span, .. }
+                | Stmt::Fun { span, .. } | Stmt::Struct { span, .. } | Stmt::Return { span, .. } | Stmt::Break { span } | Stmt::Continue { span } => *span
+        }
+    }
+
+    /// The token of a statement made of a stack word, like `swap;`.
+    pub fn word(&self) -> Option<TokenType> {
+        match self {
+            Stmt::Faran { .. } => Some(TokenType::Faran),
+            Stmt::Ke { .. } => Some(TokenType::Ke),
+            Stmt::Swap { .. } => Some(TokenType::Swap),
+            Stmt::Over { .. } => Some(TokenType::Over),
+            Stmt::Rot { .. } => Some(TokenType::Rot),
+            Stmt::Drop2 { .. } => Some(TokenType::Drop2),
+            _ => None
+        }
+    }
+
+    /// Prints the statement back as ceya source, nested blocks indented by four spaces per level.
+    pub fn fmt_source(&self, indent: usize) -> String {
+        let pad = "    ".repeat(indent);
+
+        match self {
+            Stmt::Block { statements, .. } => {
+                let mut res = String::from("{\n");
+                for stmt in statements {
+                    writeln!(&mut res, "{}    {}", pad, stmt.fmt_source(indent + 1)).unwrap();
+                }
+                write!(&mut res, "{}}}", pad).unwrap();
+                res
+            },
+            Stmt::Expression { expression, .. } => format!("{};", expression.fmt_source()),
+            Stmt::Print { expression, arguments, stderr, .. } => {
+                let mut res = format!("{} {}", if *stderr { "eprint" } else { "print" }, expression.fmt_source());
+                for arg in arguments {
+                    write!(&mut res, ", {}", arg.fmt_source()).unwrap();
+                }
+                res.push(';');
+                res
+            },
+            Stmt::Faran { .. } => "faran;".into(),
+            Stmt::Ke { .. } => "ke;".into(),
+            Stmt::Swap { .. } => "swap;".into(),
+            Stmt::Over { .. } => "over;".into(),
+            Stmt::Rot { .. } => "rot;".into(),
+            Stmt::Drop2 { .. } => "drop2;".into(),
+            Stmt::Let { name, annotation, initializer, .. } => format!("let {} = {};", annotated(name, annotation), initializer.fmt_source()),
+            Stmt::Unpack { names, initializer, .. } => format!("let ({}) = {};", fmt_names(names), initializer.fmt_source()),
+            Stmt::If { condition, then, els, .. } => {
+                let mut res = format!("if ({}) {}", condition.fmt_source(), then.fmt_source(indent));
+                if let Some(els) = els {
+                    write!(&mut res, " else {}", els.fmt_source(indent)).unwrap();
+                }
+                res
+            },
+            Stmt::While { condition, body, increment: None, .. } => {
+                format!("while ({}) {}", condition.fmt_source(), body.fmt_source(indent))
+            },
+            Stmt::While { condition, body, increment: Some(increment), .. } => {
+                format!("for (; {}; {}) {}", condition.fmt_source(), increment.fmt_source(), body.fmt_source(indent))
+            },
+            Stmt::Fun { name, params, types, returns, body, .. } => {
+                format!("fn {} {}", fmt_signature(name, params, types, returns), body.fmt_source(indent))
+            },
+            Stmt::Struct { name, fields, methods, .. } => {
+                let fields: Vec<&str> = fields.iter().map(|field| field.lexeme.as_str()).collect();
+                if !methods.is_empty() {
+                    // the fields on a line of their own, then the methods like the statements of a block
+                    let mut res = format!("struct {} {{\n", name.lexeme);
+                    if !fields.is_empty() {
+                        writeln!(&mut res, "{}    {},", pad, fields.join(", ")).unwrap();
+                    }
+                    for method in methods {
+                        writeln!(&mut res, "{}    {}", pad, method.fmt_source(indent + 1)).unwrap();
+                    }
+                    write!(&mut res, "{}}}", pad).unwrap();
+                    return res;
+                }
+                match fields.is_empty() {
+                    true => format!("struct {} {{}}", name.lexeme),
+                    false => format!("struct {} {{ {} }}", name.lexeme, fields.join(", "))
+                }
+            },
+            Stmt::Return { value, .. } => format!("return {};", value.fmt_source()),
+            Stmt::Break { .. } => "break;".into(),
+            Stmt::Continue { .. } => "continue;".into()
+        }
+    }
+
+    /// Prints the syntax tree, one statement per line under the statement it belongs to, indented
+    /// by two spaces per level, and expressions in prefix notation.
+    pub fn fmt_tree(&self, indent: usize) -> String {
+        let pad = "  ".repeat(indent);
+        let child = |stmt: &Stmt| format!("\n{}", stmt.fmt_tree(indent + 1));
+
+        match self {
+            Stmt::Block { statements, .. } => format!("{}Block{}", pad, statements.iter().map(child).collect::<String>()),
+            Stmt::Expression { expression, .. } => format!("{}Expression {}", pad, expression.fmt_output()),
+            Stmt::Print { expression, arguments, stderr, .. } => {
+                let mut res = format!("{}{} {}", pad, if *stderr { "Eprint" } else { "Print" }, expression.fmt_output());
+                for arg in arguments {
+                    write!(&mut res, " {}", arg.fmt_output()).unwrap();
+                }
+                res
+            },
+            Stmt::Faran { .. } => format!("{}Faran", pad),
+            Stmt::Ke { .. } => format!("{}Ke", pad),
+            Stmt::Swap { .. } => format!("{}Swap", pad),
+            Stmt::Over { .. } => format!("{}Over", pad),
+            Stmt::Rot { .. } => format!("{}Rot", pad),
+            Stmt::Drop2 { .. } => format!("{}Drop2", pad),
+            Stmt::Let { name, annotation, initializer, .. } => format!("{}Let {} {}", pad, annotated(name, annotation), initializer.fmt_output()),
+            Stmt::Unpack { names, initializer, .. } => format!("{}Unpack ({}) {}", pad, fmt_names(names), initializer.fmt_output()),
+            Stmt::If { condition, then, els, .. } => {
+                let mut res = format!("{}If {}{}", pad, condition.fmt_output(), child(then));
+                if let Some(els) = els {
+                    write!(&mut res, "\n{}Else{}", pad, child(els)).unwrap();
+                }
+                res
+            },
+            Stmt::While { condition, body, increment, .. } => {
+                let mut res = format!("{}While {}{}", pad, condition.fmt_output(), child(body));
+                if let Some(increment) = increment {
+                    write!(&mut res, "\n{}  Increment {}", pad, increment.fmt_output()).unwrap();
+                }
+                res
+            },
+            Stmt::Fun { name, params, types, returns, body, .. } => {
+                format!("{}Fn {}{}", pad, fmt_signature(name, params, types, returns), child(body))
+            },
+            Stmt::Struct { name, fields, methods, .. } => {
+                let fields: Vec<&str> = fields.iter().map(|field| field.lexeme.as_str()).collect();
+                format!("{}Struct {} {{{}}}{}", pad, name.lexeme, fields.join(", "), methods.iter().map(child).collect::<String>())
+            },
+            Stmt::Return { value, .. } => format!("{}Return {}", pad, value.fmt_output()),
+            Stmt::Break { .. } => format!("{}Break", pad),
+            Stmt::Continue { .. } => format!("{}Continue", pad)
+        }
+    }
+
+    /// Replaces `if` and `while` statements whose condition is a constant with what they always do,
+    /// so the interpreter doesn't evaluate the condition again: the taken branch, nothing, or a loop
+    /// on `true`. `com` does the same while compiling, depending on the optimization level.
+    pub fn eliminate_branches(self) -> Stmt {
+        match self {
+            Stmt::Block { statements, span } => Stmt::Block { statements: statements.into_iter().map(Stmt::eliminate_branches).collect(), span },
+            Stmt::If { condition, then, els, span } => match condition.constant() {
+                Some(value) if value.is_truthy() => then.eliminate_branches(),
+                Some(_) => els.map_or(Stmt::Block { statements: vec![], span }, |els| els.eliminate_branches()),
+                None => Stmt::If { condition, then: Box::new(then.eliminate_branches()), els: els.map(|els| Box::new(els.eliminate_branches())), span }
+            },
+            Stmt::While { condition, body, increment, span } => match condition.constant() {
+                Some(value) if !value.is_truthy() => Stmt::Block { statements: vec![], span },
+                Some(_) => Stmt::While { condition: Box::new(Expr::Literal { value: Value::Boolean(true), span: condition.span() }), body: Box::new(body.eliminate_branches()), increment, span },
+                None => Stmt::While { condition, body: Box::new(body.eliminate_branches()), increment, span }
+            },
+            // freshly parsed bodies aren't shared yet
+            Stmt::Fun { name, params, types, returns, body, span } => match Rc::try_unwrap(body) {
+                Ok(body) => Stmt::Fun { name, params, types, returns, body: Rc::new(body.eliminate_branches()), span },
+                Err(body) => Stmt::Fun { name, params, types, returns, body, span }
+            },
+            stmt => stmt
+        }
+    }
+
+    /// Number of statement and expression nodes in the tree, for `sim --stats`.
+    pub fn node_count(&self) -> usize {
+        1 + match self {
+            Stmt::Block { statements, .. } => statements.iter().map(Stmt::node_count).sum(),
+            Stmt::Expression { expression, .. } => expression.node_count(),
+            Stmt::Print { expression, arguments, .. } => expression.node_count() + arguments.iter().map(Expr::node_count).sum::<usize>(),
+            Stmt::Faran { .. } | Stmt::Ke { .. } | Stmt::Swap { .. } | Stmt::Over { .. } | Stmt::Rot { .. } | Stmt::Drop2 { .. }
+                | Stmt::Break { .. } | Stmt::Continue { .. } => 0,
+            Stmt::Struct { methods, .. } => methods.iter().map(Stmt::node_count).sum(),
+            Stmt::Let { initializer, .. } | Stmt::Unpack { initializer, .. } => initializer.node_count(),
+            Stmt::If { condition, then, els, .. } => condition.node_count() + then.node_count() + els.as_ref().map_or(0, |els| els.node_count()),
+            Stmt::While { condition, body, increment, .. } => condition.node_count() + body.node_count() + increment.as_ref().map_or(0, |increment| increment.node_count()),
+            Stmt::Fun { body, .. } => body.node_count(),
+            Stmt::Return { value, .. } => value.node_count()
+        }
+    }
+
+    pub fn execute(&self, env_arena: &mut EnvironmentArena, environment: usize) -> Flow {
+        if env_arena.failed() {
+            return Flow::Abort;
+        }
+
         match *self {
-            Stmt::Block { ref statements } => {
+            Stmt::Block { ref statements, .. } => {
                 let new_env = env_arena.add(Some(environment));
                 for stmt in statements {
-                    if let Some(v) = stmt.execute(env_arena, new_env) {
-                        return Some(v);
+                    match stmt.execute(env_arena, new_env) {
+                        Flow::Normal => (),
+                        flow => return flow
                     }
                 }
-                None
+                Flow::Normal
             }
-            Stmt::Expression { ref expression } => { 
-                expression.evaluate(env_arena, environment);
-                None
+            Stmt::Expression { ref expression, .. } => { 
+                let value = expression.evaluate(env_arena, environment);
+                env_arena.stack.push(value);
+                Flow::Normal
             },
-            Stmt::Print { ref expression } => {
+            Stmt::Print { ref expression, ref arguments, stderr, span } => {
                 let value = expression.evaluate(env_arena, environment);
-                println!("{}", value);
-                None
-            },
-            // Stmt::Let { ref name, ref initializer } => {
-            //     let value = initializer.evaluate(env_arena, environment);
-            //     env_arena.define(environment, &name.lexeme, value);
-            //     None
-            // },
-            Stmt::If { ref condition, ref then, ref els } => {
+                let line = if arguments.is_empty() {
+                    value.to_string()
+                } else {
+                    let template = match value {
+                        Value::String(s) => s,
+                        _ => {
+                            env_arena.report(CeyaError::Runtime { span, message: "Formatted print expects a string template.".into() });
+                            return Flow::Abort;
+                        }
+                    };
+                    let args: Vec<Value> = arguments.iter().map(|arg| arg.evaluate(env_arena, environment)).collect();
+                    match stdlib::format_template(&template, &args) {
+                        Ok(s) => s,
+                        Err(message) => {
+                            env_arena.report(CeyaError::Runtime { span, message });
+                            return Flow::Abort;
+                        }
+                    }
+                };
+                if env_arena.failed() {
+                    return Flow::Abort;
+                }
+
+                if stderr {
+                    env_arena.eprint(&line);
+                } else {
+                    env_arena.print(&line);
+                }
+                Flow::Normal
+            },
+            Stmt::Let { ref name, ref initializer, .. } => {
+                let value = initializer.evaluate(env_arena, environment);
+                env_arena.define(environment, &name.lexeme, value);
+                Flow::Normal
+            },
+            Stmt::Unpack { .. } => {
+                self.execute_unpack(env_arena, environment);
+                Flow::Normal
+            },
+            Stmt::If { ref condition, ref then, ref els, .. } => {
                 if condition.is_true(env_arena, environment) {
                     return then.execute(env_arena, environment);
                 } else if let Some(stmt) = els {
                     return stmt.execute(env_arena, environment);
                 }
-                None
+                Flow::Normal
             },
-            Stmt::While { ref condition, ref body } => {
-                while condition.is_true(env_arena, environment) {
-                    return body.execute(env_arena, environment);
+            Stmt::While { ref condition, ref body, ref increment, .. } => {
+                // left by `eliminate_branches` for conditions that are always true
+                let forever = matches!(condition.as_ref(), Expr::Literal { value: Value::Boolean(true), .. });
+                while forever || condition.is_true(env_arena, environment) {
+                    match body.execute(env_arena, environment) {
+                        Flow::Break => break,
+                        flow @ (Flow::Return(_) | Flow::Abort) => return flow,
+                        Flow::Normal | Flow::Continue => ()
+                    }
+                    if let Some(increment) = increment {
+                        increment.evaluate(env_arena, environment);
+                    }
                 }
-                None
+                Flow::Normal
+            },
+            Stmt::Fun { ref name, .. } => {
+                // the function captures the environment it is declared in, itself included so it can recurse
+                env_arena.define(environment, &name.lexeme, Value::Fun(self.closure(environment)));
+                Flow::Normal
+            },
+            Stmt::Struct { ref name, ref fields, ref methods, .. } => {
+                let methods = methods.iter().map(|method| method.closure(environment)).collect();
+                env_arena.define(environment, &name.lexeme, Stmt::declare_struct(name, fields, methods));
+                Flow::Normal
             },
-            // Stmt::Fun { ref name, ref params, ref body } => {
-            //     let fun = Fun::Code { name: String::from_str(&name.lexeme).expect("str expected"), params: params.clone(), body: Rc::clone(body), closure: environment };
-            //     env_arena.define(environment, &name.lexeme, Value::Fun(fun));
-            //     None
-            // },
-            // Stmt::Return { keyword: _, ref value } => {
-            //     let v = value.evaluate(env_arena, environment);
-            //     Some(v)
-            // },
-            Stmt::Faran => None,
-            Stmt::Ke => None
+            Stmt::Return { ref value, .. } => Flow::Return(value.evaluate(env_arena, environment)),
+            Stmt::Break { .. } => Flow::Break,
+            Stmt::Continue { .. } => Flow::Continue,
+            Stmt::Faran { span } | Stmt::Ke { span } | Stmt::Swap { span } | Stmt::Over { span } | Stmt::Rot { span } | Stmt::Drop2 { span } => {
+                stack_word(&self.word().expect("stack word"), env_arena, span);
+                Flow::Normal
+            }
         }
     }
 
-    pub fn compile(&self) -> String {
+    /// `let (a, b) = f();`, apart so that it doesn't grow the frame of every recursive `execute`.
+    #[inline(never)]
+    fn execute_unpack(&self, env_arena: &mut EnvironmentArena, environment: usize) {
+        let Stmt::Unpack { names, initializer, span } = self else { unreachable!("not an unpacking") };
+        let value = initializer.evaluate(env_arena, environment);
+        let values = unpack(value, names.len(), *span).unwrap_or_else(|e| {
+            env_arena.report(e);
+            vec![Value::Null; names.len()]
+        });
+        for (name, value) in names.iter().zip(values) {
+            env_arena.define(environment, &name.lexeme, value);
+        }
+    }
+
+    /// Number of values the function body returns at most, 1 when it never returns more.
+    fn returned_values(&self) -> usize {
         match self {
-            Stmt::Expression { expression } => {
-                expression.compile()
+            Stmt::Block { statements, .. } => statements.iter().map(Stmt::returned_values).max().unwrap_or(1),
+            Stmt::If { then, els, .. } => then.returned_values().max(els.as_ref().map_or(1, |els| els.returned_values())),
+            Stmt::While { body, .. } => body.returned_values(),
+            Stmt::Return { value, .. } => match value.as_ref() {
+                Expr::Tuple { elements, .. } => elements.len(),
+                _ => 1
             },
-            Stmt::Print { expression } => {
-                let mut res = String::new();
+            _ => 1
+        }
+    }
+
+    /// The function of a `fn` declaration, run in `environment`.
+    fn closure(&self, environment: usize) -> Fun {
+        let Stmt::Fun { name, params, body, .. } = self else { unreachable!("not a function declaration") };
+        Fun::Code { name: name.lexeme.clone(), params: params.clone(), body: Rc::clone(body), closure: environment }
+    }
 
-                writeln!(&mut res, "   ; print {}", expression.fmt_output()).unwrap();
-                write!(&mut res, "{}", expression.compile()).unwrap();
+    /// The value a `struct` declaration gives its name, with the functions of its methods.
+    pub(crate) fn declare_struct(name: &Token, fields: &[Rc<Token>], methods: Vec<Fun>) -> Value {
+        Value::Struct(Rc::new(Struct { name: name.lexeme.clone(), fields: fields.iter().map(|field| field.lexeme.clone()).collect(), methods }))
+    }
 
-                writeln!(&mut res, "   lea rcx, [msg]").unwrap();
-                writeln!(&mut res, "   pop rdx").unwrap();
-                writeln!(&mut res, "   xor al, al").unwrap();
-                writeln!(&mut res, "   sub rsp, 20h").unwrap();
-                writeln!(&mut res, "   call printf").unwrap();
-                writeln!(&mut res, "   add rsp, 20h").unwrap();
-                res
+    fn compile(&self, compiler: &Compiler) {
+        match self {
+            Stmt::Struct { span, .. } => {
+                compiler.error(*span, "Structs are not supported by the native backend yet.");
+            },
+            Stmt::Expression { expression, .. } => {
+                expression.compile_value(compiler)
             },
-            Stmt::Block { statements } => {
-                let mut res = String::new();
+            Stmt::Print { expression, arguments, stderr, .. } => {
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; {} {}", if *stderr { "eprint" } else { "print" }, expression.fmt_output()).unwrap();
+                }
+                if !arguments.is_empty() {
+                    writeln!(compiler.out(), "   ; not implemented yet!").unwrap();
+                    return;
+                }
+
+                // printf has no portable stderr symbol on Windows, so `eprint` always uses the emitted routines
+                let stream = if *stderr { Stream::Stderr } else { Stream::Stdout };
+                let builtin_print = compiler.options.builtin_print || *stderr;
+                if *stderr {
+                    compiler.stderr.set(true);
+                }
+
+                // Strings, booleans, fractional numbers and integers past 64 bits only exist as constants in
+                // compiled code: print their interpreter text so both agree.
+                if let Some(value) = expression.constant().filter(|value| !matches!(value, Value::Number(n) if n.fract() == 0. && (i64::MIN as f64..i64::MAX as f64).contains(n))) {
+                    let mut data = compiler.data.borrow_mut();
+                    if builtin_print {
+                        let text = format!("{}\n", value);
+                        compiler.emit(&emit_write(&data.string(&text), text.len(), stream));
+                    } else {
+                        writeln!(compiler.out(), "   lea rdx, [{}]", data.string(&value.to_string())).unwrap();
+                        writeln!(compiler.out(), "   mov r9, {}", stream.handle()).unwrap();
+                        writeln!(compiler.out(), "   call {}", runtime::PRINT_STR).unwrap();
+                    }
+                    return;
+                }
+
+                if expression.static_type(compiler) == StaticType::List {
+                    compiler.warn(expression.span(), "Compiled programs can't print lists yet.");
+                    expression.compile_value(compiler);
+                    writeln!(compiler.out(), "   add rsp, 8").unwrap();
+                    return;
+                }
+
+                if expression.static_type(compiler) == StaticType::String {
+                    expression.compile_value(compiler);
+                    writeln!(compiler.out(), "   pop rdx").unwrap();
+                    writeln!(compiler.out(), "   mov r9, {}", stream.handle()).unwrap();
+                    writeln!(compiler.out(), "   call {}", runtime::PRINT_STR).unwrap();
+                    return;
+                }
 
+                if expression.static_type(compiler) == StaticType::Boolean {
+                    expression.compile_value(compiler);
+                    let mut data = compiler.data.borrow_mut();
+                    if builtin_print {
+                        compiler.emit(&emit_select("rdx", &data.string("true\n"), &data.string("false\n")));
+                        writeln!(compiler.out(), "   mov r8, 6").unwrap();
+                        writeln!(compiler.out(), "   mov r10, 5").unwrap();
+                        writeln!(compiler.out(), "   cmovnz r8, r10").unwrap();
+                        writeln!(compiler.out(), "   call {}", stream.routine()).unwrap();
+                    } else {
+                        compiler.emit(&emit_select("rdx", &data.string("true"), &data.string("false")));
+                        writeln!(compiler.out(), "   mov r9, {}", stream.handle()).unwrap();
+                        writeln!(compiler.out(), "   call {}", runtime::PRINT_STR).unwrap();
+                    }
+                    return;
+                }
+
+                // `print to_hex(x);` prints in base 16 (printf's %llx), `to_bin` in base 2 which printf has no format for
+                let (printed, base) = match expression.as_ref() {
+                    Expr::Call { callee, paren: _, arguments, .. } if arguments.len() == 1 && matches!(callee.as_ref(), Expr::Variable { name, .. } if name.lexeme == "to_hex") => {
+                        (&arguments[0], 16)
+                    },
+                    Expr::Call { callee, paren: _, arguments, .. } if builtin_print && arguments.len() == 1 && matches!(callee.as_ref(), Expr::Variable { name, .. } if name.lexeme == "to_bin") => {
+                        (&arguments[0], 2)
+                    },
+                    _ => (expression.as_ref(), 10)
+                };
+                printed.compile_value(compiler);
+                compiler.emit(&emit_print(base, stream));
+            },
+            Stmt::Block { statements, .. } => {
+                compiler.scopes.borrow_mut().push(HashMap::new());
                 for stmt in statements {
-                    write!(&mut res, "{}", stmt.compile()).unwrap();
+                    stmt.compile(compiler);
+                }
+                compiler.scopes.borrow_mut().pop();
+            },
+            Stmt::Let { name, initializer, .. } => {
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; let {} = {}", name.lexeme, initializer.fmt_output()).unwrap();
                 }
 
-                res
+                // evaluated before the declaration, so `let x = x + 1;` reads the outer `x`
+                initializer.compile_scalar(compiler, "stored in a compiled variable");
+                let label = compiler.declare(&name.lexeme, initializer.static_type(compiler));
+                writeln!(compiler.out(), "   mov [{}], rax", label).unwrap();
             },
-            Stmt::If { condition, then, els } => {
-                let mut res = String::new();
+            Stmt::Unpack { names, initializer, span } => {
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; let ({}) = {}", fmt_names(names), initializer.fmt_output()).unwrap();
+                }
+
+                let (callee, arguments) = match initializer.as_ref() {
+                    Expr::Call { callee, arguments, .. } => match callee.as_ref() {
+                        Expr::Variable { name, .. } => (compiler.signatures.borrow().get(&name.lexeme).cloned(), arguments),
+                        _ => (None, arguments)
+                    },
+                    _ => return compiler.error(*span, "Only the values of a call to a function declared with `fn` can be unpacked in compiled code.")
+                };
+                match callee {
+                    Some((label, arity, 2)) if arity == arguments.len() && names.len() == 2 => {
+                        for argument in arguments {
+                            argument.compile_value(compiler);
+                        }
+                        compiler.emit(&emit_call_function(compiler.options.target, &label, arity));
+                        writeln!(compiler.out(), "   push rdx").unwrap();
+                        let labels: Vec<String> = names.iter().map(|name| compiler.declare(&name.lexeme, StaticType::Number)).collect();
+                        for label in labels.iter().rev() {
+                            writeln!(compiler.out(), "   pop rax").unwrap();
+                            writeln!(compiler.out(), "   mov [{}], rax", label).unwrap();
+                        }
+                    },
+                    Some((_, arity, values)) if arity == arguments.len() && values != names.len() => {
+                        compiler.error(*span, &format!("Expected {} values, but '{}' returns {}.", names.len(), initializer.fmt_source(), values));
+                    },
+                    // more than two, which its `return` reported
+                    Some((_, arity, _)) if arity == arguments.len() => (),
+                    _ => compiler.error(*span, "Only the values of a call to a function declared with `fn` can be unpacked in compiled code.")
+                }
+            },
+            Stmt::If { condition, then, els, .. } => {
+                condition.warn_constant_condition(compiler, "if");
+                if let Some(value) = condition.constant().filter(|_| compiler.options.opt_level.folds()) {
+                    if compiler.options.comments != AsmComments::Off {
+                        writeln!(compiler.out(), "   ; if {} (always {})", condition.fmt_output(), value.is_truthy()).unwrap();
+                    }
+                    if value.is_truthy() {
+                        then.compile(compiler);
+                    } else if let Some(e) = els {
+                        e.compile(compiler);
+                    }
+                    return;
+                }
 
-                let mut rng = rand::thread_rng();
-                let label = rng.gen_range(100..1000);
+                let label = compiler.next_label();
 
-                writeln!(&mut res, "   ; if {}", condition.fmt_output()).unwrap();
-                write!(&mut res, "{}", condition.compile()).unwrap();
-                writeln!(&mut res, "   pop rax").unwrap();
-                writeln!(&mut res, "   cmp rax, 1").unwrap();
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; if {}", condition.fmt_output()).unwrap();
+                }
                 match els {
                     Some(e) => {
-                        writeln!(&mut res, "   jne .ne_{}", label).unwrap();
-                        write!(&mut res, "{}", then.compile()).unwrap();
-                        writeln!(&mut res, "   jmp .end_{}", label).unwrap();
-                        writeln!(&mut res, ".ne_{}:", label).unwrap();
-                        write!(&mut res, "{}", e.compile()).unwrap();
+                        condition.compile_branch(compiler, &format!(".ne_{}", label), false);
+                        then.compile(compiler);
+                        writeln!(compiler.out(), "   jmp .end_{}", label).unwrap();
+                        compiler.define_label(&format!(".ne_{}", label), condition.span().start);
+                        e.compile(compiler);
                     },
                     _ => {
-                        writeln!(&mut res, "   jne .end_{}", label).unwrap();
-                        write!(&mut res, "{}", then.compile()).unwrap();
+                        condition.compile_branch(compiler, &format!(".end_{}", label), false);
+                        then.compile(compiler);
                     }
                 }
                 
-                writeln!(&mut res, ".end_{}:", label).unwrap();
+                compiler.define_label(&format!(".end_{}", label), condition.span().start);
+            },
+            Stmt::While { condition, body, increment, .. } => {
+                let label = compiler.next_label();
+                // `continue` runs the increment when there is one, the condition otherwise
+                let next = match (increment, condition.constant().filter(|_| compiler.options.opt_level.folds())) {
+                    (Some(_), _) => format!(".next_{}", label),
+                    (None, Some(_)) => format!(".body_{}", label),
+                    (None, None) => format!(".cond_{}", label)
+                };
+                let increment = || if let Some(increment) = increment {
+                    compiler.define_label(&next, increment.span().start);
+                    increment.compile_value(compiler);
+                    writeln!(compiler.out(), "   pop rax").unwrap(); // the value would pile up on every iteration
+                };
+                compiler.frame.borrow_mut().loops.push((format!(".end_{}", label), next.clone()));
 
-                res
+                condition.warn_constant_condition(compiler, "while");
+                if let Some(value) = condition.constant().filter(|_| compiler.options.opt_level.folds()) {
+                    if compiler.options.comments != AsmComments::Off {
+                        writeln!(compiler.out(), "   ; while {} (always {})", condition.fmt_output(), value.is_truthy()).unwrap();
+                    }
+                    if value.is_truthy() {
+                        compiler.define_label(&format!(".body_{}", label), condition.span().start);
+                        body.compile(compiler);
+                        increment();
+                        writeln!(compiler.out(), "   jmp .body_{}", label).unwrap();
+                        compiler.define_label(&format!(".end_{}", label), condition.span().start);
+                    }
+                    compiler.frame.borrow_mut().loops.pop();
+                    return;
+                }
+
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; while {}", condition.fmt_output()).unwrap();
+                }
+                writeln!(compiler.out(), "   jmp .cond_{}", label).unwrap();
+                compiler.define_label(&format!(".body_{}", label), condition.span().start);
+                body.compile(compiler);
+                increment();
+                compiler.define_label(&format!(".cond_{}", label), condition.span().start);
+                condition.compile_branch(compiler, &format!(".body_{}", label), true);
+                compiler.define_label(&format!(".end_{}", label), condition.span().start);
+                compiler.frame.borrow_mut().loops.pop();
+            },
+            Stmt::Faran { .. } => {
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; faran").unwrap();
+                }
+
+                writeln!(compiler.out(), "   pop rax").unwrap();
             },
-            Stmt::While { condition, body } => {
-                let mut res = String::new();
+            Stmt::Ke { .. } => {
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; ke").unwrap();
+                }
+
+                writeln!(compiler.out(), "   pop rax").unwrap();
+                writeln!(compiler.out(), "   push rax").unwrap();
+                writeln!(compiler.out(), "   push rax").unwrap();
+            },
+            Stmt::Swap { .. } | Stmt::Over { .. } | Stmt::Rot { .. } | Stmt::Drop2 { .. } => {
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; {}", self.fmt_source(0).trim_end_matches(';')).unwrap();
+                }
 
-                let mut rng = rand::thread_rng();
-                let label = rng.gen_range(100..1000);
+                let code: &[&str] = match self {
+                    // a b -- b a
+                    Stmt::Swap { .. } => &["pop rax", "pop rbx", "push rax", "push rbx"],
+                    // a b -- a b a
+                    Stmt::Over { .. } => &["push qword [rsp + 8]"],
+                    // a b c -- b c a
+                    Stmt::Rot { .. } => &["pop rcx", "pop rbx", "pop rax", "push rbx", "push rcx", "push rax"],
+                    _ => &["add rsp, 16"]
+                };
+                for line in code {
+                    writeln!(compiler.out(), "   {}", line).unwrap();
+                }
+            },
+            Stmt::Fun { name, params, types, body, span, .. } => {
+                if compiler.options.comments != AsmComments::Off {
+                    let params: Vec<&str> = params.iter().map(|param| param.lexeme.as_str()).collect();
+                    writeln!(compiler.out(), "   ; fn {}({})", name.lexeme, params.join(", ")).unwrap();
+                }
 
-                writeln!(&mut res, "   ; while {}", condition.fmt_output()).unwrap();
-                writeln!(&mut res, "   jmp .cond_{}", label).unwrap();
-                writeln!(&mut res, ".body_{}:", label).unwrap();
-                write!(&mut res, "{}", body.compile()).unwrap();
-                writeln!(&mut res, ".cond_{}:", label).unwrap();
-                write!(&mut res, "{}", condition.compile()).unwrap();
-                writeln!(&mut res, "   pop rax").unwrap();
-                writeln!(&mut res, "   cmp rax, 1").unwrap();
-                writeln!(&mut res, "   je .body_{}", label).unwrap();
+                let mut label = format!("fn_{}", name.lexeme);
+                if compiler.signatures.borrow().values().any(|(other, _, _)| *other == label) {
+                    write!(&mut label, "_{}", compiler.next_label()).unwrap();
+                }
+                // declared before its body so it can call itself
+                compiler.signatures.borrow_mut().insert(name.lexeme.clone(), (label.clone(), params.len(), body.returned_values()));
+                compiler.symbols.borrow_mut().push((label.clone(), span.start));
 
-                res
+                // the body sees the globals and its parameters, the slots of an enclosing function's frame are out of reach
+                let globals = compiler.scopes.borrow().iter()
+                    .map(|scope| scope.iter().filter(|(_, (address, _))| !address.starts_with("rbp")).map(|(name, variable)| (name.clone(), variable.clone())).collect())
+                    .collect();
+                let outer_scopes = compiler.scopes.replace(globals);
+                // annotated parameters print as their type, the others as numbers
+                let parameters = params.iter().zip(types).enumerate()
+                    .map(|(i, (param, typ))| (param.lexeme.clone(), (compiler.options.target.parameter(i), StaticType::of(*typ))))
+                    .collect();
+                compiler.scopes.borrow_mut().push(parameters);
+                let outer_frame = compiler.frame.replace(Frame { label: Some(label.clone()), locals: compiler.options.target.spilled(params.len()), loops: Vec::new() });
+                let outer_code = compiler.code.take();
+
+                body.compile(compiler);
+                writeln!(compiler.out(), "   xor eax, eax").unwrap(); // `fu` when the end is reached
+                compiler.define_label(&format!("{}_return", label), span.end);
+
+                let code = compiler.code.replace(outer_code);
+                let frame = compiler.frame.replace(outer_frame);
+                compiler.scopes.replace(outer_scopes);
+
+                let mut function = emit_procedure(compiler.options.target, &label, params.len(), frame.locals, &code);
+                if compiler.options.opt_level.peephole() {
+                    function = peephole(&function);
+                }
+                writeln!(compiler.functions.borrow_mut(), "{}", function).unwrap();
             },
-            Stmt::Faran => {
-                let mut res = String::new();
-                writeln!(&mut res, "   ; faran").unwrap();
+            Stmt::Return { value, .. } => {
+                let Some(label) = compiler.frame.borrow().label.clone() else {
+                    compiler.warn(self.span(), "Can't return from top-level code.");
+                    return;
+                };
 
-                writeln!(&mut res, "   pop rax").unwrap();
-                res
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; return {}", value.fmt_output()).unwrap();
+                }
+                match value.as_ref() {
+                    // a bare `return;` gives the same as reaching the end
+                    Expr::Literal { value: Value::Null, .. } => writeln!(compiler.out(), "   xor eax, eax").unwrap(),
+                    // a second value goes in rdx, which neither the epilogue nor the call sequence touch
+                    Expr::Tuple { elements, .. } if elements.len() == 2 => {
+                        elements[0].compile_scalar(compiler, "returned by a compiled function");
+                        writeln!(compiler.out(), "   push rax").unwrap();
+                        elements[1].compile_scalar(compiler, "returned by a compiled function");
+                        writeln!(compiler.out(), "   mov rdx, rax").unwrap();
+                        writeln!(compiler.out(), "   pop rax").unwrap();
+                    },
+                    Expr::Tuple { .. } => compiler.error(value.span(), "Compiled functions return at most two values, in rax and rdx."),
+                    value => value.compile_scalar(compiler, "returned by a compiled function")
+                }
+                writeln!(compiler.out(), "   jmp {}_return", label).unwrap();
             },
-            Stmt::Ke => {
-                let mut res = String::new();
-                writeln!(&mut res, "   ; ke").unwrap();
+            Stmt::Break { .. } | Stmt::Continue { .. } => {
+                let keyword = if matches!(self, Stmt::Break { .. }) { "break" } else { "continue" };
+                let Some((end, next)) = compiler.frame.borrow().loops.last().cloned() else {
+                    compiler.warn(self.span(), &format!("Can't {} outside a loop.", keyword));
+                    return;
+                };
 
-                writeln!(&mut res, "   pop rax").unwrap();
-                writeln!(&mut res, "   push rax").unwrap();
-                writeln!(&mut res, "   push rax").unwrap();
-                res
+                if compiler.options.comments != AsmComments::Off {
+                    writeln!(compiler.out(), "   ; {}", keyword).unwrap();
+                }
+                writeln!(compiler.out(), "   jmp {}", if keyword == "break" { end } else { next }).unwrap();
             }
         }
     }
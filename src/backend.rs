@@ -0,0 +1,545 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+/// Win64 integer argument registers, in order.
+const ARG_REGISTERS: [&str; 4] = ["rcx", "rdx", "r8", "r9"];
+
+/// System V AMD64 integer argument registers, in order.
+const SYSV_ARG_REGISTERS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
+/// Registers a function must preserve for its caller (rbp is handled by the frame itself).
+const CALLEE_SAVED: [&str; 7] = ["rbx", "rsi", "rdi", "r12", "r13", "r14", "r15"];
+
+/// Platform the compiled program runs on, deciding its calling convention and how it reaches the OS.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Target {
+    /// Win64 ABI, kernel32 and the MSVC C runtime.
+    #[default]
+    Windows,
+    /// System V AMD64 ABI, Linux syscalls and glibc, built with nasm and gcc (ld without the C runtime).
+    Linux
+}
+
+impl Target {
+    /// The platform ceya itself was built for.
+    pub fn host() -> Target {
+        if cfg!(windows) { Target::Windows } else { Target::Linux }
+    }
+
+    fn arg_registers(self) -> &'static [&'static str] {
+        match self {
+            Target::Windows => &ARG_REGISTERS,
+            Target::Linux => &SYSV_ARG_REGISTERS
+        }
+    }
+
+    /// Bytes the caller reserves above the stack arguments for the callee to spill its register arguments.
+    fn shadow_space(self) -> usize {
+        match self {
+            Target::Windows => 32,
+            Target::Linux => 0
+        }
+    }
+
+    /// Address of parameter `i` of a function wrapped by `emit_procedure`. Win64 register arguments
+    /// are spilled to their shadow space, System V ones to the first frame slots.
+    pub fn parameter(self, i: usize) -> String {
+        match self {
+            Target::Windows => format!("rbp + {}", 16 + 8 * i),
+            Target::Linux if i < SYSV_ARG_REGISTERS.len() => format!("rbp - {}", 8 * (i + 1)),
+            Target::Linux => format!("rbp + {}", 16 + 8 * (i - SYSV_ARG_REGISTERS.len()))
+        }
+    }
+
+    /// Frame slots taken by the spilled register arguments of a function with `params` parameters.
+    pub fn spilled(self, params: usize) -> usize {
+        match self {
+            Target::Windows => 0,
+            Target::Linux => params.min(SYSV_ARG_REGISTERS.len())
+        }
+    }
+
+    /// Bytes ending a line printed with printf, the C runtime on Windows doesn't translate `\n`.
+    pub fn newline(self) -> &'static str {
+        match self {
+            Target::Windows => "0xd, 0xa",
+            Target::Linux => "0xa"
+        }
+    }
+
+    pub fn executable(self, name: &str) -> String {
+        match self {
+            Target::Windows => format!("{}.exe", name),
+            Target::Linux => name.into()
+        }
+    }
+}
+
+/// Where an external call argument comes from.
+pub enum Arg<'a> {
+    /// Popped from the operand stack.
+    Pop,
+    /// Address of a label, loaded with `lea`.
+    Label(&'a str),
+    /// A register or immediate, loaded with `mov`.
+    Value(&'a str)
+}
+
+/// Emits a call to an external function following the ABI of `target`: arguments go to
+/// rcx/rdx/r8/r9 (rdi/rsi/rdx/rcx/r8/r9 on Linux), the stack is aligned to 16 bytes and on Windows
+/// 32 bytes of shadow space are reserved. The operand stack can be at any depth, so rsp is saved in
+/// rbx (callee-saved in both ABIs) and restored after the call.
+pub fn emit_call(target: Target, function: &str, args: &[Arg]) -> String {
+    let mut res = String::new();
+
+    assert!(args.len() <= target.arg_registers().len(), "stack arguments are not supported");
+
+    for (arg, register) in args.iter().zip(target.arg_registers()) {
+        match arg {
+            Arg::Pop => writeln!(&mut res, "   pop {}", register).unwrap(),
+            Arg::Label(label) => writeln!(&mut res, "   lea {}, [{}]", register, label).unwrap(),
+            Arg::Value(value) => writeln!(&mut res, "   mov {}, {}", register, value).unwrap()
+        }
+    }
+
+    writeln!(&mut res, "   mov rbx, rsp").unwrap();
+    writeln!(&mut res, "   and rsp, -16").unwrap();
+    if target.shadow_space() > 0 {
+        writeln!(&mut res, "   sub rsp, {}", target.shadow_space()).unwrap();
+    }
+    writeln!(&mut res, "   xor eax, eax").unwrap(); // no vector registers used by varargs callees
+    writeln!(&mut res, "   call {}", function).unwrap();
+    writeln!(&mut res, "   mov rsp, rbx").unwrap();
+
+    res
+}
+
+/// Ends the program with exit code `code`: `ExitProcess` on Windows, the C runtime's `exit` on Linux
+/// so that printf's buffer is flushed, or the `exit` syscall without the C runtime.
+pub fn emit_exit(target: Target, code: &str, crt: bool) -> String {
+    match target {
+        Target::Windows => emit_call(target, "ExitProcess", &[Arg::Value(code)]),
+        Target::Linux if crt => emit_call(target, "exit", &[Arg::Value(code)]),
+        Target::Linux => format!("   mov edi, {}\n   mov eax, 60\n   syscall\n", code)
+    }
+}
+
+/// Calls a ceya function following the ABI of `target`, with its `arity` arguments on the operand
+/// stack, the last one on top: the first ones go to the argument registers and the others to the
+/// stack, after the shadow space. The arguments are dropped after the call and the result in rax is
+/// pushed instead. Like `emit_call`, rbx keeps the operand stack pointer across the call.
+pub fn emit_call_function(target: Target, label: &str, arity: usize) -> String {
+    let mut res = String::new();
+    let registers = target.arg_registers();
+    let argument = |i: usize| match 8 * (arity - 1 - i) {
+        0 => "[rbx]".to_string(),
+        offset => format!("[rbx + {}]", offset)
+    };
+
+    writeln!(&mut res, "   mov rbx, rsp").unwrap();
+    writeln!(&mut res, "   and rsp, -16").unwrap();
+    let reserved = (target.shadow_space() + 8 * arity.saturating_sub(registers.len())).next_multiple_of(16);
+    if reserved > 0 {
+        writeln!(&mut res, "   sub rsp, {}", reserved).unwrap();
+    }
+    for i in registers.len()..arity {
+        writeln!(&mut res, "   mov rax, {}", argument(i)).unwrap();
+        writeln!(&mut res, "   mov [rsp + {}], rax", target.shadow_space() + 8 * (i - registers.len())).unwrap();
+    }
+    for (i, register) in registers.iter().enumerate().take(arity) {
+        writeln!(&mut res, "   mov {}, {}", register, argument(i)).unwrap();
+    }
+    writeln!(&mut res, "   call {}", label).unwrap();
+    writeln!(&mut res, "   lea rsp, [rbx + {}]", 8 * arity).unwrap();
+    writeln!(&mut res, "   push rax").unwrap();
+
+    res
+}
+
+/// Where the emitted output routines write, `print` going to stdout and `eprint` to stderr.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr
+}
+
+impl Stream {
+    /// The `GetStdHandle` argument selecting the stream.
+    pub fn handle(self) -> i32 {
+        match self {
+            Stream::Stdout => -11,
+            Stream::Stderr => -12
+        }
+    }
+
+    /// The emitted routine writing r8 bytes at rdx to the stream.
+    pub fn routine(self) -> &'static str {
+        match self {
+            Stream::Stdout => "ceya_write_stdout",
+            Stream::Stderr => "ceya_write_stderr"
+        }
+    }
+}
+
+/// Pops a value and loads the address of `when_true` into `register` if it is non-zero, of
+/// `when_false` otherwise. Leaves the flags of the test set, and clobbers r10.
+pub fn emit_select(register: &str, when_true: &str, when_false: &str) -> String {
+    let mut res = String::new();
+
+    writeln!(&mut res, "   pop rax").unwrap();
+    writeln!(&mut res, "   lea {}, [{}]", register, when_false).unwrap();
+    writeln!(&mut res, "   lea r10, [{}]", when_true).unwrap();
+    writeln!(&mut res, "   test rax, rax").unwrap();
+    writeln!(&mut res, "   cmovnz {}, r10", register).unwrap();
+
+    res
+}
+
+/// Sets rax to 1 when exactly one of rax and rbx is non-zero, to 0 otherwise. Clobbers rbx.
+pub fn emit_xor() -> String {
+    let mut res = String::new();
+
+    writeln!(&mut res, "   test rax, rax").unwrap();
+    writeln!(&mut res, "   setnz al").unwrap();
+    writeln!(&mut res, "   test rbx, rbx").unwrap();
+    writeln!(&mut res, "   setnz bl").unwrap();
+    writeln!(&mut res, "   xor al, bl").unwrap();
+    writeln!(&mut res, "   movzx rax, al").unwrap();
+
+    res
+}
+
+/// Global holding rsp right after main's prologue, only defined when a program uses `depth`.
+pub const STACK_BASE: &str = "stack_base";
+
+/// Pushes the number of values on the operand stack, counted down from `STACK_BASE`.
+pub fn emit_depth(data: &mut DataSection) -> String {
+    let mut res = String::new();
+
+    if !data.labels().contains(&STACK_BASE) {
+        data.global(STACK_BASE, 0);
+    }
+    writeln!(&mut res, "   mov rax, [{}]", STACK_BASE).unwrap();
+    writeln!(&mut res, "   sub rax, rsp").unwrap();
+    writeln!(&mut res, "   sar rax, 3").unwrap();
+    writeln!(&mut res, "   push rax").unwrap();
+
+    res
+}
+
+/// Builds the `.data` and `.bss` segments; zero-initialized storage goes to `.bss` so it takes no room in the binary.
+#[derive(Default)]
+pub struct DataSection {
+    data: Vec<String>,
+    bss: Vec<String>,
+    strings: Vec<String>
+}
+
+impl DataSection {
+    /// Adds an initialized entry, e.g. `define("msg", "db \"%d\", 0")`.
+    pub fn define(&mut self, label: &str, directive: &str) {
+        self.data.push(format!("{} {}", label, directive));
+    }
+
+    /// Reserves `qwords` zeroed 8-byte slots.
+    pub fn reserve(&mut self, label: &str, qwords: usize) {
+        self.bss.push(format!("{} resq {}", label, qwords));
+    }
+
+    /// Adds a 64-bit global variable, placed in `.bss` when its initial value is zero.
+    pub fn global(&mut self, label: &str, value: i64) {
+        if value == 0 {
+            self.reserve(label, 1);
+        } else {
+            self.define(label, &format!("dq {}", value));
+        }
+    }
+
+    /// Returns the label of a null-terminated string constant, sharing one label between identical literals.
+    pub fn string(&mut self, value: &str) -> String {
+        let index = match self.strings.iter().position(|s| s == value) {
+            Some(index) => index,
+            None => {
+                self.strings.push(value.into());
+                self.define(&format!("str_{}", self.strings.len() - 1), &format!("db {}", Self::encode(value)));
+                self.strings.len() - 1
+            }
+        };
+
+        format!("str_{}", index)
+    }
+
+    /// Encodes a string as NASM `db` operands: printable ASCII in quoted runs, every other byte (quotes,
+    /// backslashes that other dialects would read as escapes, control characters, UTF-8 sequences) as a
+    /// number, followed by the terminating 0.
+    fn encode(value: &str) -> String {
+        let mut operands: Vec<String> = vec![];
+        let mut run = String::new();
+
+        for byte in value.bytes() {
+            if (0x20..0x7f).contains(&byte) && byte != b'"' && byte != b'\\' {
+                run.push(byte as char);
+                continue;
+            }
+
+            if !run.is_empty() {
+                operands.push(format!("\"{}\"", run));
+                run.clear();
+            }
+            operands.push(byte.to_string());
+        }
+
+        if !run.is_empty() {
+            operands.push(format!("\"{}\"", run));
+        }
+        operands.push("0".into());
+
+        operands.join(", ")
+    }
+
+    /// Labels of all entries, in definition order.
+    pub fn labels(&self) -> Vec<&str> {
+        self.data.iter().chain(&self.bss).filter_map(|entry| entry.split(' ').next()).collect()
+    }
+
+    pub fn emit(&self) -> String {
+        let mut res = String::new();
+
+        writeln!(&mut res, "segment .data").unwrap();
+        for entry in &self.data {
+            writeln!(&mut res, "   {}", entry).unwrap();
+        }
+
+        if !self.bss.is_empty() {
+            writeln!(&mut res, "\nsegment .bss").unwrap();
+            for entry in &self.bss {
+                writeln!(&mut res, "   {}", entry).unwrap();
+            }
+        }
+
+        res
+    }
+}
+
+/// Callee-saved registers written by a function body, found by scanning its instructions.
+pub struct RegisterUsage {
+    saved: Vec<&'static str>
+}
+
+impl RegisterUsage {
+    pub fn scan(body: &str) -> RegisterUsage {
+        let mut saved = vec![];
+
+        for line in body.lines() {
+            let code = line.split(';').next().unwrap_or_default();
+            for word in code.split(|c: char| !c.is_ascii_alphanumeric()) {
+                if let Some(register) = Self::canonical(word) {
+                    if !saved.contains(&register) {
+                        saved.push(register);
+                    }
+                }
+            }
+        }
+
+        // keep push order stable regardless of first use
+        saved.sort_by_key(|register| CALLEE_SAVED.iter().position(|r| r == register));
+        RegisterUsage { saved }
+    }
+
+    /// Maps any sub-register name (`ebx`, `r12d`, `sil`...) to its callee-saved 64-bit register.
+    fn canonical(word: &str) -> Option<&'static str> {
+        match word {
+            "rbx" | "ebx" | "bx" | "bl" | "bh" => Some("rbx"),
+            "rsi" | "esi" | "si" | "sil" => Some("rsi"),
+            "rdi" | "edi" | "di" | "dil" => Some("rdi"),
+            _ => CALLEE_SAVED[3..].iter().find(|r| {
+                word.strip_prefix(**r).is_some_and(|suffix| matches!(suffix, "" | "d" | "w" | "b"))
+            }).copied()
+        }
+    }
+
+    /// Sets up the frame, `locals` slots right under rbp and the saved registers below them.
+    pub fn prologue(&self, locals: usize) -> String {
+        let mut res = String::new();
+
+        writeln!(&mut res, "   push rbp").unwrap();
+        writeln!(&mut res, "   mov rbp, rsp").unwrap();
+        if locals > 0 {
+            writeln!(&mut res, "   sub rsp, {}", 8 * locals).unwrap();
+        }
+        for register in &self.saved {
+            writeln!(&mut res, "   push {}", register).unwrap();
+        }
+
+        res
+    }
+
+    pub fn epilogue(&self, locals: usize) -> String {
+        let mut res = String::new();
+
+        writeln!(&mut res, "   lea rsp, [rbp - {}]", 8 * (locals + self.saved.len())).unwrap();
+        for register in self.saved.iter().rev() {
+            writeln!(&mut res, "   pop {}", register).unwrap();
+        }
+        if locals > 0 {
+            writeln!(&mut res, "   mov rsp, rbp").unwrap();
+        }
+        writeln!(&mut res, "   pop rbp").unwrap();
+        writeln!(&mut res, "   ret").unwrap();
+
+        res
+    }
+}
+
+/// Wraps a function body with a frame that saves and restores exactly the callee-saved registers it uses.
+pub fn emit_function(name: &str, body: &str) -> String {
+    emit_frame(name, "", body, 0)
+}
+
+/// Wraps the body of a ceya function called through `emit_call_function`. The register arguments
+/// are stored to memory, so parameter `i` is always at `target.parameter(i)`, and `locals` slots
+/// (spilled arguments included) are reserved from `[rbp - 8]` down. The body returns by jumping to
+/// the end with the result in rax.
+pub fn emit_procedure(target: Target, name: &str, params: usize, locals: usize, body: &str) -> String {
+    let mut spills = String::new();
+
+    for (i, register) in target.arg_registers().iter().enumerate().take(params) {
+        writeln!(&mut spills, "   mov [{}], {}", target.parameter(i), register).unwrap();
+    }
+
+    emit_frame(name, &spills, body, locals)
+}
+
+/// `entry` runs right after the prologue, reading argument registers isn't a use to preserve.
+fn emit_frame(name: &str, entry: &str, body: &str, locals: usize) -> String {
+    let usage = RegisterUsage::scan(body);
+    let mut res = String::new();
+
+    writeln!(&mut res, "{}:", name).unwrap();
+    write!(&mut res, "{}", usage.prologue(locals)).unwrap();
+    write!(&mut res, "{}", entry).unwrap();
+    write!(&mut res, "{}", body).unwrap();
+    write!(&mut res, "{}", usage.epilogue(locals)).unwrap();
+
+    res
+}
+
+/// Rewrites `push x` directly followed by `pop r` into `mov r, x`, or nothing when x is r. A `mov`
+/// left by an earlier rewrite may sit between them (`push a; push b; pop rbx; pop rax`) as long as
+/// the two moves don't touch each other's registers. Neither instruction touches flags.
+pub fn peephole(body: &str) -> String {
+    let mut lines: Vec<String> = vec![];
+
+    for line in body.lines() {
+        let Some(register) = line.trim().strip_prefix("pop ").filter(|r| !r.contains(';')) else {
+            lines.push(line.into());
+            continue;
+        };
+
+        let pushed = |line: Option<&String>| line.and_then(|l| l.trim().strip_prefix("push ")).filter(|v| !v.contains(';')).map(str::to_string);
+        let uses = |operand: &str, register: &str| operand.split(|c: char| !c.is_ascii_alphanumeric()).any(|w| w == register || w == "rsp");
+
+        if let Some(value) = pushed(lines.last()) {
+            lines.pop();
+            if value != register {
+                lines.push(format!("   mov {}, {}", register, value));
+            }
+            continue;
+        }
+
+        let between = lines.last().and_then(|l| l.trim().strip_prefix("mov ")).and_then(|m| m.split_once(", ")).map(|(d, s)| (d.to_string(), s.to_string()));
+        if let (Some((dest, source)), Some(value)) = (between, pushed(lines.len().checked_sub(2).and_then(|i| lines.get(i)))) {
+            if dest != register && !uses(&source, register) && !uses(&value, &dest) {
+                let mov = lines.pop().unwrap_or_default();
+                lines.pop();
+                lines.push(mov);
+                lines.push(format!("   mov {}, {}", register, value));
+                continue;
+            }
+        }
+
+        lines.push(line.into());
+    }
+
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emit_call, emit_call_function, emit_exit, emit_function, emit_procedure, Target, peephole, Arg, DataSection, RegisterUsage};
+
+    #[test]
+    fn test_emit_call() {
+        let asm = emit_call(Target::Windows, "printf", &[Arg::Label("msg"), Arg::Pop, Arg::Value("rbx")]);
+        let expected = "   lea rcx, [msg]\n   pop rdx\n   mov r8, rbx\n   mov rbx, rsp\n   and rsp, -16\n   sub rsp, 32\n   xor eax, eax\n   call printf\n   mov rsp, rbx\n";
+        assert_eq!(asm, expected);
+    }
+
+    #[test]
+    fn test_target() {
+        // System V: arguments from rdi, no shadow space
+        let asm = emit_call(Target::Linux, "printf", &[Arg::Label("msg"), Arg::Pop]);
+        assert_eq!(asm, "   lea rdi, [msg]\n   pop rsi\n   mov rbx, rsp\n   and rsp, -16\n   xor eax, eax\n   call printf\n   mov rsp, rbx\n");
+        assert_eq!(emit_exit(Target::Linux, "1", false), "   mov edi, 1\n   mov eax, 60\n   syscall\n");
+
+        // register parameters are spilled to the frame, the seventh is the first on the stack
+        let asm = emit_procedure(Target::Linux, "fn_f", 7, 6, "");
+        assert!(asm.contains("   sub rsp, 48\n   mov [rbp - 8], rdi\n") && asm.contains("   mov [rbp - 48], r9\n"));
+        assert_eq!(Target::Linux.parameter(6), "rbp + 16");
+        assert!(emit_call_function(Target::Linux, "fn_f", 7).contains("   sub rsp, 16\n   mov rax, [rbx]\n   mov [rsp + 0], rax\n"));
+    }
+
+    #[test]
+    fn test_register_usage() {
+        let usage = RegisterUsage::scan("   mov r12d, 1 ; rsi\n   pop rbx\n   add eax, ebx\n   mov r8, r13\n");
+        assert_eq!(usage.saved, vec!["rbx", "r12", "r13"]);
+
+        let asm = emit_function("f", "   mov rdi, 1\n");
+        let expected = "f:\n   push rbp\n   mov rbp, rsp\n   push rdi\n   mov rdi, 1\n   lea rsp, [rbp - 8]\n   pop rdi\n   pop rbp\n   ret\n";
+        assert_eq!(asm, expected);
+    }
+
+    #[test]
+    fn test_emit_procedure() {
+        let asm = emit_procedure(Target::Windows, "fn_f", 2, 1, "   pop rbx\n");
+        let expected = "fn_f:\n   push rbp\n   mov rbp, rsp\n   sub rsp, 8\n   push rbx\n   mov [rbp + 16], rcx\n   mov [rbp + 24], rdx\n   pop rbx\n   lea rsp, [rbp - 16]\n   pop rbx\n   mov rsp, rbp\n   pop rbp\n   ret\n";
+        assert_eq!(asm, expected);
+
+        // the fifth argument is under the four others, it goes right after the shadow space
+        let asm = emit_call_function(Target::Windows, "fn_g", 5);
+        assert!(asm.starts_with("   mov rbx, rsp\n   and rsp, -16\n   sub rsp, 48\n   mov rax, [rbx]\n   mov [rsp + 32], rax\n   mov rcx, [rbx + 32]\n"));
+        assert!(asm.ends_with("   mov r9, [rbx + 8]\n   call fn_g\n   lea rsp, [rbx + 40]\n   push rax\n"));
+    }
+
+    #[test]
+    fn test_data_section() {
+        let mut data = DataSection::default();
+        data.define("msg", "db \"%d\", 0");
+        data.global("x", 0);
+        data.global("y", 3);
+        data.reserve("buffer", 4);
+
+        let expected = "segment .data\n   msg db \"%d\", 0\n   y dq 3\n\nsegment .bss\n   x resq 1\n   buffer resq 4\n";
+        assert_eq!(data.emit(), expected);
+        assert_eq!(data.labels(), vec!["msg", "y", "x", "buffer"]);
+    }
+
+    #[test]
+    fn test_data_strings() {
+        let mut data = DataSection::default();
+        assert_eq!(data.string("hi"), "str_0");
+        assert_eq!(data.string("say \"é\"\\\n"), "str_1");
+        assert_eq!(data.string("hi"), "str_0");
+
+        let expected = "segment .data\n   str_0 db \"hi\", 0\n   str_1 db \"say \", 34, 195, 169, 34, 92, 10, 0\n";
+        assert_eq!(data.emit(), expected);
+    }
+
+    #[test]
+    fn test_peephole() {
+        let body = "   push 1\n   push 2\n   pop rbx\n   pop rax\n   add eax, ebx\n   push rax\n   pop rax\n   push rax ; keep\n   pop rdx\n.end_1:\n   pop rcx\n";
+        let expected = "   mov rbx, 2\n   mov rax, 1\n   add eax, ebx\n   push rax ; keep\n   pop rdx\n.end_1:\n   pop rcx\n";
+        assert_eq!(peephole(body), expected);
+    }
+}
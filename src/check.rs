@@ -0,0 +1,274 @@
+//! Mistakes found without running the program, variables used before they are declared, names
+//! declared twice in a scope and calls with the wrong number of arguments. `sim` and `com` refuse
+//! to run programs that have them, `ceya check` only reports them.
+
+use std::{borrow::Borrow, collections::{HashMap, HashSet}};
+
+use crate::{ast::{Expr, Stmt, Value}, environment::EnvironmentArena, error::CeyaError, scanner::Token};
+
+struct Scope {
+    /// Names declared so far, with the arity of the functions among them while it can't have changed.
+    declared: HashMap<String, Option<usize>>,
+    /// Every name declared in the scope. Functions run after their declaration, so they may use the later ones.
+    hoisted: HashSet<String>,
+    /// Number of function bodies around the scope.
+    functions: usize
+}
+
+struct Checker {
+    scopes: Vec<Scope>,
+    functions: usize,
+    errors: Vec<CeyaError>
+}
+
+/// Checks a program run with the globals of `global_env`, usually the natives.
+pub fn check<S: Borrow<Stmt>>(stmts: &[S], env_arena: &EnvironmentArena, global_env: usize) -> Vec<CeyaError> {
+    let globals = env_arena.bindings(global_env).map(|(name, value)| {
+        let arity = match value {
+            Value::Fun(fun) => fun.arity(),
+            _ => None
+        };
+        (name.to_string(), arity)
+    }).collect();
+
+    // the program gets a scope of its own, declaring a global doesn't clash with the native it hides
+    let globals = Scope { declared: globals, hoisted: HashSet::new(), functions: 0 };
+    let mut checker = Checker { scopes: vec![globals], functions: 0, errors: vec![] };
+    checker.scoped(|checker| checker.statements(stmts));
+    checker.errors
+}
+
+impl Checker {
+    /// Checks statements of the innermost scope.
+    fn statements<S: Borrow<Stmt>>(&mut self, stmts: &[S]) {
+        let scope = self.scopes.last_mut().expect("global scope");
+        for stmt in stmts.iter().map(Borrow::borrow) {
+            match stmt {
+                Stmt::Let { name, .. } | Stmt::Fun { name, .. } | Stmt::Struct { name, .. } => { scope.hoisted.insert(name.lexeme.clone()); },
+                Stmt::Unpack { names, .. } => scope.hoisted.extend(names.iter().map(|name| name.lexeme.clone())),
+                _ => ()
+            }
+        }
+
+        for stmt in stmts {
+            self.stmt(stmt.borrow());
+        }
+    }
+
+    fn scoped(&mut self, check: impl FnOnce(&mut Self)) {
+        self.scopes.push(Scope { declared: HashMap::new(), hoisted: HashSet::new(), functions: self.functions });
+        check(self);
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, name: &Token, arity: Option<usize>) {
+        if self.scopes.last_mut().expect("global scope").declared.insert(name.lexeme.clone(), arity).is_some() {
+            self.errors.push(CeyaError::Semantic { span: name.span(), message: format!("'{}' is already declared in this scope.", name.lexeme) });
+        }
+    }
+
+    /// The arity `name` is known to have, after reporting it if it isn't declared.
+    fn resolve(&mut self, name: &Token) -> Option<usize> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(arity) = scope.declared.get(&name.lexeme) {
+                return *arity;
+            }
+            if scope.functions < self.functions && scope.hoisted.contains(&name.lexeme) {
+                return None;
+            }
+        }
+
+        self.errors.push(CeyaError::Semantic { span: name.span(), message: format!("Undefined variable '{}'.", name.lexeme) });
+        None
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block { statements, .. } => self.scoped(|checker| checker.statements(statements)),
+            Stmt::Expression { expression, .. } | Stmt::Return { value: expression, .. } => self.expr(expression),
+            Stmt::Print { expression, arguments, .. } => {
+                self.expr(expression);
+                for argument in arguments {
+                    self.expr(argument);
+                }
+            },
+            Stmt::Let { name, initializer, .. } => {
+                self.expr(initializer);
+                self.declare(name, None);
+            },
+            Stmt::Unpack { names, initializer, .. } => {
+                self.expr(initializer);
+                for name in names {
+                    self.declare(name, None);
+                }
+            },
+            Stmt::Struct { name, methods, .. } => {
+                self.declare(name, None);
+                for method in methods {
+                    self.function(method);
+                }
+            },
+            Stmt::If { condition, then, els, .. } => {
+                self.expr(condition);
+                self.stmt(then);
+                if let Some(els) = els {
+                    self.stmt(els);
+                }
+            },
+            Stmt::While { condition, body, increment, .. } => {
+                self.expr(condition);
+                self.stmt(body);
+                if let Some(increment) = increment {
+                    self.expr(increment);
+                }
+            },
+            Stmt::Fun { name, params, .. } => {
+                self.declare(name, Some(params.len()));
+                self.function(stmt);
+            },
+            Stmt::Faran { .. } | Stmt::Ke { .. } | Stmt::Swap { .. } | Stmt::Over { .. } | Stmt::Rot { .. } | Stmt::Drop2 { .. }
+                | Stmt::Break { .. } | Stmt::Continue { .. } => ()
+        }
+    }
+
+    /// Checks the body of a `fn` declaration, which sees its parameters.
+    fn function(&mut self, stmt: &Stmt) {
+        let Stmt::Fun { params, body, .. } = stmt else { unreachable!("not a function declaration") };
+        self.functions += 1;
+        self.scoped(|checker| {
+            for param in params {
+                checker.declare(param, None);
+            }
+            checker.stmt(body);
+        });
+        self.functions -= 1;
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign { name, value, .. } => {
+                self.expr(value);
+                self.resolve(name);
+                // whatever it held, it may not be that function anymore
+                if let Some(arity) = self.scopes.iter_mut().rev().find_map(|scope| scope.declared.get_mut(&name.lexeme)) {
+                    *arity = None;
+                }
+            },
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            },
+            Expr::Grouping { expression: right, .. } | Expr::Unary { right, .. } => self.expr(right),
+            Expr::Ternary { condition, then, els, .. } => {
+                self.expr(condition);
+                self.expr(then);
+                self.expr(els);
+            },
+            Expr::Variable { name, .. } => {
+                self.resolve(name);
+            },
+            Expr::Call { callee, paren, arguments, .. } => {
+                let arity = match callee.as_ref() {
+                    Expr::Variable { name, .. } => self.resolve(name),
+                    callee => {
+                        self.expr(callee);
+                        None
+                    }
+                };
+                for argument in arguments {
+                    self.expr(argument);
+                }
+
+                if let Some(arity) = arity.filter(|arity| *arity != arguments.len()) {
+                    self.errors.push(CeyaError::Semantic { span: paren.span(), message: format!("Expected {} arguments, but found {}.", arity, arguments.len()) });
+                }
+            },
+            Expr::List { elements, .. } | Expr::Tuple { elements, .. } => {
+                for element in elements {
+                    self.expr(element);
+                }
+            },
+            Expr::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            },
+            Expr::Index { object, index, .. } => {
+                self.expr(object);
+                self.expr(index);
+            },
+            Expr::Slice { object, start, end, .. } => {
+                self.expr(object);
+                for bound in start.iter().chain(end) {
+                    self.expr(bound);
+                }
+            },
+            Expr::Instance { class, fields, .. } => {
+                self.expr(class);
+                for (_, value) in fields {
+                    self.expr(value);
+                }
+            },
+            Expr::Get { object, .. } => self.expr(object),
+            Expr::Set { object, value, .. } => {
+                self.expr(object);
+                self.expr(value);
+            },
+            Expr::SetIndex { object, index, value, .. } => {
+                self.expr(object);
+                self.expr(index);
+                self.expr(value);
+            },
+            Expr::Literal { .. } | Expr::Soro { .. } | Expr::Depth { .. } => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{environment::EnvironmentArena, parser, scanner, stdlib};
+
+    fn check(source: &str) -> Vec<String> {
+        let mut env_arena = EnvironmentArena::new();
+        let global_env = env_arena.add(None);
+        stdlib::define_natives(&mut env_arena, global_env);
+
+        let stmts = parser::parse(scanner::scan(source)).unwrap();
+        super::check(&stmts, &env_arena, global_env).iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(check("let a = 1; { let b = a; fn f(c) { return b + c + g(); } print f(2); } fn g() { return to_hex(a); }").is_empty());
+        assert_eq!(check("print a; let a = 1;\n{ let b = b; }"), ["[line 0:6] Error: Undefined variable 'a'.", "[line 1:10] Error: Undefined variable 'b'."]);
+        assert_eq!(check("x = 1; fn f() { y = 2; }"), ["[line 0:0] Error: Undefined variable 'x'.", "[line 0:16] Error: Undefined variable 'y'."]);
+        // only names declared in the scopes around a function, not in the blocks beside it
+        assert_eq!(check("{ let a = 1; } fn f() { return a; }"), ["[line 0:31] Error: Undefined variable 'a'."]);
+        assert!(check("fn f() { return P { x: 1 }.x; } struct P { x }").is_empty());
+        assert_eq!(check("struct P { fn __add__(a, b) { return P { x: c }; } fn __sub__(a, b) { return a; } }"), ["[line 0:44] Error: Undefined variable 'c'."]);
+        assert_eq!(check("fn f() { return a, 1; } let (b, c) = f(); print b + c + d;\n{ let (e, e) = f(); }"), [
+            "[line 0:16] Error: Undefined variable 'a'.",
+            "[line 0:56] Error: Undefined variable 'd'.",
+            "[line 1:10] Error: 'e' is already declared in this scope."
+        ]);
+        assert_eq!(check("let l = [a]; l[b] = c[0];"), ["[line 0:9] Error: Undefined variable 'a'.", "[line 0:15] Error: Undefined variable 'b'.", "[line 0:20] Error: Undefined variable 'c'."]);
+    }
+
+    #[test]
+    fn test_check_duplicates() {
+        assert_eq!(check("let a = 1; fn a() { }\nstruct P {} { let P = a; let P = 2; }"), ["[line 0:14] Error: 'a' is already declared in this scope.", "[line 1:29] Error: 'P' is already declared in this scope."]);
+        // shadowing one from an outer scope, a parameter or a native is fine
+        assert!(check("let a = 1; { let a = a; } fn f(a) { let a = 2; return a; } let len = 3;").is_empty());
+    }
+
+    #[test]
+    fn test_check_arity() {
+        assert_eq!(check("fn f(a, b) { return a; } print f(1); print clock(2); print format(\"{}\", 1);"), [
+            "[line 0:34] Error: Expected 2 arguments, but found 1.",
+            "[line 0:50] Error: Expected 0 arguments, but found 1."
+        ]);
+        // a reassigned function, or one called before its declaration, can't be told apart from any value
+        assert!(check("fn f(a) { return a; } f = clock; print f(); fn g() { return h(1, 2); } fn h() { }").is_empty());
+    }
+}
@@ -0,0 +1,166 @@
+use std::{fs, io::{self, ErrorKind}, path::Path};
+
+use clap::ValueEnum;
+use toml::{Table, Value};
+
+use crate::{toolchain::{Assembler, Linker}, ComArgs, Subsystem};
+use ceya::{ast::OptLevel, backend::Target, dialect::Dialect};
+
+/// Per-project settings read from the `[build]` table of `ceya.toml`, flags given on the command line win.
+#[derive(Default)]
+pub struct Config {
+    main: Option<String>,
+    opt_level: Option<OptLevel>,
+    asm_dialect: Option<Dialect>,
+    target: Option<Target>,
+    subsystem: Option<Subsystem>,
+    assembler: Option<Assembler>,
+    linker: Option<Linker>,
+    vcvars: Option<String>,
+    out_dir: Option<String>,
+    freestanding: Option<bool>,
+    builtin_print: Option<bool>,
+    checked_arith: Option<bool>,
+    release: Option<bool>,
+    keywords: Option<String>,
+    max_depth: Option<usize>
+}
+
+impl Config {
+    /// Reads `path`, a missing file being an empty config.
+    pub fn load(path: &str) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(source) => Config::parse(&source).map_err(|e| format!("{}: {}", path, e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("{}: {}", path, e))
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<Config, String> {
+        let table: Table = source.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+        let mut config = Config::default();
+
+        for (section, value) in &table {
+            match (section.as_str(), value) {
+                ("project", _) => (),
+                ("build", Value::Table(build)) => {
+                    for (key, value) in build {
+                        match key.as_str() {
+                            "main" => config.main = Some(string(key, value)?.into()),
+                            "opt-level" => config.opt_level = Some(choice(key, value)?),
+                            "asm-dialect" => config.asm_dialect = Some(choice(key, value)?),
+                            "target" => config.target = Some(choice(key, value)?),
+                            "subsystem" => config.subsystem = Some(choice(key, value)?),
+                            "assembler" => config.assembler = Some(choice(key, value)?),
+                            "linker" => config.linker = Some(choice(key, value)?),
+                            "vcvars" => config.vcvars = Some(string(key, value)?.into()),
+                            "out-dir" => config.out_dir = Some(string(key, value)?.into()),
+                            "freestanding" => config.freestanding = Some(boolean(key, value)?),
+                            "builtin-print" => config.builtin_print = Some(boolean(key, value)?),
+                            "checked-arith" => config.checked_arith = Some(boolean(key, value)?),
+                            "release" => config.release = Some(boolean(key, value)?),
+                            "keywords" => config.keywords = Some(string(key, value)?.into()),
+                            "max-depth" => config.max_depth = Some(value.as_integer().and_then(|n| usize::try_from(n).ok()).ok_or(format!("'{}' expects a positive integer", key))?),
+                            _ => return Err(format!("unknown key 'build.{}'", key))
+                        }
+                    }
+                },
+                _ => return Err(format!("unknown section '{}'", section))
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Fills in what wasn't given on the command line.
+    pub fn apply(self, mut args: ComArgs) -> ComArgs {
+        args.filepath = args.filepath.or(self.main);
+        args.opt_level = args.opt_level.or(self.opt_level);
+        args.asm_dialect = args.asm_dialect.or(self.asm_dialect);
+        args.target = args.target.or(self.target);
+        args.subsystem = args.subsystem.or(self.subsystem);
+        args.assembler = args.assembler.or(self.assembler);
+        args.linker = args.linker.or(self.linker);
+        args.vcvars = args.vcvars.or(self.vcvars);
+        args.out_dir = args.out_dir.or(self.out_dir);
+        args.freestanding = args.freestanding.or(self.freestanding);
+        args.builtin_print = args.builtin_print.or(self.builtin_print);
+        args.checked_arith = args.checked_arith.or(self.checked_arith);
+        args.release = args.release.or(self.release);
+        args.keywords = args.keywords.or(self.keywords);
+        args.max_depth = args.max_depth.or(self.max_depth);
+        args
+    }
+}
+
+/// Creates `dir` with a starter program, its `ceya.toml` and a `.gitignore` for build artifacts.
+pub fn create_project(dir: &Path) -> io::Result<()> {
+    let name = dir.file_name().and_then(|name| name.to_str()).ok_or(io::Error::other("Invalid project name."))?;
+    fs::create_dir(dir)?;
+    fs::write(dir.join("main.ceya"), "10;\nke;\nwhile (soro > 0) {\n    ke;\n    print soro;\n    soro - 1;\n    ke;\n}\n")?;
+    fs::write(dir.join("ceya.toml"), format!("[project]\nname = \"{}\"\n\n[build]\nmain = \"main.ceya\"\n", name.replace('\\', "\\\\").replace('"', "\\\"")))?;
+    fs::write(dir.join(".gitignore"), "ceya-out/\n")
+}
+
+fn string<'a>(key: &str, value: &'a Value) -> Result<&'a str, String> {
+    value.as_str().ok_or(format!("'{}' expects a string", key))
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool, String> {
+    value.as_bool().ok_or(format!("'{}' expects true or false", key))
+}
+
+/// Same spellings as the command line flag, e.g. `opt-level = "2"`.
+fn choice<T: ValueEnum>(key: &str, value: &Value) -> Result<T, String> {
+    let name = match value {
+        Value::Integer(n) => n.to_string(),
+        value => string(key, value)?.into()
+    };
+    T::from_str(&name, true).map_err(|_| format!("invalid value '{}' for '{}'", name, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use ceya::{ast::OptLevel, backend::Target, dialect::Dialect};
+
+    use clap::Parser;
+
+    use super::{create_project, Config};
+    use crate::{Cli, Commands};
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("[project]\nname = \"demo\"\n\n[build]\nmain = \"main.ceya\"\nopt-level = 2\nasm-dialect = \"gas\"\ntarget = \"linux\"\nfreestanding = true\nmax-depth = 50\n").unwrap();
+        assert!(config.main.as_deref() == Some("main.ceya") && config.opt_level == Some(OptLevel::O2) && config.asm_dialect == Some(Dialect::Gas));
+        assert_eq!(config.target, Some(Target::Linux));
+        assert!(config.freestanding == Some(true) && config.checked_arith.is_none() && config.max_depth == Some(50));
+
+        assert!(Config::parse("[build]\nopt-level = \"3\"\n").is_err());
+        assert!(Config::parse("[build]\noptlevel = \"2\"\n").is_err());
+        assert!(Config::parse("[build]\nmax-depth = -1\n").is_err());
+        assert!(Config::parse("").is_ok_and(|config| config.main.is_none()));
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = Config::parse("[build]\nmain = \"main.ceya\"\nfreestanding = true\nchecked-arith = true\nrelease = true\n").unwrap();
+        let Commands::Com(args) = Cli::parse_from(["ceya", "com", "--freestanding=false", "--builtin-print", "other.ceya"]).command else { unreachable!() };
+        // the command line wins, in both directions
+        let args = config.apply(args);
+        assert_eq!((args.freestanding, args.builtin_print, args.checked_arith, args.release), (Some(false), Some(true), Some(true), Some(true)));
+        assert_eq!(args.filepath.as_deref(), Some("other.ceya"));
+    }
+
+    #[test]
+    fn test_create_project() {
+        let dir = std::env::temp_dir().join(format!("ceya-new-{}", std::process::id()));
+        create_project(&dir).unwrap();
+
+        let config = Config::load(dir.join("ceya.toml").to_str().unwrap()).unwrap();
+        assert!(config.main.as_deref() == Some("main.ceya") && dir.join("main.ceya").exists() && dir.join(".gitignore").exists());
+        // never overwrites an existing directory
+        assert!(create_project(&dir).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+use crate::backend::Target;
+
+/// Assembler syntax of the generated file. Codegen always produces NASM, other dialects are
+/// rewritten from it line by line so instruction selection stays in one place.
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Dialect {
+    #[default]
+    Nasm,
+    Gas,
+    Masm,
+    Fasm
+}
+
+impl Dialect {
+    pub fn extension(&self) -> &str {
+        match self {
+            Self::Nasm => "asm",
+            Self::Gas => "s",
+            Self::Masm => "masm.asm",
+            Self::Fasm => "fasm.asm"
+        }
+    }
+
+    /// Rewrites NASM `asm` into the dialect, for an object file of the `target` platform.
+    pub fn translate(&self, asm: &str, target: Target) -> String {
+        match self {
+            Self::Nasm => asm.into(),
+            Self::Gas => asm.lines().map(gas_line).collect(),
+            Self::Masm => {
+                let mut res: String = asm.lines().map(masm_line).collect();
+                res.push_str("end\n");
+                res
+            },
+            Self::Fasm => asm.lines().map(|line| fasm_line(line, target)).collect()
+        }
+    }
+}
+
+fn is_register(operand: &str) -> bool {
+    matches!(operand,
+        "rax" | "rbx" | "rcx" | "rdx" | "rsi" | "rdi" | "rsp" | "rbp" |
+        "eax" | "ebx" | "ecx" | "edx" | "esi" | "edi" | "esp" | "ebp" |
+        "ax" | "bx" | "cx" | "dx" | "al" | "bl" | "cl" | "dl" | "ah" | "bh" | "ch" | "dh" |
+        "sil" | "dil" | "spl" | "bpl")
+        || operand.strip_prefix('r').is_some_and(|r| {
+            let digits = r.trim_end_matches(['d', 'w', 'b']);
+            matches!(digits, "8" | "9" | "10" | "11" | "12" | "13" | "14" | "15")
+        })
+}
+
+/// Splits `db` operands on commas outside of string quotes.
+fn data_operands(operands: &str) -> Vec<String> {
+    let mut res = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in operands.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            },
+            ',' if !quoted => res.push(std::mem::take(&mut current).trim().to_string()),
+            _ => current.push(c)
+        }
+    }
+    res.push(current.trim().to_string());
+
+    res
+}
+
+/// Splits an instruction line into its code and trailing comment.
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match line.split_once(';') {
+        Some((code, comment)) => (code, Some(comment)),
+        None => (line, None)
+    }
+}
+
+fn gas_operand(operand: &str) -> String {
+    let operand = operand.trim_start_matches("qword ").trim();
+
+    if is_register(operand) {
+        return format!("%{}", operand);
+    }
+
+    if let Some(inner) = operand.strip_prefix('[').and_then(|o| o.strip_suffix(']')) {
+        let (base, offset) = match inner.split_once(['+', '-']) {
+            Some((base, offset)) => {
+                let sign = if inner.contains('-') { "-" } else { "" };
+                (base.trim(), format!("{}{}", sign, offset.trim()))
+            },
+            None => (inner.trim(), String::new())
+        };
+
+        if is_register(base) {
+            return format!("{}(%{})", offset, base);
+        }
+        return format!("{}(%rip)", base); // `default rel`
+    }
+
+    if operand.parse::<i64>().is_ok() {
+        return format!("${}", operand);
+    }
+
+    operand.into()
+}
+
+fn gas_line(line: &str) -> String {
+    let trimmed = line.trim();
+    let indent = &line[..line.len() - line.trim_start().len()];
+
+    if trimmed.is_empty() {
+        return "\n".into();
+    }
+    if let Some(comment) = trimmed.strip_prefix(';') {
+        return format!("{}#{}\n", indent, comment);
+    }
+    if trimmed.ends_with(':') {
+        return format!("{}\n", trimmed);
+    }
+
+    let (word, rest) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+    match word {
+        "bits" | "default" => return String::new(),
+        "segment" => return format!("{}\n", rest.trim()),
+        "global" => return format!(".globl {}\n", rest.trim()),
+        "extern" => return format!(".extern {}\n", rest.trim()),
+        _ => ()
+    }
+
+    let mut res = String::new();
+
+    // data: `label db ...`, `label dq ...`, `label resq ...`
+    if let Some((directive, operands)) = rest.trim().split_once(' ') {
+        match directive {
+            "db" => {
+                writeln!(&mut res, "{}{}:", indent, word).unwrap();
+                let mut bytes: Vec<String> = vec![];
+                for operand in data_operands(operands) {
+                    if operand.starts_with('"') {
+                        if !bytes.is_empty() {
+                            writeln!(&mut res, "{}   .byte {}", indent, bytes.join(", ")).unwrap();
+                            bytes.clear();
+                        }
+                        writeln!(&mut res, "{}   .ascii {}", indent, operand).unwrap();
+                    } else {
+                        bytes.push(operand);
+                    }
+                }
+                if !bytes.is_empty() {
+                    writeln!(&mut res, "{}   .byte {}", indent, bytes.join(", ")).unwrap();
+                }
+                return res;
+            },
+            "dq" => return format!("{}{}: .quad {}\n", indent, word, operands.trim()),
+            "resq" => {
+                let qwords: usize = operands.trim().parse().expect("qword count expected");
+                return format!("{}{}: .zero {}\n", indent, word, 8 * qwords);
+            },
+            _ => ()
+        }
+    }
+
+    let (code, comment) = split_comment(trimmed);
+    let (mnemonic, operands) = code.trim().split_once(' ').unwrap_or((code.trim(), ""));
+    let operands: Vec<&str> = operands.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()).collect();
+
+    let mnemonic = match mnemonic {
+        "cqo" => "cqto".to_string(),
+        "movzx" => format!("movzb{}", if operands[0].starts_with('r') { "q" } else { "l" }),
+        // without a register operand the size can't be inferred
+        m if !operands.is_empty() && !operands.iter().any(|o| is_register(o)) && !m.starts_with('j') && m != "call" => format!("{}q", m),
+        m => m.to_string()
+    };
+
+    let converted: Vec<String> = operands.iter().rev().map(|o| gas_operand(o)).collect();
+    write!(&mut res, "{}{}", indent, mnemonic).unwrap();
+    if !converted.is_empty() {
+        write!(&mut res, " {}", converted.join(", ")).unwrap();
+    }
+    if let Some(comment) = comment {
+        write!(&mut res, " #{}", comment).unwrap();
+    }
+    res.push('\n');
+
+    res
+}
+
+/// MASM has no NASM-style `.local` labels, so they become plain `L_` labels.
+fn masm_label(name: &str) -> String {
+    match name.strip_prefix('.') {
+        Some(local) => format!("L_{}", local),
+        None => name.into()
+    }
+}
+
+/// MASM hex literals are written `0ffh`, not `0xff`.
+fn masm_number(operand: &str) -> String {
+    match operand.strip_prefix("0x") {
+        Some(hex) => format!("0{}h", hex),
+        None => operand.into()
+    }
+}
+
+fn masm_line(line: &str) -> String {
+    let trimmed = line.trim();
+    let indent = &line[..line.len() - line.trim_start().len()];
+
+    if trimmed.is_empty() || trimmed.starts_with(';') {
+        return format!("{}\n", line);
+    }
+    if let Some(label) = trimmed.strip_suffix(':') {
+        return format!("{}:\n", masm_label(label));
+    }
+
+    let (word, rest) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+    match (word, rest.trim()) {
+        ("bits", _) | ("default", _) => return String::new(),
+        ("segment", ".data") => return ".data\n".into(),
+        ("segment", ".bss") => return ".data?\n".into(),
+        ("segment", ".text") => return ".code\n".into(),
+        ("global", name) => return format!("public {}\n", name),
+        ("extern", name) => return format!("extern {}:proc\n", name),
+        _ => ()
+    }
+
+    if let Some((directive, operands)) = rest.trim().split_once(' ') {
+        match directive {
+            "db" | "dq" => {
+                let operands: Vec<String> = data_operands(operands).iter().map(|o| masm_number(o)).collect();
+                return format!("{}{} {} {}\n", indent, word, directive, operands.join(", "));
+            },
+            "resq" => return format!("{}{} dq {} dup (?)\n", indent, word, operands.trim()),
+            _ => ()
+        }
+    }
+
+    let (code, comment) = split_comment(trimmed);
+    let (mnemonic, operands) = code.trim().split_once(' ').unwrap_or((code.trim(), ""));
+    let operands: Vec<String> = operands.split(',')
+        .map(|o| o.trim())
+        .filter(|o| !o.is_empty())
+        .map(|o| masm_number(&masm_label(&o.replace("qword [", "qword ptr ["))))
+        .collect();
+
+    let mut res = format!("{}{}", indent, mnemonic);
+    if !operands.is_empty() {
+        write!(&mut res, " {}", operands.join(", ")).unwrap();
+    }
+    if let Some(comment) = comment {
+        write!(&mut res, " ;{}", comment).unwrap();
+    }
+    res.push('\n');
+
+    res
+}
+
+/// fasm shares NASM's instruction syntax, only the directives differ.
+fn fasm_line(line: &str, target: Target) -> String {
+    let trimmed = line.trim();
+    let indent = &line[..line.len() - line.trim_start().len()];
+
+    let (word, rest) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+    // ELF sections are readable without saying so, and have no code/data kind
+    match (word, rest.trim(), target) {
+        ("bits", _, Target::Windows) => "format MS64 COFF\n".into(),
+        ("bits", _, Target::Linux) => "format ELF64\n".into(),
+        ("default", _, _) => String::new(),
+        ("segment", ".data", Target::Windows) => "section '.data' data readable writeable\n".into(),
+        ("segment", ".bss", Target::Windows) => "section '.bss' data readable writeable\n".into(),
+        ("segment", ".text", Target::Windows) => "section '.text' code readable executable\n".into(),
+        ("segment", section @ (".data" | ".bss"), Target::Linux) => format!("section '{}' writeable\n", section),
+        ("segment", ".text", Target::Linux) => "section '.text' executable\n".into(),
+        ("global", name, _) => format!("public {}\n", name),
+        ("extern", name, _) => format!("extrn {}\n", name),
+        (label, rest, _) if rest.starts_with("resq ") => format!("{}{} rq {}\n", indent, label, &rest[5..]),
+        _ => format!("{}\n", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Target;
+
+    use super::Dialect;
+
+    #[test]
+    fn test_gas() {
+        let nasm = "bits 64\ndefault rel\nsegment .data\n   msg db \"%d\", 0xd, 0xa, 0\n   x resq 2\nsegment .text\nglobal main\nextern printf\nmain:\n   ; print 1\n   push 1\n   lea rcx, [msg]\n   movzx rax, al\n   lea rsp, [rbp - 8]\n   add eax, ebx ; sum\n   cqo\n   jne .end_1\n   call printf\n";
+        let expected = ".data\n   msg:\n      .ascii \"%d\"\n      .byte 0xd, 0xa, 0\n   x: .zero 16\n.text\n.globl main\n.extern printf\nmain:\n   # print 1\n   pushq $1\n   lea msg(%rip), %rcx\n   movzbq %al, %rax\n   lea -8(%rbp), %rsp\n   add %ebx, %eax # sum\n   cqto\n   jne .end_1\n   call printf\n";
+        assert_eq!(Dialect::Gas.translate(nasm, Target::Linux), expected);
+    }
+
+    #[test]
+    fn test_masm() {
+        let nasm = "bits 64\ndefault rel\nsegment .data\n   msg db \"%d\", 0xd, 0xa, 0\nsegment .bss\n   x resq 2\nsegment .text\nglobal main\nextern printf\nmain:\n   ; print 1\n   push 1\n   mov qword [x], rax ; store\n   jne .end_1\n.end_1:\n";
+        let expected = ".data\n   msg db \"%d\", 0dh, 0ah, 0\n.data?\n   x dq 2 dup (?)\n.code\npublic main\nextern printf:proc\nmain:\n   ; print 1\n   push 1\n   mov qword ptr [x], rax ; store\n   jne L_end_1\nL_end_1:\nend\n";
+        assert_eq!(Dialect::Masm.translate(nasm, Target::Windows), expected);
+    }
+
+    #[test]
+    fn test_fasm() {
+        let nasm = "bits 64\ndefault rel\nsegment .data\n   msg db \"%d\", 0xd, 0xa, 0\nsegment .bss\n   x resq 2\nsegment .text\nglobal main\nextern printf\nmain:\n   push 1\n";
+        let expected = "format MS64 COFF\nsection '.data' data readable writeable\n   msg db \"%d\", 0xd, 0xa, 0\nsection '.bss' data readable writeable\n   x rq 2\nsection '.text' code readable executable\npublic main\nextrn printf\nmain:\n   push 1\n";
+        assert_eq!(Dialect::Fasm.translate(nasm, Target::Windows), expected);
+        let expected = "format ELF64\nsection '.data' writeable\n   msg db \"%d\", 0xd, 0xa, 0\nsection '.bss' writeable\n   x rq 2\nsection '.text' executable\npublic main\nextrn printf\nmain:\n   push 1\n";
+        assert_eq!(Dialect::Fasm.translate(nasm, Target::Linux), expected);
+    }
+}
@@ -1,34 +1,72 @@
-use std::{collections::HashMap, io::{Error, ErrorKind}};
+use std::collections::HashMap;
 
-use crate::{ast::Value, scanner::Token};
+use crate::{ast::Value, error::CeyaError, scanner::Token};
 
+#[derive(Default)]
 pub struct EnvironmentArena {
-    pub envs: Vec<Environment>
+    pub envs: Vec<Environment>,
+    /// Collects what `print` writes instead of sending it to stdout, for embedders. `eprint` always goes to stderr.
+    pub output: Option<String>,
+    /// Calls to ceya functions currently running, to stop runaway recursion before it overflows the stack.
+    pub calls: usize,
+    /// Values left by expression statements, which `soro` pops and the stack words rearrange.
+    pub stack: Vec<Value>,
+    /// Runtime errors of the program, printed by `sim` and returned to embedders. The first one stops it.
+    pub errors: Vec<CeyaError>
 }
 
 impl EnvironmentArena {
     pub fn new() -> EnvironmentArena {
-        EnvironmentArena { envs: vec![].into() }
+        EnvironmentArena { envs: vec![], output: None, calls: 0, stack: vec![], errors: vec![] }
+    }
+
+    /// An arena whose printed lines are kept in `output`.
+    pub fn capturing() -> EnvironmentArena {
+        EnvironmentArena { envs: vec![], output: Some(String::new()), calls: 0, stack: vec![], errors: vec![] }
+    }
+
+    pub fn print(&mut self, line: &str) {
+        match self.output {
+            Some(ref mut output) => {
+                output.push_str(line);
+                output.push('\n');
+            },
+            None => println!("{}", line)
+        }
+    }
+
+    pub fn report(&mut self, error: CeyaError) {
+        self.errors.push(error);
+    }
+
+    /// Whether a runtime error stopped the program.
+    pub fn failed(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn eprint(&mut self, line: &str) {
+        eprintln!("{}", line);
     }
 
     pub fn add(&mut self, parent: Option<usize>) -> usize {
         let next_index = self.envs.len();
 
-        let env = Environment { index: next_index, parent, values: HashMap::new() };
+        let env = Environment { parent, values: HashMap::new() };
 
         self.envs.push(env);
         next_index
     }
 
-    pub fn default_env(&mut self) -> &mut Environment {
-        self.envs.get_mut(0).expect("default env")
-    }
-
     pub fn define(&mut self, env: usize, name: &str, value: Value) {
         self.envs.get_mut(env).expect("env").values.insert(name.into(), value);
     }
 
-    pub fn get(&self, env: usize, name: &Token) -> Result<&Value, Error> {
+    /// Names defined in `env` itself, not its parents, with their values.
+    pub fn bindings(&self, env: usize) -> impl Iterator<Item = (&str, &Value)> {
+        self.envs.get(env).expect("env").values.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    pub fn get(&self, env: usize, name: &Token) -> Result<&Value, CeyaError> {
         let env = self.envs.get(env).expect("env");
         if env.values.contains_key(&name.lexeme) {
             return Ok(env.values.get(&name.lexeme).unwrap());
@@ -38,10 +76,23 @@ impl EnvironmentArena {
             return self.get(parent, name);
         }
 
-        Err(Error::new(ErrorKind::Other, format!("Undefined variable '{}'", &name.lexeme)))
+        Err(CeyaError::Runtime { span: name.span(), message: format!("Undefined variable '{}'.", &name.lexeme) })
+    }
+
+    /// Values currently bound across all environments. Environments are never freed, so this is also the peak.
+    pub fn value_count(&self) -> usize {
+        self.envs.iter().map(|env| env.values.len()).sum()
+    }
+
+    /// Bytes held by bound string values.
+    pub fn string_bytes(&self) -> usize {
+        self.envs.iter().flat_map(|env| env.values.values()).map(|value| match value {
+            Value::String(s) => s.len(),
+            _ => 0
+        }).sum()
     }
 
-    pub fn assign(&mut self, env: usize, name: &Token, value: Value) -> Result<(), Error> {
+    pub fn assign(&mut self, env: usize, name: &Token, value: Value) -> Result<(), CeyaError> {
         let env = self.envs.get_mut(env).expect("env");
         if env.values.contains_key(&name.lexeme) {
             env.values.insert(name.lexeme.clone(), value);
@@ -52,12 +103,11 @@ impl EnvironmentArena {
             return self.assign(parent, name, value);
         }
 
-        Err(Error::new(ErrorKind::Other, format!("Undefined variable '{}'", &name.lexeme)))
+        Err(CeyaError::Runtime { span: name.span(), message: format!("Undefined variable '{}'.", &name.lexeme) })
     }
 }
 
 pub struct Environment {
-    index: usize,
     parent: Option<usize>,
     values: HashMap<String, Value>
 }
\ No newline at end of file
@@ -0,0 +1,126 @@
+//! Errors of every stage, from scanning to code generation, with where they happened in the source.
+
+use std::{cell::RefCell, fmt::{self, Display, Formatter, Write}};
+
+use crate::scanner::Span;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CeyaError {
+    /// Text that doesn't form tokens: unknown characters, unterminated strings and comments, bad escapes.
+    Lex     { span: Span, message: String },
+    /// Tokens that don't form a program.
+    Parse   { span: Span, message: String },
+    /// A running program failing, like reading an undefined variable, which stops it.
+    Runtime { span: Span, message: String },
+    /// A program `com` can't compile as written, the warnings under `--Werror`.
+    Codegen { span: Span, message: String },
+    /// Mistakes `check` finds without running the program, like using an undeclared variable.
+    Semantic { span: Span, message: String }
+}
+
+impl CeyaError {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Lex { span, .. } | Self::Parse { span, .. } | Self::Runtime { span, .. } | Self::Codegen { span, .. } | Self::Semantic { span, .. } => *span
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Lex { message, .. } | Self::Parse { message, .. } | Self::Runtime { message, .. } | Self::Codegen { message, .. } | Self::Semantic { message, .. } => message
+        }
+    }
+}
+
+impl Display for CeyaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}:{}] Error: {}", self.span().start, self.span().column, self.message())
+    }
+}
+
+impl std::error::Error for CeyaError {}
+
+/// Shows errors under the source line they point at, with `^` under the offending text.
+#[derive(Debug, Clone)]
+pub struct Renderer {
+    pub source: String,
+    /// Highlight with ANSI escape codes.
+    pub color: bool
+}
+
+impl Renderer {
+    pub fn render(&self, error: &CeyaError) -> String {
+        let span = error.span();
+        let (bold, red, blue, reset) = match self.color {
+            true => ("\x1b[1m", "\x1b[1;31m", "\x1b[34m", "\x1b[0m"),
+            false => ("", "", "", "")
+        };
+
+        let mut res = format!("{}{}{}", bold, error, reset);
+        let Some(line) = self.source.lines().nth(span.start as usize) else {
+            return res;
+        };
+        let line = line.trim_start_matches('\u{feff}');
+
+        // the underline stops at the end of the first line of spans covering several
+        let text = self.source.get(span.offset..span.offset + span.len).unwrap_or("");
+        let width = text.lines().next().map_or(0, |text| text.chars().count()).max(1);
+        // tabs are kept so the carets line up with the text above them
+        let indent: String = line.chars().take(span.column as usize).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+
+        let number = span.start.to_string();
+        let gutter = " ".repeat(number.len());
+        write!(res, "\n{}{} |{} {}", blue, number, reset, line).unwrap();
+        write!(res, "\n{}{} |{} {}{}{}{}", blue, gutter, reset, indent, red, "^".repeat(width), reset).unwrap();
+        res
+    }
+}
+
+thread_local! {
+    static RENDERER: RefCell<Option<Renderer>> = const { RefCell::new(None) };
+}
+
+/// Renders the errors reported on this thread with `renderer` from now on, or on one line with `None`.
+pub fn set_renderer(renderer: Option<Renderer>) {
+    RENDERER.with(|cell| *cell.borrow_mut() = renderer);
+}
+
+/// `error` as the scanner, parser and interpreter print it, see `set_renderer`.
+pub fn render(error: &CeyaError) -> String {
+    RENDERER.with(|cell| match &*cell.borrow() {
+        Some(renderer) => renderer.render(error),
+        None => error.to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{environment::EnvironmentArena, scanner::{Keywords, Scanner, Span, Token, TokenType}};
+
+    use super::{CeyaError, Renderer};
+
+    #[test]
+    fn test_errors() {
+        let error = CeyaError::Parse { span: Span { start: 3, end: 4, column: 5, ..Span::default() }, message: "Expect expression.".into() };
+        assert_eq!(error.to_string(), "[line 3:5] Error: Expect expression.");
+
+        let mut env_arena = EnvironmentArena::new();
+        let env = env_arena.add(None);
+        let name = Token::new("x", 7, TokenType::Identifier);
+        assert_eq!(env_arena.get(env, &name).err(), Some(CeyaError::Runtime { span: Span::line(7), message: "Undefined variable 'x'.".into() }));
+    }
+
+    #[test]
+    fn test_render() {
+        let renderer = Renderer { source: "let a = 1;\n\tprint a @;\n".into(), color: false };
+        let error = Scanner::new(&renderer.source, Keywords::default()).find_map(Result::err).expect("Error expected.");
+        assert_eq!(renderer.render(&error), "[line 1:9] Error: Unexpected token '@'.\n1 | \tprint a @;\n  | \t        ^");
+
+        let error = CeyaError::Lex { span: Span { start: 0, end: 1, column: 4, offset: 4, len: 8 }, message: "Unterminated string.".into() };
+        let renderer = Renderer { source: "let \"abc\ndef".into(), color: false };
+        assert!(renderer.render(&error).ends_with("0 | let \"abc\n  |     ^^^^"));
+
+        // without a renderer errors stay on one line
+        assert_eq!(super::render(&error), error.to_string());
+    }
+}
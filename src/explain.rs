@@ -0,0 +1,180 @@
+//! `ceya explain`: the generated assembly of each statement under its source lines, with what the
+//! statement and each of its instructions do, to learn how ceya programs map to the machine.
+
+use std::{fmt::Write, rc::Rc};
+
+use crate::{ast::{AsmComments, CompileOptions, Compiler, Stmt}, error::CeyaError, parser, scanner::Token};
+
+/// Compiles a program statement by statement, before the -O2 peephole pass which works on the whole program.
+pub fn explain(tokens: Vec<Rc<Token>>, source: &str, options: CompileOptions, max_depth: usize) -> Result<String, Vec<CeyaError>> {
+    // the prose replaces the usual source comments
+    let compiler = Compiler::new(CompileOptions { comments: AsmComments::Off, ..options });
+    let source_lines: Vec<&str> = source.lines().collect();
+    let mut res = String::new();
+    let mut listed = 0;
+
+    for (stmt, lines) in parser::parse_spanned(tokens, max_depth)? {
+        for line in listed.max(*lines.start() as usize)..=*lines.end() as usize {
+            writeln!(&mut res, ";; {:>4} | {}", line, source_lines.get(line).unwrap_or(&"")).unwrap();
+        }
+        listed = *lines.end() as usize + 1;
+        writeln!(&mut res, ";; {}", describe(&stmt)).unwrap();
+
+        // a function's own code is listed under its declaration
+        let code = compiler.compile(&stmt) + &compiler.functions.take();
+        for line in code.lines() {
+            match instruction(line) {
+                Some(prose) => writeln!(&mut res, "{:<32}; {}", line, prose).unwrap(),
+                None => writeln!(&mut res, "{}", line).unwrap()
+            }
+        }
+        writeln!(&mut res).unwrap();
+    }
+
+    Ok(res)
+}
+
+fn describe(stmt: &Stmt) -> &'static str {
+    match stmt {
+        Stmt::Block { .. } => "Block: runs its statements in order.",
+        Stmt::Expression { .. } => "Expression: computes a value and leaves it on top of the stack.",
+        Stmt::Print { stderr: false, .. } => "Print: computes a value, then pops and writes it to stdout.",
+        Stmt::Print { stderr: true, .. } => "Eprint: computes a value, then pops and writes it to stderr.",
+        Stmt::Faran { .. } => "Faran: drops the value on top of the stack.",
+        Stmt::Ke { .. } => "Ke: pushes a copy of the value on top of the stack.",
+        Stmt::Swap { .. } => "Swap: exchanges the two values on top of the stack.",
+        Stmt::Over { .. } => "Over: pushes a copy of the value under the top of the stack.",
+        Stmt::Rot { .. } => "Rot: moves the third value from the top of the stack to the top.",
+        Stmt::Drop2 { .. } => "Drop2: drops the two values on top of the stack.",
+        Stmt::Let { .. } => "Let: computes the initial value and stores it in the variable's own memory.",
+        Stmt::Unpack { .. } => "Unpack: calls the function, then stores the value it returned in rax and the one in rdx in the two variables.",
+        Stmt::If { els: None, .. } => "If: jumps over the branch when the condition is false.",
+        Stmt::If { els: Some(_), .. } => "If/else: jumps to the else branch when the condition is false, the then branch jumps over it.",
+        Stmt::While { .. } => "While: jumps to the condition at the end, which jumps back to the body as long as it holds.",
+        Stmt::Fun { .. } => "Fn: emits a procedure taking its first four arguments in rcx, rdx, r8 and r9 and the others on the stack, returning in rax and a second value in rdx.",
+        Stmt::Struct { .. } => "Struct: not compiled yet.",
+        Stmt::Return { .. } => "Return: computes the value into rax, a second one into rdx, and jumps to the function's epilogue.",
+        Stmt::Break { .. } => "Break: jumps past the end of the innermost loop.",
+        Stmt::Continue { .. } => "Continue: jumps to the increment or the condition of the innermost loop."
+    }
+}
+
+/// Meaning of a condition code suffix (`jl`, `setne`, `cmovnz`...).
+fn condition(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "e" => "equal",
+        "ne" => "not equal",
+        "z" => "zero",
+        "nz" => "not zero",
+        "l" => "less",
+        "le" => "less or equal",
+        "g" => "greater",
+        "ge" => "greater or equal",
+        "o" => "overflowed",
+        "ns" => "not negative",
+        "b" => "below (unsigned)",
+        _ => return None
+    })
+}
+
+/// One line of prose for an instruction or label, `None` for comments and what needs no explanation.
+fn instruction(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(';') {
+        return None;
+    }
+
+    if let Some(label) = line.strip_suffix(':') {
+        if label.ends_with("_return") {
+            return Some("epilogue, where `return` jumps with the result in rax".into());
+        }
+        let kind = match label.trim_start_matches('.').split('_').next().unwrap_or_default() {
+            "end" => "end of the construct, jumped to past its code",
+            "ne" => "else branch",
+            "body" => "loop body",
+            "cond" => "loop condition",
+            "next" => "loop increment",
+            "fn" => "start of the function",
+            _ => "label"
+        };
+        return Some(kind.into());
+    }
+
+    let (mnemonic, operands) = line.split_once(' ').unwrap_or((line, ""));
+    let operands: Vec<&str> = operands.split(',').map(str::trim).collect();
+    let first = operands[0];
+
+    Some(match mnemonic {
+        "push" if first.parse::<i64>().is_ok() => format!("push the constant {}", first),
+        "push" => format!("push {} on the stack", first),
+        "pop" => format!("pop the top of the stack into {}", first),
+        "mov" if first == "rbx" && operands.get(1) == Some(&"rsp") => "save the stack pointer across the call".into(),
+        "mov" if first == "rsp" => "restore the stack pointer".into(),
+        "mov" => format!("{} = {}", first, operands.get(1).unwrap_or(&"")),
+        "movzx" => format!("widen the 0/1 result in {}", operands.get(1).unwrap_or(&"")),
+        "lea" => format!("{} = address of {}", first, operands.get(1).unwrap_or(&"").trim_matches(['[', ']'])),
+        "add" if first == "rsp" => "free scratch space".into(),
+        "sub" if first == "rsp" => "reserve scratch space".into(),
+        "and" if first == "rsp" => "align the stack to 16 bytes for the call".into(),
+        "add" => "add the operands, wrapping on 64 bits".into(),
+        "sub" => "subtract the operands, wrapping on 64 bits".into(),
+        "imul" => "multiply the operands, wrapping on 64 bits".into(),
+        "cqo" => "sign-extend rax into rdx for the division".into(),
+        "idiv" => format!("divide rdx:rax by {}, the quotient goes to rax", first),
+        "neg" => format!("negate {}", first),
+        "shl" => format!("multiply by 2^{}", operands.get(1).unwrap_or(&"")),
+        "sar" | "shr" => format!("shift right by {}", operands.get(1).unwrap_or(&"")),
+        "cmp" => format!("compare {} with {}", first, operands.get(1).unwrap_or(&"")),
+        "test" => format!("check whether {} is zero", first),
+        "xor" if operands.get(1) == Some(&first) => format!("{} = 0", first),
+        "xor" => format!("{} = {} xor {}", first, first, operands.get(1).unwrap_or(&"")),
+        "jmp" => format!("jump to {}", first),
+        "ret" => "return to the caller".into(),
+        "syscall" => "call the kernel, the syscall number is in eax".into(),
+        "call" => match first {
+            "printf" => "print with the C runtime's printf".into(),
+            "ceya_print_num" => "print the number in rax through the runtime".into(),
+            "ceya_print_str" => "print the string at rdx through the runtime".into(),
+            "ceya_read_line" => "read a line of stdin through the runtime, its address goes to rax".into(),
+            "ceya_write_stdout" | "ceya_write_stderr" => format!("write r8 bytes at rdx through {}", first),
+            "ExitProcess" | "exit" => "end the program".into(),
+            function => format!("call {}", function)
+        },
+        _ => {
+            let (prefix, code) = ["set", "cmov", "j"].iter().find_map(|prefix| mnemonic.strip_prefix(prefix).map(|code| (*prefix, code)))?;
+            let condition = condition(code)?;
+            match prefix {
+                "set" => format!("{} = 1 if {}, 0 otherwise", first, condition),
+                "cmov" => format!("{} = {} if {}", first, operands.get(1).unwrap_or(&""), condition),
+                _ => format!("jump to {} if {}", first, condition)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{explain, instruction};
+
+    #[test]
+    fn test_explain() {
+        let source = "print 1 +\n2;\nwhile (soro < 3) ke;\n";
+        let res = explain(crate::scanner::scan(source), source, Default::default(), crate::parser::DEFAULT_MAX_DEPTH).unwrap();
+
+        assert!(res.starts_with(";;    0 | print 1 +\n;;    1 | 2;\n;; Print: "));
+        assert!(res.contains(";;    2 | while (soro < 3) ke;\n;; While: "));
+        assert!(res.contains("   jl .body_1                   ; jump to .body_1 if less\n"));
+    }
+
+    #[test]
+    fn test_instruction() {
+        assert_eq!(instruction("   push 42").as_deref(), Some("push the constant 42"));
+        assert_eq!(instruction("   setge al").as_deref(), Some("al = 1 if greater or equal, 0 otherwise"));
+        assert_eq!(instruction(".end_3:").as_deref(), Some("end of the construct, jumped to past its code"));
+        assert_eq!(instruction("   lea rcx, [msg]").as_deref(), Some("rcx = address of msg"));
+        assert_eq!(instruction("fn_add_return:").as_deref(), Some("epilogue, where `return` jumps with the result in rax"));
+        assert_eq!(instruction("   jz .end_2").as_deref(), Some("jump to .end_2 if zero"));
+        assert_eq!(instruction("   ; print 1"), None);
+        assert_eq!(instruction("   jpo .x"), None);
+    }
+}
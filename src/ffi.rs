@@ -0,0 +1,74 @@
+//! C interface for embedding the interpreter, see `include/ceya.h`.
+//! Strings handed out are owned by the library and must be released with `ceya_free`.
+
+use std::{ffi::{c_char, CStr, CString}, ptr};
+
+/// Runs a program and returns what it printed, or null if `source` is null or not UTF-8.
+///
+/// # Safety
+/// `source` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ceya_run(source: *const c_char) -> *mut c_char {
+    match read(source) {
+        Some(source) => into_c(crate::run(source)),
+        None => ptr::null_mut()
+    }
+}
+
+/// Evaluates an expression and returns its value as text, or null if it doesn't parse.
+///
+/// # Safety
+/// `expr` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ceya_eval(expr: *const c_char) -> *mut c_char {
+    match read(expr).map(crate::eval) {
+        Some(Ok(value)) => into_c(value.to_string()),
+        _ => ptr::null_mut()
+    }
+}
+
+/// Releases a string returned by `ceya_run` or `ceya_eval`. Null is ignored.
+///
+/// # Safety
+/// `s` must come from this library and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn ceya_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+unsafe fn read<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// Interior NULs can't cross the C boundary, the text is cut at the first one.
+fn into_c(s: String) -> *mut c_char {
+    let end = s.find('\0').unwrap_or(s.len());
+    CString::new(&s[..end]).expect("no interior NUL").into_raw()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::{CStr, CString}, ptr};
+
+    use super::{ceya_eval, ceya_free, ceya_run};
+
+    #[test]
+    fn test_c_api() {
+        let source = CString::new("print 6 * 7;").unwrap();
+        unsafe {
+            let output = ceya_run(source.as_ptr());
+            assert_eq!(CStr::from_ptr(output).to_str().unwrap(), "42\n");
+            ceya_free(output);
+
+            let expr = CString::new("1 +").unwrap();
+            assert!(ceya_eval(expr.as_ptr()).is_null());
+            assert!(ceya_run(ptr::null()).is_null());
+            ceya_free(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Editor grammars generated from the scanner tables, so highlighting follows keyword renames and new operators.
+
+use std::fmt::Write;
+
+use crate::scanner::{Keywords, TokenType, OPERATORS};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Keyword,
+    /// `soro`, `faran`, `ke`, `depth` and the other words working on the value stack.
+    Stack,
+    Constant
+}
+
+fn classify(typ: &TokenType) -> Class {
+    match typ {
+        TokenType::Soro | TokenType::Faran | TokenType::Ke | TokenType::Depth
+            | TokenType::Swap | TokenType::Over | TokenType::Rot | TokenType::Drop2 => Class::Stack,
+        TokenType::True | TokenType::False | TokenType::Null | TokenType::Number(_) => Class::Constant,
+        _ => Class::Keyword
+    }
+}
+
+fn words(keywords: &Keywords, class: Class) -> Vec<&str> {
+    keywords.words().into_iter().filter(|(_, typ)| classify(typ) == class).map(|(word, _)| word).collect()
+}
+
+/// TextMate grammar in its JSON form (`ceya.tmLanguage.json`), understood by VS Code, Sublime Text and most other editors.
+pub fn tmlanguage(keywords: &Keywords) -> String {
+    let alternatives = |class| format!("\\b({})\\b", words(keywords, class).join("|"));
+    let operators = OPERATORS.iter().map(|op| op.chars().map(|c| format!("\\{}", c)).collect::<String>()).collect::<Vec<_>>().join("|");
+
+    let patterns = [
+        ("comment.line.double-slash.ceya", "//.*$".to_string()),
+        ("string.quoted.double.ceya", "\"(\\\\.|[^\"\\\\])*\"".into()),
+        ("constant.numeric.ceya", "\\b(0[xX][0-9a-fA-F](_?[0-9a-fA-F])*|0[bB][01](_?[01])*|[0-9](_?[0-9])*(\\.[0-9](_?[0-9])*)?)\\b".into()),
+        ("keyword.control.ceya", alternatives(Class::Keyword)),
+        ("variable.language.stack.ceya", alternatives(Class::Stack)),
+        ("constant.language.ceya", alternatives(Class::Constant)),
+        ("keyword.operator.ceya", operators)
+    ];
+
+    let mut res = String::from("{\n    \"name\": \"ceya\",\n    \"scopeName\": \"source.ceya\",\n    \"fileTypes\": [\"ceya\"],\n    \"patterns\": [\n");
+    // block comments nest, hence the recursive include
+    writeln!(&mut res, "        {{ \"include\": \"#block-comment\" }},").unwrap();
+    for (i, (scope, regex)) in patterns.iter().enumerate() {
+        let separator = if i + 1 < patterns.len() { "," } else { "" };
+        writeln!(&mut res, "        {{ \"name\": {}, \"match\": {} }}{}", json_string(scope), json_string(regex), separator).unwrap();
+    }
+    res.push_str("    ],\n    \"repository\": {\n        \"block-comment\": {\n");
+    writeln!(&mut res, "            \"name\": \"comment.block.ceya\", \"begin\": {}, \"end\": {},", json_string("/\\*"), json_string("\\*/")).unwrap();
+    res.push_str("            \"patterns\": [{ \"include\": \"#block-comment\" }]\n        }\n    }\n}\n");
+    res
+}
+
+/// Vim syntax file, to save as `syntax/ceya.vim`.
+pub fn vim(keywords: &Keywords) -> String {
+    let operators = OPERATORS.iter().map(|op| op.replace('*', "\\*").replace('.', "\\.").replace('[', "\\[")).collect::<Vec<_>>().join("\\|");
+
+    let mut res = String::from("\" Vim syntax file for ceya, generated by `ceya highlight --format vim`\n");
+    res.push_str("if exists(\"b:current_syntax\")\n    finish\nendif\n\n");
+    writeln!(&mut res, "syn keyword ceyaKeyword {}", words(keywords, Class::Keyword).join(" ")).unwrap();
+    writeln!(&mut res, "syn keyword ceyaStack {}", words(keywords, Class::Stack).join(" ")).unwrap();
+    writeln!(&mut res, "syn keyword ceyaConstant {}", words(keywords, Class::Constant).join(" ")).unwrap();
+    res.push_str("syn match ceyaNumber \"\\<\\(0[xX]\\x\\(_\\=\\x\\)*\\|0[bB][01]\\(_\\=[01]\\)*\\|\\d\\(_\\=\\d\\)*\\(\\.\\d\\(_\\=\\d\\)*\\)\\=\\)\\>\"\n");
+    writeln!(&mut res, "syn match ceyaOperator \"{}\"", operators).unwrap();
+    res.push_str("syn region ceyaString start=+\"+ skip=+\\\\.+ end=+\"+\n");
+    res.push_str("syn match ceyaComment \"//.*$\"\n");
+    res.push_str("syn region ceyaComment start=\"/\\*\" end=\"\\*/\" contains=ceyaComment\n\n");
+    for (group, link) in [("Keyword", "Keyword"), ("Stack", "Special"), ("Constant", "Constant"), ("Number", "Number"), ("Operator", "Operator"), ("String", "String"), ("Comment", "Comment")] {
+        writeln!(&mut res, "hi def link ceya{} {}", group, link).unwrap();
+    }
+    res.push_str("\nlet b:current_syntax = \"ceya\"\n");
+    res
+}
+
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scanner::Keywords;
+
+    use super::{tmlanguage, vim};
+
+    #[test]
+    fn test_vim() {
+        let syntax = vim(&Keywords::default());
+        assert!(syntax.contains("syn keyword ceyaStack depth drop2 faran ke over rot soro swap\n"));
+        assert!(syntax.contains("syn keyword ceyaConstant false fu inf nan true\n"));
+        assert!(syntax.contains("\"!=\\|==\\|<=\\|>=\\|\\.\\.\\|(\\|)\\|{\\|}\\|\\[\\|]\\|"));
+
+        assert!(vim(&Keywords::english()).contains("syn keyword ceyaStack depth drop drop2 dup over rot swap top\n"));
+    }
+
+    #[test]
+    fn test_tmlanguage() {
+        let grammar: serde_json::Value = serde_json::from_str(&tmlanguage(&Keywords::english())).expect("Valid JSON expected.");
+        let patterns = grammar["patterns"].as_array().unwrap();
+        assert!(patterns.iter().any(|pattern| pattern["match"] == "\\b(depth|drop|drop2|dup|over|rot|swap|top)\\b"));
+        assert_eq!(grammar["repository"]["block-comment"]["end"], "\\*/");
+    }
+}
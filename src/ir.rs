@@ -0,0 +1,365 @@
+use std::{collections::HashMap, fmt::{Display, Formatter, Write}};
+
+use crate::{ast::{AsmComments, CompileOptions, Expr, Value}, backend::emit_xor, runtime, scanner::TokenType};
+
+/// Index of the instruction producing a value. Every value is defined exactly once (SSA).
+pub type ValueId = usize;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    Xor
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Inst {
+    Const(i64),
+    /// The n-th value popped from the operand stack by `soro`, 0 being the top.
+    Input(usize),
+    Neg(ValueId),
+    Binary(Op, ValueId, ValueId)
+}
+
+/// A straight-line expression in SSA form. Instructions are numbered on construction, so
+/// identical computations share one value (CSE) and constant operands are folded right away.
+///
+/// Lowering runs the stack machine symbolically: `soro` is not an operand of its own, an
+/// operator takes whatever is on top of the operand stack, and reaching below the values the
+/// expression pushed itself reads an input.
+#[derive(Default)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    /// Values popped from the operand stack.
+    pub inputs: usize,
+    /// Values left on the operand stack by the expression, bottom first.
+    pub outputs: Vec<ValueId>,
+    numbering: HashMap<Inst, ValueId>,
+    checked_arith: bool
+}
+
+impl Block {
+    /// Lowers an expression, `None` when it uses something the IR can't express yet
+    /// (strings, variables, calls, `!`) or a number that isn't a 64-bit integer.
+    pub fn lower(expr: &Expr, checked_arith: bool) -> Option<Block> {
+        let mut block = Block { checked_arith, ..Default::default() };
+        block.lower_expr(expr)?;
+        block.eliminate_dead()?;
+        Some(block)
+    }
+
+    fn pop(&mut self) -> ValueId {
+        match self.outputs.pop() {
+            Some(id) => id,
+            None => {
+                self.inputs += 1;
+                self.add(Inst::Input(self.inputs - 1))
+            }
+        }
+    }
+
+    fn lower_expr(&mut self, expr: &Expr) -> Option<()> {
+        match expr {
+            Expr::Literal { value: Value::Number(n), .. } if n.fract() == 0. && (i64::MIN as f64..i64::MAX as f64).contains(n) => {
+                let id = self.add(Inst::Const(*n as i64));
+                self.outputs.push(id);
+            },
+            Expr::Literal { value: Value::Boolean(b), .. } => {
+                let id = self.add(Inst::Const(*b as i64));
+                self.outputs.push(id);
+            },
+            // copies disappear: a grouping is the value of its expression
+            Expr::Grouping { expression, .. } => self.lower_expr(expression)?,
+            Expr::Soro { .. } => (),
+            Expr::Unary { operator, right, .. } if operator.typ == TokenType::Minus => {
+                self.lower_expr(right)?;
+                let right = self.pop();
+                let id = self.add(Inst::Neg(right));
+                self.outputs.push(id);
+            },
+            Expr::Binary { left, operator, right, .. } => {
+                let op = match operator.typ {
+                    TokenType::Plus => Op::Add,
+                    TokenType::Minus => Op::Sub,
+                    TokenType::Star => Op::Mul,
+                    TokenType::Slash => Op::Div,
+                    TokenType::Less => Op::Less,
+                    TokenType::LessEqual => Op::LessEqual,
+                    TokenType::Greater => Op::Greater,
+                    TokenType::GreaterEqual => Op::GreaterEqual,
+                    TokenType::EqualEqual => Op::Equal,
+                    TokenType::BangEqual => Op::NotEqual,
+                    TokenType::Xor => Op::Xor,
+                    _ => return None
+                };
+                self.lower_expr(left)?;
+                self.lower_expr(right)?;
+                let right = self.pop();
+                let left = self.pop();
+                let id = self.add(Inst::Binary(op, left, right));
+                self.outputs.push(id);
+            },
+            _ => return None
+        }
+
+        Some(())
+    }
+
+    /// Appends an instruction, reusing an existing value for constants and repeated computations.
+    pub fn add(&mut self, inst: Inst) -> ValueId {
+        let inst = match self.fold(inst) {
+            Some(constant) => Inst::Const(constant),
+            None => inst
+        };
+
+        if let Some(id) = self.numbering.get(&inst) {
+            return *id;
+        }
+
+        self.insts.push(inst);
+        self.numbering.insert(inst, self.insts.len() - 1);
+        self.insts.len() - 1
+    }
+
+    fn constant(&self, id: ValueId) -> Option<i64> {
+        match self.insts[id] {
+            Inst::Const(c) => Some(c),
+            _ => None
+        }
+    }
+
+    /// Evaluates an instruction on constant operands the way the generated code would, wrapping on
+    /// 64 bits. Operations that trap at runtime (division by zero, checked overflow) are left alone.
+    fn fold(&self, inst: Inst) -> Option<i64> {
+        match inst {
+            Inst::Neg(a) => Some(self.constant(a)?.wrapping_neg()),
+            Inst::Binary(op, a, b) => {
+                let (a, b) = (self.constant(a)?, self.constant(b)?);
+                let wrap = |checked: Option<i64>, wrapped: i64| match checked {
+                    None if self.checked_arith => None,
+                    _ => Some(wrapped)
+                };
+
+                match op {
+                    Op::Add => wrap(a.checked_add(b), a.wrapping_add(b)),
+                    Op::Sub => wrap(a.checked_sub(b), a.wrapping_sub(b)),
+                    Op::Mul => wrap(a.checked_mul(b), a.wrapping_mul(b)),
+                    Op::Div => a.checked_div(b),
+                    Op::Less => Some((a < b) as i64),
+                    Op::LessEqual => Some((a <= b) as i64),
+                    Op::Greater => Some((a > b) as i64),
+                    Op::GreaterEqual => Some((a >= b) as i64),
+                    Op::Equal => Some((a == b) as i64),
+                    Op::NotEqual => Some((a != b) as i64),
+                    Op::Xor => Some(((a != 0) != (b != 0)) as i64)
+                }
+            },
+            _ => None
+        }
+    }
+
+    /// Drops instructions the outputs don't depend on (operands folded away) and renumbers.
+    /// Inputs always stay: popping them is part of the expression's effect on the stack.
+    fn eliminate_dead(&mut self) -> Option<()> {
+        let mut live = vec![false; self.insts.len()];
+        for id in &self.outputs {
+            live[*id] = true;
+        }
+        for id in (0..self.insts.len()).rev() {
+            match self.insts[id] {
+                Inst::Input(_) => live[id] = true,
+                Inst::Neg(a) if live[id] => live[a] = true,
+                Inst::Binary(_, a, b) if live[id] => {
+                    live[a] = true;
+                    live[b] = true;
+                },
+                _ => ()
+            }
+        }
+
+        let mut renumbered: Vec<Option<ValueId>> = vec![None; self.insts.len()];
+        let mut insts = vec![];
+        for (id, inst) in self.insts.iter().enumerate() {
+            if !live[id] {
+                continue;
+            }
+            renumbered[id] = Some(insts.len());
+            insts.push(match *inst {
+                Inst::Neg(a) => Inst::Neg(renumbered[a]?),
+                Inst::Binary(op, a, b) => Inst::Binary(op, renumbered[a]?, renumbered[b]?),
+                inst => inst
+            });
+        }
+
+        self.insts = insts;
+        self.numbering.clear();
+        self.outputs = self.outputs.iter().map(|id| renumbered[*id]).collect::<Option<_>>()?;
+        Some(())
+    }
+
+    /// Computes every value into its own slot of a scratch area below the inputs, then replaces
+    /// the inputs with the outputs on the operand stack.
+    pub fn compile(&self, options: &CompileOptions) -> String {
+        let mut res = String::new();
+        let slot = |id: ValueId| format!("[rsp + {}]", 8 * id);
+
+        // constants only: push them directly
+        if self.inputs == 0 && self.insts.iter().all(|inst| matches!(inst, Inst::Const(_))) {
+            for id in &self.outputs {
+                writeln!(&mut res, "   mov rax, {}", self.constant(*id).unwrap_or_default()).unwrap();
+                writeln!(&mut res, "   push rax").unwrap();
+            }
+            return res;
+        }
+
+        writeln!(&mut res, "   sub rsp, {}", 8 * self.insts.len()).unwrap();
+        for (id, inst) in self.insts.iter().enumerate() {
+            if options.comments == AsmComments::Full {
+                writeln!(&mut res, "   ; %{} = {}", id, inst).unwrap();
+            }
+
+            match *inst {
+                Inst::Const(c) => writeln!(&mut res, "   mov rax, {}", c).unwrap(),
+                Inst::Input(n) => writeln!(&mut res, "   mov rax, [rsp + {}]", 8 * (self.insts.len() + n)).unwrap(),
+                Inst::Neg(a) => {
+                    writeln!(&mut res, "   mov rax, {}", slot(a)).unwrap();
+                    writeln!(&mut res, "   neg rax").unwrap();
+                },
+                Inst::Binary(op, a, b) => {
+                    writeln!(&mut res, "   mov rax, {}", slot(a)).unwrap();
+                    writeln!(&mut res, "   mov rbx, {}", slot(b)).unwrap();
+                    match op {
+                        Op::Add => writeln!(&mut res, "   add rax, rbx").unwrap(),
+                        Op::Sub => writeln!(&mut res, "   sub rax, rbx").unwrap(),
+                        Op::Mul => writeln!(&mut res, "   imul rax, rbx").unwrap(),
+                        Op::Div => {
+                            writeln!(&mut res, "   test rbx, rbx").unwrap();
+                            writeln!(&mut res, "   jz {}", runtime::DIV_ZERO_TRAP).unwrap();
+                            writeln!(&mut res, "   cqo").unwrap();
+                            writeln!(&mut res, "   idiv rbx").unwrap();
+                        },
+                        Op::Xor => write!(&mut res, "{}", emit_xor()).unwrap(),
+                        _ => {
+                            writeln!(&mut res, "   cmp rax, rbx").unwrap();
+                            writeln!(&mut res, "   set{} al", op.condition()).unwrap();
+                            writeln!(&mut res, "   movzx rax, al").unwrap();
+                        }
+                    }
+                    if options.checked_arith && matches!(op, Op::Add | Op::Sub | Op::Mul) {
+                        writeln!(&mut res, "   jo {}", runtime::OVERFLOW_TRAP).unwrap();
+                    }
+                }
+            }
+            writeln!(&mut res, "   mov {}, rax", slot(id)).unwrap();
+        }
+
+        // an operator replaces its two operands with one value, so an expression leaves at most one of its own
+        if let Some(id) = self.outputs.last() {
+            writeln!(&mut res, "   mov rax, {}", slot(*id)).unwrap();
+        }
+        writeln!(&mut res, "   add rsp, {}", 8 * (self.insts.len() + self.inputs)).unwrap();
+        if !self.outputs.is_empty() {
+            writeln!(&mut res, "   push rax").unwrap();
+        }
+
+        res
+    }
+}
+
+impl Op {
+    fn condition(&self) -> &str {
+        match self {
+            Op::Less => "l",
+            Op::LessEqual => "le",
+            Op::Greater => "g",
+            Op::GreaterEqual => "ge",
+            Op::Equal => "e",
+            Op::NotEqual => "ne",
+            _ => unreachable!("not a comparison")
+        }
+    }
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inst::Const(c) => write!(f, "const {}", c),
+            Inst::Input(n) => write!(f, "input {}", n),
+            Inst::Neg(a) => write!(f, "neg %{}", a),
+            Inst::Binary(op, a, b) => {
+                let name = match op {
+                    Op::Add => "add",
+                    Op::Sub => "sub",
+                    Op::Mul => "mul",
+                    Op::Div => "div",
+                    Op::Less => "lt",
+                    Op::LessEqual => "le",
+                    Op::Greater => "gt",
+                    Op::GreaterEqual => "ge",
+                    Op::Equal => "eq",
+                    Op::NotEqual => "ne",
+                    Op::Xor => "xor"
+                };
+                write!(f, "{} %{}, %{}", name, a, b)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Block, Inst, Op};
+
+    fn lower(source: &str, checked_arith: bool) -> (Vec<String>, Vec<usize>) {
+        let stmts = crate::parser::parse(crate::scanner::scan(source)).unwrap();
+        let crate::ast::Stmt::Expression { expression, .. } = &stmts[0] else { panic!("Expression statement expected.") };
+        let block = Block::lower(expression, checked_arith).expect("Lowerable expression expected.");
+        (block.insts.iter().map(|inst| inst.to_string()).collect(), block.outputs)
+    }
+
+    #[test]
+    fn test_constant_propagation() {
+        assert_eq!(lower("(2 + 3) * 4 > 19;", false), (vec!["const 1".to_string()], vec![0]));
+        // 64-bit wraparound like `add rax, rbx`
+        assert_eq!(lower("2147483647 * 2147483647 * 4;", false).0, vec!["const -17179869180"]);
+        assert_eq!(lower("2147483647 + 1;", true).0, vec!["const 2147483648"]);
+        // left for the runtime check
+        assert_eq!(lower("2147483647 * 2147483647 * 4;", true).0.len(), 3);
+        assert_eq!(lower("1 / 0;", false).0.len(), 3);
+        assert_eq!(lower("(2 xor 0) == (3 xor 5);", false).0, vec!["const 0"]);
+    }
+
+    #[test]
+    fn test_inputs_and_dead_code() {
+        // the right-hand `soro` is the top of the stack, the left one below it
+        assert_eq!(lower("soro - soro;", false), (vec!["input 0".to_string(), "input 1".into(), "sub %1, %0".into()], vec![2]));
+
+        // in `2 * (soro + 1)`, `soro` is the 2 pushed just before
+        let insts = ["const 3", "input 0", "mul %1, %0", "input 1", "mul %3, %2"];
+        assert_eq!(lower("soro * (2 * (soro + 1));", false), (insts.map(String::from).to_vec(), vec![4]));
+
+        assert_eq!(lower("soro;", false), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_cse() {
+        let mut block = Block::default();
+        let a = block.add(Inst::Input(0));
+        let b = block.add(Inst::Binary(Op::Add, a, a));
+        assert_eq!(block.add(Inst::Binary(Op::Add, a, a)), b);
+
+        let c = block.add(Inst::Const(3));
+        assert_eq!(block.add(Inst::Const(3)), c);
+        // folded to an existing constant
+        let six = block.add(Inst::Const(6));
+        assert_eq!(block.add(Inst::Binary(Op::Add, c, c)), six);
+    }
+}
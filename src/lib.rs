@@ -0,0 +1,166 @@
+//! Scanner, parser, interpreter and code generators of ceya, shared by the `ceya` binary and embedders.
+
+use std::{mem, rc::Rc};
+
+use ast::{Fun, Value};
+use error::CeyaError;
+use environment::EnvironmentArena;
+use scanner::{Keywords, Token, TokenType};
+
+pub mod error;
+pub mod scanner;
+pub mod ast;
+pub mod parser;
+pub mod environment;
+pub mod stdlib;
+pub mod backend;
+pub mod runtime;
+pub mod dialect;
+pub mod ir;
+pub mod vm;
+pub mod highlight;
+pub mod explain;
+pub mod check;
+pub mod typecheck;
+pub mod ffi;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+/// Interprets a program with only the sandboxed natives and returns everything it printed,
+/// followed by its errors one per line.
+pub fn run(source: &str) -> String {
+    let mut interpreter = Interpreter::new();
+    let errors = interpreter.run(source).err().unwrap_or_default();
+    interpreter.take_output() + &errors.iter().map(|e| format!("{}\n", e)).collect::<String>()
+}
+
+/// Evaluates a single expression like `run` does.
+pub fn eval(source: &str) -> Result<Value, CeyaError> {
+    Interpreter::new().eval(source)
+}
+
+/// The tree-walking interpreter for programs embedding ceya. Globals stay defined from one
+/// `run` to the next, and what programs print is kept until `take_output`.
+pub struct Interpreter {
+    env_arena: EnvironmentArena,
+    global_env: usize
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    /// An interpreter with the sandboxed natives, the ones that don't touch the host system.
+    pub fn new() -> Interpreter {
+        let mut env_arena = EnvironmentArena::capturing();
+        let global_env = env_arena.add(None);
+        stdlib::define_natives(&mut env_arena, global_env);
+        Interpreter { env_arena, global_env }
+    }
+
+    /// Runs a program, unless it has errors which are returned instead: its syntax errors, or the
+    /// ones `check` and `typecheck` find before `sim` runs it, in source order. The runtime errors
+    /// of a program that ran are returned too, in the order they happened.
+    pub fn run(&mut self, source: &str) -> Result<(), Vec<CeyaError>> {
+        let (tokens, mut errors) = scanner::scan_all(source, Keywords::default());
+        match parser::parse(tokens) {
+            Ok(stmts) if errors.is_empty() => {
+                errors.extend(check::check(&stmts, &self.env_arena, self.global_env));
+                errors.extend(typecheck::check(&stmts));
+                if errors.is_empty() {
+                    for stmt in stmts {
+                        stmt.eliminate_branches().execute(&mut self.env_arena, self.global_env);
+                    }
+                    return match self.env_arena.errors.is_empty() {
+                        true => Ok(()),
+                        false => Err(mem::take(&mut self.env_arena.errors))
+                    };
+                }
+                errors.sort_by_key(|e| (e.span().start, e.span().column));
+            },
+            Ok(_) => (),
+            Err(parse_errors) => errors.extend(parse_errors)
+        }
+        Err(errors)
+    }
+
+    /// Evaluates a single expression with the globals defined so far, or gives its first runtime error.
+    pub fn eval(&mut self, source: &str) -> Result<Value, CeyaError> {
+        let expr = parser::parse_expression(scanner::scan(source))?;
+        let value = expr.evaluate(&mut self.env_arena, self.global_env);
+        match self.env_arena.errors.drain(..).next() {
+            Some(e) => Err(e),
+            None => Ok(value)
+        }
+    }
+
+    /// Defines a global, replacing any previous one with the same name.
+    pub fn define(&mut self, name: &str, value: Value) {
+        self.env_arena.define(self.global_env, name, value);
+    }
+
+    /// Makes a Rust function callable from programs, `arity` being `None` for any number of arguments.
+    pub fn define_native(&mut self, name: &str, arity: Option<usize>, callee: impl Fn(Vec<Value>) -> Value + 'static) {
+        self.define(name, Value::Fun(Fun::Native { name: name.into(), arity, callee: Rc::new(move |arguments| Ok(callee(arguments))) }));
+    }
+
+    /// The value of a global, `None` if it isn't defined.
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.env_arena.get(self.global_env, &Token::new(name, 0, TokenType::Identifier)).ok().cloned()
+    }
+
+    /// Everything printed since the last call, `eprint` excepted.
+    pub fn take_output(&mut self) -> String {
+        self.env_arena.output.replace(String::new()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_run() {
+        assert_eq!(super::run("print 1 + 2; print \"{} {}\", 3, to_hex(255);"), "3\n3 ff\n");
+        assert_eq!(super::run("print exec(\"echo hi\");"), "[line 0:6] Error: Undefined variable 'exec'.\n");
+        assert_eq!(super::run("let a = 1; let a = 2;"), "[line 0:15] Error: 'a' is already declared in this scope.\n");
+        assert_eq!(super::run("print 1; print [0][1];"), "1\n[line 0:15] Error: Index 1 out of bounds for length 1.\n");
+        // nothing runs after the error, not even the rest of its statement
+        assert_eq!(super::run("fn f() { print 2; return 3; } print [0][1] + f(); print 4;"), "[line 0:36] Error: Index 1 out of bounds for length 1.\n");
+        assert_eq!(super::run("let i = 0; while (true) { print i; i = i + 1; if (i == 2) [][0]; }"), "0\n1\n[line 0:58] Error: Index 0 out of bounds for length 0.\n");
+        assert_eq!(super::run("print 1; print 2 +; @"), "[line 0:20] Error: Unexpected token '@'.\n[line 0:18] Error: Expect expression. (at ';')\n");
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(super::eval("(1 + 2) * 4").unwrap().to_string(), "12");
+        assert_eq!(super::eval("to_bin(5)").unwrap().to_string(), "101");
+        assert!(matches!(super::eval("1 +"), Err(super::CeyaError::Parse { .. })));
+        assert!(matches!(super::eval("1 2"), Err(super::CeyaError::Parse { .. })));
+        assert_eq!(super::eval("[1, 2][5]").err().unwrap().to_string(), "[line 0:0] Error: Index 5 out of bounds for length 2.");
+        assert!(matches!(super::eval("undefined"), Err(super::CeyaError::Runtime { .. })));
+        assert_eq!(super::eval("to_hex(\"a\")").err().unwrap().to_string(), "[line 0:10] Error: to_hex() expects a number.");
+    }
+
+    #[test]
+    fn test_interpreter() {
+        let mut interpreter = super::Interpreter::new();
+        interpreter.define_native("twice", Some(1), |arguments| match arguments[0] {
+            super::Value::Number(n) => super::Value::Number(2. * n),
+            _ => super::Value::Null
+        });
+        interpreter.run("let x = twice(21); fn inc(n) { return n + 1; }").unwrap();
+        interpreter.run("print inc(x);").unwrap();
+        assert!(interpreter.run("print x; let;").is_err_and(|errors| errors.len() == 1));
+        assert_eq!(interpreter.take_output(), "43\n");
+        assert_eq!(interpreter.take_output(), "");
+        assert!(interpreter.run("print x + 1; print [x][1];").is_err_and(|errors| matches!(errors.as_slice(), [super::CeyaError::Runtime { .. }])));
+        assert_eq!(interpreter.take_output(), "43\n");
+
+        assert_eq!(interpreter.eval("x == 42").unwrap(), super::Value::Boolean(true));
+        interpreter.define("y", super::Value::String("s".into()));
+        assert_eq!(interpreter.get("y").unwrap().to_string(), "s");
+        assert!(interpreter.get("z").is_none());
+    }
+}
@@ -1,17 +1,26 @@
 use std::fs::File;
-use std::io::{Read, Error, ErrorKind, Write, self, BufReader, BufRead};
+use std::path::{Path, PathBuf};
+use std::fmt::Write as _;
+use std::io::{IsTerminal, Read, Write};
 use std::process::Stdio;
+use std::rc::Rc;
 
+use clap::{Parser, Subcommand, Args, ValueEnum};
+use ceya::{check, error, explain, highlight, parser, scanner, stdlib, typecheck, vm};
+use ceya::ast::{AsmComments, CompileOptions, Compiler, OptLevel, Stmt};
+use ceya::backend::{emit_exit, emit_function, peephole, Target, STACK_BASE};
+use ceya::runtime::Runtime;
+use ceya::dialect::Dialect;
+use ceya::environment::EnvironmentArena;
+use ceya::error::{CeyaError, Renderer};
+use ceya::scanner::Keywords;
+use config::Config;
+use toolchain::{Assembler, Build, Linker};
 
-use ast::{Value, Fun};
-use clap::{Parser, Subcommand, command, Args};
-use environment::EnvironmentArena;
-use scanner::Scanner;
+mod config;
+mod toolchain;
 
-mod scanner;
-mod ast;
-mod parser;
-mod environment;
+const DEFAULT_OUT_DIR: &str = "ceya-out";
 
 #[derive(Parser)]
 #[command(name = "ceya")]
@@ -24,23 +33,209 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Sim(SimArgs),
-    Com(ComArgs)
+    Com(ComArgs),
+    /// Report the errors of a program, undefined variables and wrong argument counts included, without running or compiling it
+    Check(CheckArgs),
+    /// Run a program through both the interpreter and the compiler and compare their output
+    Difftest(DifftestArgs),
+    /// Print a program in canonical formatting (comments are not preserved)
+    Fmt(FmtArgs),
+    /// Create a project directory with a starter program
+    New(NewArgs),
+    /// Print a syntax highlighting grammar for editors
+    Highlight(HighlightArgs),
+    /// Print the assembly of a program under its source lines, explaining each statement and instruction
+    Explain(ExplainArgs),
+    /// Print the tokens, syntax tree or assembly of a program, to debug how it is read
+    Dump(DumpArgs)
 }
 
 #[derive(Args)]
 struct SimArgs {
+    #[arg(long, default_value_t = false, help = "Disable natives that touch the host system, like exec()")]
+    sandbox: bool,
+    #[arg(long, default_value_t = false, help = "Print memory statistics to stderr after the run")]
+    stats: bool,
+    #[arg(long, default_value = "default", help = "Keyword set: default, english, or a file of 'keyword = spelling' lines")]
+    keywords: String,
+    #[arg(long, default_value_t = parser::DEFAULT_MAX_DEPTH, help = "Deepest nesting of statements and expressions accepted")]
+    max_depth: usize,
+    #[arg(long, default_value_t = false, help = "Compile the program to bytecode and run it on a VM instead of walking the syntax tree")]
+    vm: bool,
+    #[arg(long, default_value_t = false, help = "Print errors without ANSI colors")]
+    no_color: bool,
     filepath: String
 }
 
-#[derive(Args)]
+#[derive(Args, Clone)]
 struct ComArgs {
     #[arg(short, default_value_t = false, help = "Run the program after compilation")]
     run: bool,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", help = "Build without the C runtime, printing through WriteFile (the write syscall on Linux)")]
+    freestanding: Option<bool>,
+    #[arg(long, value_enum, help = "Platform of the executable [default: the one ceya runs on]")]
+    target: Option<Target>,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", help = "Print through an emitted routine instead of printf, matching the interpreter's output")]
+    builtin_print: Option<bool>,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", help = "Abort on integer overflow instead of wrapping")]
+    checked_arith: Option<bool>,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", help = "Release profile: -O2, no asm comments, no runtime checks, release assembler/linker flags")]
+    release: Option<bool>,
+    #[arg(short = 'O', value_enum, help = "Optimization level [default: 1, 2 with --release]")]
+    opt_level: Option<OptLevel>,
+    #[arg(long = "Werror", default_value_t = false, help = "Treat warnings as errors")]
+    werror: bool,
+    #[arg(long, default_value_t = false, help = "Write output.ceya.lst next to the assembly, interleaving source lines with the asm of each statement (before -O2 peephole)")]
+    listing: bool,
+    #[arg(long, default_value_t = false, help = "Write output.map next to the assembly, listing every generated label with its source line")]
+    emit_map: bool,
+    #[arg(long, value_enum, help = "Syntax of the generated assembly [default: nasm]")]
+    asm_dialect: Option<Dialect>,
+    #[arg(long, value_enum, help = "Source comments in the generated assembly [default: full, off with --release]")]
+    asm_comments: Option<AsmComments>,
+    #[arg(long, value_enum, help = "Windows subsystem of the executable, ignored on Linux [default: console]")]
+    subsystem: Option<Subsystem>,
+    #[arg(long, value_enum, help = "Assembler of the nasm output [default: nasm]")]
+    assembler: Option<Assembler>,
+    #[arg(long, value_enum, help = "Linker of the executable [default: link on Windows, gcc on Linux]")]
+    linker: Option<Linker>,
+    #[arg(long, help = "Script setting up the MSVC environment when link isn't on the PATH, instead of the Visual Studio 2022 Community one")]
+    vcvars: Option<String>,
+    #[arg(long, help = "Directory receiving the build artifacts, in a <target>/<debug|release> subdirectory [default: ceya-out]")]
+    out_dir: Option<String>,
+    #[arg(long, help = "Keyword set: default, english, or a file of 'keyword = spelling' lines [default: default]")]
+    keywords: Option<String>,
+    #[arg(long, help = "Deepest nesting of statements and expressions accepted [default: 200]")]
+    max_depth: Option<usize>,
+    #[arg(long, default_value_t = false, help = "Print errors without ANSI colors")]
+    no_color: bool,
+    /// Source file, `main` of ceya.toml when omitted
+    filepath: Option<String>
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    #[arg(long, default_value_t = false, help = "Leave out the natives that touch the host system, like exec()")]
+    sandbox: bool,
+    #[arg(long, default_value = "default", help = "Keyword set: default, english, or a file of 'keyword = spelling' lines")]
+    keywords: String,
+    #[arg(long, default_value_t = parser::DEFAULT_MAX_DEPTH, help = "Deepest nesting of statements and expressions accepted")]
+    max_depth: usize,
+    #[arg(long, default_value_t = false, help = "Print errors without ANSI colors")]
+    no_color: bool,
+    filepath: String
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum Subsystem {
+    #[default]
+    /// Attached to a console, print output is visible.
+    Console,
+    /// Windowed application without a console window, print output is discarded.
+    Windows
+}
+
+#[derive(Args)]
+struct DifftestArgs {
+    filepath: String
+}
+
+#[derive(Args)]
+struct FmtArgs {
     filepath: String
 }
 
-fn error(line: u32, message: &str) -> Error {
-    Error::new(ErrorKind::Other, format!("[line {}] Error: {}", line, message))
+#[derive(Args)]
+struct NewArgs {
+    /// Directory to create, its name is the project name
+    path: String
+}
+
+#[derive(Args)]
+struct HighlightArgs {
+    #[arg(long, value_enum, help = "Editor grammar to generate")]
+    format: HighlightFormat,
+    #[arg(long, default_value = "default", help = "Keyword set: default, english, or a file of 'keyword = spelling' lines")]
+    keywords: String
+}
+
+#[derive(Args)]
+struct ExplainArgs {
+    #[arg(short = 'O', value_enum, default_value_t = OptLevel::O1, help = "Optimization level")]
+    opt_level: OptLevel,
+    #[arg(long, default_value_t = false, help = "Print through an emitted routine instead of printf")]
+    builtin_print: bool,
+    #[arg(long, default_value_t = false, help = "Abort on integer overflow instead of wrapping")]
+    checked_arith: bool,
+    #[arg(long, value_enum, help = "Platform whose calling convention the assembly follows [default: the one ceya runs on]")]
+    target: Option<Target>,
+    #[arg(long, default_value = "default", help = "Keyword set: default, english, or a file of 'keyword = spelling' lines")]
+    keywords: String,
+    filepath: String
+}
+
+#[derive(Args)]
+struct DumpArgs {
+    #[arg(long, value_enum, default_value_t = Emit::Ast, help = "What to print")]
+    emit: Emit,
+    #[arg(short = 'O', value_enum, default_value_t = OptLevel::O1, help = "Optimization level of the assembly")]
+    opt_level: OptLevel,
+    #[arg(long, default_value = "default", help = "Keyword set: default, english, or a file of 'keyword = spelling' lines")]
+    keywords: String,
+    filepath: String
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Emit {
+    /// One token per line with its position and type.
+    Tokens,
+    /// The statements parsed, nested ones indented, even when others have syntax errors.
+    Ast,
+    /// The assembly of each statement, before the -O2 peephole pass.
+    Asm
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HighlightFormat {
+    /// TextMate grammar (JSON), for VS Code, Sublime Text, ...
+    Tmlanguage,
+    /// Vim syntax file.
+    Vim
+}
+
+fn load_keywords(spec: &str) -> Keywords {
+    match spec {
+        "default" => Keywords::default(),
+        "english" => Keywords::english(),
+        path => {
+            let mut source = String::new();
+            File::open(path).expect("Keyword file not found.").read_to_string(&mut source).expect("Cannot read keyword file.");
+            Keywords::parse(&source).unwrap_or_else(|e| {
+                eprintln!("Invalid keyword file: {}", e);
+                std::process::exit(1);
+            })
+        }
+    }
+}
+
+/// Shows the errors of `source` under their line, colored unless asked not to or not printing to a terminal.
+fn render_errors(source: &str, no_color: bool) {
+    let color = !no_color && std::io::stdout().is_terminal() && std::io::stderr().is_terminal();
+    error::set_renderer(Some(Renderer { source: source.into(), color }));
+}
+
+/// Lists `errors` in source order and exits, if there are any.
+fn exit_on_errors(errors: &[CeyaError]) {
+    if errors.is_empty() {
+        return;
+    }
+    let mut errors = errors.to_vec();
+    errors.sort_by_key(|e| (e.span().start, e.span().column));
+    for e in &errors {
+        eprintln!("{}", error::render(e));
+    }
+    eprintln!("{} error(s), aborting.", errors.len());
+    std::process::exit(1);
 }
 
 fn main() {
@@ -49,103 +244,382 @@ fn main() {
     match &cli.command {
         Commands::Sim(args) => {
             let mut source = String::new();
-            File::open("./test.ceya").expect("file expected").read_to_string(&mut source).expect("string expected");
-            let scanner = Scanner {
-                source,
-                tokens: vec![],
-                start: 0usize,
-                current: 0,
-                line: 0            
-            };
-            let tokens = scanner.scan_tokens();
-            //println!("{:?}", tokens);
+            File::open(&args.filepath).expect("File not found.").read_to_string(&mut source).expect("Cannot read file.");
+            render_errors(&source, args.no_color);
+            let (tokens, mut errors) = scanner::scan_all(&source, load_keywords(&args.keywords));
+            let token_count = tokens.len();
+            let stmts = parser::parse_with_max_depth(tokens, args.max_depth).unwrap_or_else(|parse_errors| {
+                errors.extend(parse_errors);
+                vec![]
+            });
+            exit_on_errors(&errors);
 
             let mut env_arena = EnvironmentArena::new();
             let global_env = env_arena.add(None);
+            stdlib::define_natives(&mut env_arena, global_env);
+            if !args.sandbox {
+                stdlib::define_host_natives(&mut env_arena, global_env);
+            }
+            let mut errors = check::check(&stmts, &env_arena, global_env);
+            errors.extend(typecheck::check(&stmts));
+            exit_on_errors(&errors);
 
-            // env_arena.define(global_env, "clock", Value::Fun(Fun::Native { name: "clock".into(), callee: Rc::new(|_| Value::Number(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as f64)), params: vec![] }));
+            let stmts: Vec<Stmt> = stmts.into_iter().map(Stmt::eliminate_branches).collect();
+            let script = args.vm.then(|| vm::compile(&stmts));
+            match &script {
+                Some(script) => vm::run(Rc::clone(script), &mut env_arena, global_env),
+                None => for stmt in &stmts {
+                    stmt.execute(&mut env_arena, global_env);
+                }
+            }
 
-            let mut parser = parser::Parser {
-                tokens,
-                current: 0usize
-            };
-            // let stmts = parser.parse();
-            // for stmt in stmts {
-            //     stmt.execute(&mut env_arena, global_env);
-            // }
+            if args.stats {
+                eprintln!("environments: {}", env_arena.envs.len());
+                eprintln!("peak values:  {}", env_arena.value_count());
+                eprintln!("string bytes: {}", env_arena.string_bytes());
+                eprintln!("tokens:       {}", token_count);
+                eprintln!("AST nodes:    {}", stmts.iter().map(|stmt| stmt.node_count()).sum::<usize>());
+                if let Some(script) = script {
+                    eprintln!("instructions: {}", script.instruction_count());
+                }
+            }
+            exit_on_errors(&env_arena.errors);
         },
         Commands::Com(args) => {
-            let mut source = String::new();
-            File::open(&args.filepath).expect("File not found.").read_to_string(&mut source).expect("Cannot read file.");
-
-            let scanner = Scanner {
-                source,
-                tokens: vec![],
-                current: 0,
-                line: 0,
-                start: 0
+            let config = Config::load("ceya.toml").unwrap_or_else(|e| {
+                eprintln!("Invalid project configuration: {}", e);
+                std::process::exit(1);
+            });
+            let args = config.apply(args.clone());
+            let Some(filepath) = &args.filepath else {
+                eprintln!("No source file given and no `main` in ceya.toml.");
+                std::process::exit(1);
             };
+            let dialect = args.asm_dialect.unwrap_or_default();
+            let target = args.target.unwrap_or_else(Target::host);
+            let (freestanding, builtin_print, checked_arith, release) = (args.freestanding.unwrap_or(false), args.builtin_print.unwrap_or(false), args.checked_arith.unwrap_or(false), args.release.unwrap_or(false));
+            let build_dir = build_dir(args.out_dir.as_deref().unwrap_or(DEFAULT_OUT_DIR), target, release);
+            std::fs::create_dir_all(&build_dir).expect("Cannot create build directory.");
 
-            print!("Scanning source code... ");
-            let tokens = scanner.scan_tokens();
-            println!("OK");
+            let mut source = String::new();
+            File::open(filepath).expect("File not found.").read_to_string(&mut source).expect("Cannot read file.");
+            render_errors(&source, args.no_color);
 
-            let mut parser = parser::Parser {
-                tokens,
-                current: 0
-            };
+            print!("Scanning source code... ");
+            let (tokens, mut errors) = scanner::scan_all(&source, load_keywords(args.keywords.as_deref().unwrap_or("default")));
+            println!("{}", if errors.is_empty() { "OK" } else { "ERROR!" });
 
             print!("Parsing tokens... ");
-            let stmts = parser.parse();
+            let stmts = parser::parse_spanned(tokens, args.max_depth.unwrap_or(parser::DEFAULT_MAX_DEPTH)).unwrap_or_else(|parse_errors| {
+                println!("ERROR!");
+                errors.extend(parse_errors);
+                vec![]
+            });
+            exit_on_errors(&errors);
             println!("OK");
 
+            let program: Vec<&Stmt> = stmts.iter().map(|(stmt, _)| stmt).collect();
+            print!("Resolving names... ");
+            // compiled programs have no host natives, calls to the others are warned about while generating
+            let mut env_arena = EnvironmentArena::new();
+            let global_env = env_arena.add(None);
+            stdlib::define_natives(&mut env_arena, global_env);
+            let errors = check::check(&program, &env_arena, global_env);
+            println!("{}", if errors.is_empty() { "OK" } else { "ERROR!" });
+            exit_on_errors(&errors);
+
+            print!("Checking types... ");
+            let errors = typecheck::check(&program);
+            println!("{}", if errors.is_empty() { "OK" } else { "ERROR!" });
+            exit_on_errors(&errors);
+
             print!("Generating assembly... ");
-            let mut file = File::create("output.asm").expect("Cannot create file.");
-
-            writeln!(&mut file, "bits 64").unwrap();
-            writeln!(&mut file, "default rel\n").unwrap();
-            writeln!(&mut file, "segment .data").unwrap();
-            writeln!(&mut file, "   msg db \"%d\", 0xd, 0xa, 0\n").unwrap();
-            writeln!(&mut file, "segment .text\n").unwrap();
-            writeln!(&mut file, "global main\n").unwrap();
-            writeln!(&mut file, "extern ExitProcess").unwrap();
-            writeln!(&mut file, "extern printf\n").unwrap();
-            writeln!(&mut file, "main:").unwrap();
-            writeln!(&mut file, "   push rbp").unwrap();
-            writeln!(&mut file, "   mov rbp, rsp").unwrap();
-            writeln!(&mut file, "   sub rsp, 32").unwrap();
+            let mut asm = String::new();
 
-            for stmt in stmts {
-                write!(&mut file, "{}", stmt.compile()).unwrap();
+            writeln!(&mut asm, "bits 64").unwrap();
+            writeln!(&mut asm, "default rel\n").unwrap();
+            let compiler = Compiler::new(CompileOptions {
+                checked_arith,
+                comments: args.asm_comments.unwrap_or(if release { AsmComments::Off } else { AsmComments::Full }),
+                werror: args.werror,
+                opt_level: args.opt_level.unwrap_or(if release { OptLevel::O2 } else { OptLevel::O1 }),
+                target,
+                builtin_print: builtin_print || freestanding
+            });
+            let mut main = String::new();
+            let mut functions = String::new();
+            let mut listing = String::new();
+            let source_lines: Vec<&str> = source.lines().collect();
+            let mut listed = 0;
+            for (stmt, lines) in stmts {
+                let code = compiler.compile(&stmt);
+                let procedures = compiler.functions.take();
+                if args.listing {
+                    for line in listed.max(*lines.start() as usize)..=*lines.end() as usize {
+                        writeln!(&mut listing, ";; {:>4} | {}", line, source_lines.get(line).unwrap_or(&"")).unwrap();
+                    }
+                    listed = *lines.end() as usize + 1;
+                    write!(&mut listing, "{}{}", code, procedures).unwrap();
+                }
+                write!(&mut main, "{}", code).unwrap();
+                write!(&mut functions, "{}", procedures).unwrap();
+            }
+            if args.listing {
+                File::create(build_dir.join("output.ceya.lst")).expect("Cannot create file.").write_all(listing.as_bytes()).unwrap();
             }
+            if compiler.errors.get() > 0 {
+                println!("ERROR!");
+                eprintln!("{} error(s) in the code to compile.", compiler.errors.get());
+                std::process::exit(1);
+            }
+            if compiler.options.werror && compiler.warnings.get() > 0 {
+                println!("ERROR!");
+                eprintln!("{} warning(s) treated as errors.", compiler.warnings.get());
+                std::process::exit(1);
+            }
+
+            writeln!(&mut main).unwrap();
+            write!(&mut main, "{}", emit_exit(target, "0", !freestanding)).unwrap();
+            if compiler.options.opt_level.peephole() {
+                main = peephole(&main);
+            }
+
+            let mut data = compiler.data.take();
+            if data.labels().contains(&STACK_BASE) {
+                main.insert_str(0, &format!("   mov [{}], rsp\n", STACK_BASE));
+            }
+            let runtime = Runtime {
+                target,
+                crt: !freestanding,
+                builtin_print: compiler.options.builtin_print,
+                checked_arith,
+                stderr: compiler.stderr.get(),
+                input: compiler.input.get(),
+                alloc: compiler.alloc.get()
+            };
+            let externs = runtime.externs();
+            let runtime = runtime.emit(&mut data);
+
+            if args.emit_map {
+                let mut map = String::new();
+                writeln!(&mut map, "{:<24} line", "; label").unwrap();
+                let runtime_labels = runtime.lines().filter_map(|line| line.strip_suffix(':')).map(str::to_string);
+                let generated = compiler.symbols.take().into_iter()
+                    .map(|(label, line)| (label, line.to_string()));
+                let symbols = std::iter::once("main".to_string())
+                    .chain(runtime_labels)
+                    .chain(data.labels().into_iter().map(str::to_string))
+                    .map(|label| (label, "(runtime)".to_string()))
+                    .chain(generated);
+                for (label, line) in symbols {
+                    writeln!(&mut map, "{:<24} {}", label, line).unwrap();
+                }
+                File::create(build_dir.join("output.map")).expect("Cannot create file.").write_all(map.as_bytes()).unwrap();
+            }
+
+            writeln!(&mut asm, "{}", data.emit()).unwrap();
+            writeln!(&mut asm, "segment .text\n").unwrap();
+            writeln!(&mut asm, "global main\n").unwrap();
+            for symbol in externs {
+                writeln!(&mut asm, "extern {}", symbol).unwrap();
+            }
+            writeln!(&mut asm).unwrap();
+            writeln!(&mut asm, "{}", emit_function("main", &main)).unwrap();
+            write!(&mut asm, "{}", functions).unwrap();
+            write!(&mut asm, "{}", runtime).unwrap();
 
-            writeln!(&mut file, "\n   lea rcx, [msg]").unwrap();
-            writeln!(&mut file, "   pop rdx").unwrap();
-            writeln!(&mut file, "   call printf\n").unwrap();
-            writeln!(&mut file, "   xor rcx, rcx").unwrap();
-            writeln!(&mut file, "   call ExitProcess").unwrap();
+            let output_path = build_dir.join(format!("output.{}", dialect.extension()));
+            let mut file = File::create(&output_path).expect("Cannot create file.");
+            write!(&mut file, "{}", dialect.translate(&asm, target)).unwrap();
 
             println!("OK");
 
+            if dialect != Dialect::Nasm {
+                println!("Wrote {}, assemble it with your {} toolchain.", output_path.display(), dialect.to_possible_value().unwrap().get_name());
+                return;
+            }
+
+
             print!("Assembling program... ");
-            let output = std::process::Command::new(".\\build.bat")
-                                  .arg("release")
-                                  .arg("output")
-                                  .output().unwrap();
-            // io::stdout().write_all(&output.stdout).unwrap();
-            if output.status.success() {
+            let build = Build {
+                dir: &build_dir,
+                target,
+                release,
+                freestanding,
+                subsystem: args.subsystem.unwrap_or_default(),
+                assembler: args.assembler.unwrap_or_default(),
+                linker: args.linker.unwrap_or(Linker::default_for(target)),
+                vcvars: args.vcvars.as_deref()
+            };
+            if build.run() {
                 println!("OK");
             } else {
                 println!("ERROR!");
+                std::process::exit(1);
             }
 
             if args.run {
                 println!("Running program");
-                std::process::Command::new(".\\msbuild\\output.exe")
+                std::process::Command::new(build_dir.join(target.executable("output")))
                                       .stdout(Stdio::inherit())
                                       .output()
                                       .unwrap();
             }
+        },
+        Commands::Check(args) => {
+            let mut source = String::new();
+            File::open(&args.filepath).expect("File not found.").read_to_string(&mut source).expect("Cannot read file.");
+            render_errors(&source, args.no_color);
+
+            let (tokens, mut errors) = scanner::scan_all(&source, load_keywords(&args.keywords));
+            let stmts = parser::parse_with_max_depth(tokens, args.max_depth).unwrap_or_else(|parse_errors| {
+                errors.extend(parse_errors);
+                vec![]
+            });
+            // a program missing statements would have names looking undefined
+            exit_on_errors(&errors);
+
+            let mut env_arena = EnvironmentArena::new();
+            let global_env = env_arena.add(None);
+            stdlib::define_natives(&mut env_arena, global_env);
+            if !args.sandbox {
+                stdlib::define_host_natives(&mut env_arena, global_env);
+            }
+            let mut errors = check::check(&stmts, &env_arena, global_env);
+            errors.extend(typecheck::check(&stmts));
+            exit_on_errors(&errors);
+        },
+        Commands::Difftest(args) => {
+            let exe = std::env::current_exe().expect("Cannot locate ceya executable.");
+
+            let sim = std::process::Command::new(&exe).arg("sim").arg(&args.filepath).output().expect("Cannot run interpreter.");
+
+            // pinned so the executable lands where we look for it, whatever ceya.toml says
+            let target = Target::host();
+            let com = std::process::Command::new(&exe).arg("com").arg("--asm-dialect").arg("nasm").arg("--out-dir").arg(DEFAULT_OUT_DIR)
+                .arg("--target").arg(target.to_possible_value().unwrap().get_name()).arg("--release=false")
+                .arg(&args.filepath).output().expect("Cannot run compiler.");
+            if !com.status.success() {
+                eprintln!("Compilation failed:\n{}{}", String::from_utf8_lossy(&com.stdout), String::from_utf8_lossy(&com.stderr));
+                std::process::exit(1);
+            }
+            let bin = std::process::Command::new(build_dir(DEFAULT_OUT_DIR, target, false).join(target.executable("output"))).output().expect("Cannot run compiled program.");
+
+            let mut matching = report_mismatches(&String::from_utf8_lossy(&sim.stdout), &String::from_utf8_lossy(&bin.stdout));
+            if sim.status.code() != bin.status.code() {
+                println!("exit code: sim {:?} | com {:?}", sim.status.code(), bin.status.code());
+                matching = false;
+            }
+
+            if !matching {
+                std::process::exit(1);
+            }
+            println!("OK: sim and com agree.");
+        },
+        Commands::Fmt(args) => {
+            let mut source = String::new();
+            File::open(&args.filepath).expect("File not found.").read_to_string(&mut source).expect("Cannot read file.");
+
+            let (tokens, mut errors) = scanner::scan_all(&source, Keywords::default());
+            let stmts = parser::parse(tokens).unwrap_or_else(|parse_errors| {
+                errors.extend(parse_errors);
+                vec![]
+            });
+            // printing the valid statements alone would drop the broken ones from the file
+            exit_on_errors(&errors);
+            for stmt in stmts {
+                println!("{}", stmt.fmt_source(0));
+            }
+        },
+        Commands::New(args) => {
+            if let Err(e) = config::create_project(Path::new(&args.path)) {
+                eprintln!("Cannot create project {}: {}", args.path, e);
+                std::process::exit(1);
+            }
+            println!("Created {}, build it with `ceya com` from inside.", args.path);
+        },
+        Commands::Highlight(args) => {
+            let keywords = load_keywords(&args.keywords);
+            match args.format {
+                HighlightFormat::Tmlanguage => print!("{}", highlight::tmlanguage(&keywords)),
+                HighlightFormat::Vim => print!("{}", highlight::vim(&keywords))
+            }
+        },
+        Commands::Explain(args) => {
+            let mut source = String::new();
+            File::open(&args.filepath).expect("File not found.").read_to_string(&mut source).expect("Cannot read file.");
+
+            let (tokens, mut errors) = scanner::scan_all(&source, load_keywords(&args.keywords));
+            let options = CompileOptions {
+                opt_level: args.opt_level,
+                builtin_print: args.builtin_print,
+                checked_arith: args.checked_arith,
+                target: args.target.unwrap_or_else(Target::host),
+                ..Default::default()
+            };
+            match explain::explain(tokens, &source, options, parser::DEFAULT_MAX_DEPTH) {
+                Ok(res) if errors.is_empty() => print!("{}", res),
+                Ok(_) => exit_on_errors(&errors),
+                Err(parse_errors) => {
+                    errors.extend(parse_errors);
+                    exit_on_errors(&errors);
+                }
+            }
+        }
+        Commands::Dump(args) => {
+            let mut source = String::new();
+            File::open(&args.filepath).expect("File not found.").read_to_string(&mut source).expect("Cannot read file.");
+
+            let (tokens, mut errors) = scanner::scan_all(&source, load_keywords(&args.keywords));
+            match args.emit {
+                Emit::Tokens => {
+                    for token in &tokens {
+                        println!("{:>4}:{:<4} {:<16} {}", token.span.start, token.span.column, token.typ, token.lexeme);
+                    }
+                    exit_on_errors(&errors);
+                },
+                Emit::Ast => {
+                    let mut parser = parser::Parser::new(tokens, parser::DEFAULT_MAX_DEPTH);
+                    for (stmt, _) in parser.parse_spanned() {
+                        println!("{}", stmt.fmt_tree(0));
+                    }
+                    errors.extend(parser.errors);
+                    exit_on_errors(&errors);
+                },
+                Emit::Asm => {
+                    let stmts = parser::parse(tokens).unwrap_or_else(|parse_errors| {
+                        errors.extend(parse_errors);
+                        vec![]
+                    });
+                    exit_on_errors(&errors);
+                    let compiler = Compiler::new(CompileOptions { opt_level: args.opt_level, ..Default::default() });
+                    for stmt in &stmts {
+                        print!("{}{}", compiler.compile(stmt), compiler.functions.take());
+                    }
+                }
+            }
         }
     }
 }
+
+/// Where the artifacts of a build go, kept apart per target and profile so switching between them doesn't mix objects.
+fn build_dir(out_dir: &str, target: Target, release: bool) -> PathBuf {
+    Path::new(out_dir).join(target.to_possible_value().unwrap().get_name()).join(if release { "release" } else { "debug" })
+}
+
+/// Prints every line where the two outputs differ, returns whether they were identical.
+fn report_mismatches(sim: &str, com: &str) -> bool {
+    let sim: Vec<&str> = sim.lines().collect();
+    let com: Vec<&str> = com.lines().collect();
+    let mut matching = true;
+
+    for i in 0..sim.len().max(com.len()) {
+        let (s, c) = (sim.get(i), com.get(i));
+        if s != c {
+            println!("line {}: sim {:?} | com {:?}", i + 1, s.unwrap_or(&"<missing>"), c.unwrap_or(&"<missing>"));
+            matching = false;
+        }
+    }
+
+    matching
+}
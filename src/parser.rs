@@ -1,100 +1,271 @@
-use std::{io::{Error, ErrorKind}, rc::Rc};
+use std::{ops::RangeInclusive, rc::Rc};
 
-use crate::{scanner::{Token, TokenType}, ast::{Expr, Value, Stmt}, error};
+use crate::{scanner::{Span, Token, TokenType}, ast::{self, Expr, Value, Stmt}, error::CeyaError, typecheck::Type};
+
+/// Deepest nesting of statements/expressions accepted before bailing out, keeps hostile input from overflowing the stack.
+/// Operator and call chains count too since `1 + 1 + ...` builds a tree as deep as it is long, and
+/// evaluation, codegen and drop all recurse over it.
+pub const DEFAULT_MAX_DEPTH: usize = 200;
+
+/// Parses a token stream, giving all the syntax errors if it has any. Never panics, whatever the input.
+pub fn parse(tokens: Vec<Rc<Token>>) -> Result<Vec<Stmt>, Vec<CeyaError>> {
+    parse_with_max_depth(tokens, DEFAULT_MAX_DEPTH)
+}
+
+pub fn parse_with_max_depth(tokens: Vec<Rc<Token>>, max_depth: usize) -> Result<Vec<Stmt>, Vec<CeyaError>> {
+    parse_spanned(tokens, max_depth).map(|stmts| stmts.into_iter().map(|(stmt, _)| stmt).collect())
+}
+
+/// Like `parse_with_max_depth`, also giving the source lines each top-level statement spans.
+pub fn parse_spanned(tokens: Vec<Rc<Token>>, max_depth: usize) -> Result<Vec<(Stmt, RangeInclusive<u32>)>, Vec<CeyaError>> {
+    let mut parser = Parser::new(tokens, max_depth);
+    let stmts = parser.parse_spanned();
+    match parser.errors.is_empty() {
+        true => Ok(stmts),
+        false => Err(parser.errors)
+    }
+}
+
+/// Parses a lone expression making up the whole token stream, for embedders evaluating snippets.
+pub fn parse_expression(tokens: Vec<Rc<Token>>) -> Result<Expr, CeyaError> {
+    let mut parser = Parser::new(tokens, DEFAULT_MAX_DEPTH);
+    let expr = parser.expression()?;
+    if !parser.is_at_end() {
+        return Err(parser.error(parser.peek(), "Expect end of expression."));
+    }
+    Ok(expr)
+}
+
+fn terminated(mut tokens: Vec<Rc<Token>>) -> Vec<Rc<Token>> {
+    if !tokens.last().is_some_and(|token| token.typ == TokenType::EOF) {
+        let line = tokens.last().map_or(0, |token| token.span.end);
+        tokens.push(Rc::new(Token::new("", line, TokenType::EOF)));
+    }
+    tokens
+}
 
 pub struct Parser {
     pub tokens: Vec<Rc<Token>>,
-    pub current: usize
+    pub current: usize,
+    pub depth: usize,
+    pub max_depth: usize,
+    /// Number of function bodies being parsed, `return` is only valid inside one.
+    pub functions: usize,
+    /// Number of loop bodies being parsed in the current function, for `break` and `continue`.
+    pub loops: usize,
+    /// Syntax errors met so far. Parsing goes on after each one, so a run reports them all.
+    pub errors: Vec<CeyaError>
 }
 
 impl Parser {
-    pub fn parse(&mut self) -> Vec<Stmt> {
-        let mut statements: Vec<Stmt> = vec![];
+    /// A parser of `tokens`, which get an EOF token if they don't end with one.
+    pub fn new(tokens: Vec<Rc<Token>>, max_depth: usize) -> Parser {
+        Parser { tokens: terminated(tokens), current: 0, depth: 0, max_depth, functions: 0, loops: 0, errors: vec![] }
+    }
+
+    /// Parses the valid statements, recording the errors of the others in `errors`.
+    pub fn parse_spanned(&mut self) -> Vec<(Stmt, RangeInclusive<u32>)> {
+        let mut statements: Vec<(Stmt, RangeInclusive<u32>)> = vec![];
 
         while !self.is_at_end() {
-            let statement = match self.statement() { // TODO: declaration()
+            let statement = match self.declaration() {
                 Ok(statement) => statement,
                 Err(e) => { 
-                    println!("Error occured while parsing: {}", e);
+                    self.errors.push(e);
                     self.synchronise();
                     continue; 
             } 
             };
-            statements.push(statement)
+            let span = statement.span();
+            statements.push((statement, span.start..=span.end))
         }
 
         statements
     }
 
-    // fn declaration(&mut self) -> Result<Stmt, Error> {
-    //     match self.peek().typ {
-    //         TokenType::Let => {
-    //             self.advance();
-    //             self.var_declaration()
-    //         },
-    //         TokenType::Fn => {
-    //             self.advance();
-    //             self.function()
-    //         }
-    //         _ => self.statement()
-    //     }
-    // }
-
-    // fn function(&mut self) -> Result<Stmt, Error> {
-    //     let name = Rc::clone(self.consume(TokenType::Identifier, "Expect function name.")?);
-    //     self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
-    //     let mut params = vec![];
-    //     match self.peek().typ {
-    //         TokenType::RightParen => (),
-    //         _ => {
-    //             loop {
-    //                 params.push(Rc::clone(self.consume(TokenType::Identifier, "Expect parameter name.")?));
-
-    //                 match self.peek().typ {
-    //                     TokenType::Comma => {
-    //                         self.advance();
-    //                     },
-    //                     _ => break
-    //                 }
-    //             }
-    //         }
-    //     }
-    //     self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
-
-    //     self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
-    //     let body = self.block()?;
-
-    //     Ok(Stmt::Fun { name, params, body: Rc::new(Stmt::Block { statements: body }) })
-    // }
-
-    // fn var_declaration(&mut self) -> Result<Stmt, Error> {
-    //     let name = Rc::clone(self.consume(TokenType::Identifier, "Expect variable name.")?);
-
-    //     let initializer = match self.peek().typ {
-    //         TokenType::Equal => {
-    //             self.advance();
-    //             self.expression()
-    //         }
-    //         _ => Ok(Expr::Literal { value: Value::Null })
-    //     };
-
-    //     if let Err(e) = initializer {
-    //         return Err(e);
-    //     }
-
-    //     self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.")?;
-    //     Ok(Stmt::Let { name, initializer: Box::new(initializer.unwrap()) })
-    // }
-
-    fn statement(&mut self) -> Result<Stmt, Error> {
+    fn declaration(&mut self) -> Result<Stmt, CeyaError> {
+        match self.peek().typ {
+            TokenType::Let => {
+                self.advance();
+                self.var_declaration()
+            },
+            TokenType::Fn => {
+                self.advance();
+                self.function()
+            },
+            TokenType::Struct => {
+                self.advance();
+                self.struct_declaration()
+            },
+            _ => self.statement()
+        }
+    }
+
+    fn function(&mut self) -> Result<Stmt, CeyaError> {
+        let start = self.previous().span();
+        let name = Rc::clone(self.consume(TokenType::Identifier, "Expect function name.")?);
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
+        let mut params: Vec<Rc<Token>> = vec![];
+        let mut types = vec![];
+        match self.peek().typ {
+            TokenType::RightParen => (),
+            _ => {
+                loop {
+                    let param = Rc::clone(self.consume(TokenType::Identifier, "Expect parameter name.")?);
+                    if params.iter().any(|other| other.lexeme == param.lexeme) {
+                        return Err(self.error(&param, "Duplicate parameter name."));
+                    }
+                    params.push(param);
+                    types.push(self.annotation()?);
+
+                    match self.peek().typ {
+                        TokenType::Comma => {
+                            self.advance();
+                        },
+                        _ => break
+                    }
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+        let returns = self.annotation()?;
+
+        let body_start = self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?.span();
+        // a loop around the declaration can't be left from inside the function
+        let loops = std::mem::take(&mut self.loops);
+        self.functions += 1;
+        let body = self.block();
+        self.functions -= 1;
+        self.loops = loops;
+        let body = Stmt::Block { statements: body?, span: self.since(body_start) };
+
+        Ok(Stmt::Fun { name, params, types, returns, body: Rc::new(body), span: self.since(start) })
+    }
+
+    /// The type after a `:`, if there is one.
+    fn annotation(&mut self) -> Result<Option<Type>, CeyaError> {
+        if !self.check(TokenType::Colon) {
+            return Ok(None);
+        }
+        self.advance();
+        let name = Rc::clone(self.consume(TokenType::Identifier, "Expect type after ':'.")?);
+        match Type::from_name(&name.lexeme) {
+            Some(typ) => Ok(Some(typ)),
+            None => Err(self.error(&name, "Unknown type, expect num, str, bool, list, map or any."))
+        }
+    }
+
+    /// `struct Name { field, other }`, a trailing comma allowed, then the operator methods.
+    fn struct_declaration(&mut self) -> Result<Stmt, CeyaError> {
+        let start = self.previous().span();
+        let name = Rc::clone(self.consume(TokenType::Identifier, "Expect struct name.")?);
+        self.consume(TokenType::LeftBrace, "Expect '{' after struct name.")?;
+        let mut fields: Vec<Rc<Token>> = vec![];
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Fn) {
+            let field = Rc::clone(self.consume(TokenType::Identifier, "Expect field name.")?);
+            if fields.iter().any(|other| other.lexeme == field.lexeme) {
+                return Err(self.error(&field, "Duplicate field name."));
+            }
+            fields.push(field);
+            if !self.check(TokenType::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        let mut methods = vec![];
+        while self.check(TokenType::Fn) {
+            self.advance();
+            let method = self.function()?;
+            let Stmt::Fun { name, params, .. } = &method else { unreachable!("a function") };
+            if !ast::OPERATOR_METHODS.iter().any(|(_, operator)| *operator == name.lexeme) {
+                return Err(self.error(name, "Structs only declare operator methods, like __add__ or __eq__."));
+            }
+            if params.len() != 2 {
+                return Err(self.error(name, "Operator methods take two parameters, the operands."));
+            }
+            methods.push(method);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after struct fields.")?;
+
+        Ok(Stmt::Struct { name, fields, methods, span: self.since(start) })
+    }
+
+    fn var_declaration(&mut self) -> Result<Stmt, CeyaError> {
+        let start = self.previous().span();
+        if self.check(TokenType::LeftParen) {
+            return self.unpack_declaration(start);
+        }
+        let name = Rc::clone(self.consume(TokenType::Identifier, "Expect variable name.")?);
+        let annotation = self.annotation()?;
+
+        let initializer = match self.peek().typ {
+            TokenType::Equal => {
+                self.advance();
+                self.expression()?
+            }
+            _ => Expr::Literal { value: Value::Null, span: name.span() }
+        };
+
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.")?;
+        Ok(Stmt::Let { name, annotation, initializer: Box::new(initializer), span: self.since(start) })
+    }
+
+    /// `let (a, b) = f();`, unpacking the values a function returns together.
+    fn unpack_declaration(&mut self, start: Span) -> Result<Stmt, CeyaError> {
+        self.advance();
+        let mut names = vec![Rc::clone(self.consume(TokenType::Identifier, "Expect variable name.")?)];
+        while self.check(TokenType::Comma) {
+            self.advance();
+            names.push(Rc::clone(self.consume(TokenType::Identifier, "Expect variable name.")?));
+        }
+        let paren = Rc::clone(self.consume(TokenType::RightParen, "Expect ')' after the names to unpack.")?);
+        if names.len() < 2 {
+            return Err(self.error(&paren, "Expect at least two names to unpack."));
+        }
+
+        self.consume(TokenType::Equal, "Expect '=' after the names to unpack.")?;
+        let initializer = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.")?;
+        Ok(Stmt::Unpack { names, initializer: Box::new(initializer), span: self.since(start) })
+    }
+
+    /// Runs `rule` one nesting level deeper, failing once `max_depth` is reached.
+    fn nested<T>(&mut self, rule: impl FnOnce(&mut Self) -> Result<T, CeyaError>) -> Result<T, CeyaError> {
+        let depth = self.depth;
+        self.deeper()?;
+        let res = rule(self);
+        // also drops the levels taken by chains inside `rule`
+        self.depth = depth;
+        res
+    }
+
+    /// Takes one nesting level for the rest of the current rule, `nested` gives it back.
+    fn deeper(&mut self) -> Result<(), CeyaError> {
+        if self.depth >= self.max_depth {
+            return Err(self.error(self.peek(), "Expression too deeply nested, raise --max-depth to accept it."));
+        }
+
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<Stmt, CeyaError> {
+        self.nested(Self::statement_inner)
+    }
+
+    fn statement_inner(&mut self) -> Result<Stmt, CeyaError> {
         match self.peek().typ {
             TokenType::Print => {
                 self.advance();    
-                self.print_statement()
+                self.print_statement(false)
             },
-            TokenType::LeftBrace => {
+            TokenType::Eprint => {
                 self.advance();
-                Ok(Stmt::Block { statements: self.block()? })
+                self.print_statement(true)
+            },
+            TokenType::LeftBrace => {
+                let start = self.advance().span();
+                Ok(Stmt::Block { statements: self.block()?, span: self.since(start) })
             },
             TokenType::If => {
                 self.advance();
@@ -111,388 +282,454 @@ impl Parser {
             TokenType::Ke => {
                 self.advance();
                 self.ke_statement()
-            }
-            // TokenType::For => {
-            //     self.advance();
-            //     self.for_statement()
-            // },
-            // TokenType::Return => {
-            //     self.advance();
-            //     self.return_statement()
-            // }
+            },
+            TokenType::Swap | TokenType::Over | TokenType::Rot | TokenType::Drop2 => {
+                self.advance();
+                self.stack_statement()
+            },
+            TokenType::For => {
+                self.advance();
+                self.for_statement()
+            },
+            TokenType::Return => {
+                self.advance();
+                self.return_statement()
+            },
+            TokenType::Break | TokenType::Continue => {
+                self.advance();
+                self.jump_statement()
+            },
             _ => self.expression_statement()
         }
     }
 
-    // fn return_statement(&mut self) -> Result<Stmt, Error> {
-    //     let keyword = Rc::clone(self.previous());
-
-    //     let expr = match self.peek().typ {
-    //         TokenType::Semicolon => {
-    //             Expr::Literal { value: Value::Null }
-    //         },
-    //         _ => self.expression()?
-    //     };
-
-    //     self.consume(TokenType::Semicolon, "Expect ';' after return statement.")?;
-    //     Ok(Stmt::Return { keyword, value: Box::new(expr) })
-    // }
-
-    // fn for_statement(&mut self) -> Result<Stmt, Error> {
-    //     self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
-    //     let initializer = match self.peek().typ {
-    //         TokenType::Semicolon => {
-    //             self.advance();
-    //             None
-    //         },
-    //         TokenType::Let => {
-    //             self.advance();
-    //             Some(self.var_declaration()?)
-    //         },
-    //         _ => {
-    //             Some(self.expression_statement()?)
-    //         }
-    //     };
-    //     let mut condition = match self.peek().typ {
-    //         TokenType::Semicolon => {
-    //             None
-    //         },
-    //         _ => {
-    //             Some(self.expression()?)
-    //         }
-    //     };
-    //     self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
-    //     let increment = match self.peek().typ {
-    //         TokenType::RightParen => {
-    //             None
-    //         },
-    //         _ => {
-    //             Some(self.expression()?)
-    //         }
-    //     };
-    //     self.consume(TokenType::RightParen, "Expect ')' after for loop.")?;
-        
-    //     let mut body = self.statement()?;
-    //     if let Some(inc) = increment {
-    //         body = Stmt::Block { statements: vec![body, Stmt::Expression { expression: Box::new(inc) }] };
-    //     }
-    //     if condition.is_none() {
-    //         condition = Some(Expr::Literal { value: Value::Boolean(true) });
-    //     }
-    //     body = Stmt::While { condition: Box::new(condition.unwrap()), body: Box::new(body) };
-    //     if let Some(init) = initializer {
-    //         body = Stmt::Block { statements: vec![init, body] };
-    //     }
-
-    //     Ok(body)
-    // }
-
-    fn while_statement(&mut self) -> Result<Stmt, Error> {
+    fn return_statement(&mut self) -> Result<Stmt, CeyaError> {
+        let keyword = Rc::clone(self.previous());
+        if self.functions == 0 {
+            return Err(self.error(&keyword, "Can't return from top-level code."));
+        }
+
+        let mut expr = match self.peek().typ {
+            TokenType::Semicolon => Expr::Literal { value: Value::Null, span: keyword.span() },
+            _ => self.expression()?
+        };
+        // `return a, b;` gives both values together
+        if self.check(TokenType::Comma) {
+            let start = expr.span();
+            let mut elements = vec![expr];
+            while self.check(TokenType::Comma) {
+                self.advance();
+                elements.push(self.expression()?);
+            }
+            expr = Expr::Tuple { elements, span: self.since(start) };
+        }
+
+        self.consume(TokenType::Semicolon, "Expect ';' after return statement.")?;
+        Ok(Stmt::Return { span: self.since(keyword.span()), keyword, value: Box::new(expr) })
+    }
+
+    /// Desugars `for (init; condition; increment) body` to a `while` loop carrying the increment, in a
+    /// block scoping `init`. The value of an expression initializer is dropped like the increment's, so
+    /// the loop leaves the operand stack as it found it.
+    fn for_statement(&mut self) -> Result<Stmt, CeyaError> {
+        let start = self.previous().span();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+        let initializer = match self.peek().typ {
+            TokenType::Semicolon => {
+                self.advance();
+                None
+            },
+            TokenType::Let => {
+                self.advance();
+                Some(self.var_declaration()?)
+            },
+            _ => {
+                let init = self.expression_statement()?;
+                let span = init.span();
+                Some(Stmt::Block { statements: vec![init, Stmt::Faran { span }], span })
+            }
+        };
+        let condition = match self.peek().typ {
+            TokenType::Semicolon => Expr::Literal { value: Value::Boolean(true), span: self.peek().span() },
+            _ => self.expression()?
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
+        let increment = match self.peek().typ {
+            TokenType::RightParen => None,
+            _ => Some(self.expression()?)
+        };
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+        let body = self.loop_body()?;
+        let mut body = Stmt::While { condition: Box::new(condition), body: Box::new(body), increment: increment.map(Box::new), span: self.since(start) };
+        if let Some(initializer) = initializer {
+            body = Stmt::Block { statements: vec![initializer, body], span: self.since(start) };
+        }
+
+        Ok(body)
+    }
+
+    fn while_statement(&mut self) -> Result<Stmt, CeyaError> {
+        let start = self.previous().span();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
-        let condition = self.expression().expect("expression expected");
+        let condition = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
 
-        let body = self.statement().expect("statement expected");
-        Ok(Stmt::While { condition: Box::new(condition), body: Box::new(body) })
+        let body = self.loop_body()?;
+        Ok(Stmt::While { condition: Box::new(condition), body: Box::new(body), increment: None, span: self.since(start) })
     }
 
-    fn if_statement(&mut self) -> Result<Stmt, Error> {
+    fn loop_body(&mut self) -> Result<Stmt, CeyaError> {
+        self.loops += 1;
+        let body = self.statement();
+        self.loops -= 1;
+        body
+    }
+
+    /// `break` or `continue`, whichever `previous()` is.
+    fn jump_statement(&mut self) -> Result<Stmt, CeyaError> {
+        let keyword = Rc::clone(self.previous());
+        if self.loops == 0 {
+            return Err(self.error(&keyword, &format!("Can't {} outside a loop.", keyword.lexeme)));
+        }
+
+        self.consume(TokenType::Semicolon, &format!("Expect ';' after '{}'.", keyword.lexeme))?;
+        Ok(match keyword.typ {
+            TokenType::Break => Stmt::Break { span: self.since(keyword.span()) },
+            _ => Stmt::Continue { span: self.since(keyword.span()) }
+        })
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, CeyaError> {
+        let start = self.previous().span();
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
-        let condition = self.expression().expect("expression expected");
+        let condition = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
 
-        let then = self.statement().expect("statement expected");
+        let then = self.statement()?;
         match self.peek().typ {
             TokenType::Else => {
                 self.advance();
-                let els = self.statement().expect("statement expected");
-                Ok(Stmt::If { condition: Box::new(condition), then: Box::new(then), els: Some(Box::new(els)) })
+                let els = self.statement()?;
+                Ok(Stmt::If { condition: Box::new(condition), then: Box::new(then), els: Some(Box::new(els)), span: self.since(start) })
         },
-            _ => Ok(Stmt::If { condition: Box::new(condition), then: Box::new(then), els: None })
+            _ => Ok(Stmt::If { condition: Box::new(condition), then: Box::new(then), els: None, span: self.since(start) })
         }
     }
 
-    fn print_statement(&mut self) -> Result<Stmt, Error> {
-        let value = self.expression().expect("expression expected");
+    fn print_statement(&mut self, stderr: bool) -> Result<Stmt, CeyaError> {
+        let start = self.previous().span();
+        let value = self.expression()?;
+        let mut arguments: Vec<Expr> = vec![];
+        while self.check(TokenType::Comma) {
+            self.advance();
+            arguments.push(self.expression()?);
+        }
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-        Ok(Stmt::Print { expression: Box::new(value) })
+        Ok(Stmt::Print { expression: Box::new(value), arguments, stderr, span: self.since(start) })
     }
 
-    fn faran_statement(&mut self) -> Result<Stmt, Error> {
+    fn faran_statement(&mut self) -> Result<Stmt, CeyaError> {
+        let start = self.previous().span();
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-        Ok(Stmt::Faran)
+        Ok(Stmt::Faran { span: self.since(start) })
     }
 
-    fn ke_statement(&mut self) -> Result<Stmt, Error> {
+    fn ke_statement(&mut self) -> Result<Stmt, CeyaError> {
+        let start = self.previous().span();
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-        Ok(Stmt::Ke)
+        Ok(Stmt::Ke { span: self.since(start) })
+    }
+
+    /// `swap`, `over`, `rot` and `drop2`, which only rearrange the stack.
+    fn stack_statement(&mut self) -> Result<Stmt, CeyaError> {
+        let word = Rc::clone(self.previous());
+        self.consume(TokenType::Semicolon, &format!("Expect ';' after '{}'.", word.lexeme))?;
+        let span = self.since(word.span());
+        Ok(match word.typ {
+            TokenType::Swap => Stmt::Swap { span },
+            TokenType::Over => Stmt::Over { span },
+            TokenType::Rot => Stmt::Rot { span },
+            _ => Stmt::Drop2 { span }
+        })
     }
 
-    fn block(&mut self) -> Result<Vec<Stmt>, Error> {
+    fn block(&mut self) -> Result<Vec<Stmt>, CeyaError> {
         let mut statements: Vec<Stmt> = vec![];
 
-        while !match self.peek().typ {
-            TokenType::RightBrace => true,
-            _ => false
-        } && !self.is_at_end() {
-            let stmt = match self.statement() { // TODO: declaration()
-                Ok(stmt) => stmt,
-                Err(e) => return Err(e)
-            };
-            statements.push(stmt);
+        while !matches!(self.peek().typ, TokenType::RightBrace) && !self.is_at_end() {
+            match self.declaration() {
+                Ok(stmt) => statements.push(stmt),
+                // record and carry on with the rest of the block, so one typo doesn't hide the errors after it
+                Err(e) => {
+                    self.errors.push(e);
+                    // a statement cut short by the closing brace, e.g. a missing ';', has nothing left to skip
+                    if !self.check(TokenType::RightBrace) {
+                        self.synchronise();
+                    }
+                }
+            }
         }
 
         self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
         Ok(statements)
     }
 
-    fn expression_statement(&mut self) -> Result<Stmt, Error> {
-        let expr = self.expression().expect("expression expected");
+    fn expression_statement(&mut self) -> Result<Stmt, CeyaError> {
+        let expr = self.expression()?;
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-        Ok(Stmt::Expression { expression: Box::new(expr) })
-    }
-
-    fn expression(&mut self) -> Result<Expr, Error> {
-        self.equality()
-    }
-
-    // fn assignement(&mut self) -> Result<Expr, Error> {
-    //     let expr = self.or();
-
-    //     if match self.peek().typ {
-    //         TokenType::Equal => {
-    //             self.advance();
-    //             true
-    //         },
-    //         _ => false
-    //     } {
-    //         let equals = Rc::clone(self.previous());
-    //         let value = self.assignement();
-
-    //         if let Err(e) = value {
-    //             return Err(e);
-    //         }
-
-    //         return match expr {
-    //             Ok(Expr::Variable { ref name }) => Ok(Expr::Assign { name: Rc::clone(name), value: Box::new(value.unwrap()) }),
-    //             _ => Err(self.error(&equals, "Invalid assignement target."))
-    //         };
-    //     }
-
-    //     expr
-    // }
-
-    // fn or(&mut self) -> Result<Expr, Error> {
-    //     let expr = self.and()?;
-
-    //     while match self.peek().typ {
-    //         TokenType::Or => {
-    //             self.advance();
-    //             true
-    //         },
-    //         _ => false
-    //     } {
-    //         let operator = Rc::clone(self.previous());
-    //         let right = self.and()?;
-    //         return Ok(Expr::Logical { left: Box::new(expr), operator, right: Box::new(right) });
-    //     }
-
-    //     Ok(expr)
-    // }
-
-    // fn and(&mut self) -> Result<Expr, Error> {
-    //     let expr = self.equality()?;
-
-    //     while match self.peek().typ {
-    //         TokenType::And => {
-    //             self.advance();
-    //             true
-    //         },
-    //         _ => false
-    //     } {
-    //         let operator = Rc::clone(self.previous());
-    //         let right = self.equality()?;
-    //         return Ok(Expr::Logical { left: Box::new(expr), operator, right: Box::new(right) });
-    //     }
-
-    //     Ok(expr)
-    // }
-
-    fn equality(&mut self) -> Result<Expr, Error> {
-        let mut expr = match self.comparison() {
-            Ok(expr) => expr,
-            Err(e) => return Err(e)
-        };
+        Ok(Stmt::Expression { span: self.since(expr.span()), expression: Box::new(expr) })
+    }
 
-        while match self.peek().typ {
-            TokenType::BangEqual | TokenType::EqualEqual => {
-                self.advance();
-                true
-            },
-            _ => false
-        } {
-            let operator = Rc::clone(self.previous());
-            let right = match self.comparison() {
-                Ok(right) => right,
-                Err(e) => return Err(e)
-            };
-            expr = Expr::Binary { left: Box::new(expr), operator, right: Box::new(right) };
+    fn expression(&mut self) -> Result<Expr, CeyaError> {
+        self.nested(Self::assignment)
+    }
+
+    /// An assignment or a conditional expression. Both are parsed by their own rule once the operand
+    /// before them is known, so only this small frame stays on the stack for each level of parentheses.
+    fn assignment(&mut self) -> Result<Expr, CeyaError> {
+        let expr = self.binary(0)?;
+        match self.peek().typ {
+            TokenType::Question => self.conditional(expr),
+            TokenType::Equal => self.assign(expr),
+            _ => Ok(expr)
         }
+    }
 
-        Ok(expr)
+    /// `=` is right-associative: `a = b = 1` assigns 1 to `b`, then to `a`.
+    fn assign(&mut self, target: Expr) -> Result<Expr, CeyaError> {
+        let equals = Rc::clone(self.advance());
+        self.deeper()?;
+        let value = self.assignment()?;
+
+        match target {
+            Expr::Variable { name, span } => Ok(Expr::Assign { span: span.to(value.span()), name, value: Box::new(value) }),
+            Expr::Index { object, index, span } => Ok(Expr::SetIndex { span: span.to(value.span()), object, index, value: Box::new(value) }),
+            Expr::Get { object, name, span } => Ok(Expr::Set { span: span.to(value.span()), object, name, value: Box::new(value) }),
+            _ => Err(self.error(&equals, "Invalid assignment target."))
+        }
     }
 
-    fn comparison(&mut self) -> Result<Expr, Error> {
-        let mut expr = match self.term() {
-            Ok(expr) => expr,
-            Err(e) => return Err(e)
-        };
+    /// The rest of `condition ? then : els`, from the `?`. It is right-associative: `a ? b : c ? d : e`
+    /// is `a ? b : (c ? d : e)`. Like in the parentheses of a call, any expression goes between `?` and `:`.
+    fn conditional(&mut self, condition: Expr) -> Result<Expr, CeyaError> {
+        self.advance();
+        self.deeper()?;
+        let then = self.expression()?;
+        self.consume(TokenType::Colon, "Expect ':' in conditional expression.")?;
+        let mut els = self.binary(0)?;
+        if self.check(TokenType::Question) {
+            els = self.conditional(els)?;
+        }
 
-        while match self.peek().typ {
-            TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
-                self.advance();
-                true
-            },
-            _ => false
-        } {
+        let expr = Expr::Ternary { span: condition.span().to(els.span()), condition: Box::new(condition), then: Box::new(then), els: Box::new(els) };
+        match self.peek().typ {
+            TokenType::Equal => self.assign(expr),
+            _ => Ok(expr)
+        }
+    }
+
+    /// Parses the operators binding at least as tightly as `min` by precedence climbing, so a level of
+    /// parentheses costs one call rather than one per precedence level. Operators are left-associative:
+    /// the right operand only takes tighter ones. `xor` always needs both operands, so it is a plain
+    /// binary operator, while `and` and `or` short-circuit.
+    fn binary(&mut self, min: u8) -> Result<Expr, CeyaError> {
+        let mut expr = self.unary()?;
+
+        while let Some(precedence) = Expr::precedence(&self.peek().typ).filter(|precedence| *precedence >= min) {
+            self.advance();
             let operator = Rc::clone(self.previous());
-            let right = match self.term() {
-                Ok(right) => right,
-                Err(e) => return Err(e)
+            self.deeper()?;
+            let right = self.binary(precedence + 1)?;
+            let span = expr.span().to(right.span());
+            expr = match operator.typ {
+                TokenType::And | TokenType::Or => Expr::Logical { span, left: Box::new(expr), operator, right: Box::new(right) },
+                _ => Expr::Binary { span, left: Box::new(expr), operator, right: Box::new(right) }
             };
-            expr = Expr::Binary { left: Box::new(expr), operator, right: Box::new(right) };
         }
 
         Ok(expr)
     }
 
-    fn term(&mut self) -> Result<Expr, Error> {
-        let mut expr = match self.factor() {
-            Ok(expr) => expr,
-            Err(e) => return Err(e)
-        };
-
-        while match self.peek().typ {
-            TokenType::Minus | TokenType::Plus => {
+    fn unary(&mut self) -> Result<Expr, CeyaError> {
+        if match self.peek().typ {
+            TokenType::Bang | TokenType::Minus => {
                 self.advance();
                 true
             },
             _ => false
         } {
             let operator = Rc::clone(self.previous());
-            let right = match self.factor() {
-                Ok(right) => right,
-                Err(e) => return Err(e)
+            let right = self.nested(Self::unary)?;
+            return Ok(Expr::Unary { span: operator.span().to(right.span()), operator, right: Box::new(right) });
+        }
+
+        self.call()
+    }
+
+    fn call(&mut self) -> Result<Expr, CeyaError> {
+        let mut expr = self.primary()?;
+
+        loop {
+            let postfix = match self.peek().typ {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::Dot => true,
+                // the name of a struct, a `{` after any other expression being a block or a syntax error
+                TokenType::LeftBrace => matches!(expr, Expr::Variable { .. }),
+                _ => false
             };
-            expr = Expr::Binary { left: Box::new(expr), operator, right: Box::new(right) };
+            if !postfix {
+                return Ok(expr);
+            }
+            expr = self.postfix(expr)?;
         }
+    }
 
-        Ok(expr)
+    /// A call, subscript, field or instance after `expr`. Apart so that `call`, which every nested expression
+    /// goes through, keeps a small frame.
+    #[inline(never)]
+    fn postfix(&mut self, expr: Expr) -> Result<Expr, CeyaError> {
+        self.deeper()?;
+        let token = self.advance().typ.clone();
+        if token == TokenType::Dot {
+            let name = Rc::clone(self.consume(TokenType::Identifier, "Expect field name after '.'.")?);
+            return Ok(Expr::Get { span: self.since(expr.span()), object: Box::new(expr), name });
+        }
+
+        match token {
+            TokenType::LeftParen => self.finish_call(expr),
+            TokenType::LeftBracket => self.subscript(expr),
+            _ => self.instance(expr)
+        }
     }
 
-    fn factor(&mut self) -> Result<Expr, Error> {
-        let mut expr = match self.unary() {
-            Ok(expr) => expr,
-            Err(e) => return Err(e)
+    /// `object[index]` or `object[start..end]`, after the `[`.
+    fn subscript(&mut self, object: Expr) -> Result<Expr, CeyaError> {
+        let index = match self.check(TokenType::DotDot) {
+            true => None,
+            false => Some(Box::new(self.expression()?))
         };
+        if !self.check(TokenType::DotDot) {
+            self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+            let index = index.expect("an index unless slicing");
+            return Ok(Expr::Index { span: self.since(object.span()), object: Box::new(object), index });
+        }
 
-        while match self.peek().typ {
-            TokenType::Star | TokenType::Slash => {
-                self.advance();
-                true
-            },
-            _ => false
-        } {
-            let operator = Rc::clone(self.previous());
-            let right = match self.unary() {
-                Ok(right) => right,
-                Err(e) => return Err(e)
-            };
-            expr = Expr::Binary { left: Box::new(expr), operator, right: Box::new(right) };
+        self.advance();
+        let end = match self.check(TokenType::RightBracket) {
+            true => None,
+            false => Some(Box::new(self.expression()?))
+        };
+        self.consume(TokenType::RightBracket, "Expect ']' after slice.")?;
+        Ok(Expr::Slice { span: self.since(object.span()), object: Box::new(object), start: index, end })
+    }
+
+    /// The fields of `Name { field: value }`, after the `{`.
+    fn instance(&mut self, class: Expr) -> Result<Expr, CeyaError> {
+        let mut fields: Vec<(Rc<Token>, Expr)> = vec![];
+        while !self.check(TokenType::RightBrace) {
+            let name = Rc::clone(self.consume(TokenType::Identifier, "Expect field name.")?);
+            if fields.iter().any(|(other, _)| other.lexeme == name.lexeme) {
+                return Err(self.error(&name, "Duplicate field name."));
+            }
+            self.consume(TokenType::Colon, "Expect ':' after field name.")?;
+            fields.push((name, self.expression()?));
+            if !self.check(TokenType::Comma) {
+                break;
+            }
+            self.advance();
         }
+        self.consume(TokenType::RightBrace, "Expect '}' after fields.")?;
 
-        Ok(expr)
+        Ok(Expr::Instance { span: self.since(class.span()), class: Box::new(class), fields })
     }
 
-    fn unary(&mut self) -> Result<Expr, Error> {
-        if match self.peek().typ {
-            TokenType::Bang | TokenType::Minus => {
-                self.advance();
-                true
-            },
-            _ => false
-        } {
-            let operator = Rc::clone(self.previous());
-            let right = match self.unary() {
-                Ok(right) => right,
-                Err(e) => return Err(e)
-            };
-            return Ok(Expr::Unary { operator, right: Box::new(right) });
-        }
-
-        // self.call()
-        self.primary()
-    }
-
-    // fn call(&mut self) -> Result<Expr, Error> {
-    //     let mut expr = self.primary()?;
-
-    //     loop {
-    //         match self.peek().typ {
-    //             TokenType::LeftParen => {
-    //                 self.advance();
-    //                 expr = self.finish_call(expr)?;
-    //             },
-    //             _ => break
-    //         }
-    //     }
-
-    //     Ok(expr)
-    // }
-
-    // fn finish_call(&mut self, callee: Expr) -> Result<Expr, Error> {
-    //     let mut arguments: Vec<Box<Expr>> = vec![];
-    //     match self.peek().typ {
-    //         TokenType::RightParen => (),
-    //         _ => {
-    //             loop {
-    //                 arguments.push(Box::new(self.expression()?));
-    //                 match self.peek().typ {
-    //                     TokenType::Comma => {
-    //                         self.advance();
-    //                     },
-    //                     _ => {
-    //                         break;
-    //                     }
-    //                 };
-    //             }
-    //         }
-    //     }
-
-    //     let paren = self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
-
-    //     Ok(Expr::Call { callee: Box::new(callee), paren: Rc::clone(paren), arguments })
-    // }
-
-    fn primary(&mut self) -> Result<Expr, Error> {
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, CeyaError> {
+        let mut arguments: Vec<Expr> = vec![];
+        match self.peek().typ {
+            TokenType::RightParen => (),
+            _ => {
+                loop {
+                    arguments.push(self.expression()?);
+                    match self.peek().typ {
+                        TokenType::Comma => {
+                            self.advance();
+                        },
+                        _ => {
+                            break;
+                        }
+                    };
+                }
+            }
+        }
+
+        let paren = self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+
+        let paren = Rc::clone(paren);
+        Ok(Expr::Call { span: callee.span().to(paren.span()), callee: Box::new(callee), paren, arguments })
+    }
+
+    /// `[a, b, c]`, a trailing comma allowed, from the `[`.
+    fn list(&mut self) -> Result<Expr, CeyaError> {
+        let span = self.advance().span();
+        self.deeper()?;
+        let mut elements = vec![];
+        while !self.check(TokenType::RightBracket) {
+            elements.push(self.expression()?);
+            if !self.check(TokenType::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+
+        Ok(Expr::List { elements, span: self.since(span) })
+    }
+
+    /// `{key: value}`, a trailing comma allowed, from the `{`.
+    fn map(&mut self) -> Result<Expr, CeyaError> {
+        let span = self.advance().span();
+        self.deeper()?;
+        let mut entries = vec![];
+        while !self.check(TokenType::RightBrace) {
+            let key = self.expression()?;
+            self.consume(TokenType::Colon, "Expect ':' after map key.")?;
+            entries.push((key, self.expression()?));
+            if !self.check(TokenType::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.")?;
+
+        Ok(Expr::Map { entries, span: self.since(span) })
+    }
+
+    fn primary(&mut self) -> Result<Expr, CeyaError> {
+        if self.check(TokenType::LeftBracket) {
+            return self.list();
+        }
+        // a `{` starting a statement is a block
+        if self.check(TokenType::LeftBrace) {
+            return self.map();
+        }
+
+        let span = self.peek().span();
         if let Ok(res) = match &self.peek().typ {
-            TokenType::False => Ok(Expr::Literal { value: Value::Boolean(false) }),
-            TokenType::True => Ok(Expr::Literal { value: Value::Boolean(true) }),
-            TokenType::Null => Ok(Expr::Literal { value: Value::Null }),
-            TokenType::Number(n) => Ok(Expr::Literal { value: Value::Number(*n) }),
-            TokenType::String(s) => Ok(Expr::Literal { value: Value::String(s.clone()) }),
-            TokenType::Soro => Ok(Expr::Soro),
-            // TokenType::Identifier => Ok(Expr::Variable { name: Rc::clone(self.peek()) }),
+            TokenType::False => Ok(Expr::Literal { value: Value::Boolean(false), span }),
+            TokenType::True => Ok(Expr::Literal { value: Value::Boolean(true), span }),
+            TokenType::Null => Ok(Expr::Literal { value: Value::Null, span }),
+            TokenType::Number(n) => Ok(Expr::Literal { value: Value::Number(*n), span }),
+            TokenType::String(s) => Ok(Expr::Literal { value: Value::String(s.as_str().into()), span }),
+            TokenType::Soro => Ok(Expr::Soro { span }),
+            TokenType::Depth => Ok(Expr::Depth { span }),
+            TokenType::Identifier => Ok(Expr::Variable { name: Rc::clone(self.peek()), span }),
             TokenType::LeftParen => {
                 self.advance();
-                let expr = match self.expression() {
-                    Ok(right) => right,
-                    Err(e) => return Err(e)
-                };
+                let expr = self.expression()?;
                 match self.consume(TokenType::RightParen, "Expect ')' after expression.") {
                     Ok(_) => {
                         self.current -= 1;
-                        Ok(Expr::Grouping { expression: Box::new(expr) })
+                        Ok(Expr::Grouping { expression: Box::new(expr), span: span.to(self.peek().span()) })
                     },
                     Err(_) => Err(())
                 }
@@ -506,15 +743,24 @@ impl Parser {
         Err(self.error(self.peek(), "Expect expression."))
     }
 
-    fn consume(&mut self, typ: TokenType, message: &str) -> Result<&Rc<Token>, Error> {
+    /// Span from `start` to the last consumed token.
+    fn since(&self, start: Span) -> Span {
+        start.to(self.previous().span())
+    }
+
+    fn consume(&mut self, typ: TokenType, message: &str) -> Result<&Rc<Token>, CeyaError> {
         if self.check(typ) {
             return Ok(self.advance());
         }
         Err(self.error(self.peek(), message))
     }
 
-    fn error(&self, token: &Token, message: &str) -> Error {
-        Error::new(ErrorKind::Other, error(token.line, message))
+    fn error(&self, token: &Token, message: &str) -> CeyaError {
+        let found = match token.typ {
+            TokenType::EOF => "end of file".to_string(),
+            _ => format!("'{}'", token.lexeme)
+        };
+        CeyaError::Parse { span: token.span(), message: format!("{} (at {})", message, found) }
     }
 
     fn synchronise(&mut self) {
@@ -526,7 +772,9 @@ impl Parser {
             }
 
             match self.peek().typ {
-                TokenType::Fn | TokenType::Let | TokenType::For | TokenType::If | TokenType::While | TokenType::Print | TokenType::Return => {
+                // leaves the closing brace to the enclosing block
+                TokenType::RightBrace => return,
+                TokenType::Fn | TokenType::Struct | TokenType::Let | TokenType::For | TokenType::If | TokenType::While | TokenType::Print | TokenType::Eprint | TokenType::Return | TokenType::Break | TokenType::Continue => {
                     return;
                 }
                 _ => ()
@@ -569,34 +817,46 @@ impl Parser {
 mod tests {
     use std::rc::Rc;
 
-    use crate::{scanner::{Token, TokenType}, ast::{Expr, Value}};
+    use crate::{scanner::{Span, Token, TokenType}, ast::{Expr, Stmt, Value}, error::CeyaError};
+
+    use super::{Parser, DEFAULT_MAX_DEPTH};
 
-    use super::Parser;
+    /// The statements parsed despite the errors, and the errors.
+    fn parse_recovering(source: &str) -> (Vec<Stmt>, Vec<CeyaError>) {
+        let mut parser = Parser::new(crate::scanner::scan(source), DEFAULT_MAX_DEPTH);
+        let stmts = parser.parse_spanned().into_iter().map(|(stmt, _)| stmt).collect();
+        (stmts, parser.errors)
+    }
 
     #[test]
     fn test_parse_primary() {
         let tokens = vec![
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "\"string\"".into(), line: 0, typ: TokenType::String("string".into()) }),
-            Rc::new(Token { lexeme: "true".into(), line: 0, typ: TokenType::True }),
-            Rc::new(Token { lexeme: "false".into(), line: 0, typ: TokenType::False }),
-            Rc::new(Token { lexeme: "fu".into(), line: 0, typ: TokenType::Null }),
-            Rc::new(Token { lexeme: "(".into(), line: 0, typ: TokenType::LeftParen }),
-            Rc::new(Token { lexeme: "true".into(), line: 0, typ: TokenType::True }),
-            Rc::new(Token { lexeme: ")".into(), line: 0, typ: TokenType::RightParen }),
-            Rc::new(Token { lexeme: "".into(), line: 0, typ: TokenType::EOF })
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "\"string\"".into(), span: Span::default(), typ: TokenType::String("string".into()) }),
+            Rc::new(Token { lexeme: "true".into(), span: Span::default(), typ: TokenType::True }),
+            Rc::new(Token { lexeme: "false".into(), span: Span::default(), typ: TokenType::False }),
+            Rc::new(Token { lexeme: "fu".into(), span: Span::default(), typ: TokenType::Null }),
+            Rc::new(Token { lexeme: "(".into(), span: Span::default(), typ: TokenType::LeftParen }),
+            Rc::new(Token { lexeme: "true".into(), span: Span::default(), typ: TokenType::True }),
+            Rc::new(Token { lexeme: ")".into(), span: Span::default(), typ: TokenType::RightParen }),
+            Rc::new(Token { lexeme: "".into(), span: Span::default(), typ: TokenType::EOF })
         ];
         let expected = vec![
-            Expr::Literal { value: Value::Number(12.0) },
-            Expr::Literal { value: Value::String("string".into()) },
-            Expr::Literal { value: Value::Boolean(true) },
-            Expr::Literal { value: Value::Boolean(false) },
-            Expr::Literal { value: Value::Null },
-            Expr::Grouping { expression: Box::new(Expr::Literal { value: Value::Boolean(true) }) }
+            Expr::Literal { value: Value::Number(12.0), span: Span::default() },
+            Expr::Literal { value: Value::String("string".into()), span: Span::default() },
+            Expr::Literal { value: Value::Boolean(true), span: Span::default() },
+            Expr::Literal { value: Value::Boolean(false), span: Span::default() },
+            Expr::Literal { value: Value::Null, span: Span::default() },
+            Expr::Grouping { expression: Box::new(Expr::Literal { value: Value::Boolean(true), span: Span::default() }), span: Span::default() }
         ];
 
         let mut parser = Parser {
             current: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            functions: 0,
+            loops: 0,
+            errors: vec![],
             tokens
         };
 
@@ -611,33 +871,41 @@ mod tests {
     #[test]
     fn test_parse_unary() {
         let tokens = vec![
-            Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "!".into(), line: 0, typ: TokenType::Bang }),
-            Rc::new(Token { lexeme: "false".into(), line: 0, typ: TokenType::False }),
-            Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }),
-            Rc::new(Token { lexeme: "!".into(), line: 0, typ: TokenType::Bang }),
-            Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }),
-            Rc::new(Token { lexeme: "true".into(), line: 0, typ: TokenType::True }),
-            Rc::new(Token { lexeme: "".into(), line: 0, typ: TokenType::EOF })
+            Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "!".into(), span: Span::default(), typ: TokenType::Bang }),
+            Rc::new(Token { lexeme: "false".into(), span: Span::default(), typ: TokenType::False }),
+            Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }),
+            Rc::new(Token { lexeme: "!".into(), span: Span::default(), typ: TokenType::Bang }),
+            Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }),
+            Rc::new(Token { lexeme: "true".into(), span: Span::default(), typ: TokenType::True }),
+            Rc::new(Token { lexeme: "".into(), span: Span::default(), typ: TokenType::EOF })
         ];
         let expected = vec![
-            Expr::Unary { operator: Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }), right: Box::new(Expr::Literal { value: Value::Number(12.0) }) },
-            Expr::Unary { operator: Rc::new(Token { lexeme: "!".into(), line: 0, typ: TokenType::Bang }), right: Box::new(Expr::Literal { value: Value::Boolean(false) }) },
+            Expr::Unary { operator: Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }), right: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), span: Span::default() },
+            Expr::Unary { operator: Rc::new(Token { lexeme: "!".into(), span: Span::default(), typ: TokenType::Bang }), right: Box::new(Expr::Literal { value: Value::Boolean(false), span: Span::default() }), span: Span::default() },
             Expr::Unary { 
-                operator: Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }), 
+                operator: Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }), 
                 right: Box::new(Expr::Unary { 
-                    operator: Rc::new(Token { lexeme: "!".into(), line: 0, typ: TokenType::Bang }), 
+                    operator: Rc::new(Token { lexeme: "!".into(), span: Span::default(), typ: TokenType::Bang }), 
                     right: Box::new(Expr::Unary { 
-                        operator: Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }), 
-                        right: Box::new(Expr::Literal { value: Value::Boolean(true) })
-                    })
-                })
+                        operator: Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }), 
+                        right: Box::new(Expr::Literal { value: Value::Boolean(true), span: Span::default() }),
+                        span: Span::default()
+                    }),
+                    span: Span::default()
+                }),
+                span: Span::default()
             }
         ];
 
         let mut parser = Parser {
             current: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            functions: 0,
+            loops: 0,
+            errors: vec![],
             tokens
         };
 
@@ -652,54 +920,64 @@ mod tests {
     #[test]
     fn test_parse_factor() {
         let tokens = vec![
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "*".into(), line: 0, typ: TokenType::Star }),
-            Rc::new(Token { lexeme: "0.1".into(), line: 0, typ: TokenType::Number(0.1) }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "/".into(), line: 0, typ: TokenType::Slash }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "*".into(), line: 0, typ: TokenType::Star }),
-            Rc::new(Token { lexeme: "2".into(), line: 0, typ: TokenType::Number(2.0) }),
-            Rc::new(Token { lexeme: "/".into(), line: 0, typ: TokenType::Slash }),
-            Rc::new(Token { lexeme: "4".into(), line: 0, typ: TokenType::Number(4.0) }),
-            Rc::new(Token { lexeme: "*".into(), line: 0, typ: TokenType::Star }),
-            Rc::new(Token { lexeme: "2".into(), line: 0, typ: TokenType::Number(2.0) }),
-            Rc::new(Token { lexeme: "".into(), line: 0, typ: TokenType::EOF })
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "*".into(), span: Span::default(), typ: TokenType::Star }),
+            Rc::new(Token { lexeme: "0.1".into(), span: Span::default(), typ: TokenType::Number(0.1) }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "/".into(), span: Span::default(), typ: TokenType::Slash }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "*".into(), span: Span::default(), typ: TokenType::Star }),
+            Rc::new(Token { lexeme: "2".into(), span: Span::default(), typ: TokenType::Number(2.0) }),
+            Rc::new(Token { lexeme: "/".into(), span: Span::default(), typ: TokenType::Slash }),
+            Rc::new(Token { lexeme: "4".into(), span: Span::default(), typ: TokenType::Number(4.0) }),
+            Rc::new(Token { lexeme: "*".into(), span: Span::default(), typ: TokenType::Star }),
+            Rc::new(Token { lexeme: "2".into(), span: Span::default(), typ: TokenType::Number(2.0) }),
+            Rc::new(Token { lexeme: "".into(), span: Span::default(), typ: TokenType::EOF })
         ];
         let expected = vec![
             Expr::Binary { 
-                left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                operator: Rc::new(Token { lexeme: "*".into(), line: 0, typ: TokenType::Star }), 
-                right: Box::new(Expr::Literal { value: Value::Number(0.1) }) 
+                left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                operator: Rc::new(Token { lexeme: "*".into(), span: Span::default(), typ: TokenType::Star }), 
+                right: Box::new(Expr::Literal { value: Value::Number(0.1), span: Span::default() }),
+                span: Span::default()
             },
             Expr::Binary { 
-                left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                operator: Rc::new(Token { lexeme: "/".into(), line: 0, typ: TokenType::Slash }), 
-                right: Box::new(Expr::Literal { value: Value::Number(12.0) }) 
+                left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                operator: Rc::new(Token { lexeme: "/".into(), span: Span::default(), typ: TokenType::Slash }), 
+                right: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }),
+                span: Span::default()
             },
             Expr::Binary { 
                 left: Box::new(Expr::Binary { 
                     left: Box::new(Expr::Binary { 
-                        left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                        operator: Rc::new(Token { lexeme: "*".into(), line: 0, typ: TokenType::Star }), 
-                        right: Box::new(Expr::Literal { value: Value::Number(2.0) })  
+                        left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                        operator: Rc::new(Token { lexeme: "*".into(), span: Span::default(), typ: TokenType::Star }), 
+                        right: Box::new(Expr::Literal { value: Value::Number(2.0), span: Span::default() }),
+                        span: Span::default()
                     }), 
-                    operator: Rc::new(Token { lexeme: "/".into(), line: 0, typ: TokenType::Slash }), 
-                    right: Box::new(Expr::Literal { value: Value::Number(4.0) }) 
+                    operator: Rc::new(Token { lexeme: "/".into(), span: Span::default(), typ: TokenType::Slash }), 
+                    right: Box::new(Expr::Literal { value: Value::Number(4.0), span: Span::default() }),
+                    span: Span::default()
                 }), 
-                operator: Rc::new(Token { lexeme: "*".into(), line: 0, typ: TokenType::Star }), 
-                right: Box::new(Expr::Literal { value: Value::Number(2.0) }) 
+                operator: Rc::new(Token { lexeme: "*".into(), span: Span::default(), typ: TokenType::Star }), 
+                right: Box::new(Expr::Literal { value: Value::Number(2.0), span: Span::default() }),
+                span: Span::default()
             },
         ];
 
         let mut parser = Parser {
             current: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            functions: 0,
+            loops: 0,
+            errors: vec![],
             tokens
         };
 
         for expect in expected {
-            let parsed = parser.factor().expect("Expr expected.");
+            let parsed = parser.binary(7).expect("Expr expected.");
             if !equal_expr(&expect,&parsed) {
                 panic!("{:?} is not equal to {:?}", parsed, expect);
             }
@@ -709,54 +987,64 @@ mod tests {
     #[test]
     fn test_parse_term() {
         let tokens = vec![
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "+".into(), line: 0, typ: TokenType::Plus }),
-            Rc::new(Token { lexeme: "0.1".into(), line: 0, typ: TokenType::Number(0.1) }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "+".into(), line: 0, typ: TokenType::Plus }),
-            Rc::new(Token { lexeme: "2".into(), line: 0, typ: TokenType::Number(2.0) }),
-            Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }),
-            Rc::new(Token { lexeme: "4".into(), line: 0, typ: TokenType::Number(4.0) }),
-            Rc::new(Token { lexeme: "+".into(), line: 0, typ: TokenType::Plus }),
-            Rc::new(Token { lexeme: "2".into(), line: 0, typ: TokenType::Number(2.0) }),
-            Rc::new(Token { lexeme: "".into(), line: 0, typ: TokenType::EOF })
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "+".into(), span: Span::default(), typ: TokenType::Plus }),
+            Rc::new(Token { lexeme: "0.1".into(), span: Span::default(), typ: TokenType::Number(0.1) }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "+".into(), span: Span::default(), typ: TokenType::Plus }),
+            Rc::new(Token { lexeme: "2".into(), span: Span::default(), typ: TokenType::Number(2.0) }),
+            Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }),
+            Rc::new(Token { lexeme: "4".into(), span: Span::default(), typ: TokenType::Number(4.0) }),
+            Rc::new(Token { lexeme: "+".into(), span: Span::default(), typ: TokenType::Plus }),
+            Rc::new(Token { lexeme: "2".into(), span: Span::default(), typ: TokenType::Number(2.0) }),
+            Rc::new(Token { lexeme: "".into(), span: Span::default(), typ: TokenType::EOF })
         ];
         let expected = vec![
             Expr::Binary { 
-                left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                operator: Rc::new(Token { lexeme: "+".into(), line: 0, typ: TokenType::Plus }), 
-                right: Box::new(Expr::Literal { value: Value::Number(0.1) }) 
+                left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                operator: Rc::new(Token { lexeme: "+".into(), span: Span::default(), typ: TokenType::Plus }), 
+                right: Box::new(Expr::Literal { value: Value::Number(0.1), span: Span::default() }),
+                span: Span::default()
             },
             Expr::Binary { 
-                left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                operator: Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }), 
-                right: Box::new(Expr::Literal { value: Value::Number(12.0) }) 
+                left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                operator: Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }), 
+                right: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }),
+                span: Span::default()
             },
             Expr::Binary { 
                 left: Box::new(Expr::Binary { 
                     left: Box::new(Expr::Binary { 
-                        left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                        operator: Rc::new(Token { lexeme: "+".into(), line: 0, typ: TokenType::Plus }), 
-                        right: Box::new(Expr::Literal { value: Value::Number(2.0) })  
+                        left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                        operator: Rc::new(Token { lexeme: "+".into(), span: Span::default(), typ: TokenType::Plus }), 
+                        right: Box::new(Expr::Literal { value: Value::Number(2.0), span: Span::default() }),
+                        span: Span::default()
                     }), 
-                    operator: Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }), 
-                    right: Box::new(Expr::Literal { value: Value::Number(4.0) }) 
+                    operator: Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }), 
+                    right: Box::new(Expr::Literal { value: Value::Number(4.0), span: Span::default() }),
+                    span: Span::default()
                 }), 
-                operator: Rc::new(Token { lexeme: "+".into(), line: 0, typ: TokenType::Plus }), 
-                right: Box::new(Expr::Literal { value: Value::Number(2.0) }) 
+                operator: Rc::new(Token { lexeme: "+".into(), span: Span::default(), typ: TokenType::Plus }), 
+                right: Box::new(Expr::Literal { value: Value::Number(2.0), span: Span::default() }),
+                span: Span::default()
             },
         ];
 
         let mut parser = Parser {
             current: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            functions: 0,
+            loops: 0,
+            errors: vec![],
             tokens
         };
 
         for expect in expected {
-            let parsed = parser.term().expect("Expr expected.");
+            let parsed = parser.binary(6).expect("Expr expected.");
             if !equal_expr(&expect,&parsed) {
                 panic!("{:?} is not equal to {:?}", parsed, expect);
             }
@@ -766,54 +1054,64 @@ mod tests {
     #[test]
     fn test_parse_comparison() {
         let tokens = vec![
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "<".into(), line: 0, typ: TokenType::Less }),
-            Rc::new(Token { lexeme: "0.1".into(), line: 0, typ: TokenType::Number(0.1) }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: ">".into(), line: 0, typ: TokenType::Greater }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "<".into(), line: 0, typ: TokenType::Less }),
-            Rc::new(Token { lexeme: "2".into(), line: 0, typ: TokenType::Number(2.0) }),
-            Rc::new(Token { lexeme: ">=".into(), line: 0, typ: TokenType::GreaterEqual }),
-            Rc::new(Token { lexeme: "4".into(), line: 0, typ: TokenType::Number(4.0) }),
-            Rc::new(Token { lexeme: "<=".into(), line: 0, typ: TokenType::LessEqual }),
-            Rc::new(Token { lexeme: "2".into(), line: 0, typ: TokenType::Number(2.0) }),
-            Rc::new(Token { lexeme: "".into(), line: 0, typ: TokenType::EOF })
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "<".into(), span: Span::default(), typ: TokenType::Less }),
+            Rc::new(Token { lexeme: "0.1".into(), span: Span::default(), typ: TokenType::Number(0.1) }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: ">".into(), span: Span::default(), typ: TokenType::Greater }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "<".into(), span: Span::default(), typ: TokenType::Less }),
+            Rc::new(Token { lexeme: "2".into(), span: Span::default(), typ: TokenType::Number(2.0) }),
+            Rc::new(Token { lexeme: ">=".into(), span: Span::default(), typ: TokenType::GreaterEqual }),
+            Rc::new(Token { lexeme: "4".into(), span: Span::default(), typ: TokenType::Number(4.0) }),
+            Rc::new(Token { lexeme: "<=".into(), span: Span::default(), typ: TokenType::LessEqual }),
+            Rc::new(Token { lexeme: "2".into(), span: Span::default(), typ: TokenType::Number(2.0) }),
+            Rc::new(Token { lexeme: "".into(), span: Span::default(), typ: TokenType::EOF })
         ];
         let expected = vec![
             Expr::Binary { 
-                left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                operator: Rc::new(Token { lexeme: "<".into(), line: 0, typ: TokenType::Less }), 
-                right: Box::new(Expr::Literal { value: Value::Number(0.1) }) 
+                left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                operator: Rc::new(Token { lexeme: "<".into(), span: Span::default(), typ: TokenType::Less }), 
+                right: Box::new(Expr::Literal { value: Value::Number(0.1), span: Span::default() }),
+                span: Span::default()
             },
             Expr::Binary { 
-                left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                operator: Rc::new(Token { lexeme: ">".into(), line: 0, typ: TokenType::Greater }), 
-                right: Box::new(Expr::Literal { value: Value::Number(12.0) }) 
+                left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                operator: Rc::new(Token { lexeme: ">".into(), span: Span::default(), typ: TokenType::Greater }), 
+                right: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }),
+                span: Span::default()
             },
             Expr::Binary { 
                 left: Box::new(Expr::Binary { 
                     left: Box::new(Expr::Binary { 
-                        left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                        operator: Rc::new(Token { lexeme: "<".into(), line: 0, typ: TokenType::Less }), 
-                        right: Box::new(Expr::Literal { value: Value::Number(2.0) })  
+                        left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                        operator: Rc::new(Token { lexeme: "<".into(), span: Span::default(), typ: TokenType::Less }), 
+                        right: Box::new(Expr::Literal { value: Value::Number(2.0), span: Span::default() }),
+                        span: Span::default()
                     }), 
-                    operator: Rc::new(Token { lexeme: ">=".into(), line: 0, typ: TokenType::GreaterEqual }), 
-                    right: Box::new(Expr::Literal { value: Value::Number(4.0) }) 
+                    operator: Rc::new(Token { lexeme: ">=".into(), span: Span::default(), typ: TokenType::GreaterEqual }), 
+                    right: Box::new(Expr::Literal { value: Value::Number(4.0), span: Span::default() }),
+                    span: Span::default()
                 }), 
-                operator: Rc::new(Token { lexeme: "<=".into(), line: 0, typ: TokenType::LessEqual }), 
-                right: Box::new(Expr::Literal { value: Value::Number(2.0) }) 
+                operator: Rc::new(Token { lexeme: "<=".into(), span: Span::default(), typ: TokenType::LessEqual }), 
+                right: Box::new(Expr::Literal { value: Value::Number(2.0), span: Span::default() }),
+                span: Span::default()
             },
         ];
 
         let mut parser = Parser {
             current: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            functions: 0,
+            loops: 0,
+            errors: vec![],
             tokens
         };
 
         for expect in expected {
-            let parsed = parser.comparison().expect("Expr expected.");
+            let parsed = parser.binary(5).expect("Expr expected.");
             if !equal_expr(&expect,&parsed) {
                 panic!("{:?} is not equal to {:?}", parsed, expect);
             }
@@ -823,54 +1121,64 @@ mod tests {
     #[test]
     fn test_parse_equality() {
         let tokens = vec![
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "==".into(), line: 0, typ: TokenType::EqualEqual }),
-            Rc::new(Token { lexeme: "0.1".into(), line: 0, typ: TokenType::Number(0.1) }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "!=".into(), line: 0, typ: TokenType::BangEqual }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "==".into(), line: 0, typ: TokenType::EqualEqual }),
-            Rc::new(Token { lexeme: "2".into(), line: 0, typ: TokenType::Number(2.0) }),
-            Rc::new(Token { lexeme: "!=".into(), line: 0, typ: TokenType::BangEqual }),
-            Rc::new(Token { lexeme: "4".into(), line: 0, typ: TokenType::Number(4.0) }),
-            Rc::new(Token { lexeme: "!=".into(), line: 0, typ: TokenType::BangEqual }),
-            Rc::new(Token { lexeme: "2".into(), line: 0, typ: TokenType::Number(2.0) }),
-            Rc::new(Token { lexeme: "".into(), line: 0, typ: TokenType::EOF })
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "==".into(), span: Span::default(), typ: TokenType::EqualEqual }),
+            Rc::new(Token { lexeme: "0.1".into(), span: Span::default(), typ: TokenType::Number(0.1) }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "!=".into(), span: Span::default(), typ: TokenType::BangEqual }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "==".into(), span: Span::default(), typ: TokenType::EqualEqual }),
+            Rc::new(Token { lexeme: "2".into(), span: Span::default(), typ: TokenType::Number(2.0) }),
+            Rc::new(Token { lexeme: "!=".into(), span: Span::default(), typ: TokenType::BangEqual }),
+            Rc::new(Token { lexeme: "4".into(), span: Span::default(), typ: TokenType::Number(4.0) }),
+            Rc::new(Token { lexeme: "!=".into(), span: Span::default(), typ: TokenType::BangEqual }),
+            Rc::new(Token { lexeme: "2".into(), span: Span::default(), typ: TokenType::Number(2.0) }),
+            Rc::new(Token { lexeme: "".into(), span: Span::default(), typ: TokenType::EOF })
         ];
         let expected = vec![
             Expr::Binary { 
-                left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                operator: Rc::new(Token { lexeme: "==".into(), line: 0, typ: TokenType::EqualEqual }), 
-                right: Box::new(Expr::Literal { value: Value::Number(0.1) }) 
+                left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                operator: Rc::new(Token { lexeme: "==".into(), span: Span::default(), typ: TokenType::EqualEqual }), 
+                right: Box::new(Expr::Literal { value: Value::Number(0.1), span: Span::default() }),
+                span: Span::default()
             },
             Expr::Binary { 
-                left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                operator: Rc::new(Token { lexeme: "!=".into(), line: 0, typ: TokenType::BangEqual }), 
-                right: Box::new(Expr::Literal { value: Value::Number(12.0) }) 
+                left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                operator: Rc::new(Token { lexeme: "!=".into(), span: Span::default(), typ: TokenType::BangEqual }), 
+                right: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }),
+                span: Span::default()
             },
             Expr::Binary { 
                 left: Box::new(Expr::Binary { 
                     left: Box::new(Expr::Binary { 
-                        left: Box::new(Expr::Literal { value: Value::Number(12.0) }), 
-                        operator: Rc::new(Token { lexeme: "==".into(), line: 0, typ: TokenType::EqualEqual }), 
-                        right: Box::new(Expr::Literal { value: Value::Number(2.0) })  
+                        left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }), 
+                        operator: Rc::new(Token { lexeme: "==".into(), span: Span::default(), typ: TokenType::EqualEqual }), 
+                        right: Box::new(Expr::Literal { value: Value::Number(2.0), span: Span::default() }),
+                        span: Span::default()
                     }), 
-                    operator: Rc::new(Token { lexeme: "!=".into(), line: 0, typ: TokenType::BangEqual }), 
-                    right: Box::new(Expr::Literal { value: Value::Number(4.0) }) 
+                    operator: Rc::new(Token { lexeme: "!=".into(), span: Span::default(), typ: TokenType::BangEqual }), 
+                    right: Box::new(Expr::Literal { value: Value::Number(4.0), span: Span::default() }),
+                    span: Span::default()
                 }), 
-                operator: Rc::new(Token { lexeme: "!=".into(), line: 0, typ: TokenType::BangEqual }), 
-                right: Box::new(Expr::Literal { value: Value::Number(2.0) }) 
+                operator: Rc::new(Token { lexeme: "!=".into(), span: Span::default(), typ: TokenType::BangEqual }), 
+                right: Box::new(Expr::Literal { value: Value::Number(2.0), span: Span::default() }),
+                span: Span::default()
             },
         ];
 
         let mut parser = Parser {
             current: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            functions: 0,
+            loops: 0,
+            errors: vec![],
             tokens
         };
 
         for expect in expected {
-            let parsed = parser.equality().expect("Expr expected.");
+            let parsed = parser.binary(4).expect("Expr expected.");
             if !equal_expr(&expect,&parsed) {
                 panic!("{:?} is not equal to {:?}", parsed, expect);
             }
@@ -880,47 +1188,58 @@ mod tests {
     #[test]
     fn test_parse_expression() {
         let tokens = vec![
-            Rc::new(Token { lexeme: "12".into(), line: 0, typ: TokenType::Number(12.0) }),
-            Rc::new(Token { lexeme: "<".into(), line: 0, typ: TokenType::Less }),
-            Rc::new(Token { lexeme: "(".into(), line: 0, typ: TokenType::LeftParen }),
-            Rc::new(Token { lexeme: "0.1".into(), line: 0, typ: TokenType::Number(0.1) }),
-            Rc::new(Token { lexeme: "+".into(), line: 0, typ: TokenType::Plus }),
-            Rc::new(Token { lexeme: "5".into(), line: 0, typ: TokenType::Number(5.0) }),
-            Rc::new(Token { lexeme: ")".into(), line: 0, typ: TokenType::RightParen }),
-            Rc::new(Token { lexeme: "*".into(), line: 0, typ: TokenType::Star }),
-            Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }),
-            Rc::new(Token { lexeme: "2".into(), line: 0, typ: TokenType::Number(2.0) }),
-            Rc::new(Token { lexeme: "==".into(), line: 0, typ: TokenType::EqualEqual }),
-            Rc::new(Token { lexeme: "true".into(), line: 0, typ: TokenType::True }),
-            Rc::new(Token { lexeme: "".into(), line: 0, typ: TokenType::EOF })
+            Rc::new(Token { lexeme: "12".into(), span: Span::default(), typ: TokenType::Number(12.0) }),
+            Rc::new(Token { lexeme: "<".into(), span: Span::default(), typ: TokenType::Less }),
+            Rc::new(Token { lexeme: "(".into(), span: Span::default(), typ: TokenType::LeftParen }),
+            Rc::new(Token { lexeme: "0.1".into(), span: Span::default(), typ: TokenType::Number(0.1) }),
+            Rc::new(Token { lexeme: "+".into(), span: Span::default(), typ: TokenType::Plus }),
+            Rc::new(Token { lexeme: "5".into(), span: Span::default(), typ: TokenType::Number(5.0) }),
+            Rc::new(Token { lexeme: ")".into(), span: Span::default(), typ: TokenType::RightParen }),
+            Rc::new(Token { lexeme: "*".into(), span: Span::default(), typ: TokenType::Star }),
+            Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }),
+            Rc::new(Token { lexeme: "2".into(), span: Span::default(), typ: TokenType::Number(2.0) }),
+            Rc::new(Token { lexeme: "==".into(), span: Span::default(), typ: TokenType::EqualEqual }),
+            Rc::new(Token { lexeme: "true".into(), span: Span::default(), typ: TokenType::True }),
+            Rc::new(Token { lexeme: "".into(), span: Span::default(), typ: TokenType::EOF })
         ];
         let expected = vec![
             Expr::Binary { 
                 left: Box::new(Expr::Binary { 
-                    left: Box::new(Expr::Literal { value: Value::Number(12.0) }),
-                    operator: Rc::new(Token { lexeme: "<".into(), line: 0, typ: TokenType::Less }), 
+                    left: Box::new(Expr::Literal { value: Value::Number(12.0), span: Span::default() }),
+                    operator: Rc::new(Token { lexeme: "<".into(), span: Span::default(), typ: TokenType::Less }), 
                     right: Box::new(Expr::Binary { 
                         left: Box::new(Expr::Grouping { 
                             expression: Box::new(Expr::Binary { 
-                                left: Box::new(Expr::Literal { value: Value::Number(0.1) }), 
-                                operator: Rc::new(Token { lexeme: "+".into(), line: 0, typ: TokenType::Plus }), 
-                                right: Box::new(Expr::Literal { value: Value::Number(5.0) })
-                            }) 
+                                left: Box::new(Expr::Literal { value: Value::Number(0.1), span: Span::default() }), 
+                                operator: Rc::new(Token { lexeme: "+".into(), span: Span::default(), typ: TokenType::Plus }), 
+                                right: Box::new(Expr::Literal { value: Value::Number(5.0), span: Span::default() }),
+                                span: Span::default()
+                            }),
+                            span: Span::default()
                         }), 
-                        operator: Rc::new(Token { lexeme: "*".into(), line: 0, typ: TokenType::Star }), 
+                        operator: Rc::new(Token { lexeme: "*".into(), span: Span::default(), typ: TokenType::Star }), 
                         right: Box::new(Expr::Unary { 
-                            operator: Rc::new(Token { lexeme: "-".into(), line: 0, typ: TokenType::Minus }), 
-                            right: Box::new(Expr::Literal { value: Value::Number(2.0) }) 
-                        })
-                    }) 
+                            operator: Rc::new(Token { lexeme: "-".into(), span: Span::default(), typ: TokenType::Minus }), 
+                            right: Box::new(Expr::Literal { value: Value::Number(2.0), span: Span::default() }),
+                            span: Span::default()
+                        }),
+                        span: Span::default()
+                    }),
+                    span: Span::default()
                 }), 
-                operator: Rc::new(Token { lexeme: "==".into(), line: 0, typ: TokenType::EqualEqual }), 
-                right: Box::new(Expr::Literal { value: Value::Boolean(true) }) 
+                operator: Rc::new(Token { lexeme: "==".into(), span: Span::default(), typ: TokenType::EqualEqual }), 
+                right: Box::new(Expr::Literal { value: Value::Boolean(true), span: Span::default() }),
+                span: Span::default()
             },
         ];
 
         let mut parser = Parser {
             current: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            functions: 0,
+            loops: 0,
+            errors: vec![],
             tokens
         };
 
@@ -933,17 +1252,17 @@ mod tests {
     }
 
     fn equal_expr(expr1: &Expr, expr2: &Expr) -> bool {
-        match (expr1, expr2) {
-            (Expr::Literal { value: v1 }, Expr::Literal { value: v2 }) => v1 == v2,
-            (Expr::Unary { operator: op1, right: r1 }, Expr::Unary { operator: op2, right: r2 }) => {
+        expr1.span() == expr2.span() && match (expr1, expr2) {
+            (Expr::Literal { value: v1, .. }, Expr::Literal { value: v2, .. }) => v1 == v2,
+            (Expr::Unary { operator: op1, right: r1, .. }, Expr::Unary { operator: op2, right: r2, .. }) => {
                 if op1.typ != op2.typ {
                     return false;
                 }
 
                 equal_expr(r1, r2)
             },
-            (Expr::Grouping { expression: expr1 }, Expr::Grouping { expression: expr2 }) => equal_expr(expr1, expr2),
-            (Expr::Binary { left: l1, operator: op1, right: r1 }, Expr::Binary { left: l2, operator: op2, right: r2 }) => {
+            (Expr::Grouping { expression: expr1, .. }, Expr::Grouping { expression: expr2, .. }) => equal_expr(expr1, expr2),
+            (Expr::Binary { left: l1, operator: op1, right: r1, .. }, Expr::Binary { left: l2, operator: op2, right: r2, .. }) => {
                 if op1.typ != op2.typ {
                     return false;
                 }
@@ -953,4 +1272,692 @@ mod tests {
             (_, _) => false
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_round_trip() {
+        let source = "print 12 - (3 - 1) * -(2 + 1);\nprint !true == (1 < 2), \"x\\t\\\"y\\\"\\n\";\nif (soro > 0) {\n    print format(\"{}\", soro);\n    ke;\n} else faran;\nwhile (fu != 0.5) {\n    if (false) print 1; else {\n        f()(1, 2);\n    }\n}\n";
+        let printed = |source: &str| -> String {
+            super::parse(crate::scanner::scan(source)).unwrap().iter().map(|stmt| stmt.fmt_source(0) + "\n").collect()
+        };
+
+        let once = printed(source);
+        assert_eq!(once, source);
+        assert_eq!(printed(&once), once);
+
+        let source = "print nan != nan, inf, -inf;\n";
+        assert_eq!(printed(source), source);
+        let value = super::parse(crate::scanner::scan(&printed(source))).unwrap().into_iter().next().map(|stmt| stmt.fmt_tree(0));
+        assert_eq!(value.as_deref(), Some("Print (!= nan nan) inf (- inf)"));
+    }
+
+    #[test]
+    fn test_block_recovery() {
+        let (stmts, errors) = parse_recovering("{ print 1 print 2; print 3; } print 4;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(stmts.iter().map(|stmt| stmt.fmt_source(0)).collect::<Vec<_>>(), vec!["{\n    print 3;\n}", "print 4;"]);
+
+        // the missing ';' doesn't swallow the closing brace
+        let (stmts, errors) = parse_recovering("while (soro) { faran; print 1 } print 2;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(stmts.iter().map(|stmt| stmt.fmt_source(0)).collect::<Vec<_>>(), vec!["while (soro) {\n    faran;\n}", "print 2;"]);
+    }
+
+    #[test]
+    fn test_spans() {
+        let stmts = super::parse(crate::scanner::scan("print 1 +\n2;\nwhile (soro)\n{\n    faran;\n}")).unwrap();
+        assert_eq!((stmts[0].span(), stmts[1].span()), (Span { start: 0, end: 1, column: 0, offset: 0, len: 12 }, Span { start: 2, end: 5, column: 0, offset: 13, len: 27 }));
+        if let crate::ast::Stmt::While { condition, .. } = &stmts[1] {
+            assert!(condition.span() == Span { start: 2, end: 2, column: 7, offset: 20, len: 4 });
+        }
+    }
+
+    #[test]
+    fn test_parse_hostile_input() {
+        let inputs = [
+            "print (1 + ;".to_string(),
+            "if (true print 1; else".into(),
+            "\"unterminated".into(),
+            "é ü 𝄞 print \"é\";".into(),
+            "(".repeat(100_000),
+            "!".repeat(100_000),
+            "{".repeat(100_000),
+            "f(((((((".into(),
+            format!("print 1{};", " + 1".repeat(100_000)),
+            format!("print f{};", "()".repeat(100_000)),
+            format!("print a{};", ".a".repeat(100_000)),
+            format!("a{} = 1;", ".a".repeat(100_000)),
+            "".into()
+        ];
+
+        for input in inputs {
+            let _ = super::parse(crate::scanner::scan(&input));
+        }
+
+        // token streams without an EOF are accepted too
+        let _ = super::parse(vec![Rc::new(Token { lexeme: "(".into(), span: Span::default(), typ: TokenType::LeftParen })]);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let tokens = crate::scanner::scan("print ((1));");
+        assert_eq!(super::parse_with_max_depth(tokens.clone(), 4).unwrap().len(), 1);
+        assert!(super::parse_with_max_depth(tokens, 3).is_err());
+
+        // field chains take a level per access like call chains
+        let tokens = crate::scanner::scan("print a.b.c;");
+        assert_eq!(super::parse_with_max_depth(tokens.clone(), 4).unwrap().len(), 1);
+        assert!(super::parse_with_max_depth(tokens, 3).is_err());
+        assert!(super::parse(crate::scanner::scan(&format!("print a{};", ".a".repeat(100_000)))).is_err());
+    }
+
+    #[test]
+    fn test_node_count() {
+        let stmts = super::parse(crate::scanner::scan("print -(1 + 2); if (true) { f(3, 4); }")).unwrap();
+        let counts: Vec<usize> = stmts.iter().map(|stmt| stmt.node_count()).collect();
+        assert_eq!(counts, vec![6, 8]);
+    }
+
+    #[test]
+    fn test_compile_deterministic() {
+        let compile = || {
+            let compiler = crate::ast::Compiler::default();
+            let stmts = super::parse(crate::scanner::scan("if (soro) { print 1; } while (soro) { faran; } if (soro) print 2;")).unwrap();
+            stmts.iter().map(|stmt| compiler.compile(stmt)).collect::<String>()
+        };
+
+        let asm = compile();
+        assert_eq!(asm, compile());
+        assert!(asm.contains(".end_1:") && asm.contains(".body_2:") && asm.contains(".end_3:"));
+    }
+
+    #[test]
+    fn test_fmt_tree() {
+        let stmts = super::parse(crate::scanner::scan("fn f(a) { if (a) return -a; else print a, 1; } for (;;) break;")).unwrap();
+        let tree: Vec<String> = stmts.iter().map(|stmt| stmt.fmt_tree(0)).collect();
+        assert_eq!(tree, [
+            "Fn f(a)\n  Block\n    If a\n      Return (- a)\n    Else\n      Print a 1",
+            "While true\n  Break"
+        ]);
+    }
+
+    #[test]
+    fn test_parse_spanned() {
+        let stmts = super::parse_spanned(crate::scanner::scan("print 1; print 2;\nif (soro) {\n    faran;\n}\n"), DEFAULT_MAX_DEPTH).unwrap();
+        let spans: Vec<_> = stmts.into_iter().map(|(_, span)| span).collect();
+        assert_eq!(spans, vec![0..=0, 0..=0, 1..=3]);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_round_trip() {
+        let source = "print \"a\", 1.5; if (soro < 2) { ke; } else faran; print clock();";
+        let stmts = super::parse(crate::scanner::scan(source)).unwrap();
+        let json = serde_json::to_string(&stmts).unwrap();
+        let back: Vec<crate::ast::Stmt> = serde_json::from_str(&json).unwrap();
+        let fmt = |stmts: &[crate::ast::Stmt]| stmts.iter().map(|stmt| stmt.fmt_source(0)).collect::<Vec<_>>();
+        assert_eq!(fmt(&back), fmt(&stmts));
+    }
+
+    #[test]
+    fn test_strength_reduction() {
+        let compile = |source: &str, options: crate::ast::CompileOptions| {
+            let compiler = crate::ast::Compiler::new(options);
+            super::parse(crate::scanner::scan(source)).unwrap().iter().map(|stmt| compiler.compile(stmt)).collect::<String>()
+        };
+
+        let asm = compile("print soro * 8; print 4 * soro; print soro / 4; print soro * 6;", Default::default());
+        assert!(asm.contains("shl rax, 3") && asm.contains("shl rax, 2") && asm.contains("sar rax, 2"));
+        assert!(!asm.contains("idiv") && asm.matches("imul").count() == 1);
+
+        assert!(compile("print 2 * (soro + 1);", Default::default()).contains("imul"));
+
+        let checked = crate::ast::CompileOptions { checked_arith: true, ..Default::default() };
+        assert!(!compile("print soro * 8;", checked).contains("shl"));
+    }
+
+    #[test]
+    fn test_print_dispatch() {
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { builtin_print: true, ..Default::default() });
+        let asm: String = super::parse(crate::scanner::scan("print \"a%d\"; print 0.5; print soro < 1; print soro;")).unwrap()
+            .iter().map(|stmt| compiler.compile(stmt)).collect();
+
+        assert_eq!(asm.matches("call ceya_write_stdout").count(), 3);
+        assert!(asm.contains("cmovnz rdx, r10") && asm.contains("call ceya_print_num"));
+        let data = compiler.data.take().emit();
+        assert!(data.contains("db \"a%d\", 10, 0") && data.contains("db \"0.5\", 10, 0") && data.contains("db \"false\", 10, 0"));
+    }
+
+    #[test]
+    fn test_comment_escapes() {
+        let compiler = crate::ast::Compiler::default();
+        let asm: String = super::parse(crate::scanner::scan("print \"a\\nmov rax, 60\\r\\nsyscall\";")).unwrap()
+            .iter().map(|stmt| compiler.compile(stmt)).collect();
+
+        assert!(asm.contains("   ; print a\\nmov rax, 60\\r\\nsyscall\n"));
+        assert!(!asm.lines().any(|line| line.trim_start().starts_with("mov rax, 60") || line.trim() == "syscall"));
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(crate::run("print 0.1 + 0.2; print -0; print 1 / 0; print 100000000000000000000 * 10; print 0.0000001;"), "0.30000000000000004\n0\ninf\n1e21\n1e-7\n");
+
+        // what doesn't fit a compiled integer is printed as text, the same text as `sim`
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { builtin_print: true, ..Default::default() });
+        let asm: String = super::parse(crate::scanner::scan("print 0.1 + 0.2; print 100000000000000000000 * 10; print 4611686018427387904;")).unwrap()
+            .iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert_eq!(asm.matches("call ceya_print_num").count(), 1);
+        let data = compiler.data.take().emit();
+        assert!(data.contains("db \"0.30000000000000004\", 10, 0") && data.contains("db \"1e21\", 10, 0"));
+    }
+
+    #[test]
+    fn test_compile_fractions() {
+        let compiler = crate::ast::Compiler::default();
+        let asm: String = super::parse(crate::scanner::scan("print soro * 2.5; print soro + 5000000000; print soro < 100000000000000000000000;")).unwrap()
+            .iter().map(|stmt| compiler.compile(stmt)).collect();
+        // truncated with a warning, loaded whole, out of range
+        assert!(asm.contains("   push 2\n") && asm.contains("   mov rax, 5000000000\n   push rax\n"));
+        assert_eq!((compiler.warnings.get(), compiler.errors.get()), (1, 1));
+
+        // nan and inf would compare like 0, they fail the build
+        let compiler = crate::ast::Compiler::default();
+        for stmt in super::parse(crate::scanner::scan("let x = nan; print x == x; print soro < -inf;")).unwrap() {
+            compiler.compile(&stmt);
+        }
+        assert_eq!((compiler.warnings.get(), compiler.errors.get()), (0, 2));
+    }
+
+    #[test]
+    fn test_xor() {
+        assert_eq!(crate::run("print true xor false; print 1 == 1 xor 2 == 2; print \"a\" xor fu;"), "true\nfalse\ntrue\n");
+
+        let stmts = super::parse(crate::scanner::scan("print (1 xor 2) == soro xor 3;")).unwrap();
+        assert_eq!(stmts[0].fmt_source(0), "print (1 xor 2) == soro xor 3;");
+
+        let compiler = crate::ast::Compiler::default();
+        let asm: String = stmts.iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert!(asm.contains("   xor al, bl\n") && asm.contains("cmovnz"));
+    }
+
+    #[test]
+    fn test_let() {
+        assert_eq!(crate::run("let x = 5; print x + 1; { let x = 2; print x; } print x; let y; print y;"), "6\n2\n5\nnull\n");
+
+        let compiler = crate::ast::Compiler::default();
+        let stmts = super::parse(crate::scanner::scan("let x = 5; { let x = x + 1; print x; } print x == 5; let h = 0.5;")).unwrap();
+        assert_eq!(stmts[0].fmt_source(0), "let x = 5;");
+        let asm: String = stmts.iter().map(|stmt| compiler.compile(stmt)).collect();
+        // the inner `x` gets its own storage, initialized from the outer one
+        assert!(asm.contains("   push qword [var_0]\n") && asm.contains("   mov [var_1], rax\n") && asm.contains("   mov [var_2], rax\n"));
+        assert_eq!(compiler.warnings.get(), 1);
+        assert!(compiler.data.take().labels().contains(&"var_2"));
+    }
+
+    #[test]
+    fn test_functions() {
+        let source = "fn fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(10); \
+            fn adder(n) { fn add(x) { return x + n; } return add; } let add2 = adder(2); print add2(3); print adder; \
+            fn nothing() { } print nothing(); let f = fib; print f(1, 2); print 1(2);";
+        assert_eq!(crate::run(source), "55\n5\nfun adder\nnull\n[line 0:246] Error: Expected 1 arguments, but found 2.\n");
+        assert_eq!(crate::run("print 1(2); print 3;"), "[line 0:9] Error: Can only call functions.\n");
+        // runaway recursion is stopped, not a crash
+        assert_eq!(crate::run("fn down(n) { return down(n + 1); } print down(0);"), "[line 0:30] Error: Stack overflow calling 'down', more than 200 nested calls.\n");
+
+        let stmts = super::parse(crate::scanner::scan("fn add(a, b) { return a + b; }")).unwrap();
+        assert_eq!(stmts[0].fmt_source(0), "fn add(a, b) {\n    return a + b;\n}");
+        assert!(super::parse(crate::scanner::scan("fn f(a, a) { }")).is_err());
+
+        // returns leave nested blocks and loops, but not the top level
+        let source = "fn find(n) { let i = 0; while (true) { { if (i * i >= n) { return i; } } i = i + 1; } } print find(50);";
+        assert_eq!(crate::run(source), "8\n");
+        let (stmts, errors) = parse_recovering("return 1; print 2; fn f() { return; }");
+        assert_eq!((stmts.len(), errors.len()), (2, 1));
+    }
+
+    #[test]
+    fn test_logical() {
+        // the deciding operand is the result, and the right one only runs when needed
+        let source = "fn t() { print \"t\"; return true; } print 2 or 7; print fu or 7; print fu and t(); print 1 and t(); print t() or t();";
+        assert_eq!(crate::run(source), "2\n7\nnull\nt\ntrue\nt\ntrue\n");
+
+        // `and` binds tighter than `or`, both looser than `xor`
+        let stmts = super::parse(crate::scanner::scan("print a or b and c xor d; print (a or b) and c; print 1 + (a or b);")).unwrap();
+        let Stmt::Print { expression, .. } = &stmts[0] else { panic!("print expected") };
+        assert_eq!(format!("{:?}", expression), "(or a (and b (xor c d)))");
+        assert_eq!(stmts[1].fmt_source(0), "print (a or b) and c;");
+        assert_eq!(stmts[2].fmt_source(0), "print 1 + (a or b);");
+    }
+
+    #[test]
+    fn test_for() {
+        let source = "fn first(n) { for (let i = n; i > 0; i + 1) { return i; } return 0; } print first(3); print first(0); \
+            fn forever() { for (;;) return 1; } print forever();";
+        assert_eq!(crate::run(source), "3\n0\n1\n");
+
+        // a loop with the increment in a block scoping the initializer
+        let stmts = super::parse(crate::scanner::scan("for (let i = 0; i < 3; i + 1) print i;")).unwrap();
+        assert_eq!(stmts[0].fmt_source(0), "{\n    let i = 0;\n    for (; i < 3; i + 1) print i;\n}");
+
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let asm: String = stmts.iter().map(|stmt| compiler.compile(stmt)).collect();
+        // the increment's value is dropped before the condition
+        assert!(asm.contains("   mov [var_0], rax\n   jmp .cond_1\n") && asm.contains("   push rax\n   pop rax\n.cond_1:\n") && asm.contains(".next_1:\n"));
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let source = "for (let i = 0; i < 10; i = i + 1) { if (i == 2) continue; if (i == 5) break; print i; } \
+            let n = 0; while (true) { n = n + 1; if (n < 3) continue; break; } print n; \
+            fn f() { for (;;) { while (true) break; return 7; } } print f();";
+        assert_eq!(crate::run(source), "0\n1\n3\n4\n3\n7\n");
+
+        // only inside loops, and not through a function declared in one
+        let (stmts, errors) = parse_recovering("break; if (true) continue; while (true) { fn f() { break; } }");
+        assert_eq!((stmts.len(), errors.len()), (1, 3));
+
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let asm: String = super::parse(crate::scanner::scan("for (let i = 0; i < 3; i = i + 1) { continue; } while (soro) { while (soro) break; continue; }")).unwrap()
+            .iter().map(|stmt| compiler.compile(stmt)).collect();
+        // `continue` goes through the increment when there is one, `break` leaves the innermost loop
+        assert!(asm.contains("   jmp .next_1\n.next_1:\n") && asm.contains("   jmp .end_3\n") && asm.contains("   jmp .cond_2\n"));
+    }
+
+    #[test]
+    fn test_assignment() {
+        let source = "let a; let b; a = b = 2; print a + b; for (let i = 0; i < 3; i = i + 1) print i; \
+            fn bump() { a = a + 1; } bump(); print a; { let a = 0; a = 5; } print a;";
+        assert_eq!(crate::run(source), "4\n0\n1\n2\n3\n3\n");
+        assert_eq!(crate::run("c = 1;"), "[line 0:0] Error: Undefined variable 'c'.\n");
+
+        let stmts = super::parse(crate::scanner::scan("a = b = 1 + 2; print 1 + (a = 2); print -(a = 1);")).unwrap();
+        assert_eq!(stmts.iter().map(|stmt| stmt.fmt_source(0)).collect::<Vec<_>>(), ["a = b = 1 + 2;", "print 1 + (a = 2);", "print -(a = 1);"]);
+        assert!(super::parse(crate::scanner::scan("a + b = 1;")).is_err());
+
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let asm: String = super::parse(crate::scanner::scan("let x = 1; x = x + 1; x = \"s\"; y = 2;")).unwrap().iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert!(asm.contains("   pop rax\n   mov [var_0], rax\n   push rax\n"));
+        // the string changes the type, `y` was never declared
+        assert_eq!(compiler.warnings.get(), 2);
+    }
+
+    #[test]
+    fn test_compile_functions() {
+        let compiler = crate::ast::Compiler::default();
+        let source = "let g = 1; fn f(a, b) { let c = a + g; return f(c, b); } print f(1, 2); print f(1); print missing();";
+        let asm: String = super::parse(crate::scanner::scan(source)).unwrap().iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert!(asm.contains("   call fn_f\n") && asm.contains("   push 0\n"));
+        // wrong arity, undeclared function
+        assert_eq!(compiler.warnings.get(), 2);
+
+        let functions = compiler.functions.take();
+        assert!(functions.starts_with("fn_f:\n   push rbp\n   mov rbp, rsp\n   sub rsp, 8\n"));
+        // parameters in the shadow space, the local in the frame, the global in .bss
+        assert!(functions.contains("   push qword [rbp + 16]\n") && functions.contains("   mov [rbp - 8], rax\n") && functions.contains("   push qword [var_0]\n"));
+        assert!(functions.contains("   jmp fn_f_return\n") && functions.contains("   call fn_f\n"));
+    }
+
+    #[test]
+    fn test_compile_strings() {
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { builtin_print: true, ..Default::default() });
+        let source = "let s = \"hi\"; print s; eprint s; print s == \"hi\";";
+        let asm: String = super::parse(crate::scanner::scan(source)).unwrap().iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert!(asm.contains("   lea rax, [str_0]\n   push rax\n") && asm.matches("call ceya_print_str").count() == 2);
+        // comparing addresses isn't comparing strings
+        assert_eq!(compiler.warnings.get(), 1);
+
+        let compiler = crate::ast::Compiler::default();
+        let asm: String = super::parse(crate::scanner::scan(source)).unwrap().iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert!(asm.contains("   pop rdx\n   mov r9, -11\n   call ceya_print_str\n") && !asm.contains("printf"));
+        assert!(compiler.data.take().emit().contains("str_0 db \"hi\", 0"));
+    }
+
+    #[test]
+    fn test_lists() {
+        let source = "let l = [1, \"two\", [3],]; print l; l[2][0] = l[0] + 1; print l[2]; print len(l) + push(l, fu); print l[3]; print [] or chars(\"hé\");";
+        assert_eq!(crate::run(source), "[1, \"two\", [3]]\n[2]\n7\nnull\n[\"h\", \"é\"]\n");
+        // lists are shared, and equal only to themselves
+        assert_eq!(crate::run("let a = [1]; let b = a; b[0] = 2; print a[0]; print a == b; print a == [2];"), "2\ntrue\nfalse\n");
+        // a bad index ends the program
+        for (source, error) in [
+            ("print l[1];", "[line 0:19] Error: Index 1 out of bounds for length 1."),
+            ("print l[-1];", "[line 0:19] Error: Index -1 out of bounds for length 1."),
+            ("print l[0.5];", "[line 0:19] Error: Index 0.5 out of bounds for length 1."),
+            ("print l[\"0\"];", "[line 0:19] Error: List indices must be numbers."),
+            ("print 1[0];", "[line 0:19] Error: Can only index strings, lists and maps."),
+            ("l = 2; l[0] = 1;", "[line 0:20] Error: Can only assign elements of lists and maps.")
+        ] {
+            assert_eq!(crate::run(&format!("let l = [1]; {} print 0;", source)), format!("{}\n", error));
+        }
+
+        let stmts = super::parse(crate::scanner::scan("a[i][j] = [1, b[2]];")).unwrap();
+        assert_eq!(stmts[0].fmt_source(0), "a[i][j] = [1, b[2]];");
+        assert!(super::parse(crate::scanner::scan("[1, 2] = 3;")).is_err());
+        assert!(super::parse(crate::scanner::scan("print [1 2];")).is_err());
+    }
+
+    #[test]
+    fn test_string_indexing() {
+        let source = "let s = \"héllo\"; print s[1]; print s[1..3]; print s[..2] + s[3..]; print s[..]; print [1, 2, 3][1..]; print s[5..5] == \"\";";
+        assert_eq!(crate::run(source), "é\nél\nhélo\nhéllo\n[2, 3]\ntrue\n");
+        // out of bounds or reversed slices end the program
+        for (source, error) in [
+            ("print s[2];", "[line 0:20] Error: Index 2 out of bounds for length 2."),
+            ("print s[1..3];", "[line 0:20] Error: Slice 1..3 out of bounds for length 2."),
+            ("print s[2..1];", "[line 0:20] Error: Slice 2..1 out of bounds for length 2."),
+            ("print s[\"0\"..];", "[line 0:20] Error: Slice bounds must be numbers."),
+            ("print 1[0..];", "[line 0:20] Error: Can only slice strings and lists."),
+            ("s[0] = \"c\";", "[line 0:14] Error: Can only assign elements of lists and maps.")
+        ] {
+            assert_eq!(crate::run(&format!("let s = \"ab\"; {} print 0;", source)), format!("{}\n", error));
+        }
+        assert_eq!(crate::run("let l = [1, 2]; let m = l[..]; m[0] = 3; print l;"), "[1, 2]\n");
+
+        for source in ["s[a..b];", "s[..b];", "s[a..];", "s[..];"] {
+            assert_eq!(super::parse(crate::scanner::scan(source)).unwrap()[0].fmt_source(0), source);
+        }
+        assert_eq!(super::parse(crate::scanner::scan("s[1..];")).unwrap()[0].fmt_tree(0), "Expression ([..] s 1 _)");
+        assert!(super::parse(crate::scanner::scan("s[0..1] = \"a\";")).is_err());
+        assert!(super::parse(crate::scanner::scan("print s[0..1;")).is_err());
+
+        let compiler = crate::ast::Compiler::default();
+        for stmt in super::parse(crate::scanner::scan("let s = \"ab\"; print s[0]; print [1][0..];")).unwrap() {
+            compiler.compile(&stmt);
+        }
+        assert_eq!(compiler.errors.get(), 2);
+    }
+
+    #[test]
+    fn test_structs() {
+        let source = "struct Point { x, y, } let p = Point { y: 2, x: 1 }; p.x = p.x + p.y; print p; print p.x; print Point { x: \"a\" }; print Point;";
+        assert_eq!(crate::run(source), "Point { x: 3, y: 2 }\n3\nPoint { x: \"a\", y: null }\nstruct Point\n");
+        // instances are shared, and equal only to themselves
+        assert_eq!(crate::run("struct Box {} struct Pair { a, b } let p = Pair { a: Box {} }; let q = p; q.b = p; print p.b == q; print p.a == Box {}; print Box {};"), "true\nfalse\nBox {}\n");
+        // unknown fields or instances of something else end the program
+        for (source, error) in [
+            ("print p.y;", "[line 0:41] Error: Struct P has no field 'y'."),
+            ("print P { y: 1 };", "[line 0:41] Error: Struct P has no field 'y'."),
+            ("print 1.x;", "[line 0:41] Error: Only struct instances have fields."),
+            ("print p { x: 1 };", "[line 0:41] Error: Can only instantiate structs, not 'P { x: 1 }'."),
+            ("p.y = 2;", "[line 0:35] Error: Struct P has no field 'y'.")
+        ] {
+            assert_eq!(crate::run(&format!("struct P {{ x }} let p = P {{ x: 1 }}; {} print p.x;", source)), format!("{}\n", error));
+        }
+
+        for source in ["struct P { x, y }", "struct E {}", "print P { x: 1, y: a.b }.x;", "a.b.c = E {};"] {
+            assert_eq!(super::parse(crate::scanner::scan(source)).unwrap()[0].fmt_source(0), source);
+        }
+        assert_eq!(super::parse(crate::scanner::scan("struct P { x } p.x = P { x: 1 };")).unwrap().iter().map(|stmt| stmt.fmt_tree(0)).collect::<Vec<_>>(), ["Struct P {x}", "Expression (.= p x (new P x 1))"]);
+        for source in ["struct P { x, x }", "P { x: 1, x: 2 };", "P { x };", "a.1;", "(a) { x: 1 };"] {
+            assert!(super::parse(crate::scanner::scan(source)).is_err(), "{}", source);
+        }
+
+        let compiler = crate::ast::Compiler::default();
+        for stmt in super::parse(crate::scanner::scan("struct P { x } print P { x: 1 }.x;")).unwrap() {
+            compiler.compile(&stmt);
+        }
+        assert_eq!(compiler.errors.get(), 2);
+    }
+
+    #[test]
+    fn test_operator_overloading() {
+        let source = "struct V { x, fn __add__(a, b) { return V { x: a.x + b.x }; } fn __eq__(a, b) { return a.x == b.x; } } let v = V { x: 1 } + V { x: 2 }; print v; print v == V { x: 3 }; print v != V { x: 3 };";
+        assert_eq!(crate::run(source), "V { x: 3 }\ntrue\nfalse\n");
+        // only the left operand's struct is asked, the other operators give what they gave before
+        assert_eq!(crate::run("struct N { fn __ne__(a, b) { return 1; } } let n = N {}; print n != 2; print 2 != n; print n - n; print n == n;"), "1\ntrue\nnull\ntrue\n");
+
+        for source in ["struct V {\n    x, y,\n    fn __sub__(a, b) {\n        return fu;\n    }\n}", "struct E {\n    fn __lt__(a, b) {\n    }\n}"] {
+            assert_eq!(super::parse(crate::scanner::scan(source)).unwrap()[0].fmt_source(0), source);
+        }
+        assert_eq!(super::parse(crate::scanner::scan("struct V { x, fn __eq__(a, b) {} }")).unwrap()[0].fmt_tree(0), "Struct V {x}\n  Fn __eq__(a, b)\n    Block");
+        for source in ["struct V { fn add(a, b) {} }", "struct V { fn __add__(a) {} }", "struct V { fn __add__(a, b) {} x }"] {
+            assert!(super::parse(crate::scanner::scan(source)).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_multiple_returns() {
+        let source = "fn pair(a) { return a, \"b\"; } let (x, y) = pair(1); print y + x; print pair(2); fn f() { let (a, b) = pair(3); return b, a; } let (b, a) = f(); print a;";
+        assert_eq!(crate::run(source), "b1\n(2, \"b\")\n3\n");
+        // unpacking anything else, or a wrong number of values, ends the program
+        assert_eq!(crate::run("fn pair() { return 1, 2; } let (a, b, c) = pair(); print c;"), "[line 0:27] Error: Expected 3 values, but found 2.\n");
+        assert_eq!(crate::run("let (d, e) = 1; print d;"), "[line 0:0] Error: Can only unpack the values a function returns together.\n");
+
+        for source in ["let (a, b) = f(1);", "fn f() {\n    return 1, g(), 3;\n}"] {
+            assert_eq!(super::parse(crate::scanner::scan(source)).unwrap()[0].fmt_source(0), source);
+        }
+        for source in ["let (a) = f();", "let (a, 1) = f();", "let (a, b);", "fn f() { return 1,; }"] {
+            assert!(super::parse(crate::scanner::scan(source)).is_err(), "{}", source);
+        }
+
+        // the second value comes back in rdx
+        let compiler = crate::ast::Compiler::default();
+        let asm: String = super::parse(crate::scanner::scan("fn pair(a) { return a, a + 1; } let (x, y) = pair(1); print y;")).unwrap().iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert!(asm.contains("   call fn_pair\n") && asm.contains("   push rdx\n"));
+        assert!(compiler.functions.take().contains("   mov rdx, rax\n   pop rax\n   jmp fn_pair_return\n"));
+        assert_eq!(compiler.errors.get(), 0);
+
+        let compiler = crate::ast::Compiler::default();
+        for stmt in super::parse(crate::scanner::scan("fn one() { return 1; } fn three() { return 1, 2, 3; } let (a, b) = one(); let (c, d) = 5; let (e, f, g) = three();")).unwrap() {
+            compiler.compile(&stmt);
+        }
+        assert_eq!(compiler.errors.get(), 3);
+    }
+
+    #[test]
+    fn test_annotations() {
+        for source in ["let x: num = 1;", "fn add(a: num, b): num {\n    return a + b;\n}", "fn f(l: list) {\n}"] {
+            assert_eq!(super::parse(crate::scanner::scan(source)).unwrap()[0].fmt_source(0), source);
+        }
+        for source in ["let x: int = 1;", "let x: = 1;", "fn f(a: 1) {}"] {
+            assert!(super::parse(crate::scanner::scan(source)).is_err(), "{}", source);
+        }
+        // programs breaking their annotations don't run
+        assert_eq!(crate::run("let x: num = \"a\"; fn f(a: str): bool { return a; } print f(x);"), "[line 0:13] Error: Cannot initialize 'x' of type num with a str.\n\
+            [line 0:46] Error: Cannot return a str from 'f', declared to return bool.\n[line 0:59] Error: Argument 1 of 'f' must be a str, found num.\n");
+    }
+
+    #[test]
+    fn test_maps() {
+        let source = "let m = {\"one\": 1, 2: \"two\",}; m[\"one\"] = m[\"one\"] + 1; m[-0] = {}; print m; print m[0]; print m[\"three\"]; print {} or len(m);";
+        assert_eq!(crate::run(source), "{\"one\": 2, 2: \"two\", 0: {}}\n{}\nnull\n3\n");
+        assert_eq!(crate::run("let a = {1: 2}; let b = a; b[1] = 3; print a[1]; print a == b; print a == {1: 3}; let k = keys(a); print k;"), "3\ntrue\nfalse\n[1]\n");
+        for (source, column) in [("print m[[]];", 18), ("m[fu] = 1;", 12), ("print {[]: 1};", 18)] {
+            assert_eq!(crate::run(&format!("let m = {{}}; {} print len(m);", source)), format!("[line 0:{}] Error: Map keys must be strings, numbers or booleans.\n", column));
+        }
+
+        let stmts = super::parse(crate::scanner::scan("let m = {a: {}, \"b\": [1]};")).unwrap();
+        assert_eq!(stmts[0].fmt_source(0), "let m = {a: {}, \"b\": [1]};");
+        assert!(super::parse(crate::scanner::scan("print {1 2};")).is_err());
+        assert!(super::parse(crate::scanner::scan("print {1};")).is_err());
+    }
+
+    #[test]
+    fn test_compile_maps() {
+        let compiler = crate::ast::Compiler::default();
+        let source = "let m = {1: 2}; print m[1];";
+        super::parse(crate::scanner::scan(source)).unwrap().iter().for_each(|stmt| { compiler.compile(stmt); });
+        assert_eq!(compiler.errors.get(), 1);
+    }
+
+    #[test]
+    fn test_compile_lists() {
+        let compiler = crate::ast::Compiler::default();
+        let source = "let l = [4, 5]; l[1] = l[0]; print len(l); print l;";
+        let asm: String = super::parse(crate::scanner::scan(source)).unwrap().iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert!(asm.contains("   mov rcx, 3\n   call ceya_alloc\n   mov rcx, rax\n   pop rax\n   mov [rcx + 16], rax\n   pop rax\n   mov [rcx + 8], rax\n   mov rax, 2\n   mov [rcx], rax\n"));
+        assert_eq!(asm.matches("   mov rbx, [rcx]\n   cmp rax, rbx\n   jae ceya_bounds_fail\n").count(), 2);
+        assert!(asm.contains("   pop rax\n   push qword [rax]\n"));
+        // printing a list is reported
+        assert_eq!(compiler.warnings.get(), 1);
+        assert!(compiler.alloc.get());
+    }
+
+    #[test]
+    fn test_compile_runtime_calls() {
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { builtin_print: true, ..Default::default() });
+        let source = "let name = input(); print name; let n = 4; print 8 / n;";
+        let asm: String = super::parse(crate::scanner::scan(source)).unwrap().iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert!(asm.contains("   call ceya_read_line\n   push rax\n") && asm.contains("   call ceya_print_str\n"));
+        assert!(asm.contains("   test rbx, rbx\n   jz ceya_div_zero_fail\n   cqo\n"));
+        assert!(compiler.input.get() && compiler.warnings.get() == 0);
+    }
+
+    #[test]
+    fn test_depth() {
+        assert_eq!(crate::run("print depth; 1; 2; print depth;"), "0\n2\n");
+
+        let compiler = crate::ast::Compiler::default();
+        let stmts = super::parse(crate::scanner::scan("print 8 * depth;")).unwrap();
+        assert_eq!(stmts[0].fmt_source(0), "print 8 * depth;");
+        let asm: String = stmts.iter().map(|stmt| compiler.compile(stmt)).collect();
+        // the 8 is pushed before the depth is taken, so no shift
+        assert!(asm.contains("   mov rax, [stack_base]\n") && !asm.contains("shl"));
+        assert!(compiler.data.take().labels().contains(&"stack_base"));
+    }
+
+    #[test]
+    fn test_stack_words() {
+        assert_eq!(crate::run("1; 2; swap; print soro; print soro;"), "1\n2\n");
+        assert_eq!(crate::run("1; 2; over; print soro; print depth;"), "1\n2\n");
+        assert_eq!(crate::run("1; 2; 3; rot; print soro; print soro; print soro;"), "1\n3\n2\n");
+        assert_eq!(crate::run("1; 2; 3; drop2; ke; print soro + soro;"), "2\n");
+        // a function's leftovers are dropped when it returns
+        assert_eq!(crate::run("fn f() { 1; 2; return depth; } print f(); print depth;"), "2\n0\n");
+        // the underflow ends the program
+        assert_eq!(crate::run("1; swap; print soro;"), "[line 0:3] Error: Stack underflow.\n");
+
+        let stmts = super::parse(crate::scanner::scan("swap; over; rot; drop2;")).unwrap();
+        assert_eq!(stmts.iter().map(|stmt| stmt.fmt_source(0)).collect::<Vec<_>>(), ["swap;", "over;", "rot;", "drop2;"]);
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let asm: Vec<String> = stmts.iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert_eq!(asm, [
+            "   pop rax\n   pop rbx\n   push rax\n   push rbx\n",
+            "   push qword [rsp + 8]\n",
+            "   pop rcx\n   pop rbx\n   pop rax\n   push rbx\n   push rcx\n   push rax\n",
+            "   add rsp, 16\n"
+        ]);
+        assert!(matches!(parse_recovering("swap 1;").1[..], [CeyaError::Parse { .. }]));
+    }
+
+    #[test]
+    fn test_stack_like_com() {
+        // the left operand and the arguments before are on the stack, where `soro` pops and `depth` counts them
+        assert_eq!(crate::run("5; print 1 - soro;"), "4\n");
+        assert_eq!(crate::run("fn sub(a, b) { return a - b; } 10; print sub(1, soro);"), "9\n");
+        assert_eq!(crate::run("1; 2; print depth + depth;"), "5\n");
+        assert_eq!(crate::run("print 1 - soro;"), "[line 0:6] Error: Stack underflow.\n");
+
+        // the same reads in the code `com` emits, without any instruction for `soro`
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let stmts = super::parse(crate::scanner::scan("print 1 - soro;")).unwrap();
+        assert!(compiler.compile(&stmts[0]).starts_with("   push 1\n   pop rbx\n   pop rax\n   sub rax, rbx\n"));
+    }
+
+    #[test]
+    fn test_compile_64_bit() {
+        // negative results stay negative for `idiv` and `cmp`, which work on the whole register
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let stmts = super::parse(crate::scanner::scan("(0 - soro) * soro; fu;")).unwrap();
+        assert_eq!(compiler.compile(&stmts[0]), "   push 0\n   pop rbx\n   pop rax\n   sub rax, rbx\n   push rax\n   pop rbx\n   pop rax\n   imul rax, rbx\n   push rax\n");
+        assert_eq!(compiler.compile(&stmts[1]), "   push 0\n");
+    }
+
+    #[test]
+    fn test_eliminate_branches() {
+        let stmts = super::parse(crate::scanner::scan("if (1 < 2) print 1; else print 2; while (1 > 2) print 3; if (soro) { if (fu) print 4; } while (2 * 2) ke;")).unwrap();
+        let folded: Vec<String> = stmts.into_iter().map(|stmt| stmt.eliminate_branches().fmt_source(0)).collect();
+        assert_eq!(folded, vec!["print 1;", "{\n}", "if (soro) {\n    {\n    }\n}", "while (true) ke;"]);
+
+        assert_eq!(crate::run("if (\"a\" == \"a\") print 1; else print 2;"), "1\n");
+    }
+
+    #[test]
+    fn test_constant_condition_warnings() {
+        let warnings = |source: &str| {
+            let compiler = crate::ast::Compiler::default();
+            for stmt in super::parse(crate::scanner::scan(source)).unwrap() {
+                compiler.compile(&stmt);
+            }
+            compiler.warnings.get()
+        };
+
+        assert_eq!(warnings("if (1 < 2) print 1; while (fu) ke; while (true == true) ke; let x = soro; if ((x) >= x) ke;"), 4);
+        // different stack values, a call or the idiomatic infinite loop
+        assert_eq!(warnings("if (soro == soro) ke; fn f() { return 1; } if (f() != f()) ke; if (depth < depth) ke; while (true) ke; if (soro < 1) ke;"), 0);
+    }
+
+    #[test]
+    fn test_eprint() {
+        // only `print` is captured
+        assert_eq!(crate::run("eprint \"{}!\", 1; print 2;"), "2\n");
+
+        let compiler = crate::ast::Compiler::default();
+        let stmts = super::parse(crate::scanner::scan("eprint 1 < 2; eprint soro; print soro;")).unwrap();
+        assert_eq!(stmts[0].fmt_source(0), "eprint 1 < 2;");
+        let asm: String = stmts.iter().map(|stmt| compiler.compile(stmt)).collect();
+        // stderr goes through the emitted routines even with printf
+        assert!(asm.contains("   call ceya_write_stderr\n") && asm.contains("   mov r9, -12\n   call ceya_print_num\n") && asm.contains("   mov r9, -11\n   call ceya_print_num\n"));
+        assert!(compiler.stderr.get());
+    }
+
+    #[test]
+    fn test_fused_branch() {
+        let compiler = crate::ast::Compiler::default();
+        let asm: String = super::parse(crate::scanner::scan("if (soro < 1) print 1; while (soro != 2) faran;")).unwrap()
+            .iter().map(|stmt| compiler.compile(stmt)).collect();
+        assert!(asm.contains("   jge .end_1\n") && asm.contains("   jne .body_2\n"));
+        assert!(!asm.contains("set"));
+    }
+
+    #[test]
+    fn test_compile_logical() {
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let asm: String = super::parse(crate::scanner::scan("if (soro < 1 and soro > 2) print 1; if (soro < 1 or soro > 2) print 2; print soro and 3;")).unwrap()
+            .iter().map(|stmt| compiler.compile(stmt)).collect();
+        // `and` leaves as soon as a side fails, `or` skips the right side when the left holds
+        assert!(asm.contains("   jge .end_1\n") && asm.contains("   jle .end_1\n"));
+        assert!(asm.contains("   jl .end_3\n") && asm.contains("   jle .end_2\n") && asm.contains(".end_3:\n"));
+        // as a value, the left operand is kept when it decides
+        assert!(asm.contains("   mov rax, [rsp]\n   test rax, rax\n   jz .end_4\n   pop rax\n   push 3\n.end_4:\n"));
+    }
+
+    #[test]
+    fn test_number_literals() {
+        assert_eq!(crate::run("print 0xFF + 0b11; print 1_000_000;"), "258\n1000000\n");
+
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let stmts = super::parse(crate::scanner::scan("print soro + 0x10;")).unwrap();
+        assert!(compiler.compile(&stmts[0]).starts_with("   push 16\n"));
+        // printed back in decimal
+        assert_eq!(stmts[0].fmt_source(0), "print soro + 16;");
+    }
+
+    #[test]
+    fn test_ternary() {
+        assert_eq!(crate::run("let n = 3; print n > 2 ? \"big\" : \"small\"; print n < 2 ? 1 : n < 4 ? 2 : 3; print fu ? 1 : 0;"), "big\n2\n0\n");
+        // only the branch taken runs
+        assert_eq!(crate::run("let a = 0; true ? a = 1 : (a = 2); print a;"), "1\n");
+
+        let parse = |source: &str| super::parse_expression(crate::scanner::scan(source));
+        assert_eq!(format!("{:?}", parse("a or b ? c : d ? e : f").unwrap()), "(?: (or a b) c (?: d e f))");
+        assert_eq!(parse("a ? b : c = 1").map_err(|e| e.to_string()).unwrap_err(), "[line 0:10] Error: Invalid assignment target. (at '=')");
+        assert!(matches!(parse("a ? b"), Err(CeyaError::Parse { .. })));
+
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let stmts = super::parse(crate::scanner::scan("print soro < 1 ? 2 : 3; print true ? 4 : 5;")).unwrap();
+        assert!(compiler.compile(&stmts[0]).starts_with("   push 1\n   pop rbx\n   pop rax\n   cmp rax, rbx\n   jge .else_1\n   push 2\n   jmp .end_1\n.else_1:\n   push 3\n.end_1:\n"));
+        // a constant condition keeps the branch taken only
+        assert!(compiler.compile(&stmts[1]).starts_with("   push 4\n"));
+    }
+
+    #[test]
+    fn test_compile_not() {
+        assert_eq!(crate::run("print !0; print !1; print !fu; print !!\"a\";"), "true\nfalse\ntrue\ntrue\n");
+
+        let compiler = crate::ast::Compiler::new(crate::ast::CompileOptions { comments: crate::ast::AsmComments::Off, ..Default::default() });
+        let stmts = super::parse(crate::scanner::scan("print !soro; if (!(soro < 1)) print 1;")).unwrap();
+        assert!(compiler.compile(&stmts[0]).starts_with("   pop rax\n   cmp rax, 0\n   sete al\n   movzx rax, al\n   push rax\n"));
+        // a negated condition branches the other way
+        assert!(compiler.compile(&stmts[1]).contains("   cmp rax, rbx\n   jl .end_1\n"));
+    }
+}
@@ -0,0 +1,507 @@
+//! Support routines of compiled programs, emitted after the generated code of every executable:
+//! printing, reading stdin, allocating lists and the traps ending the program on runtime errors.
+//! Generated code calls them by their `ceya_` labels, which can't clash with the `fn_` labels of
+//! functions.
+
+use std::fmt::Write;
+
+use crate::backend::{emit_call, emit_exit, Arg, DataSection, Stream, Target};
+
+/// Prints rax in base rcx to the stream selected by r9, base 2 needing `--builtin-print`.
+pub const PRINT_NUM: &str = "ceya_print_num";
+/// Prints the zero-terminated string at rdx to the stream selected by r9.
+pub const PRINT_STR: &str = "ceya_print_str";
+/// The emitted `PRINT_NUM` when printf has the name, for the streams printf doesn't write to.
+const WRITE_NUM: &str = "ceya_write_num";
+/// The emitted `PRINT_STR` when printf has the name.
+const WRITE_STR: &str = "ceya_write_str";
+/// The emitted `PRINT_NUM` without the newline when r10 is 0, or followed by the character in r10.
+const WRITE_DIGITS: &str = "ceya_write_digits";
+/// Reads a line of stdin, returning a zero-terminated string in rax.
+pub const READ_LINE: &str = "ceya_read_line";
+/// Jumped to with an index in rax out of bounds for the length in rbx.
+pub const BOUNDS_TRAP: &str = "ceya_bounds_fail";
+/// Jumped to on signed overflow under `--checked-arith`.
+pub const OVERFLOW_TRAP: &str = "ceya_overflow_fail";
+/// Jumped to before dividing by zero.
+pub const DIV_ZERO_TRAP: &str = "ceya_div_zero_fail";
+/// Allocates rcx qwords, returning their address in rax. Clobbers rcx.
+pub const ALLOC: &str = "ceya_alloc";
+/// Jumped to when the heap `ceya_alloc` hands out is used up.
+const ALLOC_TRAP: &str = "ceya_alloc_fail";
+
+/// Lines `ceya_read_line` keeps at once, reused in turn, a power of two.
+const LINES: usize = 64;
+/// Bytes of each line, the terminating 0 included.
+const LINE_SIZE: usize = 1024;
+/// Bytes `ceya_alloc` hands out, never freed.
+const HEAP_SIZE: usize = 1 << 20;
+
+/// What a compiled program needs from the runtime.
+pub struct Runtime {
+    pub target: Target,
+    /// Linked with the C runtime: printf is available and `exit` flushes it.
+    pub crt: bool,
+    /// Print through the emitted routines instead of printf.
+    pub builtin_print: bool,
+    pub checked_arith: bool,
+    /// Writes to stderr, which always goes through the emitted routines.
+    pub stderr: bool,
+    /// Reads stdin with `input()`.
+    pub input: bool,
+    /// Allocates lists with `ceya_alloc`.
+    pub alloc: bool
+}
+
+impl Runtime {
+    fn output_routines(&self) -> bool {
+        self.builtin_print || self.stderr
+    }
+
+    /// External symbols the runtime calls.
+    pub fn externs(&self) -> Vec<&'static str> {
+        let mut externs = vec![];
+        match self.target {
+            Target::Windows => {
+                externs.push("ExitProcess");
+                if self.output_routines() || self.input {
+                    externs.push("GetStdHandle");
+                }
+                if self.output_routines() {
+                    externs.push("WriteFile");
+                }
+                if self.input {
+                    externs.push("ReadFile");
+                }
+            },
+            Target::Linux if self.crt => externs.push("exit"),
+            Target::Linux => ()
+        }
+        if !self.builtin_print {
+            externs.push("printf");
+        }
+        externs
+    }
+
+    /// The routines, with the constants they use added to `data`.
+    pub fn emit(&self, data: &mut DataSection) -> String {
+        let mut res = String::new();
+
+        if !self.builtin_print {
+            let newline = self.target.newline();
+            data.define("msg", &format!("db \"%lld\", {}, 0", newline));
+            data.define("hex_msg", &format!("db \"%llx\", {}, 0", newline));
+            data.define("str_msg", &format!("db \"%s\", {}, 0", newline));
+        }
+
+        // index in rax, length in rbx
+        writeln!(&mut res, "{}:", BOUNDS_TRAP).unwrap();
+        if self.builtin_print {
+            // the writes clobber rax, the numbers are popped back in turn
+            writeln!(&mut res, "   push rbx").unwrap();
+            writeln!(&mut res, "   push rax").unwrap();
+            for text in ["Index ", " out of bounds for length "] {
+                write!(&mut res, "{}", emit_write(&data.string(text), text.len(), Stream::Stdout)).unwrap();
+                writeln!(&mut res, "   pop rax").unwrap();
+                writeln!(&mut res, "   mov rcx, 10").unwrap();
+                writeln!(&mut res, "   mov r9, {}", Stream::Stdout.handle()).unwrap();
+                writeln!(&mut res, "   xor r10d, r10d").unwrap();
+                writeln!(&mut res, "   call {}", WRITE_DIGITS).unwrap();
+            }
+            let text = ".\n";
+            write!(&mut res, "{}", emit_write(&data.string(text), text.len(), Stream::Stdout)).unwrap();
+        } else {
+            data.define("bounds_msg", &format!("db \"Index %lld out of bounds for length %lld.\", {}, 0", self.target.newline()));
+            write!(&mut res, "{}", emit_call(self.target, "printf", &[Arg::Label("bounds_msg"), Arg::Value("rax"), Arg::Value("rbx")])).unwrap();
+        }
+        write!(&mut res, "{}", emit_exit(self.target, "1", self.crt)).unwrap();
+
+        if self.checked_arith {
+            write!(&mut res, "\n{}", self.emit_trap(OVERFLOW_TRAP, "Integer overflow.", data)).unwrap();
+        }
+        write!(&mut res, "\n{}", self.emit_trap(DIV_ZERO_TRAP, "Division by zero.", data)).unwrap();
+
+        if self.output_routines() {
+            let (print_str, print_num) = if self.builtin_print { (PRINT_STR, PRINT_NUM) } else { (WRITE_STR, WRITE_NUM) };
+            writeln!(&mut res).unwrap();
+            write!(&mut res, "{}", emit_output(self.target)).unwrap();
+            write!(&mut res, "{}", emit_print_num(print_str, print_num)).unwrap();
+        }
+        if !self.builtin_print {
+            writeln!(&mut res).unwrap();
+            write!(&mut res, "{}", self.emit_printf()).unwrap();
+        }
+        if self.input {
+            writeln!(&mut res).unwrap();
+            write!(&mut res, "{}", emit_read_line(self.target, data)).unwrap();
+        }
+        if self.alloc {
+            write!(&mut res, "\n{}", self.emit_trap(ALLOC_TRAP, "Out of memory.", data)).unwrap();
+            writeln!(&mut res).unwrap();
+            write!(&mut res, "{}", emit_alloc(data)).unwrap();
+        }
+
+        res
+    }
+
+    /// A routine printing `message` and ending the program with exit code 1.
+    fn emit_trap(&self, label: &str, message: &str, data: &mut DataSection) -> String {
+        let mut res = String::new();
+
+        writeln!(&mut res, "{}:", label).unwrap();
+        if self.builtin_print {
+            let text = format!("{}\n", message);
+            write!(&mut res, "{}", emit_write(&data.string(&text), text.len(), Stream::Stdout)).unwrap();
+        } else {
+            let format = format!("{}_msg", label);
+            data.define(&format, &format!("db \"{}\", {}, 0", message, self.target.newline()));
+            write!(&mut res, "{}", emit_call(self.target, "printf", &[Arg::Label(&format)])).unwrap();
+        }
+        write!(&mut res, "{}", emit_exit(self.target, "1", self.crt)).unwrap();
+
+        res
+    }
+
+    /// `PRINT_NUM` and `PRINT_STR` through printf, which only writes to stdout: with `eprint` in the
+    /// program, the other streams go on to the emitted routines. Base 16 maps onto `%llx`.
+    fn emit_printf(&self) -> String {
+        let mut res = String::new();
+
+        writeln!(&mut res, "{}:", PRINT_NUM).unwrap();
+        if self.stderr {
+            writeln!(&mut res, "   cmp r9, {}", Stream::Stdout.handle()).unwrap();
+            writeln!(&mut res, "   jne {}", WRITE_NUM).unwrap();
+        }
+        // `emit_call` keeps rsp in rbx, which the caller may hold
+        writeln!(&mut res, "   push rbx").unwrap();
+        writeln!(&mut res, "   lea r10, [msg]").unwrap();
+        writeln!(&mut res, "   lea r11, [hex_msg]").unwrap();
+        writeln!(&mut res, "   cmp rcx, 16").unwrap();
+        writeln!(&mut res, "   cmove r10, r11").unwrap();
+        writeln!(&mut res, "   mov r11, rax").unwrap();
+        write!(&mut res, "{}", emit_call(self.target, "printf", &[Arg::Value("r10"), Arg::Value("r11")])).unwrap();
+        writeln!(&mut res, "   pop rbx").unwrap();
+        writeln!(&mut res, "   ret\n").unwrap();
+
+        writeln!(&mut res, "{}:", PRINT_STR).unwrap();
+        if self.stderr {
+            writeln!(&mut res, "   cmp r9, {}", Stream::Stdout.handle()).unwrap();
+            writeln!(&mut res, "   jne {}", WRITE_STR).unwrap();
+        }
+        writeln!(&mut res, "   push rbx").unwrap();
+        writeln!(&mut res, "   mov r11, rdx").unwrap();
+        write!(&mut res, "{}", emit_call(self.target, "printf", &[Arg::Label("str_msg"), Arg::Value("r11")])).unwrap();
+        writeln!(&mut res, "   pop rbx").unwrap();
+        writeln!(&mut res, "   ret").unwrap();
+
+        res
+    }
+}
+
+/// Prints the value on top of the operand stack through `ceya_print_num`, in `base` 10, 16 or 2.
+pub fn emit_print(base: u32, stream: Stream) -> String {
+    let mut res = String::new();
+
+    writeln!(&mut res, "   pop rax").unwrap();
+    writeln!(&mut res, "   mov rcx, {}", base).unwrap();
+    writeln!(&mut res, "   mov r9, {}", stream.handle()).unwrap();
+    writeln!(&mut res, "   call {}", PRINT_NUM).unwrap();
+
+    res
+}
+
+/// Writes `len` bytes at `label` through the emitted routine of `stream`.
+pub fn emit_write(label: &str, len: usize, stream: Stream) -> String {
+    let mut res = String::new();
+
+    writeln!(&mut res, "   lea rdx, [{}]", label).unwrap();
+    writeln!(&mut res, "   mov r8, {}", len).unwrap();
+    writeln!(&mut res, "   call {}", stream.routine()).unwrap();
+
+    res
+}
+
+/// Output routines that only depend on kernel32 (GetStdHandle/WriteFile), or on the `write` syscall
+/// on Linux, used instead of printf when building without the C runtime, and by `eprint`. All can
+/// be called with the stack at any alignment.
+fn emit_output(target: Target) -> String {
+    let mut res = String::new();
+
+    // rdx = buffer, r8 = length
+    writeln!(&mut res, "{}:", Stream::Stderr.routine()).unwrap();
+    writeln!(&mut res, "   mov r9, {}", Stream::Stderr.handle()).unwrap();
+    writeln!(&mut res, "   jmp ceya_write_handle").unwrap();
+    writeln!(&mut res, "{}:", Stream::Stdout.routine()).unwrap();
+    writeln!(&mut res, "   mov r9, {}", Stream::Stdout.handle()).unwrap();
+    // same with r9 = GetStdHandle argument
+    writeln!(&mut res, "ceya_write_handle:").unwrap();
+    if target == Target::Linux {
+        // the streams keep their GetStdHandle numbers: -11 is fd 1 and -12 fd 2
+        writeln!(&mut res, "   push rsi").unwrap();
+        writeln!(&mut res, "   push rdi").unwrap();
+        writeln!(&mut res, "   mov rdi, -10").unwrap();
+        writeln!(&mut res, "   sub rdi, r9").unwrap();
+        writeln!(&mut res, "   mov rsi, rdx").unwrap();
+        writeln!(&mut res, "   mov rdx, r8").unwrap();
+        writeln!(&mut res, "   mov eax, 1").unwrap(); // write
+        writeln!(&mut res, "   syscall").unwrap();
+        writeln!(&mut res, "   pop rdi").unwrap();
+        writeln!(&mut res, "   pop rsi").unwrap();
+        writeln!(&mut res, "   ret\n").unwrap();
+        return res;
+    }
+    writeln!(&mut res, "   push rbp").unwrap();
+    writeln!(&mut res, "   mov rbp, rsp").unwrap();
+    writeln!(&mut res, "   and rsp, -16").unwrap();
+    writeln!(&mut res, "   sub rsp, 64").unwrap(); // shadow space, 5th argument, bytes written, saved rdx/r8
+    writeln!(&mut res, "   mov [rsp + 48], rdx").unwrap();
+    writeln!(&mut res, "   mov [rsp + 56], r8").unwrap();
+    writeln!(&mut res, "   mov rcx, r9").unwrap();
+    writeln!(&mut res, "   call GetStdHandle").unwrap();
+    writeln!(&mut res, "   mov rcx, rax").unwrap();
+    writeln!(&mut res, "   mov rdx, [rsp + 48]").unwrap();
+    writeln!(&mut res, "   mov r8, [rsp + 56]").unwrap();
+    writeln!(&mut res, "   lea r9, [rsp + 40]").unwrap();
+    writeln!(&mut res, "   mov qword [rsp + 32], 0").unwrap();
+    writeln!(&mut res, "   call WriteFile").unwrap();
+    writeln!(&mut res, "   mov rsp, rbp").unwrap();
+    writeln!(&mut res, "   pop rbp").unwrap();
+    writeln!(&mut res, "   ret\n").unwrap();
+
+    res
+}
+
+/// `print_num` writes rax in base rcx to the stream selected by r9 (a GetStdHandle argument), through
+/// `ceya_write_handle`, and `print_str` the same for the zero-terminated string at rdx.
+fn emit_print_num(print_str: &str, print_num: &str) -> String {
+    let mut res = String::new();
+
+    writeln!(&mut res, "{}:", print_str).unwrap();
+    writeln!(&mut res, "   push rbp").unwrap();
+    writeln!(&mut res, "   mov rbp, rsp").unwrap();
+    writeln!(&mut res, "   push r9").unwrap();
+    writeln!(&mut res, "   push 10").unwrap(); // the newline, written from the stack
+    writeln!(&mut res, "   mov r8, rdx").unwrap();
+    writeln!(&mut res, ".ps_length:").unwrap();
+    writeln!(&mut res, "   mov al, [r8]").unwrap();
+    writeln!(&mut res, "   test al, al").unwrap();
+    writeln!(&mut res, "   jz .ps_write").unwrap();
+    writeln!(&mut res, "   inc r8").unwrap();
+    writeln!(&mut res, "   jmp .ps_length").unwrap();
+    writeln!(&mut res, ".ps_write:").unwrap();
+    writeln!(&mut res, "   sub r8, rdx").unwrap();
+    writeln!(&mut res, "   call ceya_write_handle").unwrap();
+    writeln!(&mut res, "   mov r9, [rbp - 8]").unwrap();
+    writeln!(&mut res, "   lea rdx, [rbp - 16]").unwrap();
+    writeln!(&mut res, "   mov r8, 1").unwrap();
+    writeln!(&mut res, "   call ceya_write_handle").unwrap();
+    writeln!(&mut res, "   mov rsp, rbp").unwrap();
+    writeln!(&mut res, "   pop rbp").unwrap();
+    writeln!(&mut res, "   ret\n").unwrap();
+
+    // rax = value, rcx = base, r9 = GetStdHandle argument; base 10 is signed, others print the two's complement like %llx
+    writeln!(&mut res, "{}:", print_num).unwrap();
+    writeln!(&mut res, "   mov r10, 10").unwrap(); // the newline
+    writeln!(&mut res, "{}:", WRITE_DIGITS).unwrap();
+    writeln!(&mut res, "   push rbp").unwrap();
+    writeln!(&mut res, "   mov rbp, rsp").unwrap();
+    writeln!(&mut res, "   push rbx").unwrap();
+    writeln!(&mut res, "   push rsi").unwrap();
+    writeln!(&mut res, "   sub rsp, 80").unwrap(); // digits are written backwards from rbp - 16
+    writeln!(&mut res, "   lea rsi, [rbp - 16]").unwrap();
+    writeln!(&mut res, "   test r10, r10").unwrap();
+    writeln!(&mut res, "   jz .pn_number").unwrap();
+    writeln!(&mut res, "   dec rsi").unwrap();
+    writeln!(&mut res, "   mov [rsi], r10b").unwrap();
+    writeln!(&mut res, ".pn_number:").unwrap();
+    writeln!(&mut res, "   mov rbx, rax").unwrap();
+    writeln!(&mut res, "   cmp rcx, 10").unwrap();
+    writeln!(&mut res, "   jne .pn_digit").unwrap();
+    writeln!(&mut res, "   test rax, rax").unwrap();
+    writeln!(&mut res, "   jns .pn_digit").unwrap();
+    writeln!(&mut res, "   neg rax").unwrap(); // also right for the minimum, the division is unsigned
+    writeln!(&mut res, ".pn_digit:").unwrap();
+    writeln!(&mut res, "   xor edx, edx").unwrap();
+    writeln!(&mut res, "   div rcx").unwrap();
+    writeln!(&mut res, "   cmp dl, 10").unwrap();
+    writeln!(&mut res, "   jb .pn_decimal").unwrap();
+    writeln!(&mut res, "   add dl, 39").unwrap(); // 'a' - '0' - 10
+    writeln!(&mut res, ".pn_decimal:").unwrap();
+    writeln!(&mut res, "   add dl, 48").unwrap();
+    writeln!(&mut res, "   dec rsi").unwrap();
+    writeln!(&mut res, "   mov [rsi], dl").unwrap();
+    writeln!(&mut res, "   test rax, rax").unwrap();
+    writeln!(&mut res, "   jnz .pn_digit").unwrap();
+    writeln!(&mut res, "   cmp rcx, 10").unwrap();
+    writeln!(&mut res, "   jne .pn_write").unwrap();
+    writeln!(&mut res, "   test rbx, rbx").unwrap();
+    writeln!(&mut res, "   jns .pn_write").unwrap();
+    writeln!(&mut res, "   mov dl, 45").unwrap(); // '-'
+    writeln!(&mut res, "   dec rsi").unwrap();
+    writeln!(&mut res, "   mov [rsi], dl").unwrap();
+    writeln!(&mut res, ".pn_write:").unwrap();
+    writeln!(&mut res, "   mov rdx, rsi").unwrap();
+    writeln!(&mut res, "   lea r8, [rbp - 16]").unwrap();
+    writeln!(&mut res, "   sub r8, rsi").unwrap();
+    writeln!(&mut res, "   call ceya_write_handle").unwrap();
+    writeln!(&mut res, "   lea rsp, [rbp - 16]").unwrap();
+    writeln!(&mut res, "   pop rsi").unwrap();
+    writeln!(&mut res, "   pop rbx").unwrap();
+    writeln!(&mut res, "   pop rbp").unwrap();
+    writeln!(&mut res, "   ret").unwrap();
+
+    res
+}
+
+/// `ceya_read_line`: reads stdin a byte at a time up to the end of the line, without the line ending,
+/// into the next of the `LINES` buffers. Longer lines are cut, their rest read as the next line, and
+/// the end of input gives an empty string. Preserves the registers generated code may hold across it.
+fn emit_read_line(target: Target, data: &mut DataSection) -> String {
+    let mut res = String::new();
+
+    data.reserve("ceya_lines", LINES * LINE_SIZE / 8);
+    data.global("ceya_line_count", 0);
+
+    writeln!(&mut res, "{}:", READ_LINE).unwrap();
+    writeln!(&mut res, "   push rbp").unwrap();
+    writeln!(&mut res, "   mov rbp, rsp").unwrap();
+    writeln!(&mut res, "   push rbx").unwrap();
+    writeln!(&mut res, "   push rsi").unwrap();
+    writeln!(&mut res, "   push rdi").unwrap();
+    writeln!(&mut res, "   push r12").unwrap();
+    writeln!(&mut res, "   push r13").unwrap();
+    writeln!(&mut res, "   and rsp, -16").unwrap();
+    writeln!(&mut res, "   sub rsp, 48").unwrap(); // shadow space, 5th argument, bytes read
+    // r13 = start of the line, rbx = where the next byte goes
+    writeln!(&mut res, "   mov rax, [ceya_line_count]").unwrap();
+    writeln!(&mut res, "   inc qword [ceya_line_count]").unwrap();
+    writeln!(&mut res, "   and rax, {}", LINES - 1).unwrap();
+    writeln!(&mut res, "   shl rax, {}", LINE_SIZE.trailing_zeros()).unwrap();
+    writeln!(&mut res, "   lea r13, [ceya_lines]").unwrap();
+    writeln!(&mut res, "   add r13, rax").unwrap();
+    writeln!(&mut res, "   mov rbx, r13").unwrap();
+    if target == Target::Windows {
+        writeln!(&mut res, "   mov rcx, -10").unwrap(); // STD_INPUT_HANDLE
+        writeln!(&mut res, "   call GetStdHandle").unwrap();
+        writeln!(&mut res, "   mov r12, rax").unwrap();
+    }
+    writeln!(&mut res, ".rl_next:").unwrap();
+    writeln!(&mut res, "   lea rax, [r13 + {}]", LINE_SIZE - 1).unwrap();
+    writeln!(&mut res, "   cmp rbx, rax").unwrap();
+    writeln!(&mut res, "   jae .rl_end").unwrap();
+    // eax = bytes read, 0 at the end of input or on error
+    match target {
+        Target::Windows => {
+            writeln!(&mut res, "   mov rcx, r12").unwrap();
+            writeln!(&mut res, "   mov rdx, rbx").unwrap();
+            writeln!(&mut res, "   mov r8, 1").unwrap();
+            writeln!(&mut res, "   lea r9, [rsp + 40]").unwrap();
+            writeln!(&mut res, "   mov qword [rsp + 40], 0").unwrap();
+            writeln!(&mut res, "   mov qword [rsp + 32], 0").unwrap();
+            writeln!(&mut res, "   call ReadFile").unwrap();
+            writeln!(&mut res, "   mov eax, [rsp + 40]").unwrap();
+        },
+        Target::Linux => {
+            writeln!(&mut res, "   xor eax, eax").unwrap(); // read
+            writeln!(&mut res, "   xor edi, edi").unwrap();
+            writeln!(&mut res, "   mov rsi, rbx").unwrap();
+            writeln!(&mut res, "   mov edx, 1").unwrap();
+            writeln!(&mut res, "   syscall").unwrap();
+        }
+    }
+    writeln!(&mut res, "   cmp rax, 1").unwrap();
+    writeln!(&mut res, "   jne .rl_end").unwrap();
+    writeln!(&mut res, "   mov al, [rbx]").unwrap();
+    writeln!(&mut res, "   cmp al, 10").unwrap();
+    writeln!(&mut res, "   je .rl_end").unwrap();
+    writeln!(&mut res, "   cmp al, 13").unwrap(); // '\r' is overwritten by the next byte
+    writeln!(&mut res, "   je .rl_next").unwrap();
+    writeln!(&mut res, "   inc rbx").unwrap();
+    writeln!(&mut res, "   jmp .rl_next").unwrap();
+    writeln!(&mut res, ".rl_end:").unwrap();
+    writeln!(&mut res, "   xor eax, eax").unwrap();
+    writeln!(&mut res, "   mov [rbx], al").unwrap();
+    writeln!(&mut res, "   mov rax, r13").unwrap();
+    writeln!(&mut res, "   lea rsp, [rbp - 40]").unwrap();
+    writeln!(&mut res, "   pop r13").unwrap();
+    writeln!(&mut res, "   pop r12").unwrap();
+    writeln!(&mut res, "   pop rdi").unwrap();
+    writeln!(&mut res, "   pop rsi").unwrap();
+    writeln!(&mut res, "   pop rbx").unwrap();
+    writeln!(&mut res, "   pop rbp").unwrap();
+    writeln!(&mut res, "   ret").unwrap();
+
+    res
+}
+
+/// `ceya_alloc`: hands out the next rcx qwords of a fixed heap, ending the program once it is used up.
+fn emit_alloc(data: &mut DataSection) -> String {
+    let mut res = String::new();
+
+    data.reserve("ceya_heap", HEAP_SIZE / 8);
+    // bytes handed out so far
+    data.global("ceya_heap_used", 0);
+
+    writeln!(&mut res, "{}:", ALLOC).unwrap();
+    writeln!(&mut res, "   mov rax, [ceya_heap_used]").unwrap();
+    writeln!(&mut res, "   shl rcx, 3").unwrap();
+    writeln!(&mut res, "   add rcx, rax").unwrap();
+    writeln!(&mut res, "   cmp rcx, {}", HEAP_SIZE).unwrap();
+    writeln!(&mut res, "   ja {}", ALLOC_TRAP).unwrap();
+    writeln!(&mut res, "   mov [ceya_heap_used], rcx").unwrap();
+    writeln!(&mut res, "   lea rcx, [ceya_heap]").unwrap();
+    writeln!(&mut res, "   add rax, rcx").unwrap();
+    writeln!(&mut res, "   ret").unwrap();
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{DataSection, Stream, Target};
+
+    use super::{emit_print, emit_write, Runtime};
+
+    #[test]
+    fn test_emit_print() {
+        assert_eq!(emit_print(16, Stream::Stdout), "   pop rax\n   mov rcx, 16\n   mov r9, -11\n   call ceya_print_num\n");
+        assert_eq!(emit_write("str_0", 5, Stream::Stderr), "   lea rdx, [str_0]\n   mov r8, 5\n   call ceya_write_stderr\n");
+    }
+
+    #[test]
+    fn test_runtime() {
+        let mut runtime = Runtime { target: Target::Linux, crt: false, builtin_print: true, checked_arith: false, stderr: false, input: true, alloc: false };
+        let mut data = DataSection::default();
+        let asm = runtime.emit(&mut data);
+        // the fd is derived from the stream's handle number
+        assert!(asm.contains("   mov rdi, -10\n   sub rdi, r9\n") && !asm.contains("WriteFile") && !asm.contains("ceya_overflow_fail"));
+        assert!(asm.contains("ceya_div_zero_fail:\n") && asm.contains("ceya_read_line:\n") && asm.contains("   syscall\n"));
+        assert!(data.labels().contains(&"ceya_lines"));
+        assert!(runtime.externs().is_empty());
+        // the bounds message has the index and the length, printed without their newline
+        assert_eq!(asm.matches("   xor r10d, r10d\n   call ceya_write_digits\n").count(), 2);
+        assert!(asm.contains("ceya_print_num:\n   mov r10, 10\nceya_write_digits:\n"));
+
+        runtime.target = Target::Windows;
+        runtime.builtin_print = false;
+        runtime.checked_arith = true;
+        assert_eq!(runtime.externs(), ["ExitProcess", "GetStdHandle", "ReadFile", "printf"]);
+        let mut data = DataSection::default();
+        let asm = runtime.emit(&mut data);
+        assert!(asm.contains("   lea rcx, [ceya_overflow_fail_msg]\n") && asm.contains("   call ReadFile\n") && !asm.contains("ceya_alloc:"));
+        // the print routines wrap printf, without the emitted ones
+        assert!(asm.contains("ceya_print_num:\n   push rbx\n") && asm.contains("   mov rcx, r10\n   mov rdx, r11\n") && !asm.contains("ceya_write_handle"));
+
+        // which stderr still needs
+        runtime.stderr = true;
+        let asm = runtime.emit(&mut DataSection::default());
+        assert!(asm.contains("ceya_print_num:\n   cmp r9, -11\n   jne ceya_write_num\n") && asm.contains("ceya_write_num:\n") && asm.contains("ceya_write_str:\n"));
+        runtime.stderr = false;
+
+        runtime.alloc = true;
+        let mut data = DataSection::default();
+        let asm = runtime.emit(&mut data);
+        assert!(asm.contains("ceya_alloc:\n   mov rax, [ceya_heap_used]\n") && asm.contains("   ja ceya_alloc_fail\n") && asm.contains("ceya_alloc_fail:\n"));
+        assert!(data.labels().contains(&"ceya_heap"));
+    }
+}
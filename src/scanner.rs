@@ -1,20 +1,27 @@
-use std::{fmt::{Display, Formatter, self, Debug}, str::FromStr, rc::Rc, io::Error};
+use std::{collections::HashMap, fmt::{Display, Formatter, self, Debug}, str::FromStr, rc::Rc, io::Error};
 
-use crate::error;
+use crate::error::{self, CeyaError};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TokenType {
     LeftParen, 
     RightParen, 
     LeftBrace, 
     RightBrace, 
+    LeftBracket,
+    RightBracket,
     Comma, 
     Dot, 
+    DotDot,
     Minus, 
     Plus,
     Semicolon, 
     Slash, 
     Star,
+    Question,
+    Colon,
 
     Bang, 
     BangEqual, 
@@ -33,18 +40,28 @@ pub enum TokenType {
     Else, 
     False, 
     Fn, 
+    Struct,
     For, 
     If, 
     Null, 
     Or, 
+    Xor,
     Print, 
+    Eprint,
     Return, 
+    Break,
+    Continue,
     True, 
     Let, 
     While,
     Soro,
     Faran,
     Ke,
+    Depth,
+    Swap,
+    Over,
+    Rot,
+    Drop2,
 
     EOF
 }
@@ -56,58 +73,305 @@ impl Display for TokenType {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Token {
     pub lexeme: String,
-    pub line: u32,
+    pub span: Span,
     pub typ: TokenType
 }
 
 impl Display for Token {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{} ({})", self.lexeme, self.line)
+        write!(f, "{} ({}:{})", self.lexeme, self.span.start, self.span.column)
     }
 }
 
 impl Token {
-    fn new(lexeme: &str, line: u32, typ: TokenType) -> Token {
-        Token { lexeme: String::from_str(lexeme).expect("string expected"), line, typ }
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// A token at the start of `line`, for tokens that don't come from a scanned source.
+    pub fn new(lexeme: &str, line: u32, typ: TokenType) -> Token {
+        Token { lexeme: String::from_str(lexeme).expect("string expected"), span: Span::line(line), typ }
+    }
+}
+
+/// Source covered by a token or syntax tree node. Lines and columns are 0-based like the diagnostics,
+/// lines inclusive; `offset` and `len` are in bytes of the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+    /// Column of the first character on the `start` line, counted in characters.
+    pub column: u32,
+    pub offset: usize,
+    pub len: usize
+}
+
+impl Span {
+    pub fn line(line: u32) -> Span {
+        Span { start: line, end: line, ..Span::default() }
+    }
+
+    /// Smallest span covering both.
+    pub fn to(self, other: Span) -> Span {
+        let first = if (other.start, other.column) < (self.start, self.column) { other } else { self };
+        let end = (self.offset + self.len).max(other.offset + other.len);
+        Span { start: first.start, end: self.end.max(other.end), column: first.column, offset: first.offset, len: end - first.offset }
     }
 }
 
+/// Lexemes of the operators and punctuation `scan_token` accepts, two-character ones first so editors match `<=` before `<`.
+pub const OPERATORS: [&str; 24] = ["!=", "==", "<=", ">=", "..", "(", ")", "{", "}", "[", "]", ",", ".", "-", "+", ";", "*", "/", "!", "=", "<", ">", "?", ":"];
+
+/// Maps words to keyword tokens, so alternative or localized spellings can be used without touching the scanner.
+#[derive(Clone)]
+pub struct Keywords {
+    table: HashMap<String, TokenType>
+}
+
+impl Default for Keywords {
+    fn default() -> Self {
+        let table = [
+            ("and", TokenType::And),
+            ("else", TokenType::Else),
+            ("false", TokenType::False),
+            ("for", TokenType::For),
+            ("fn", TokenType::Fn),
+            ("struct", TokenType::Struct),
+            ("if", TokenType::If),
+            ("fu", TokenType::Null),
+            ("or", TokenType::Or),
+            ("xor", TokenType::Xor),
+            ("print", TokenType::Print),
+            ("eprint", TokenType::Eprint),
+            ("return", TokenType::Return),
+            ("break", TokenType::Break),
+            ("continue", TokenType::Continue),
+            ("true", TokenType::True),
+            ("let", TokenType::Let),
+            ("while", TokenType::While),
+            ("soro", TokenType::Soro),
+            ("faran", TokenType::Faran),
+            ("ke", TokenType::Ke),
+            ("depth", TokenType::Depth),
+            ("swap", TokenType::Swap),
+            ("over", TokenType::Over),
+            ("rot", TokenType::Rot),
+            ("drop2", TokenType::Drop2),
+            ("nan", TokenType::Number(f64::NAN)),
+            ("inf", TokenType::Number(f64::INFINITY))
+        ];
+
+        Keywords { table: table.into_iter().map(|(word, typ)| (word.to_string(), typ)).collect() }
+    }
+}
+
+impl Keywords {
+    /// Default keywords with the stack words and null spelled in English.
+    pub fn english() -> Keywords {
+        let mut keywords = Keywords::default();
+        for (from, to) in [("soro", "top"), ("faran", "drop"), ("ke", "dup"), ("fu", "null")] {
+            keywords.rename(from, to).expect("default keyword");
+        }
+        keywords
+    }
+
+    /// Reads renames from `keyword = spelling` lines on top of the defaults, `#` starts a comment.
+    pub fn parse(source: &str) -> Result<Keywords, Error> {
+        let mut keywords = Keywords::default();
+
+        for (i, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            match line.split_once('=') {
+                Some((from, to)) => keywords.rename(from.trim(), to.trim()).map_err(|e| Error::other(format!("[line {}] Error: {}", i, e)))?,
+                None => return Err(Error::other(format!("[line {}] Error: Expected 'keyword = spelling'.", i)))
+            }
+        }
+
+        Ok(keywords)
+    }
+
+    /// Replaces the spelling `from` of a keyword with `to`.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        if to.is_empty() || !to.chars().all(Scanner::is_alpha_numeric) || !to.starts_with(Scanner::is_alpha) {
+            return Err(Error::other(format!("'{}' is not a valid keyword spelling.", to)));
+        }
+        if self.table.contains_key(to) {
+            return Err(Error::other(format!("'{}' is already a keyword.", to)));
+        }
+
+        match self.table.remove(from) {
+            Some(typ) => {
+                self.table.insert(to.into(), typ);
+                Ok(())
+            },
+            None => Err(Error::other(format!("Unknown keyword '{}'.", from)))
+        }
+    }
+
+    fn get(&self, word: &str) -> Option<&TokenType> {
+        self.table.get(word)
+    }
+
+    /// Every spelling with its token, sorted by spelling.
+    pub fn words(&self) -> Vec<(&str, &TokenType)> {
+        let mut words: Vec<_> = self.table.iter().map(|(word, typ)| (word.as_str(), typ)).collect();
+        words.sort_by_key(|(word, _)| *word);
+        words
+    }
+}
+
+/// Decodes the escape sequences of a string literal: `\n`, `\t`, `\r`, `\0`, `\"` and `\\`.
+/// Both `sim` and `com` work on the decoded text, so they print the same bytes.
+pub fn unescape(raw: &str) -> Result<String, String> {
+    let mut res = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some('r') => res.push('\r'),
+            Some('0') => res.push('\0'),
+            Some('"') => res.push('"'),
+            Some('\\') => res.push('\\'),
+            Some(c) => return Err(format!("Unknown escape sequence '\\{}'.", c)),
+            None => return Err("Unterminated escape sequence.".into())
+        }
+    }
+
+    Ok(res)
+}
+
+/// Inverse of `unescape`, to print a string back as a literal.
+pub fn escape(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            '\r' => res.push_str("\\r"),
+            '\0' => res.push_str("\\0"),
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c => res.push(c)
+        }
+    }
+    res
+}
+
+/// Scans `source` into tokens, reporting and skipping invalid characters. Never panics, whatever the input.
+pub fn scan(source: &str) -> Vec<Rc<Token>> {
+    scan_with_keywords(source, Keywords::default())
+}
+
+pub fn scan_with_keywords(source: &str, keywords: Keywords) -> Vec<Rc<Token>> {
+    Scanner::new(source, keywords).scan_tokens()
+}
+
+/// Scans all of `source`, giving the errors instead of reporting them, along with the tokens of the valid parts.
+pub fn scan_all(source: &str, keywords: Keywords) -> (Vec<Rc<Token>>, Vec<CeyaError>) {
+    let (tokens, errors): (Vec<_>, Vec<_>) = Scanner::new(source, keywords).partition(Result::is_ok);
+    (tokens.into_iter().flatten().collect(), errors.into_iter().filter_map(Result::err).collect())
+}
+
 pub struct Scanner {
     pub source: String,
+    /// Tokens scanned but not yet handed out by the iterator.
     pub tokens: Vec<Rc<Token>>,
     pub start: usize,
     pub current: usize,
-    pub line: u32
+    pub line: u32,
+    /// Byte offset where the current line begins, to count columns from.
+    pub line_start: usize,
+    /// Line and column of `start`.
+    pub start_line: u32,
+    pub start_column: u32,
+    pub keywords: Keywords,
+    /// Set once the EOF token has been produced.
+    pub finished: bool
 }
 
-impl Scanner {
-    pub fn scan_tokens(mut self) -> Vec<Rc<Token>> {
-        while !self.is_at_end() {
+/// Scans lazily, one token per call, ending with an EOF token. Errors are yielded in place
+/// of the offending token and scanning resumes after them.
+impl Iterator for Scanner {
+    type Item = Result<Rc<Token>, CeyaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // editors on Windows like to start UTF-8 files with a byte order mark
+        if self.current == 0 && self.source.starts_with('\u{feff}') {
+            self.current = '\u{feff}'.len_utf8();
+            self.line_start = self.current;
+        }
+
+        while self.tokens.is_empty() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.source[self.line_start..self.current].chars().count() as u32;
+
+            if self.is_at_end() {
+                if self.finished {
+                    return None;
+                }
+                self.finished = true;
+                return Some(Ok(Rc::new(Token { lexeme: "".into(), span: self.span(), typ: TokenType::EOF })));
+            }
+
             if let Err(e) = self.scan_token() {
-                println!("Error occured while scanning: {}", e);
+                return Some(Err(e));
             }
         }
 
-        self.tokens.push(Rc::new(Token::new("", self.line, TokenType::EOF)));
-        self.tokens
+        Some(Ok(self.tokens.remove(0)))
+    }
+}
+
+impl Scanner {
+    pub fn new(source: &str, keywords: Keywords) -> Scanner {
+        Scanner { source: source.into(), tokens: vec![], start: 0, current: 0, line: 0, line_start: 0, start_line: 0, start_column: 0, keywords, finished: false }
+    }
+
+    pub fn scan_tokens(self) -> Vec<Rc<Token>> {
+        self.filter_map(|token| match token {
+            Ok(token) => Some(token),
+            Err(e) => {
+                println!("Error occured while scanning: {}", error::render(&e));
+                None
+            }
+        }).collect()
     }
 
-    fn scan_token(&mut self) -> Result<(), Error> {
+    fn scan_token(&mut self) -> Result<(), CeyaError> {
         let c = self.advance();
         match c {
             '(' => self.add_token(TokenType::LeftParen),
             ')' => self.add_token(TokenType::RightParen),
             '{' => self.add_token(TokenType::LeftBrace),
             '}' => self.add_token(TokenType::RightBrace),
+            '[' => self.add_token(TokenType::LeftBracket),
+            ']' => self.add_token(TokenType::RightBracket),
             ',' => self.add_token(TokenType::Comma),
-            '.' => self.add_token(TokenType::Dot),
+            '.' => match self.char_match('.') {
+                true => self.add_token(TokenType::DotDot),
+                false => self.add_token(TokenType::Dot)
+            },
             '-' => self.add_token(TokenType::Minus),
             '+' => self.add_token(TokenType::Plus),
             ';' => self.add_token(TokenType::Semicolon),
             '*' => self.add_token(TokenType::Star),
+            '?' => self.add_token(TokenType::Question),
+            ':' => self.add_token(TokenType::Colon),
 
             '!' => match self.char_match('=') {
                 true => self.add_token(TokenType::BangEqual),
@@ -126,27 +390,30 @@ impl Scanner {
                 false => self.add_token(TokenType::Greater)
             },
             
-            '/' => match self.char_match('/') {
-                true => {
+            '/' => match self.peek() {
+                '/' => {
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
                 },
-                false => self.add_token(TokenType::Slash)
+                '*' => {
+                    self.advance();
+                    return self.block_comment();
+                },
+                _ => self.add_token(TokenType::Slash)
             },
 
-            '"' => self.string(),
+            '"' => return self.string(),
 
-            ' ' | '\r' | '\t' => (),
-            '\n' => self.line += 1,
+            ' ' | '\r' | '\t' | '\n' => (),
 
             c => {
                 if Self::is_digit(c) {
-                    self.number()
+                    return self.number(c);
                 } else if Self::is_alpha(c) {
                     self.identifier()
                 } else {
-                    return Err(error(self.line, &format!("Unexpected token '{}'.", c)));
+                    return Err(self.error(&format!("Unexpected token '{}'.", c)));
                 }
             }
         };
@@ -157,10 +424,23 @@ impl Scanner {
         self.current >= self.source.len()
     }
 
+    /// Where the lexeme scanned so far lies in the source.
+    fn span(&self) -> Span {
+        Span { start: self.start_line, end: self.line, column: self.start_column, offset: self.start, len: self.current - self.start }
+    }
+
+    fn error(&self, message: &str) -> CeyaError {
+        CeyaError::Lex { span: self.span(), message: message.into() }
+    }
+
     fn advance(&mut self) -> char {
-        let c = &self.source[self.current..self.current+1];
-        self.current += 1;
-        char::from_str(c).expect("char expected")
+        let c = self.source[self.current..].chars().next().unwrap_or('\0');
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.line_start = self.current;
+        }
+        c
     }
 
     fn get_lexeme(&self) -> &str {
@@ -168,7 +448,7 @@ impl Scanner {
     }
 
     fn add_token(&mut self, typ: TokenType) {
-        self.tokens.push(Rc::new(Token::new(self.get_lexeme(), self.line, typ)));
+        self.tokens.push(Rc::new(Token { lexeme: self.get_lexeme().into(), span: self.span(), typ }));
     }
 
     fn char_match(&mut self, expected: char) -> bool {
@@ -176,12 +456,12 @@ impl Scanner {
             return false;
         }
 
-        if char::from_str(&self.source[self.current..self.current+1]).expect("char expected") != expected {
+        if self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
-        return true;
+        self.current += expected.len_utf8();
+        true
     }
 
     fn peek(&mut self) -> char {
@@ -189,49 +469,123 @@ impl Scanner {
             return '\0';
         }
 
-        char::from_str(&self.source[self.current..self.current+1]).expect("char expected")
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&mut self) -> char{
-        if self.current + 1 >= self.source.len() {
-            return '\0';
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
+    }
+
+    /// Skips a `/* ... */` comment, nested ones included.
+    fn block_comment(&mut self) -> Result<(), CeyaError> {
+        let mut depth = 1;
+
+        while depth > 0 {
+            if self.is_at_end() {
+                return Err(self.error("Unterminated block comment."));
+            }
+
+            match self.advance() {
+                '/' if self.char_match('*') => depth += 1,
+                '*' if self.char_match('/') => depth -= 1,
+                _ => ()
+            }
         }
 
-        char::from_str(&self.source[self.current+1..self.current+2]).expect("char expected")
+        Ok(())
     }
 
-    fn string(&mut self) {
+    fn string(&mut self) -> Result<(), CeyaError> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            // an escaped quote doesn't end the string
+            if self.peek() == '\\' {
+                self.advance();
             }
             self.advance();
         }
 
         if self.is_at_end() {
-            error(self.line, "Unterminated string.");
-            return;
+            return Err(self.error("Unterminated string."));
         }
 
         self.advance();
 
-        self.add_token(TokenType::String(String::from_str(&self.source[self.start+1..self.current-1]).expect("string expected")));
+        let raw = &self.source[self.start+1..self.current-1];
+        match unescape(raw) {
+            Ok(value) => {
+                self.add_token(TokenType::String(value));
+                Ok(())
+            },
+            Err(e) => {
+                // the string is still there for the parser, which would report it missing otherwise
+                let raw = raw.into();
+                let e = self.error(&e);
+                self.add_token(TokenType::String(raw));
+                Err(e)
+            }
+        }
     }
 
-    fn number(&mut self) {
-        while Self::is_digit(self.peek()) {
-            self.advance();
+    /// Reads a decimal number, or an integer in hexadecimal (`0xFF`) or binary (`0b1010`). Digits can be
+    /// grouped with single underscores between them: `1_000_000`.
+    fn number(&mut self, first: char) -> Result<(), CeyaError> {
+        let radix = match (first, self.peek()) {
+            ('0', 'x' | 'X') => 16,
+            ('0', 'b' | 'B') => 2,
+            _ => 10
+        };
+
+        let mut digits = String::from(first);
+        let value = if radix == 10 {
+            self.digits(10, &mut digits)?;
+            if self.peek() == '.' && Self::is_digit(self.peek_next()) {
+                digits.push(self.advance());
+                self.digits(10, &mut digits)?;
+            }
+            f64::from_str(&digits).expect("number expected")
+        } else {
+            let prefix = self.advance();
+            if !self.peek().is_digit(radix) {
+                return Err(self.invalid_number(&format!("Expect digits after '0{}'.", prefix)));
+            }
+            digits.clear();
+            self.digits(radix, &mut digits)?;
+            match u64::from_str_radix(&digits, radix) {
+                Ok(n) => n as f64,
+                Err(_) => return Err(self.invalid_number("Number literal too large."))
+            }
+        };
+
+        // `0b102` or `12px` would otherwise scan as two tokens
+        let next = self.peek();
+        if Self::is_alpha_numeric(next) {
+            return Err(self.invalid_number(&format!("Invalid digit '{}' in number literal.", next)));
         }
 
-        if self.peek() == '.' && Self::is_digit(self.peek_next()) {
-            self.advance();
+        self.add_token(TokenType::Number(value));
+        Ok(())
+    }
 
-            while Self::is_digit(self.peek()) {
-                self.advance();
+    /// Appends the digits of `radix` that follow to `text`, without the underscores separating them.
+    fn digits(&mut self, radix: u32, text: &mut String) -> Result<(), CeyaError> {
+        loop {
+            match self.peek() {
+                '_' if self.peek_next().is_digit(radix) => {
+                    self.advance();
+                },
+                '_' => return Err(self.invalid_number("Underscores in numbers must be between digits.")),
+                c if c.is_digit(radix) => text.push(self.advance()),
+                _ => return Ok(())
             }
         }
+    }
 
-        self.add_token(TokenType::Number(f64::from_str(&self.source[self.start..self.current]).expect("number expected")));
+    /// An error about the number literal being read, which takes the rest of the word with it.
+    fn invalid_number(&mut self, message: &str) -> CeyaError {
+        while Self::is_alpha_numeric(self.peek()) {
+            self.advance();
+        }
+        self.error(message)
     }
 
     fn identifier(&mut self) {
@@ -240,34 +594,16 @@ impl Scanner {
         }
 
         let txt = &self.source[self.start..self.current];
-        let typ = match txt {
-            "and" => TokenType::And,
-            "else" => TokenType::Else,
-            "false" => TokenType::False,
-            "for" => TokenType::For,
-            "fn" => TokenType::Fn,
-            "if" => TokenType::If,
-            "fu" => TokenType::Null,
-            "or" => TokenType::Or,
-            "print" => TokenType::Print,
-            "return" => TokenType::Return,
-            "true" => TokenType::True,
-            "let" => TokenType::Let,
-            "while" => TokenType::While,
-            "soro" => TokenType::Soro,
-            "faran" => TokenType::Faran,
-            "ke" => TokenType::Ke,
-            _ => TokenType::Identifier,
-        };
+        let typ = self.keywords.get(txt).cloned().unwrap_or(TokenType::Identifier);
         self.add_token(typ);
     }
 
     fn is_digit(c: char) -> bool {
-        c >= '0' && c <= '9'
+        c.is_ascii_digit()
     }
 
     fn is_alpha(c: char) -> bool {
-        (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+        c.is_ascii_alphabetic() || c == '_'
     }
 
     fn is_alpha_numeric(c: char) -> bool {
@@ -279,26 +615,23 @@ impl Scanner {
 mod tests {
     use std::str::FromStr;
 
-    use super::{Scanner, TokenType};
+    use super::{Keywords, Scanner, TokenType};
 
     #[test]
     fn test_scan() {
-        let template = String::from_str("(){},.-+;*!=! == =<=<>=>/\"string\" 12 0.12 and else false for fn if fu or print return true let while soro faran ke // ignored").expect("Cannot parse &str.");
-        let scanner = Scanner {
-            source: template,
-            tokens: vec![],
-            current: 0,
-            line: 0,
-            start: 0
-        };
+        let template = String::from_str("(){}[],. ..-+;*!=! == =<=<>=>/\"string\" 12 0.12 and else false for fn struct if fu or xor print eprint return true let while soro faran ke depth swap over rot drop2 // ignored").expect("Cannot parse &str.");
+        let scanner = Scanner::new(&template, Keywords::default());
         let tokens = scanner.scan_tokens();
         let expected = vec![
             TokenType::LeftParen, 
             TokenType::RightParen, 
             TokenType::LeftBrace, 
             TokenType::RightBrace, 
+            TokenType::LeftBracket,
+            TokenType::RightBracket,
             TokenType::Comma, 
             TokenType::Dot,
+            TokenType::DotDot,
             TokenType::Minus, 
             TokenType::Plus, 
             TokenType::Semicolon, 
@@ -320,10 +653,13 @@ mod tests {
             TokenType::False,
             TokenType::For,
             TokenType::Fn,
+            TokenType::Struct,
             TokenType::If,
             TokenType::Null,
             TokenType::Or,
+            TokenType::Xor,
             TokenType::Print,
+            TokenType::Eprint,
             TokenType::Return,
             TokenType::True,
             TokenType::Let,
@@ -331,6 +667,11 @@ mod tests {
             TokenType::Soro,
             TokenType::Faran,
             TokenType::Ke,
+            TokenType::Depth,
+            TokenType::Swap,
+            TokenType::Over,
+            TokenType::Rot,
+            TokenType::Drop2,
             TokenType::EOF
         ];
         
@@ -340,4 +681,127 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_scan_nan_inf() {
+        let scanner = Scanner::new("nan inf -inf", Keywords::default());
+        let tokens = scanner.scan_tokens();
+
+        assert!(matches!(tokens[0].typ, TokenType::Number(n) if n.is_nan()));
+        assert_eq!(tokens[1].typ, TokenType::Number(f64::INFINITY));
+        assert_eq!(tokens[2].typ, TokenType::Minus);
+        assert_eq!(tokens[3].typ, TokenType::Number(f64::INFINITY));
+    }
+
+    #[test]
+    fn test_scan_block_comments() {
+        let tokens = super::scan("1 /* a /* b */ c */ 2 /**/ / /* \n */ 3 /* /* */");
+        let expected = vec![TokenType::Number(1.0), TokenType::Number(2.0), TokenType::Slash, TokenType::Number(3.0), TokenType::EOF];
+
+        assert_eq!(tokens.iter().map(|t| t.typ.clone()).collect::<Vec<_>>(), expected);
+        assert_eq!(tokens[3].span.start, 1);
+
+        // reported where the comment opens, not at the end of the file
+        let (tokens, errors) = super::scan_all("1;\n  /* a /* b */\n\n", Keywords::default());
+        assert_eq!(errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(), ["[line 1:2] Error: Unterminated block comment."]);
+        assert_eq!(tokens.last().map(|t| t.typ.clone()), Some(TokenType::EOF));
+    }
+
+    #[test]
+    fn test_scan_columns() {
+        // columns count characters, offsets count bytes
+        let tokens = super::scan("\"é\" x \"a\nb\";\n  y");
+        assert_eq!((tokens[0].span.column, tokens[0].span.offset, tokens[0].span.len), (0, 0, 4));
+        assert_eq!((tokens[1].span.column, tokens[1].span.offset), (4, 5));
+        assert_eq!((tokens[2].span.start, tokens[2].span.end, tokens[2].span.column), (0, 1, 6));
+        assert_eq!((tokens[4].span.start, tokens[4].span.column), (2, 2));
+
+        let errors: Vec<_> = Scanner::new("print 1;\n  @ \"open", Keywords::default()).filter_map(Result::err).collect();
+        assert_eq!(errors.iter().map(|e| (e.span().start, e.span().column)).collect::<Vec<_>>(), vec![(1, 2), (1, 4)]);
+        assert_eq!(errors[0].to_string(), "[line 1:2] Error: Unexpected token '@'.");
+    }
+
+    #[test]
+    fn test_scan_bom() {
+        let tokens = super::scan("\u{feff}print 1;");
+        let expected = vec![TokenType::Print, TokenType::Number(1.0), TokenType::Semicolon, TokenType::EOF];
+
+        assert_eq!(tokens.iter().map(|t| t.typ.clone()).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_keywords() {
+        let keywords = Keywords::parse("# french\nprint = affiche\nwhile=tantque\n").expect("Keywords expected.");
+        let tokens = super::scan_with_keywords("affiche print tantque top", keywords);
+        let expected = vec![TokenType::Print, TokenType::Identifier, TokenType::While, TokenType::Identifier, TokenType::EOF];
+        assert_eq!(tokens.iter().map(|t| t.typ.clone()).collect::<Vec<_>>(), expected);
+
+        let tokens = super::scan_with_keywords("top soro", Keywords::english());
+        assert_eq!(tokens[0].typ, TokenType::Soro);
+        assert_eq!(tokens[1].typ, TokenType::Identifier);
+
+        assert!(Keywords::parse("nothing = x").is_err());
+        assert!(Keywords::parse("print = if").is_err());
+        assert!(Keywords::parse("print = 1x").is_err());
+        assert!(Keywords::parse("print").is_err());
+    }
+
+    #[test]
+    fn test_scan_lazily() {
+        let mut scanner = Scanner::new("print 1; @ print 2;", Keywords::default());
+
+        assert_eq!(scanner.next().expect("Token expected.").expect("Ok expected.").typ, TokenType::Print);
+        assert_eq!(scanner.current, "print".len());
+
+        let rest: Vec<_> = scanner.collect();
+        assert_eq!(rest.len(), 7);
+        assert!(matches!(&rest[2], Err(crate::error::CeyaError::Lex { message, .. }) if message.starts_with("Unexpected token")));
+        assert!(rest[6].as_ref().is_ok_and(|token| token.typ == TokenType::EOF));
+    }
+
+    #[test]
+    fn test_operators_table() {
+        for op in super::OPERATORS {
+            let tokens = super::scan(op);
+            assert!(tokens.len() == 2 && tokens[0].lexeme == op, "'{}' is not a single token", op);
+        }
+    }
+
+    #[test]
+    fn test_scan_number_literals() {
+        let tokens = super::scan("0xFF 0Xff 0b1010 1_000_000 1_000.000_1 0 007 0xFFFF_FFFF 0b1_0");
+        let numbers: Vec<TokenType> = [255., 255., 10., 1e6, 1000.0001, 0., 7., 4294967295., 2.].into_iter().map(TokenType::Number).collect();
+        assert_eq!(tokens.iter().map(|t| t.typ.clone()).filter(|typ| *typ != TokenType::EOF).collect::<Vec<_>>(), numbers);
+
+        let errors = |source: &str| super::scan_all(source, Keywords::default()).1.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(errors("0x 0b2 1__0 1_ 0xfg 12px"), [
+            "[line 0:0] Error: Expect digits after '0x'.",
+            "[line 0:3] Error: Expect digits after '0b'.",
+            "[line 0:7] Error: Underscores in numbers must be between digits.",
+            "[line 0:12] Error: Underscores in numbers must be between digits.",
+            "[line 0:15] Error: Invalid digit 'g' in number literal.",
+            "[line 0:20] Error: Invalid digit 'p' in number literal."
+        ]);
+        assert_eq!(errors("0x1_0000_0000_0000_0000"), ["[line 0:0] Error: Number literal too large."]);
+        // the whole literal is underlined, and scanning goes on after it
+        let (tokens, errors) = super::scan_all("0b102 + 1", Keywords::default());
+        assert_eq!(errors[0].span().len, 5);
+        assert_eq!(tokens.iter().map(|t| t.typ.clone()).collect::<Vec<_>>(), [TokenType::Plus, TokenType::Number(1.), TokenType::EOF]);
+    }
+
+    #[test]
+    fn test_scan_escapes() {
+        let tokens = super::scan("\"a\\n\\t\\\"b\\\\\" \"c\\q\" 1");
+        assert_eq!(tokens[0].typ, TokenType::String("a\n\t\"b\\".into()));
+        // the bad escape keeps its string, undecoded
+        assert_eq!((&tokens[1].typ, &tokens[2].typ), (&TokenType::String("c\\q".into()), &TokenType::Number(1.)));
+        let (tokens, errors) = super::scan_all("print \"ok\";\n  \"a\\x\";", Keywords::default());
+        assert_eq!(errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(), ["[line 1:2] Error: Unknown escape sequence '\\x'."]);
+        // so the parser has nothing more to report
+        assert!(crate::parser::parse(tokens).is_ok());
+
+        let text = "tab\there \"quoted\" back\\slash\r\n\0";
+        assert_eq!(super::unescape(&super::escape(text)).as_deref(), Ok(text));
+        assert!(super::unescape("trailing\\").is_err());
+    }
+}
@@ -0,0 +1,448 @@
+//! Native functions defined in the global environment of `sim`, as `Fun::Native` values.
+//!
+//! `com` has no value representation for most of them: a call to a native compiles to 0 with a
+//! warning. Each one is meant to get a routine prefixed with `ceya_` in the runtime emitted into
+//! every executable, taking its arguments and returning its value following the ABI of user
+//! functions, like `input()` compiles to a call to `ceya_read_line`.
+
+use std::{cell::{Cell, RefCell}, io::{self, BufRead, Write}, rc::Rc};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{process::Command, time::SystemTime};
+
+use crate::{ast::{Fun, Value}, environment::EnvironmentArena};
+
+/// Gives the message of a runtime error instead of a value when called with wrong arguments.
+type Native = fn(Vec<Value>) -> Result<Value, String>;
+
+/// Name, arity (`None` when variadic) and function of the natives available everywhere.
+const NATIVES: &[(&str, Option<usize>, Native)] = &[
+    ("format", None, format),
+    ("to_hex", Some(1), to_hex),
+    ("to_bin", Some(1), to_bin),
+    ("len", Some(1), len),
+    ("push", Some(2), push),
+    ("chars", Some(1), native_chars),
+    ("substr", Some(3), substr),
+    ("find", Some(2), find),
+    ("split", Some(2), split),
+    ("join", Some(2), join),
+    ("upper", Some(1), upper),
+    ("lower", Some(1), lower),
+    ("keys", Some(1), keys),
+    ("values", Some(1), values),
+    ("str", Some(1), str),
+    ("num", Some(1), num),
+    ("input", Some(0), input),
+    ("prompt", Some(1), prompt)
+];
+
+/// Natives reading the system clock, which panics on wasm32-unknown-unknown.
+#[cfg(not(target_arch = "wasm32"))]
+const TIME_NATIVES: &[(&str, Option<usize>, Native)] = &[
+    ("clock", Some(0), clock),
+    ("now_ms", Some(0), now_ms),
+    ("elapsed_ms", Some(1), elapsed_ms),
+    ("date_string", None, date_string)
+];
+
+pub fn define_natives(env_arena: &mut EnvironmentArena, env: usize) {
+    for (name, arity, callee) in NATIVES {
+        define_native(env_arena, env, name, *arity, *callee);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    for (name, arity, callee) in TIME_NATIVES {
+        define_native(env_arena, env, name, *arity, *callee);
+    }
+}
+
+/// Natives that reach outside of the interpreter, left out under `--sandbox` and in wasm builds.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn define_host_natives(env_arena: &mut EnvironmentArena, env: usize) {
+    // exit code of the last `exec`, read back with `exec_status()`
+    let status = Rc::new(Cell::new(0.));
+
+    let exec_status = Rc::clone(&status);
+    env_arena.define(env, "exec", Value::Fun(Fun::Native { name: "exec".into(), arity: Some(1), callee: Rc::new(move |arguments| exec(arguments, &exec_status)) }));
+    env_arena.define(env, "exec_status", Value::Fun(Fun::Native { name: "exec_status".into(), arity: Some(0), callee: Rc::new(move |_| Ok(Value::Number(status.get()))) }));
+}
+
+fn define_native(env_arena: &mut EnvironmentArena, env: usize, name: &str, arity: Option<usize>, callee: Native) {
+    env_arena.define(env, name, Value::Fun(Fun::Native { name: name.into(), arity, callee: Rc::new(callee) }));
+}
+
+fn format(arguments: Vec<Value>) -> Result<Value, String> {
+    let (template, arguments) = match arguments.split_first() {
+        Some((Value::String(template), arguments)) => (template, arguments),
+        _ => return Err("format() expects a string template as first argument.".into())
+    };
+
+    format_template(template, arguments).map(|s| Value::String(s.into()))
+}
+
+/// Formats the integer part of a number in base 16, negatives in two's complement like printf's `%llx`.
+fn to_hex(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::Number(n)) => Ok(Value::String(format!("{:x}", *n as i64).into())),
+        _ => Err("to_hex() expects a number.".into())
+    }
+}
+
+fn to_bin(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::Number(n)) => Ok(Value::String(format!("{:b}", *n as i64).into())),
+        _ => Err("to_bin() expects a number.".into())
+    }
+}
+
+/// Number of elements of a list, of entries of a map, or of characters of a string, counting Unicode scalar values like `chars`.
+fn len(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::String(s)) => Ok(Value::Number(s.chars().count() as f64)),
+        Some(Value::List(list)) => Ok(Value::Number(list.borrow().len() as f64)),
+        Some(Value::Map(map)) => Ok(Value::Number(map.borrow().len() as f64)),
+        _ => Err("len() expects a string, a list or a map.".into())
+    }
+}
+
+/// Appends a value to a list and gives its new length.
+fn push(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.as_slice() {
+        [Value::List(list), value] => {
+            list.borrow_mut().push(value.clone());
+            Ok(Value::Number(list.borrow().len() as f64))
+        },
+        _ => Err("push() expects a list and a value.".into())
+    }
+}
+
+/// The keys of a map as a list, in the order they were first set, to loop over it.
+fn keys(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::Map(map)) => Ok(Value::List(Rc::new(RefCell::new(map.borrow().entries().iter().map(|(key, _)| key.clone()).collect())))),
+        _ => Err("keys() expects a map.".into())
+    }
+}
+
+/// The values of a map as a list, in the order of `keys`.
+fn values(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::Map(map)) => Ok(Value::List(Rc::new(RefCell::new(map.borrow().entries().iter().map(|(_, value)| value.clone()).collect())))),
+        _ => Err("values() expects a map.".into())
+    }
+}
+
+/// Any value as the string `print` would show.
+fn str(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::String(s)) => Ok(Value::String(s.clone())),
+        Some(value) => Ok(Value::String(value.to_string().into())),
+        None => Ok(Value::Null)
+    }
+}
+
+/// Reads a number from a string, surrounding whitespace allowed, `fu` if it isn't one.
+fn num(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::Number(n)) => Ok(Value::Number(*n)),
+        Some(Value::String(s)) => Ok(s.trim().parse().map_or(Value::Null, Value::Number)),
+        _ => Err("num() expects a string or a number.".into())
+    }
+}
+
+/// Runs a command through the system shell and returns its stdout, the exit code is stored in `status`.
+#[cfg(not(target_arch = "wasm32"))]
+fn exec(arguments: Vec<Value>, status: &Cell<f64>) -> Result<Value, String> {
+    let command = match arguments.first() {
+        Some(Value::String(command)) => command,
+        _ => return Err("exec() expects a command string.".into())
+    };
+
+    let output = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(&**command).output()
+    } else {
+        Command::new("sh").arg("-c").arg(&**command).output()
+    };
+
+    match output {
+        Ok(output) => {
+            // killed by a signal: no exit code
+            status.set(output.status.code().map_or(-1., f64::from));
+            Ok(Value::String(String::from_utf8_lossy(&output.stdout).into_owned().into()))
+        },
+        Err(e) => {
+            status.set(-1.);
+            Err(format!("exec() failed: {}", e))
+        }
+    }
+}
+
+/// Prints a message without newline and reads a line of input, `fu` once stdin is exhausted.
+fn prompt(arguments: Vec<Value>) -> Result<Value, String> {
+    if let Some(message) = arguments.first() {
+        print!("{}", message);
+        io::stdout().flush().unwrap_or_default();
+    }
+
+    read_line(&mut io::stdin().lock())
+}
+
+/// Reads a line of input without prompting, `fu` once stdin is exhausted.
+fn input(_arguments: Vec<Value>) -> Result<Value, String> {
+    read_line(&mut io::stdin().lock())
+}
+
+fn read_line(input: &mut impl BufRead) -> Result<Value, String> {
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(0) => Ok(Value::Null),
+        Ok(_) => Ok(Value::String(line.trim_end_matches(['\n', '\r']).into())),
+        Err(e) => Err(format!("Cannot read stdin: {}", e))
+    }
+}
+
+/// Splits a string into one-character strings, one per Unicode scalar value rather than per byte.
+pub fn chars(s: &str) -> Vec<Value> {
+    s.chars().map(|c| Value::String(c.to_string().into())).collect()
+}
+
+fn native_chars(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::String(s)) => Ok(Value::List(Rc::new(RefCell::new(chars(s))))),
+        _ => Err("chars() expects a string.".into())
+    }
+}
+
+/// The `length` characters of a string from `start`, both clamped to the string, unlike slices which fail out of bounds.
+fn substr(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.as_slice() {
+        [Value::String(s), Value::Number(start), Value::Number(length)] => {
+            // casts saturate: negatives become 0
+            Ok(Value::String(s.chars().skip(*start as usize).take(*length as usize).collect::<String>().into()))
+        },
+        _ => Err("substr() expects a string, a start and a length.".into())
+    }
+}
+
+/// Index of the first occurrence of a string in another, in characters like `s[i]`, -1 when there is none.
+fn find(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.as_slice() {
+        [Value::String(s), Value::String(needle)] => match s.find(&**needle) {
+            Some(byte) => Ok(Value::Number(s[..byte].chars().count() as f64)),
+            None => Ok(Value::Number(-1.))
+        },
+        _ => Err("find() expects two strings.".into())
+    }
+}
+
+/// Splits a string on each occurrence of a separator, into characters when it is empty.
+fn split(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.as_slice() {
+        [Value::String(s), Value::String(separator)] if separator.is_empty() => Ok(Value::List(Rc::new(RefCell::new(chars(s))))),
+        [Value::String(s), Value::String(separator)] => {
+            Ok(Value::List(Rc::new(RefCell::new(s.split(&**separator).map(|part| Value::String(part.into())).collect()))))
+        },
+        _ => Err("split() expects two strings.".into())
+    }
+}
+
+/// Concatenates the elements of a list with a separator between them, the reverse of `split`.
+fn join(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.as_slice() {
+        [Value::List(list), Value::String(separator)] => {
+            Ok(Value::String(list.borrow().iter().map(Value::to_string).collect::<Vec<_>>().join(separator).into()))
+        },
+        _ => Err("join() expects a list and a string.".into())
+    }
+}
+
+fn upper(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::String(s)) => Ok(Value::String(s.to_uppercase().into())),
+        _ => Err("upper() expects a string.".into())
+    }
+}
+
+fn lower(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::String(s)) => Ok(Value::String(s.to_lowercase().into())),
+        _ => Err("lower() expects a string.".into())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn unix_ms() -> f64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0., |d| d.as_millis() as f64)
+}
+
+/// Seconds since the Unix epoch, with sub-second precision.
+#[cfg(not(target_arch = "wasm32"))]
+fn clock(_arguments: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Number(unix_ms() / 1000.))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now_ms(_arguments: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Number(unix_ms()))
+}
+
+/// Milliseconds elapsed since a previous `now_ms()`.
+#[cfg(not(target_arch = "wasm32"))]
+fn elapsed_ms(arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments.first() {
+        Some(Value::Number(start)) => Ok(Value::Number(unix_ms() - start)),
+        _ => Err("elapsed_ms() expects a number.".into())
+    }
+}
+
+/// Formats a `now_ms()` timestamp (the current time without argument) as `YYYY-MM-DD HH:MM:SS` UTC.
+#[cfg(not(target_arch = "wasm32"))]
+fn date_string(arguments: Vec<Value>) -> Result<Value, String> {
+    let ms = match arguments.as_slice() {
+        [] => unix_ms(),
+        [Value::Number(ms)] => *ms,
+        _ => return Err("date_string() expects no argument or a timestamp in milliseconds.".into())
+    };
+
+    let seconds = (ms / 1000.).floor() as i64;
+    let (days, time) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+
+    // civil_from_days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    Ok(Value::String(format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, time / 60 % 60, time % 60).into()))
+}
+
+/// Replaces each `{}` in `template` with the next argument; `{{` and `}}` produce literal braces.
+pub fn format_template(template: &str, arguments: &[Value]) -> Result<String, String> {
+    let mut res = String::new();
+    let mut arguments = arguments.iter();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                res.push(c);
+            },
+            ('{', Some('}')) => {
+                chars.next();
+                match arguments.next() {
+                    Some(arg) => res.push_str(&arg.to_string()),
+                    None => return Err("Not enough arguments for format template.".into())
+                }
+            },
+            ('{', _) | ('}', _) => return Err(format!("Unmatched '{}' in format template.", c)),
+            _ => res.push(c)
+        }
+    }
+
+    if arguments.next().is_some() {
+        return Err("Too many arguments for format template.".into());
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::Value;
+
+    use std::{cell::{Cell, RefCell}, rc::Rc};
+
+    use super::{chars, date_string, exec, find, format_template, keys, len, lower, num, read_line, str, substr, to_bin, to_hex, upper};
+
+    #[test]
+    fn test_format_template() {
+        let args = vec![Value::Number(12.0), Value::String("ceya".into()), Value::Boolean(true)];
+        let formatted = format_template("{} {{{}}} {}", &args).expect("Formatted string expected.");
+        assert_eq!(formatted, "12 {ceya} true");
+
+        assert!(format_template("{}", &[]).is_err());
+        assert!(format_template("", &args).is_err());
+        assert!(format_template("{ }", &args).is_err());
+    }
+
+    #[test]
+    fn test_to_hex_bin() {
+        assert_eq!(to_hex(vec![Value::Number(255.0)]), Ok(Value::String("ff".into())));
+        assert_eq!(to_hex(vec![Value::Number(-1.0)]), Ok(Value::String("ffffffffffffffff".into())));
+        assert_eq!(to_bin(vec![Value::Number(10.0)]), Ok(Value::String("1010".into())));
+        assert_eq!(to_bin(vec![Value::String("10".into())]), Err("to_bin() expects a number.".into()));
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(len(vec![Value::String("hé𝄞".into())]), Ok(Value::Number(3.)));
+        assert_eq!(len(vec![Value::Number(12.)]), Err("len() expects a string, a list or a map.".into()));
+        assert_eq!(len(vec![Value::List(Rc::new(RefCell::new(chars("abc"))))]), Ok(Value::Number(3.)));
+        assert_eq!(str(vec![Value::Number(1.5)]), Ok(Value::String("1.5".into())));
+        assert_eq!(str(vec![Value::Boolean(true)]), Ok(Value::String("true".into())));
+        assert_eq!(num(vec![Value::String(" 42\n".into())]), Ok(Value::Number(42.)));
+        assert_eq!(num(vec![Value::String("-2.5e1".into())]), Ok(Value::Number(-25.)));
+        assert_eq!(num(vec![Value::String("ceya".into())]), Ok(Value::Null));
+
+        assert_eq!(crate::run("print len(str(12.5)) + num(\"1\"); print str(len) + \"!\";"), "5\nfun len!\n");
+    }
+
+    #[test]
+    fn test_keys_values() {
+        assert_eq!(crate::run("let m = {\"b\": 1, \"a\": [2]}; m[\"b\"] = 3; m[true] = fu; print keys(m); print values(m); print len(m);"), "[\"b\", \"a\", true]\n[3, [2], null]\n3\n");
+        assert_eq!(keys(vec![Value::List(Rc::new(RefCell::new(vec![])))]), Err("keys() expects a map.".into()));
+    }
+
+    #[test]
+    fn test_strings() {
+        let string = |s: &str| Value::String(s.into());
+        assert_eq!(substr(vec![string("hé𝄞!"), Value::Number(1.), Value::Number(2.)]), Ok(string("é𝄞")));
+        assert_eq!(substr(vec![string("abc"), Value::Number(-1.), Value::Number(9.)]), Ok(string("abc")));
+        assert_eq!(find(vec![string("héllo"), string("llo")]), Ok(Value::Number(2.)));
+        assert_eq!(find(vec![string("abc"), string("d")]), Ok(Value::Number(-1.)));
+        assert_eq!(upper(vec![string("Ceya é")]), Ok(string("CEYA É")));
+        assert_eq!(lower(vec![string("CeYa")]), Ok(string("ceya")));
+        assert_eq!(upper(vec![Value::Number(1.)]), Err("upper() expects a string.".into()));
+
+        assert_eq!(crate::run("let parts = split(\"a,b,,c\", \",\"); print parts; print join(parts, \"-\"); print split(\"ab\", \"\"); print join([1, true], \"\");"), "[\"a\", \"b\", \"\", \"c\"]\na-b--c\n[\"a\", \"b\"]\n1true\n");
+    }
+
+    #[test]
+    fn test_date_string() {
+        assert_eq!(date_string(vec![Value::Number(0.)]), Ok(Value::String("1970-01-01 00:00:00".into())));
+        assert_eq!(date_string(vec![Value::Number(951_782_400_000.)]), Ok(Value::String("2000-02-29 00:00:00".into())));
+        assert_eq!(date_string(vec![Value::Number(1_700_000_000_999.)]), Ok(Value::String("2023-11-14 22:13:20".into())));
+        assert_eq!(date_string(vec![Value::Number(-1000.)]), Ok(Value::String("1969-12-31 23:59:59".into())));
+    }
+
+    #[test]
+    fn test_exec() {
+        let status = Cell::new(0.);
+        assert_eq!(exec(vec![Value::String("echo hi".into())], &status), Ok(Value::String(if cfg!(windows) { "hi\r\n" } else { "hi\n" }.into())));
+        assert_eq!(status.get(), 0.);
+
+        assert_eq!(exec(vec![Value::String("exit 3".into())], &status), Ok(Value::String("".into())));
+        assert_eq!(status.get(), 3.);
+        assert_eq!(exec(vec![Value::Number(1.)], &status), Err("exec() expects a command string.".into()));
+    }
+
+    #[test]
+    fn test_chars() {
+        let expected: Vec<Value> = ["h", "é", "𝄞"].iter().map(|c| Value::String((*c).into())).collect();
+        assert_eq!(chars("hé𝄞"), expected);
+        assert!(chars("").is_empty());
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut input = "ceya\r\nlast".as_bytes();
+        assert_eq!(read_line(&mut input), Ok(Value::String("ceya".into())));
+        assert_eq!(read_line(&mut input), Ok(Value::String("last".into())));
+        assert_eq!(read_line(&mut input), Ok(Value::Null));
+    }
+}
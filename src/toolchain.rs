@@ -0,0 +1,259 @@
+//! Turns the generated `output.asm` into an executable, running the assembler and the linker
+//! found on the PATH with the flags each platform needs.
+
+use std::{env, ffi::OsString, path::{Path, PathBuf}, process::Command};
+
+use clap::ValueEnum;
+use ceya::backend::Target;
+
+use crate::Subsystem;
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum Assembler {
+    #[default]
+    Nasm,
+    /// Reads the same syntax as nasm.
+    Yasm
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Linker {
+    /// The MSVC linker, Windows only.
+    Link,
+    /// MinGW's when targeting Windows.
+    Gcc,
+    /// Through lld-link when targeting Windows.
+    Clang
+}
+
+impl Linker {
+    pub fn default_for(target: Target) -> Linker {
+        match target {
+            Target::Windows => Linker::Link,
+            Target::Linux => Linker::Gcc
+        }
+    }
+}
+
+const DEFAULT_VCVARS: &str = "C:\\Program Files\\Microsoft Visual Studio\\2022\\Community\\VC\\Auxiliary\\Build\\vcvars64.bat";
+
+/// How to build the executable of a build directory holding `output.asm`.
+pub struct Build<'a> {
+    pub dir: &'a Path,
+    pub target: Target,
+    pub release: bool,
+    pub freestanding: bool,
+    pub subsystem: Subsystem,
+    pub assembler: Assembler,
+    pub linker: Linker,
+    /// Script setting up the MSVC environment, run when `link` isn't on the PATH.
+    pub vcvars: Option<&'a str>
+}
+
+impl Build<'_> {
+    fn object(&self) -> &'static str {
+        match self.target {
+            Target::Windows => "output.obj",
+            Target::Linux => "output.o"
+        }
+    }
+
+    pub fn assemble(&self) -> Command {
+        let mut assemble = Command::new(self.assembler.to_possible_value().unwrap().get_name());
+        let format = match self.target {
+            Target::Windows => "win64",
+            Target::Linux => "elf64"
+        };
+        assemble.args(["-f", format, "-l", "output.lst", "-o", self.object(), "output.asm"]);
+        if !self.release {
+            let debug: &[&str] = match (self.assembler, self.target) {
+                (Assembler::Nasm, Target::Windows) => &["-g", "-F", "cv8"],
+                (Assembler::Nasm, Target::Linux) => &["-g", "-F", "dwarf"],
+                (Assembler::Yasm, Target::Windows) => &["-g", "cv8"],
+                (Assembler::Yasm, Target::Linux) => &["-g", "dwarf2"]
+            };
+            assemble.args(debug);
+        }
+        assemble
+    }
+
+    pub fn link(&self) -> Result<Command, String> {
+        let mut link = Command::new(self.linker.to_possible_value().unwrap().get_name());
+        let subsystem = self.subsystem.to_possible_value().unwrap().get_name().to_string();
+        // `main` is the entry point for both subsystems, through the C runtime unless freestanding
+        let entry = if self.freestanding { "main" } else { "mainCRTStartup" };
+
+        match (self.target, self.linker) {
+            (Target::Windows, Linker::Link) if !cfg!(windows) => return Err("link only runs on Windows, cross-link with --linker clang or gcc.".into()),
+            (Target::Windows, Linker::Link) => {
+                link.args([self.object(), "/nologo", "/incremental:no", "/out:output.exe"]);
+                link.arg(format!("/subsystem:{}", subsystem)).arg(format!("/entry:{}", entry));
+                // legacy_stdio_definitions has printf, inlined in the headers since VS2015
+                let libs: &[&str] = match self.freestanding {
+                    true => &["/nodefaultlib", "/defaultlib:Kernel32.lib"],
+                    false => &["/defaultlib:ucrt.lib", "/defaultlib:msvcrt.lib", "/defaultlib:legacy_stdio_definitions.lib", "/defaultlib:Kernel32.lib", "/defaultlib:Shell32.lib"]
+                };
+                link.args(libs);
+                match self.release {
+                    true => link.arg("/opt:ref"),
+                    false => link.args(["/opt:noref", "/debug", "/pdb:output.pdb"])
+                };
+            },
+            (Target::Windows, Linker::Clang) => {
+                link.args(["-fuse-ld=lld-link", "-o", "output.exe", self.object()]);
+                link.arg(format!("-Wl,-machine:x64,-incremental:no,-subsystem:{},-entry:{}", subsystem, entry));
+                if self.freestanding {
+                    link.args(["-nostdlib", "-lkernel32"]);
+                }
+                link.arg(if self.release { "-O3" } else { "-O0" });
+            },
+            (Target::Windows, Linker::Gcc) => {
+                link.args(["-o", "output.exe", self.object()]);
+                link.arg(format!("-Wl,--subsystem,{}", subsystem));
+                if self.freestanding {
+                    link.args(["-nostdlib", "-e", "main", "-lkernel32"]);
+                }
+                if self.release {
+                    link.arg("-s");
+                }
+            },
+            (Target::Linux, Linker::Link) => return Err("link only builds Windows executables, use --linker gcc or clang.".into()),
+            (Target::Linux, _) => {
+                // the code addresses printf and its data directly, not through the PLT/GOT
+                link.args(["-no-pie", "-o", "output", self.object()]);
+                if self.freestanding {
+                    link.args(["-nostdlib", "-static", "-e", "main"]);
+                }
+                if self.release {
+                    link.arg("-s");
+                }
+            }
+        }
+        Ok(link)
+    }
+
+    /// Assembles and links, reporting the step that failed or the tool that is missing. Returns whether it succeeded.
+    pub fn run(&self) -> bool {
+        let link = match self.link() {
+            Ok(link) => link,
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        };
+
+        for mut step in [self.assemble(), link] {
+            let program = step.get_program().to_string_lossy().into_owned();
+            if let Err(e) = self.locate(&mut step, &program) {
+                eprintln!("{}", e);
+                return false;
+            }
+
+            match step.current_dir(self.dir).output() {
+                // link reports its errors on stdout
+                Ok(output) if !output.status.success() => {
+                    eprintln!("{} failed:\n{}{}", program, String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+                    return false;
+                },
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("Cannot run {}: {}", program, e);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Makes sure `program` can be found, setting up the MSVC environment for `link` if needed.
+    fn locate(&self, step: &mut Command, program: &str) -> Result<(), String> {
+        let path = env::var_os("PATH").unwrap_or_default();
+        if find_tool(program, &path).is_some() {
+            return Ok(());
+        }
+
+        if program == "link" {
+            let vcvars = self.vcvars.unwrap_or(DEFAULT_VCVARS);
+            if let Some(vars) = vcvars_env(vcvars) {
+                let found = vars.iter().any(|(key, value)| key.eq_ignore_ascii_case("PATH") && find_tool(program, &OsString::from(value)).is_some());
+                step.envs(vars);
+                if found {
+                    return Ok(());
+                }
+            }
+            return Err(format!("link not found on the PATH nor set up by {}. Run ceya from a Developer Command Prompt, point --vcvars at the vcvars64.bat of your Visual Studio, or pick --linker clang.", vcvars));
+        }
+
+        let hint = match program {
+            "nasm" => "Install it from https://nasm.us or pick --assembler yasm.",
+            "yasm" => "Install it or pick --assembler nasm.",
+            _ => "Install it or pick another with --linker."
+        };
+        Err(format!("{} not found on the PATH. {}", program, hint))
+    }
+}
+
+/// Where `name` is in the directories of `path`, the PATH environment variable.
+fn find_tool(name: &str, path: &OsString) -> Option<PathBuf> {
+    env::split_paths(path).map(|dir| dir.join(format!("{}{}", name, env::consts::EXE_SUFFIX))).find(|file| file.is_file())
+}
+
+/// Environment variables after running `vcvars`, `None` if it didn't run.
+#[cfg(windows)]
+fn vcvars_env(vcvars: &str) -> Option<Vec<(String, String)>> {
+    use std::os::windows::process::CommandExt;
+
+    // cmd strips the outer quotes and keeps the ones around the path
+    let output = Command::new("cmd").arg("/C").raw_arg(format!("\"call \"{}\" >nul && set\"", vcvars)).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).lines().filter_map(|line| line.split_once('=')).map(|(key, value)| (key.into(), value.into())).collect())
+}
+
+#[cfg(not(windows))]
+fn vcvars_env(_: &str) -> Option<Vec<(String, String)>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, process::Command};
+
+    use ceya::backend::Target;
+
+    use super::{find_tool, Assembler, Build, Linker};
+    use crate::Subsystem;
+
+    fn args(command: &Command) -> Vec<String> {
+        command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut build = Build { dir: Path::new("."), target: Target::Linux, release: false, freestanding: false, subsystem: Subsystem::Console, assembler: Assembler::Nasm, linker: Linker::Gcc, vcvars: None };
+        assert_eq!(args(&build.assemble()), ["-f", "elf64", "-l", "output.lst", "-o", "output.o", "output.asm", "-g", "-F", "dwarf"]);
+        assert_eq!(args(&build.link().unwrap()), ["-no-pie", "-o", "output", "output.o"]);
+
+        build.release = true;
+        build.freestanding = true;
+        build.assembler = Assembler::Yasm;
+        assert_eq!(build.assemble().get_program(), "yasm");
+        assert_eq!(args(&build.link().unwrap()), ["-no-pie", "-o", "output", "output.o", "-nostdlib", "-static", "-e", "main", "-s"]);
+        build.linker = Linker::Link;
+        assert!(build.link().is_err());
+
+        build.target = Target::Windows;
+        build.linker = Linker::Clang;
+        build.subsystem = Subsystem::Windows;
+        assert_eq!(args(&build.assemble())[..7], ["-f", "win64", "-l", "output.lst", "-o", "output.obj", "output.asm"]);
+        assert!(args(&build.link().unwrap()).contains(&"-Wl,-machine:x64,-incremental:no,-subsystem:windows,-entry:main".to_string()));
+    }
+
+    #[test]
+    fn test_find_tool() {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        assert!(find_tool("ceya-no-such-tool", &path).is_none());
+    }
+}
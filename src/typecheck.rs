@@ -0,0 +1,384 @@
+//! Types of annotated programs, `let x: num = 1;` and `fn f(s: str): bool`, checked between parsing
+//! and running or compiling: `"a" - 1` is rejected instead of giving `fu` in `sim` and garbage in
+//! compiled code. Annotations are optional, what can't be known before running is `any`.
+
+use std::{borrow::Borrow, collections::HashMap, fmt::{self, Display, Formatter}, rc::Rc};
+
+use crate::{ast::{Expr, Stmt, Value}, error::CeyaError, scanner::{Span, Token, TokenType}};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Type {
+    Num,
+    Str,
+    Bool,
+    List,
+    Map,
+    /// Any value, unannotated variables and parameters included.
+    Any
+}
+
+impl Type {
+    /// The type named in an annotation.
+    pub fn from_name(name: &str) -> Option<Type> {
+        Some(match name {
+            "num" => Type::Num,
+            "str" => Type::Str,
+            "bool" => Type::Bool,
+            "list" => Type::List,
+            "map" => Type::Map,
+            "any" => Type::Any,
+            _ => return None
+        })
+    }
+
+    /// Whether a value of type `other` can be stored where `self` is expected.
+    fn accepts(self, other: Type) -> bool {
+        self == Type::Any || other == Type::Any || self == other
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Num => "num",
+            Type::Str => "str",
+            Type::Bool => "bool",
+            Type::List => "list",
+            Type::Map => "map",
+            Type::Any => "any"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+enum Binding {
+    Variable(Type),
+    /// A function as declared, until something else is assigned to its name.
+    Function { params: Rc<[Type]>, returns: Type }
+}
+
+struct TypeChecker {
+    scopes: Vec<HashMap<String, Binding>>,
+    /// Name and return type of the functions being checked, innermost last.
+    functions: Vec<(String, Type)>,
+    errors: Vec<CeyaError>
+}
+
+/// Checks a whole program, returning the type errors found.
+pub fn check<S: Borrow<Stmt>>(stmts: &[S]) -> Vec<CeyaError> {
+    let mut checker = TypeChecker { scopes: vec![HashMap::new()], functions: vec![], errors: vec![] };
+    checker.statements(stmts);
+    checker.errors
+}
+
+impl TypeChecker {
+    /// Checks statements of the innermost scope, whose functions can be called before their declaration.
+    fn statements<S: Borrow<Stmt>>(&mut self, stmts: &[S]) {
+        for stmt in stmts {
+            if let Stmt::Fun { name, types, returns, .. } = stmt.borrow() {
+                self.declare_function(name, types, *returns);
+            }
+        }
+
+        for stmt in stmts {
+            self.stmt(stmt.borrow());
+        }
+    }
+
+    fn scoped(&mut self, check: impl FnOnce(&mut Self)) {
+        self.scopes.push(HashMap::new());
+        check(self);
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, name: &Token, binding: Binding) {
+        self.scopes.last_mut().expect("global scope").insert(name.lexeme.clone(), binding);
+    }
+
+    fn declare_function(&mut self, name: &Token, types: &[Option<Type>], returns: Option<Type>) {
+        let params = types.iter().map(|typ| typ.unwrap_or(Type::Any)).collect();
+        self.declare(name, Binding::Function { params, returns: returns.unwrap_or(Type::Any) });
+    }
+
+    fn lookup(&mut self, name: &Token) -> Option<&mut Binding> {
+        self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(&name.lexeme))
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.errors.push(CeyaError::Semantic { span, message });
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block { statements, .. } => self.scoped(|checker| checker.statements(statements)),
+            Stmt::Expression { expression, .. } => {
+                self.expr(expression);
+            },
+            Stmt::Print { expression, arguments, .. } => {
+                self.expr(expression);
+                for argument in arguments {
+                    self.expr(argument);
+                }
+            },
+            Stmt::Let { name, annotation, initializer, .. } => {
+                let typ = self.expr(initializer);
+                let declared = annotation.unwrap_or(Type::Any);
+                if !declared.accepts(typ) {
+                    self.error(initializer.span(), format!("Cannot initialize '{}' of type {} with a {}.", name.lexeme, declared, typ));
+                }
+                self.declare(name, Binding::Variable(declared));
+            },
+            Stmt::Unpack { names, initializer, .. } => {
+                self.expr(initializer);
+                for name in names {
+                    self.declare(name, Binding::Variable(Type::Any));
+                }
+            },
+            Stmt::If { condition, then, els, .. } => {
+                self.expr(condition);
+                self.stmt(then);
+                if let Some(els) = els {
+                    self.stmt(els);
+                }
+            },
+            Stmt::While { condition, body, increment, .. } => {
+                self.expr(condition);
+                self.stmt(body);
+                if let Some(increment) = increment {
+                    self.expr(increment);
+                }
+            },
+            // already declared with the other functions of the block
+            Stmt::Fun { .. } => self.function(stmt),
+            Stmt::Struct { name, methods, .. } => {
+                self.declare(name, Binding::Variable(Type::Any));
+                for method in methods {
+                    self.function(method);
+                }
+            },
+            Stmt::Return { value, .. } => {
+                if let (Expr::Tuple { elements, .. }, Some((name, returns))) = (value.as_ref(), self.functions.last()) {
+                    if *returns != Type::Any {
+                        let message = format!("Cannot return {} values from '{}', declared to return {}.", elements.len(), name, returns);
+                        self.error(value.span(), message);
+                    }
+                }
+                let typ = self.expr(value);
+                if let Some((name, returns)) = self.functions.last().filter(|(_, returns)| !returns.accepts(typ)) {
+                    let message = format!("Cannot return a {} from '{}', declared to return {}.", typ, name, returns);
+                    self.error(value.span(), message);
+                }
+            },
+            Stmt::Faran { .. } | Stmt::Ke { .. } | Stmt::Swap { .. } | Stmt::Over { .. } | Stmt::Rot { .. } | Stmt::Drop2 { .. }
+                | Stmt::Break { .. } | Stmt::Continue { .. } => ()
+        }
+    }
+
+    /// Checks the body of a `fn` declaration, which sees its parameters.
+    fn function(&mut self, stmt: &Stmt) {
+        let Stmt::Fun { name, params, types, returns, body, .. } = stmt else { unreachable!("not a function declaration") };
+        self.functions.push((name.lexeme.clone(), returns.unwrap_or(Type::Any)));
+        self.scoped(|checker| {
+            for (param, typ) in params.iter().zip(types) {
+                checker.declare(param, Binding::Variable(typ.unwrap_or(Type::Any)));
+            }
+            checker.stmt(body);
+        });
+        self.functions.pop();
+    }
+
+    /// The type of an expression, after reporting the errors in it.
+    fn expr(&mut self, expr: &Expr) -> Type {
+        match expr {
+            Expr::Literal { value, .. } => match value {
+                Value::Number(_) => Type::Num,
+                Value::String(_) => Type::Str,
+                Value::Boolean(_) => Type::Bool,
+                // `fu` stands for a missing value of any type
+                _ => Type::Any
+            },
+            Expr::Grouping { expression, .. } => self.expr(expression),
+            Expr::Unary { operator, right, .. } => {
+                let typ = self.expr(right);
+                match operator.typ {
+                    TokenType::Minus => {
+                        if !Type::Num.accepts(typ) {
+                            self.error(expr.span(), format!("Operand of '-' must be a number, found {}.", typ));
+                        }
+                        Type::Num
+                    },
+                    _ => Type::Bool
+                }
+            },
+            Expr::Binary { left, operator, right, .. } => {
+                let (l, r) = (self.expr(left), self.expr(right));
+                self.binary(operator, l, r, expr.span())
+            },
+            Expr::Logical { left, right, .. } => {
+                let (l, r) = (self.expr(left), self.expr(right));
+                if l == r { l } else { Type::Any }
+            },
+            Expr::Ternary { condition, then, els, .. } => {
+                self.expr(condition);
+                let (then, els) = (self.expr(then), self.expr(els));
+                if then == els { then } else { Type::Any }
+            },
+            Expr::Variable { name, .. } => match self.lookup(name) {
+                Some(Binding::Variable(typ)) => *typ,
+                _ => Type::Any
+            },
+            Expr::Assign { name, value, .. } => {
+                let typ = self.expr(value);
+                match self.lookup(name) {
+                    Some(Binding::Variable(declared)) if !declared.accepts(typ) => {
+                        let message = format!("Cannot assign a {} to '{}' of type {}.", typ, name.lexeme, declared);
+                        self.error(value.span(), message);
+                    },
+                    // whatever it held, it may not be that function anymore
+                    Some(binding @ Binding::Function { .. }) => *binding = Binding::Variable(Type::Any),
+                    _ => ()
+                }
+                typ
+            },
+            Expr::Call { callee, arguments, .. } => {
+                let types: Vec<Type> = arguments.iter().map(|argument| self.expr(argument)).collect();
+                let Expr::Variable { name, .. } = callee.as_ref() else {
+                    self.expr(callee);
+                    return Type::Any;
+                };
+                let Some(Binding::Function { params, returns }) = self.lookup(name) else {
+                    return Type::Any;
+                };
+
+                let (params, returns) = (Rc::clone(params), *returns);
+                for (i, ((param, typ), argument)) in params.iter().zip(&types).zip(arguments).enumerate() {
+                    if !param.accepts(*typ) {
+                        self.error(argument.span(), format!("Argument {} of '{}' must be a {}, found {}.", i + 1, name.lexeme, param, typ));
+                    }
+                }
+                returns
+            },
+            Expr::List { elements, .. } => {
+                for element in elements {
+                    self.expr(element);
+                }
+                Type::List
+            },
+            Expr::Tuple { elements, .. } => {
+                for element in elements {
+                    self.expr(element);
+                }
+                Type::Any
+            },
+            Expr::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                Type::Map
+            },
+            Expr::Index { object, index, .. } => {
+                let (object, _) = (self.expr(object), self.expr(index));
+                if object == Type::Str { Type::Str } else { Type::Any }
+            },
+            Expr::Slice { object, start, end, .. } => {
+                let object = self.expr(object);
+                for bound in start.iter().chain(end) {
+                    self.expr(bound);
+                }
+                if matches!(object, Type::Str | Type::List) { object } else { Type::Any }
+            },
+            Expr::SetIndex { object, index, value, .. } => {
+                self.expr(object);
+                self.expr(index);
+                self.expr(value)
+            },
+            Expr::Instance { class, fields, .. } => {
+                self.expr(class);
+                for (_, value) in fields {
+                    self.expr(value);
+                }
+                Type::Any
+            },
+            Expr::Get { object, .. } => {
+                self.expr(object);
+                Type::Any
+            },
+            Expr::Set { object, value, .. } => {
+                self.expr(object);
+                self.expr(value)
+            },
+            Expr::Depth { .. } => Type::Num,
+            Expr::Soro { .. } => Type::Any
+        }
+    }
+
+    fn binary(&mut self, operator: &Token, l: Type, r: Type, span: Span) -> Type {
+        match operator.typ {
+            TokenType::Minus | TokenType::Star | TokenType::Slash => {
+                if !Type::Num.accepts(l) || !Type::Num.accepts(r) {
+                    self.error(span, format!("Operands of '{}' must be numbers, found {} and {}.", operator.lexeme, l, r));
+                }
+                Type::Num
+            },
+            TokenType::Plus => {
+                if [l, r].iter().any(|typ| !matches!(typ, Type::Num | Type::Str | Type::Any)) {
+                    self.error(span, format!("Operands of '+' must be numbers or strings, found {} and {}.", l, r));
+                }
+                match (l, r) {
+                    (Type::Num, Type::Num) => Type::Num,
+                    (Type::Str, _) | (_, Type::Str) => Type::Str,
+                    _ => Type::Any
+                }
+            },
+            TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
+                if !Type::Num.accepts(l) || !Type::Num.accepts(r) {
+                    self.error(span, format!("Operands of '{}' must be numbers, found {} and {}.", operator.lexeme, l, r));
+                }
+                Type::Bool
+            },
+            _ => Type::Bool
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parser, scanner};
+
+    fn check(source: &str) -> Vec<String> {
+        let stmts = parser::parse(scanner::scan(source)).unwrap();
+        super::check(&stmts).iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_typecheck() {
+        assert!(check("let x: num = 5; let s: str = \"a\" + x; fn add(a: num, b: num): num { return a + b; } print add(x, 2) * 3; let y = fu; print y - 1;").is_empty());
+        assert_eq!(check("print \"a\" - 1;\nprint -true; print [1] + 1; print 1 < \"2\";"), [
+            "[line 0:6] Error: Operands of '-' must be numbers, found str and num.",
+            "[line 1:6] Error: Operand of '-' must be a number, found bool.",
+            "[line 1:19] Error: Operands of '+' must be numbers or strings, found list and num.",
+            "[line 1:34] Error: Operands of '<' must be numbers, found num and str."
+        ]);
+    }
+
+    #[test]
+    fn test_typecheck_annotations() {
+        assert_eq!(check("let x: num = \"a\"; x = true; let b: bool = x > 1; b = fu;"), [
+            "[line 0:13] Error: Cannot initialize 'x' of type num with a str.",
+            "[line 0:22] Error: Cannot assign a bool to 'x' of type num."
+        ]);
+        // functions can be called before their declaration, and checked until reassigned
+        assert_eq!(check("print f(\"a\") - 1; fn f(n: num): str { return n; } f = 1; print f(\"b\");"), [
+            "[line 0:8] Error: Argument 1 of 'f' must be a num, found str.",
+            "[line 0:6] Error: Operands of '-' must be numbers, found str and num.",
+            "[line 0:45] Error: Cannot return a num from 'f', declared to return str."
+        ]);
+        assert_eq!(check("fn f(): num { return 1, 2; } fn g() { return 1, 2; } let (a, b) = g(); let c: str = a;"), [
+            "[line 0:21] Error: Cannot return 2 values from 'f', declared to return num."
+        ]);
+        // a parameter shadows the variables around the function
+        assert!(check("let n: str = \"a\"; fn g(n) { n = 1; } { let n: num = 1; }").is_empty());
+    }
+}
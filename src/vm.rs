@@ -0,0 +1,997 @@
+//! Bytecode for `sim --vm`: each function is compiled once to a flat list of stack instructions,
+//! which a loop runs without walking the syntax tree again. Locals live in slots of the value
+//! stack resolved at compile time, globals and natives stay in the interpreter's global
+//! environment, and operators and runtime errors behave like the interpreter's.
+//!
+//! Names are resolved where they are used, so a function only sees the locals of the enclosing
+//! blocks declared before it, where the interpreter would also find the ones declared later.
+
+use std::{cell::RefCell, collections::HashSet, mem, rc::Rc};
+
+use crate::{ast::{apply_binary, apply_unary, get_field, get_index, instantiate, overload, pop_operand, set_field, set_index, slice, stack_overflow, stack_word, take_operands, unpack, Expr, Fun, Map, Stmt, Struct, Value, MAX_CALL_DEPTH}, environment::EnvironmentArena, error::CeyaError, scanner::{Span, Token, TokenType}, stdlib};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Null,
+    /// Pushes a value of the constant table.
+    Constant(usize),
+    Pop,
+    /// Pops the locals of a block being left.
+    PopN(usize),
+    /// Reads a global, the operand indexes the table of names.
+    GetGlobal(usize),
+    /// Assigns the value on top of the stack to a global, leaving it there like all assignments.
+    SetGlobal(usize),
+    /// Pops the value of a `let` or `fn` into a global.
+    DefineGlobal(usize),
+    /// Reads a stack slot of the frame, parameters first.
+    GetLocal(usize),
+    SetLocal(usize),
+    /// Reads a local captured by a function, which outlives the frame in a cell.
+    GetCell(usize),
+    SetCell(usize),
+    /// Pops a value into a new cell, closures made before keep the previous one.
+    DefineCell(usize),
+    /// Reads a variable of an enclosing function captured by the running closure.
+    GetCapture(usize),
+    SetCapture(usize),
+    /// Applies an operator, `span` locating the errors of the operator method it may call.
+    Binary { operator: TokenType, span: Span },
+    Unary(TokenType),
+    Jump(usize),
+    /// Pops the condition and jumps when it is falsy.
+    JumpIfFalse(usize),
+    /// Pops the condition and jumps when it is truthy.
+    JumpIfTrue(usize),
+    /// `and`: jumps keeping the left operand when it is falsy, pops it otherwise.
+    And(usize),
+    /// `or`: jumps keeping the left operand when it is truthy, pops it otherwise.
+    Or(usize),
+    /// Checks the callee on top of the stack before its arguments are evaluated, reporting a
+    /// wrong arity or a value that isn't a function and jumping over the call with `fu`.
+    Callee { arguments: usize, span: Span, skip: usize },
+    /// Calls the function below the arguments, which are replaced with its result.
+    Call { arguments: usize, span: Span },
+    /// Creates a closure of a function of the table, capturing the variables it uses.
+    Closure(usize),
+    Return,
+    /// Checks that a formatted `print` has a string template before its arguments are evaluated,
+    /// jumping over the print otherwise.
+    Template { span: Span, skip: usize },
+    Print { arguments: usize, stderr: bool, span: Span },
+    /// Pops a value onto the operand stack of `soro`: the value of an expression statement, or an
+    /// operand set aside while the next one may pop it, like in compiled code.
+    Leave,
+    /// Moves the operands set aside by `Leave` back, in the order they were left.
+    Take { count: usize, span: Span },
+    Soro(Span),
+    Depth,
+    /// Replaces the elements on top of the stack with a new list of them.
+    List(usize),
+    /// Replaces the values on top of the stack with a tuple of them.
+    Tuple(usize),
+    /// Moves the values of the tuple on top of the stack to the operand stack, the first on top,
+    /// where `Take` gets them back one at a time for the names of `let (a, b)`.
+    Unpack { count: usize, span: Span },
+    /// Replaces the keys and values alternating on top of the stack with a new map of them.
+    Map { entries: usize, span: Span },
+    /// Replaces a list or a map and an index or a key with the element.
+    GetIndex(Span),
+    /// Replaces a struct and the closures of its operator methods with the struct holding them.
+    Methods(usize),
+    /// Replaces a struct and the values of the fields named by the table of names with a new instance.
+    Instance { fields: Vec<usize>, span: Span },
+    /// Replaces an instance with the value of the field named by the table of names.
+    GetField { name: usize, span: Span },
+    /// Replaces an instance and a value with the value, stored in the field.
+    SetField { name: usize, span: Span },
+    /// Replaces a string or a list and the bounds given with the slice between them.
+    Slice { start: bool, end: bool, span: Span },
+    /// Replaces a list or a map, an index or a key and a value with the value, stored there.
+    SetIndex(Span),
+    /// `faran`, `ke`, `swap`, `over`, `rot` or `drop2` on the operand stack.
+    Word { word: TokenType, span: Span }
+}
+
+/// Where a closure takes a captured variable from when it is created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    /// A cell of the enclosing function's frame.
+    Cell(usize),
+    /// A variable the enclosing function captured itself.
+    Capture(usize)
+}
+
+/// A compiled function, or the top-level code of the program.
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub code: Vec<Op>,
+    pub constants: Vec<Value>,
+    /// Globals it uses, as tokens so errors can name them.
+    pub names: Vec<Rc<Token>>,
+    /// Functions declared in its body.
+    pub functions: Vec<Rc<Function>>,
+    /// Cells of its frame, one per captured local.
+    pub cells: usize,
+    pub captures: Vec<Capture>
+}
+
+impl Function {
+    fn new(name: &str, arity: usize) -> Self {
+        Function { name: name.into(), arity, code: vec![], constants: vec![], names: vec![], functions: vec![], cells: 0, captures: vec![] }
+    }
+
+    /// Instructions of the function and of the ones declared in it, for `sim --stats`.
+    pub fn instruction_count(&self) -> usize {
+        self.code.len() + self.functions.iter().map(|function| function.instruction_count()).sum::<usize>()
+    }
+}
+
+/// Compiles a program to the code of its top level.
+pub fn compile(stmts: &[Stmt]) -> Rc<Function> {
+    let mut captured = HashSet::new();
+    loop {
+        let mut compiler = Compiler { functions: vec![State::new(Function::new("script", 0))], captured: &mut captured, stale: false };
+        for stmt in stmts {
+            compiler.statement(stmt);
+        }
+        compiler.emit(Op::Null);
+        compiler.emit(Op::Return);
+
+        if !compiler.stale {
+            return Rc::new(compiler.functions.pop().expect("script").function);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Storage {
+    Slot(usize),
+    Cell(usize)
+}
+
+struct Local {
+    /// The token of the declaration, which also identifies it.
+    name: Rc<Token>,
+    depth: usize,
+    storage: Storage
+}
+
+struct Loop {
+    /// Stack slots taken when the loop starts, the others are popped by `break` and `continue`.
+    slots: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>
+}
+
+/// A function being compiled.
+struct State {
+    function: Function,
+    locals: Vec<Local>,
+    /// Blocks the code is nested in, 0 at the top level where variables are globals.
+    depth: usize,
+    slots: usize,
+    loops: Vec<Loop>
+}
+
+impl State {
+    fn new(function: Function) -> Self {
+        State { function, locals: vec![], depth: 0, slots: 0, loops: vec![] }
+    }
+}
+
+enum Variable {
+    Global(usize),
+    Local(Storage),
+    Capture(usize)
+}
+
+struct Compiler<'a> {
+    /// The function being compiled last, the ones it is declared in before it.
+    functions: Vec<State>,
+    /// Declarations of the locals some function captures. Found while compiling the functions,
+    /// they make the program compile again with these locals in cells.
+    captured: &'a mut HashSet<*const Token>,
+    stale: bool
+}
+
+impl Compiler<'_> {
+    fn current(&mut self) -> &mut State {
+        self.functions.last_mut().expect("function")
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let code = &mut self.current().function.code;
+        code.push(op);
+        code.len() - 1
+    }
+
+    /// Points the jump at `index` to the next instruction.
+    fn patch(&mut self, index: usize) {
+        let next = self.current().function.code.len();
+        match &mut self.current().function.code[index] {
+            Op::Jump(target) | Op::JumpIfFalse(target) | Op::JumpIfTrue(target) | Op::And(target) | Op::Or(target)
+                | Op::Callee { skip: target, .. } | Op::Template { skip: target, .. } => *target = next,
+            op => unreachable!("{:?} doesn't jump", op)
+        }
+    }
+
+    fn constant(&mut self, value: Value) -> usize {
+        let constants = &mut self.current().function.constants;
+        constants.push(value);
+        constants.len() - 1
+    }
+
+    /// Declares a variable holding the value on top of the stack.
+    fn define(&mut self, name: &Rc<Token>) {
+        if self.current().depth == 0 {
+            let name = self.name(name);
+            self.emit(Op::DefineGlobal(name));
+        } else if let Some(storage) = self.redeclared(name) {
+            self.assign(storage);
+        } else if let Storage::Cell(cell) = self.declare(name) {
+            self.emit(Op::DefineCell(cell));
+        } // a slot is the value left on the stack
+    }
+
+    fn name(&mut self, name: &Rc<Token>) -> usize {
+        let names = &mut self.current().function.names;
+        match names.iter().position(|other| other.lexeme == name.lexeme) {
+            Some(index) => index,
+            None => {
+                names.push(Rc::clone(name));
+                names.len() - 1
+            }
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.current();
+        let depth = state.depth;
+        let mut slots = 0;
+        while state.locals.last().is_some_and(|local| local.depth == depth) {
+            if let Some(Local { storage: Storage::Slot(_), .. }) = state.locals.pop() {
+                slots += 1;
+            }
+        }
+        state.slots -= slots;
+        state.depth -= 1;
+        self.pop(slots);
+    }
+
+    fn pop(&mut self, slots: usize) {
+        match slots {
+            0 => (),
+            1 => { self.emit(Op::Pop); },
+            n => { self.emit(Op::PopN(n)); }
+        }
+    }
+
+    /// A local of the current scope with this name: declaring it again replaces its value, like the
+    /// interpreter does in the same environment.
+    fn redeclared(&mut self, name: &Token) -> Option<Storage> {
+        let state = self.current();
+        let depth = state.depth;
+        state.locals.iter().rev().take_while(|local| local.depth == depth).find(|local| local.name.lexeme == name.lexeme).map(|local| local.storage)
+    }
+
+    /// Adds a local to the current scope, in a cell when a function captures it.
+    fn declare(&mut self, name: &Rc<Token>) -> Storage {
+        let cell = self.captured.contains(&Rc::as_ptr(name));
+        let state = self.current();
+        let storage = if cell {
+            state.function.cells += 1;
+            Storage::Cell(state.function.cells - 1)
+        } else {
+            state.slots += 1;
+            Storage::Slot(state.slots - 1)
+        };
+        state.locals.push(Local { name: Rc::clone(name), depth: state.depth, storage });
+        storage
+    }
+
+    /// Stores the value on top of the stack in an existing local.
+    fn assign(&mut self, storage: Storage) {
+        match storage {
+            Storage::Slot(slot) => self.emit(Op::SetLocal(slot)),
+            Storage::Cell(cell) => self.emit(Op::SetCell(cell))
+        };
+        self.emit(Op::Pop);
+    }
+
+    fn resolve(&mut self, name: &Rc<Token>) -> Variable {
+        let level = self.functions.len() - 1;
+        if let Some(local) = self.functions[level].locals.iter().rev().find(|local| local.name.lexeme == name.lexeme) {
+            return Variable::Local(local.storage);
+        }
+        match self.capture(level, name) {
+            Some(capture) => Variable::Capture(capture),
+            None => Variable::Global(self.name(name))
+        }
+    }
+
+    /// Index of a variable of the functions enclosing `level` in the ones it captures, `None` for globals.
+    fn capture(&mut self, level: usize, name: &Token) -> Option<usize> {
+        if level == 0 {
+            return None;
+        }
+
+        let capture = match self.functions[level - 1].locals.iter().rev().find(|local| local.name.lexeme == name.lexeme) {
+            Some(Local { storage: Storage::Cell(cell), .. }) => Capture::Cell(*cell),
+            // only cells outlive their frame, the code compiled in this pass is thrown away
+            Some(local) => {
+                self.captured.insert(Rc::as_ptr(&local.name));
+                self.stale = true;
+                Capture::Cell(0)
+            },
+            None => Capture::Capture(self.capture(level - 1, name)?)
+        };
+
+        let captures = &mut self.functions[level].function.captures;
+        match captures.iter().position(|other| *other == capture) {
+            Some(index) => Some(index),
+            None => {
+                captures.push(capture);
+                Some(captures.len() - 1)
+            }
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block { statements, .. } => {
+                self.begin_scope();
+                for stmt in statements {
+                    self.statement(stmt);
+                }
+                self.end_scope();
+            },
+            Stmt::Expression { expression, .. } => {
+                self.expression(expression);
+                self.emit(Op::Leave);
+            },
+            Stmt::Print { expression, arguments, stderr, span } => {
+                self.expression(expression);
+                if arguments.is_empty() {
+                    self.emit(Op::Print { arguments: 0, stderr: *stderr, span: *span });
+                    return;
+                }
+
+                let template = self.emit(Op::Template { span: *span, skip: 0 });
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.emit(Op::Print { arguments: arguments.len(), stderr: *stderr, span: *span });
+                self.patch(template);
+            },
+            Stmt::Let { name, initializer, .. } => {
+                self.expression(initializer);
+                self.define(name);
+            },
+            Stmt::Unpack { names, initializer, span } => {
+                self.expression(initializer);
+                self.emit(Op::Unpack { count: names.len(), span: *span });
+                for name in names {
+                    self.emit(Op::Take { count: 1, span: *span });
+                    self.define(name);
+                }
+            },
+            Stmt::Struct { name, fields, methods, .. } => {
+                let index = self.constant(Stmt::declare_struct(name, fields, vec![]));
+                self.emit(Op::Constant(index));
+                if !methods.is_empty() {
+                    for method in methods {
+                        let Stmt::Fun { name, params, body, .. } = method else { unreachable!("a method") };
+                        self.function(name, params, body);
+                    }
+                    self.emit(Op::Methods(methods.len()));
+                }
+                self.define(name);
+            },
+            Stmt::If { condition, then, els, .. } => {
+                self.expression(condition);
+                let skip_then = self.emit(Op::JumpIfFalse(0));
+                self.statement(then);
+                match els {
+                    Some(els) => {
+                        let skip_els = self.emit(Op::Jump(0));
+                        self.patch(skip_then);
+                        self.statement(els);
+                        self.patch(skip_els);
+                    },
+                    None => self.patch(skip_then)
+                }
+            },
+            Stmt::While { condition, body, increment, .. } => {
+                let constant = condition.constant();
+                if constant.as_ref().is_some_and(|value| !value.is_truthy()) {
+                    return;
+                }
+
+                // the condition is tested after the body, a single jump per iteration
+                let to_condition = constant.is_none().then(|| self.emit(Op::Jump(0)));
+                let body_start = self.current().function.code.len();
+                let slots = self.current().slots;
+                self.current().loops.push(Loop { slots, breaks: vec![], continues: vec![] });
+                self.statement(body);
+                let lp = self.current().loops.pop().expect("loop");
+
+                for index in lp.continues {
+                    self.patch(index);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                    self.emit(Op::Pop);
+                }
+                match to_condition {
+                    Some(index) => {
+                        self.patch(index);
+                        self.expression(condition);
+                        self.emit(Op::JumpIfTrue(body_start));
+                    },
+                    None => {
+                        self.emit(Op::Jump(body_start));
+                    }
+                }
+                for index in lp.breaks {
+                    self.patch(index);
+                }
+            },
+            Stmt::Fun { name, params, body, .. } => {
+                if self.current().depth == 0 {
+                    self.function(name, params, body);
+                    let name = self.name(name);
+                    self.emit(Op::DefineGlobal(name));
+                } else if let Some(storage) = self.redeclared(name) {
+                    self.function(name, params, body);
+                    self.assign(storage);
+                } else {
+                    // declared before its body so it can call itself
+                    match self.declare(name) {
+                        Storage::Cell(cell) => {
+                            self.emit(Op::Null);
+                            self.emit(Op::DefineCell(cell));
+                            self.function(name, params, body);
+                            self.assign(Storage::Cell(cell));
+                        },
+                        Storage::Slot(_) => self.function(name, params, body)
+                    }
+                }
+            },
+            Stmt::Return { value, .. } => {
+                self.expression(value);
+                self.emit(Op::Return);
+            },
+            Stmt::Break { .. } | Stmt::Continue { .. } => {
+                let state = self.current();
+                let lp = state.loops.last().expect("the parser rejects jumps outside loops");
+                let slots = state.slots - lp.slots;
+                self.pop(slots);
+                let jump = self.emit(Op::Jump(0));
+                let lp = self.current().loops.last_mut().expect("loop");
+                if matches!(stmt, Stmt::Break { .. }) {
+                    lp.breaks.push(jump);
+                } else {
+                    lp.continues.push(jump);
+                }
+            },
+            Stmt::Faran { span } | Stmt::Ke { span } | Stmt::Swap { span } | Stmt::Over { span } | Stmt::Rot { span } | Stmt::Drop2 { span } => {
+                self.emit(Op::Word { word: stmt.word().expect("stack word"), span: *span });
+            }
+        }
+    }
+
+    /// Compiles a function declared in the current one and pushes a closure of it.
+    fn function(&mut self, name: &Token, params: &[Rc<Token>], body: &Stmt) {
+        self.functions.push(State::new(Function::new(&name.lexeme, params.len())));
+        self.begin_scope();
+        for param in params {
+            let slot = self.current().slots;
+            if let Storage::Cell(cell) = self.declare(param) {
+                // the argument still takes its slot
+                self.current().slots += 1;
+                self.emit(Op::GetLocal(slot));
+                self.emit(Op::DefineCell(cell));
+            }
+        }
+        self.statement(body);
+        self.emit(Op::Null);
+        self.emit(Op::Return);
+
+        let function = self.functions.pop().expect("function").function;
+        let functions = &mut self.current().function.functions;
+        functions.push(Rc::new(function));
+        let index = functions.len() - 1;
+        self.emit(Op::Closure(index));
+    }
+
+    /// Pushes operands from left to right, through the operand stack of `soro` when one of them uses it.
+    fn operands(&mut self, operands: &[&Expr], span: Span) {
+        let parked = operands.iter().any(|operand| operand.uses_soro());
+        for operand in operands {
+            self.expression(operand);
+            if parked {
+                self.emit(Op::Leave);
+            }
+        }
+        if parked {
+            self.emit(Op::Take { count: operands.len(), span });
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal { value: Value::Null, .. } => {
+                self.emit(Op::Null);
+            },
+            Expr::Literal { value, .. } => {
+                let index = self.constant(value.clone());
+                self.emit(Op::Constant(index));
+            },
+            Expr::Grouping { expression, .. } => self.expression(expression),
+            Expr::Unary { operator, right, .. } => {
+                self.expression(right);
+                self.emit(Op::Unary(operator.typ.clone()));
+            },
+            Expr::Binary { left, operator, right, span } => {
+                self.expression(left);
+                if right.uses_soro() {
+                    self.emit(Op::Leave);
+                    self.expression(right);
+                    self.emit(Op::Leave);
+                    self.emit(Op::Take { count: 2, span: *span });
+                } else {
+                    self.expression(right);
+                }
+                self.emit(Op::Binary { operator: operator.typ.clone(), span: *span });
+            },
+            Expr::Ternary { condition, then, els, .. } => {
+                self.expression(condition);
+                let skip_then = self.emit(Op::JumpIfFalse(0));
+                self.expression(then);
+                let skip_els = self.emit(Op::Jump(0));
+                self.patch(skip_then);
+                self.expression(els);
+                self.patch(skip_els);
+            },
+            Expr::Logical { left, operator, right, .. } => {
+                self.expression(left);
+                let jump = self.emit(if operator.typ == TokenType::Or { Op::Or(0) } else { Op::And(0) });
+                self.expression(right);
+                self.patch(jump);
+            },
+            Expr::Variable { name, .. } => {
+                let op = match self.resolve(name) {
+                    Variable::Global(index) => Op::GetGlobal(index),
+                    Variable::Local(Storage::Slot(slot)) => Op::GetLocal(slot),
+                    Variable::Local(Storage::Cell(cell)) => Op::GetCell(cell),
+                    Variable::Capture(index) => Op::GetCapture(index)
+                };
+                self.emit(op);
+            },
+            Expr::Assign { name, value, .. } => {
+                self.expression(value);
+                let op = match self.resolve(name) {
+                    Variable::Global(index) => Op::SetGlobal(index),
+                    Variable::Local(Storage::Slot(slot)) => Op::SetLocal(slot),
+                    Variable::Local(Storage::Cell(cell)) => Op::SetCell(cell),
+                    Variable::Capture(index) => Op::SetCapture(index)
+                };
+                self.emit(op);
+            },
+            Expr::Call { callee, paren, arguments, .. } => {
+                self.expression(callee);
+                let check = self.emit(Op::Callee { arguments: arguments.len(), span: paren.span(), skip: 0 });
+                self.operands(&arguments.iter().collect::<Vec<_>>(), paren.span());
+                self.emit(Op::Call { arguments: arguments.len(), span: paren.span() });
+                self.patch(check);
+            },
+            Expr::List { elements, span } => {
+                self.operands(&elements.iter().collect::<Vec<_>>(), *span);
+                self.emit(Op::List(elements.len()));
+            },
+            Expr::Tuple { elements, span } => {
+                self.operands(&elements.iter().collect::<Vec<_>>(), *span);
+                self.emit(Op::Tuple(elements.len()));
+            },
+            Expr::Map { entries, span } => {
+                self.operands(&entries.iter().flat_map(|(key, value)| [key, value]).collect::<Vec<_>>(), *span);
+                self.emit(Op::Map { entries: entries.len(), span: *span });
+            },
+            Expr::Index { object, index, span } => {
+                self.operands(&[object, index], *span);
+                self.emit(Op::GetIndex(*span));
+            },
+            Expr::Slice { object, start, end, span } => {
+                let operands: Vec<&Expr> = [Some(object), start.as_ref(), end.as_ref()].into_iter().flatten().map(Box::as_ref).collect();
+                self.operands(&operands, *span);
+                self.emit(Op::Slice { start: start.is_some(), end: end.is_some(), span: *span });
+            },
+            Expr::Instance { class, fields, span } => {
+                let operands: Vec<&Expr> = std::iter::once(class.as_ref()).chain(fields.iter().map(|(_, value)| value)).collect();
+                self.operands(&operands, *span);
+                let fields = fields.iter().map(|(name, _)| self.name(name)).collect();
+                self.emit(Op::Instance { fields, span: *span });
+            },
+            Expr::Get { object, name, span } => {
+                self.expression(object);
+                let name = self.name(name);
+                self.emit(Op::GetField { name, span: *span });
+            },
+            Expr::Set { object, name, value, span } => {
+                self.operands(&[object, value], *span);
+                let name = self.name(name);
+                self.emit(Op::SetField { name, span: *span });
+            },
+            Expr::SetIndex { object, index, value, span } => {
+                self.operands(&[object, index, value], *span);
+                self.emit(Op::SetIndex(*span));
+            },
+            Expr::Soro { span } => {
+                self.emit(Op::Soro(*span));
+            },
+            Expr::Depth { .. } => {
+                self.emit(Op::Depth);
+            }
+        }
+    }
+}
+
+type Cell = Rc<RefCell<Value>>;
+
+struct Frame {
+    function: Rc<Function>,
+    ip: usize,
+    /// Stack index of the first argument, the callee is just below.
+    base: usize,
+    cells: Vec<Cell>,
+    captures: Rc<[Cell]>,
+    /// Size of the operand stack when called, what the body leaves above is dropped on return.
+    operands: usize,
+    /// Set when `!=` calls `__eq__`, whose result is negated on return.
+    negated: bool
+}
+
+impl Frame {
+    fn new(function: Rc<Function>, base: usize, captures: Rc<[Cell]>, operands: usize) -> Self {
+        let cells = (0..function.cells).map(|_| Rc::new(RefCell::new(Value::Null))).collect();
+        Frame { function, ip: 0, base, cells, captures, operands, negated: false }
+    }
+}
+
+/// Runs a compiled program, its globals and natives in `global_env`.
+pub fn run(script: Rc<Function>, env_arena: &mut EnvironmentArena, global_env: usize) {
+    let mut stack: Vec<Value> = vec![];
+    let mut callers: Vec<Frame> = vec![];
+    let mut frame = Frame::new(script, 0, Rc::new([]), env_arena.stack.len());
+
+    // a runtime error stops the program where it happened, like in the interpreter
+    while !env_arena.failed() {
+        let op = &frame.function.code[frame.ip];
+        frame.ip += 1;
+
+        match op {
+            Op::Null => stack.push(Value::Null),
+            Op::Constant(index) => stack.push(frame.function.constants[*index].clone()),
+            Op::Pop => {
+                stack.pop();
+            },
+            Op::PopN(n) => stack.truncate(stack.len() - n),
+            Op::GetGlobal(index) => {
+                let value = match env_arena.get(global_env, &frame.function.names[*index]) {
+                    Ok(value) => value.clone(),
+                    Err(e) => {
+                        env_arena.report(e);
+                        Value::Null
+                    }
+                };
+                stack.push(value);
+            },
+            Op::SetGlobal(index) => {
+                if let Err(e) = env_arena.assign(global_env, &frame.function.names[*index], top(&stack).clone()) {
+                    env_arena.report(e);
+                }
+            },
+            Op::DefineGlobal(index) => env_arena.define(global_env, &frame.function.names[*index].lexeme, pop(&mut stack)),
+            Op::GetLocal(slot) => stack.push(stack[frame.base + slot].clone()),
+            Op::SetLocal(slot) => stack[frame.base + slot] = top(&stack).clone(),
+            Op::GetCell(cell) => stack.push(frame.cells[*cell].borrow().clone()),
+            Op::SetCell(cell) => *frame.cells[*cell].borrow_mut() = top(&stack).clone(),
+            Op::DefineCell(cell) => frame.cells[*cell] = Rc::new(RefCell::new(pop(&mut stack))),
+            Op::GetCapture(index) => stack.push(frame.captures[*index].borrow().clone()),
+            Op::SetCapture(index) => *frame.captures[*index].borrow_mut() = top(&stack).clone(),
+            Op::Binary { operator, span } => {
+                let r = pop(&mut stack);
+                let l = pop(&mut stack);
+                let Some((method, negated)) = overload(operator, &l) else {
+                    stack.push(apply_binary(operator, l, r));
+                    continue;
+                };
+
+                // called with the operands as arguments, like `Call` does
+                let Fun::Bytecode { function, captures } = method.clone() else { unreachable!("methods are compiled with their struct") };
+                if callers.len() >= MAX_CALL_DEPTH {
+                    env_arena.report(stack_overflow(&function.name, *span));
+                    stack.push(Value::Null);
+                    continue;
+                }
+                stack.extend([Value::Fun(method), l, r]);
+                let mut called = Frame::new(function, stack.len() - 2, captures, env_arena.stack.len());
+                called.negated = negated;
+                callers.push(mem::replace(&mut frame, called));
+            },
+            Op::Unary(operator) => {
+                let r = pop(&mut stack);
+                stack.push(apply_unary(operator, r));
+            },
+            Op::Jump(target) => frame.ip = *target,
+            Op::JumpIfFalse(target) => if !pop(&mut stack).is_truthy() {
+                frame.ip = *target;
+            },
+            Op::JumpIfTrue(target) => if pop(&mut stack).is_truthy() {
+                frame.ip = *target;
+            },
+            Op::And(target) => if top(&stack).is_truthy() {
+                stack.pop();
+            } else {
+                frame.ip = *target;
+            },
+            Op::Or(target) => if top(&stack).is_truthy() {
+                frame.ip = *target;
+            } else {
+                stack.pop();
+            },
+            Op::Callee { arguments, span, skip } => {
+                let message = match top(&stack) {
+                    Value::Fun(fun) => fun.arity().filter(|arity| arity != arguments).map(|arity| format!("Expected {} arguments, but found {}.", arity, arguments)),
+                    _ => Some("Can only call functions.".into())
+                };
+                if let Some(message) = message {
+                    env_arena.report(CeyaError::Runtime { span: *span, message });
+                    stack.pop();
+                    stack.push(Value::Null);
+                    frame.ip = *skip;
+                }
+            },
+            Op::Call { arguments, span } => {
+                let base = stack.len() - arguments;
+                let Value::Fun(fun) = stack[base - 1].clone() else { unreachable!("checked by Callee") };
+                match fun {
+                    Fun::Bytecode { function, captures } => {
+                        if callers.len() >= MAX_CALL_DEPTH {
+                            env_arena.report(stack_overflow(&function.name, *span));
+                            stack.truncate(base - 1);
+                            stack.push(Value::Null);
+                            continue;
+                        }
+                        let operands = env_arena.stack.len();
+                        callers.push(mem::replace(&mut frame, Frame::new(function, base, captures, operands)));
+                    },
+                    fun => {
+                        let arguments = stack.split_off(base);
+                        stack.pop();
+                        stack.push(fun.call(arguments, *span, env_arena));
+                    }
+                }
+            },
+            Op::Closure(index) => {
+                let function = Rc::clone(&frame.function.functions[*index]);
+                let captures = function.captures.iter().map(|capture| match capture {
+                    Capture::Cell(cell) => Rc::clone(&frame.cells[*cell]),
+                    Capture::Capture(index) => Rc::clone(&frame.captures[*index])
+                }).collect();
+                stack.push(Value::Fun(Fun::Bytecode { function, captures }));
+            },
+            Op::Return => {
+                let mut value = pop(&mut stack);
+                if frame.negated {
+                    value = Value::Boolean(!value.is_truthy());
+                }
+                let Some(caller) = callers.pop() else { return };
+                stack.truncate(frame.base - 1);
+                env_arena.stack.truncate(frame.operands);
+                stack.push(value);
+                frame = caller;
+            },
+            Op::Template { span, skip } => if !matches!(top(&stack), Value::String(_)) {
+                env_arena.report(CeyaError::Runtime { span: *span, message: "Formatted print expects a string template.".into() });
+                stack.pop();
+                frame.ip = *skip;
+            },
+            Op::Print { arguments, stderr, span } => {
+                let line = if *arguments == 0 {
+                    pop(&mut stack).to_string()
+                } else {
+                    let arguments = stack.split_off(stack.len() - arguments);
+                    let Value::String(template) = pop(&mut stack) else { unreachable!("checked by Template") };
+                    match stdlib::format_template(&template, &arguments) {
+                        Ok(line) => line,
+                        Err(message) => {
+                            env_arena.report(CeyaError::Runtime { span: *span, message });
+                            continue;
+                        }
+                    }
+                };
+
+                if *stderr {
+                    env_arena.eprint(&line);
+                } else {
+                    env_arena.print(&line);
+                }
+            },
+            Op::Leave => env_arena.stack.push(pop(&mut stack)),
+            Op::Take { count, span } => stack.extend(take_operands(env_arena, *count, *span)),
+            Op::Soro(span) => stack.push(pop_operand(env_arena, *span)),
+            Op::Depth => stack.push(Value::Number(env_arena.stack.len() as f64)),
+            Op::List(count) => {
+                let elements = stack.split_off(stack.len() - count);
+                stack.push(Value::List(Rc::new(RefCell::new(elements))));
+            },
+            Op::Methods(count) => {
+                let methods = stack.split_off(stack.len() - count).into_iter().map(|method| match method {
+                    Value::Fun(method) => method,
+                    _ => unreachable!("closures of the methods")
+                }).collect();
+                let Value::Struct(class) = pop(&mut stack) else { unreachable!("the struct of the methods") };
+                stack.push(Value::Struct(Rc::new(Struct { name: class.name.clone(), fields: class.fields.clone(), methods })));
+            },
+            Op::Tuple(count) => {
+                let values = stack.split_off(stack.len() - count);
+                stack.push(Value::Tuple(values.into()));
+            },
+            Op::Unpack { count, span } => {
+                let values = unpack(pop(&mut stack), *count, *span).unwrap_or_else(|e| {
+                    env_arena.report(e);
+                    vec![Value::Null; *count]
+                });
+                env_arena.stack.extend(values.into_iter().rev());
+            },
+            Op::Map { entries, span } => {
+                let values = stack.split_off(stack.len() - 2 * entries);
+                stack.push(Map::from_pairs(values, *span).map_or_else(|e| {
+                    env_arena.report(e);
+                    Value::Null
+                }, |map| Value::Map(Rc::new(RefCell::new(map)))));
+            },
+            Op::GetIndex(span) => {
+                let index = pop(&mut stack);
+                let object = pop(&mut stack);
+                stack.push(get_index(object, index, *span).unwrap_or_else(|e| {
+                    env_arena.report(e);
+                    Value::Null
+                }));
+            },
+            Op::Slice { start, end, span } => {
+                let end = end.then(|| pop(&mut stack));
+                let start = start.then(|| pop(&mut stack));
+                let object = pop(&mut stack);
+                stack.push(slice(object, start, end, *span).unwrap_or_else(|e| {
+                    env_arena.report(e);
+                    Value::Null
+                }));
+            },
+            Op::Instance { fields, span } => {
+                let values = stack.split_off(stack.len() - fields.len());
+                let class = pop(&mut stack);
+                let fields = fields.iter().map(|name| frame.function.names[*name].lexeme.as_str()).zip(values).collect();
+                stack.push(instantiate(class, fields, *span).unwrap_or_else(|e| {
+                    env_arena.report(e);
+                    Value::Null
+                }));
+            },
+            Op::GetField { name, span } => {
+                let object = pop(&mut stack);
+                stack.push(get_field(object, &frame.function.names[*name].lexeme, *span).unwrap_or_else(|e| {
+                    env_arena.report(e);
+                    Value::Null
+                }));
+            },
+            Op::SetField { name, span } => {
+                let value = pop(&mut stack);
+                let object = pop(&mut stack);
+                stack.push(set_field(object, &frame.function.names[*name].lexeme, value, *span).unwrap_or_else(|e| {
+                    env_arena.report(e);
+                    Value::Null
+                }));
+            },
+            Op::SetIndex(span) => {
+                let value = pop(&mut stack);
+                let index = pop(&mut stack);
+                let object = pop(&mut stack);
+                stack.push(set_index(object, index, value, *span).unwrap_or_else(|e| {
+                    env_arena.report(e);
+                    Value::Null
+                }));
+            },
+            Op::Word { word, span } => stack_word(word, env_arena, *span)
+        }
+    }
+}
+
+fn top(stack: &[Value]) -> &Value {
+    stack.last().expect("value on the stack")
+}
+
+fn pop(stack: &mut Vec<Value>) -> Value {
+    stack.pop().expect("value on the stack")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ast::Stmt, environment::EnvironmentArena, parser, scanner, stdlib};
+
+    use super::Op;
+
+    fn run(source: &str) -> String {
+        let mut env_arena = EnvironmentArena::capturing();
+        let global_env = env_arena.add(None);
+        stdlib::define_natives(&mut env_arena, global_env);
+        let stmts: Vec<Stmt> = parser::parse(scanner::scan(source)).unwrap().into_iter().map(Stmt::eliminate_branches).collect();
+        super::run(super::compile(&stmts), &mut env_arena, global_env);
+        env_arena.output.unwrap_or_default()
+    }
+
+    /// The tree-walking interpreter without the checks of `crate::run`, to compare how both handle errors at runtime.
+    fn interpret(source: &str) -> String {
+        let mut env_arena = EnvironmentArena::capturing();
+        let global_env = env_arena.add(None);
+        stdlib::define_natives(&mut env_arena, global_env);
+        for stmt in parser::parse(scanner::scan(source)).unwrap() {
+            stmt.eliminate_branches().execute(&mut env_arena, global_env);
+        }
+        env_arena.output.unwrap_or_default()
+    }
+
+    #[test]
+    fn test_same_output_as_interpreter() {
+        let sources = [
+            "let total = 0; for (let i = 0; i < 10; i = i + 1) { if (i == 3) continue; if (i == 8) break; total = total + i; } print total;",
+            "fn fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(15);",
+            "{ let x = \"a\"; let y = x + \"b\"; print y; let x = 1; print x; } print 1 or 2; print fu and 2; print !fu;",
+            "print \"{} and {}\", 1, to_hex(255); print 5, 1; print nope; print nope = 1;",
+            "fn f(a) { return a; } print f(1, 2); print 3(1); fn deep(n) { return deep(n + 1); } print deep(0);",
+            "let i = 0; while (true) { i = i + 1; if (i > 4) break; } print i; while (false) print 1;",
+            "100; ke; while (soro > 97) { ke; print soro; soro - 1; ke; } print depth; 1; 2; 3; rot; swap; over; print soro - soro * soro; drop2; print soro;",
+            "5; print 1 - soro; fn sub(a, b) { 7; return a - b; } 10; print sub(1, soro); print depth + depth; faran; print soro;",
+            "let n = 3; print n > 2 ? \"big\" : \"small\"; print n < 2 ? 1 : n < 4 ? 2 : 3; let a = 0; true ? a = 1 : (a = 2); print a;",
+            "let l = [1, [2, 3], \"a\"]; l[1][0] = l[0] + 5; push(l, len(l)); print l; print l[5]; print 1[0]; 8; print [soro, l[2]]; let m = l; m[0] = 0; print l[0];",
+            "let m = {\"a\": 1, 2: [3], true: {}}; m[\"a\"] = m[2]; print m; print m[\"b\"]; print m[[]]; 9; print {soro: len(m)}; print {1: 2}[fu];",
+            "let s = \"héllo\"; print s[1] + s[2..] + s[..1]; print s[9]; print [1, 2, 3][..2]; 4; print s[soro - 3..]; print s[1..0];",
+            "struct P { x, y } let p = P { x: 1 }; p.y = p; print p.y.x; print p.z; 5; print P { x: soro }; { struct Q { a } print Q { a: [Q {}] }; }",
+            "fn pair(a) { return a, a + 1; } let (x, y) = pair(1); print x + y; fn f() { let (a, b) = pair(5); fn g() { return a; } print g() + b; 7; let (c, d) = pair(soro); return d, c; } print f(); print pair(1) == pair(1); let (e, h) = 1; print e;",
+            "struct V { x, fn __add__(a, b) { return V { x: a.x + b.x }; } fn __eq__(a, b) { return a.x == b.x; } } let v = V { x: 1 } + V { x: 2 }; print v; print v != V { x: 3 }; 4; print V { x: soro } + v; print v * 2;"
+        ];
+        for source in sources {
+            assert_eq!(run(source), interpret(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_closures() {
+        let counter = "fn counter() { let count = 0; fn inc() { count = count + 1; return count; } return inc; }";
+        assert_eq!(run(&format!("{} let c = counter(); c(); print c(); print counter()();", counter)), "2\n1\n");
+        // each iteration gets its own `j`, and a function declared in a block can recurse
+        assert_eq!(run("let g = fu; { let i = 0; while (i < 3) { let j = i; fn f() { return j; } if (i == 1) g = f; i = i + 1; } } print g();"), "1\n");
+        assert_eq!(run("{ fn down(n) { if (n > 0) return down(n - 1) + 1; return 0; } print down(5); }"), "5\n");
+        assert_eq!(run("fn outer(a) { fn mid() { fn inner() { return a; } return inner; } return mid()(); } print outer(42);"), "42\n");
+    }
+
+    #[test]
+    fn test_locals_in_slots() {
+        let stmts = parser::parse(scanner::scan("{ let a = 1; let b = a; }")).unwrap();
+        let script = super::compile(&stmts);
+        assert_eq!(script.code, [Op::Constant(0), Op::GetLocal(0), Op::PopN(2), Op::Null, Op::Return]);
+        assert_eq!(script.cells, 0);
+
+        // captured, the local lives in a cell instead
+        let stmts = parser::parse(scanner::scan("{ let a = 1; fn f() { return a; } }")).unwrap();
+        let script = super::compile(&stmts);
+        assert_eq!(script.code[..2], [Op::Constant(0), Op::DefineCell(0)]);
+        assert_eq!(script.functions[0].captures, [super::Capture::Cell(0)]);
+    }
+}
@@ -0,0 +1,7 @@
+use wasm_bindgen::prelude::wasm_bindgen;
+
+/// Runs a program in the browser playground, returning what it printed.
+#[wasm_bindgen]
+pub fn run(source: &str) -> String {
+    crate::run(source)
+}
@@ -0,0 +1,26 @@
+//! `ceya difftest` end to end: it needs nasm and the platform's linker on the PATH, and passes by
+//! itself when nasm is missing.
+
+use std::process::Command;
+
+#[test]
+fn test_difftest() {
+    if Command::new("nasm").arg("-v").output().is_err() {
+        eprintln!("nasm not found, skipping the difftest run.");
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("ceya-difftest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // difftest has to pin what moves the executable away from where it looks
+    let other = if cfg!(windows) { "linux" } else { "windows" };
+    std::fs::write(dir.join("ceya.toml"), format!("[build]\ntarget = \"{}\"\nrelease = true\n", other)).unwrap();
+    std::fs::write(dir.join("main.ceya"), "print 1 + 2;\nprint \"text\";\nprint 3 < 4;\n7;\nke;\nprint soro * soro;\nlet x = 10;\nwhile (x > 8) {\n    print x;\n    x = x - 1;\n}\nprint (0 - 6) / 2;\nif (0 - 1 < 0) print 5;\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ceya")).arg("difftest").arg("main.ceya").current_dir(&dir).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("OK: sim and com agree."));
+}